@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// 時間のかかる処理をワーカースレッドで実行し、結果をキャッシュする
+/// timeout 以内に終わらなければ None を返し、処理は裏で続ける (次回の呼び出しで結果を受け取る)
+pub struct AsyncLookup<V> {
+    ttl: Duration,
+    done: HashMap<String, (Instant, V)>,
+    pending: HashMap<String, Receiver<V>>,
+}
+
+impl<V: Clone + Send + 'static> AsyncLookup<V> {
+    /// ttl: 取得済みの結果を使い回す時間
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            done: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// key の結果を返す。キャッシュが無ければ f をワーカースレッドで実行し、最大 timeout だけ待つ
+    pub fn get_or_spawn<F>(&mut self, key: &str, timeout: Duration, f: F) -> Option<V>
+    where
+        F: FnOnce() -> V + Send + 'static,
+    {
+        if let Some((at, value)) = self.done.get(key)
+            && at.elapsed() < self.ttl
+        {
+            return Some(value.clone());
+        }
+
+        let rx = match self.pending.remove(key) {
+            Some(rx) => rx,
+            None => {
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    let _ = tx.send(f());
+                });
+                rx
+            }
+        };
+
+        match rx.recv_timeout(timeout) {
+            Ok(value) => {
+                self.done
+                    .insert(key.to_string(), (Instant::now(), value.clone()));
+                Some(value)
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // 計算中: 次回の呼び出しで結果を受け取る
+                self.pending.insert(key.to_string(), rx);
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    #[test]
+    fn test_get_or_spawn_caches_result() {
+        let mut lookup = AsyncLookup::new(Duration::from_secs(60));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..3 {
+            let calls = calls.clone();
+            let value = lookup.get_or_spawn("key", Duration::from_secs(5), move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                42
+            });
+            assert_eq!(value, Some(42));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_get_or_spawn_timeout_then_ready() {
+        let mut lookup = AsyncLookup::new(Duration::from_secs(60));
+        let timeout = Duration::from_millis(10);
+        let slow = || {
+            thread::sleep(Duration::from_millis(100));
+            "done"
+        };
+
+        // 時間内に終わらなければ None を返してブロックしない
+        assert_eq!(lookup.get_or_spawn("slow", timeout, slow), None);
+
+        // 裏で終わった結果は次回の呼び出しで受け取れる (再実行はしない)
+        thread::sleep(Duration::from_millis(200));
+        let value = lookup.get_or_spawn("slow", timeout, || panic!("should not run twice"));
+        assert_eq!(value, Some("done"));
+    }
+}
@@ -0,0 +1,419 @@
+use crate::parser::{TargetContext, env_var_name, find_closing_paren};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// 起動時の引数を解釈した結果
+#[derive(Debug, Default, PartialEq)]
+pub struct CliArgs {
+    /// `--resolve-paths`: 相対パスらしき引数を起動時のディレクトリ基準の絶対パスにする
+    pub resolve_paths: bool,
+    /// `--quiet` / `-q`: 実行後の要約行や終了時のサマリを表示しない
+    pub quiet: bool,
+    /// `--no-expand`: コンテキストの引数の `$VAR` / `$(...)` を展開しない
+    pub no_expand: bool,
+    /// `--record <path>`: 入力・出力・時刻を JSON で記録する
+    pub record: Option<PathBuf>,
+    /// `--log <path>`: 入力・出力・終了コードを時刻付きでファイルへ追記する
+    pub log: Option<PathBuf>,
+    /// `--replay <path>`: 記録を再生して終了する
+    pub replay: Option<PathBuf>,
+    /// `--prompt-command <cmd>`: コマンドの標準出力をプロンプトにする (設定の prompt.command より優先)
+    pub prompt_command: Option<String>,
+    /// `--print-stack`: 親の with から受け取ったコンテキストのスタックを表示して終了する
+    pub print_stack: bool,
+    pub context: Option<TargetContext>,
+    /// 起動は続けられるが利用者に知らせたいこと (main が標準エラー出力に表示する)
+    pub warnings: Vec<String>,
+}
+
+/// `KEY=VALUE` 形式の環境変数指定を分解する純粋関数
+fn parse_env_assignment(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid --env '{}': expected KEY=VALUE", spec)),
+    }
+}
+
+/// 起動時の引数 (プログラム名を除く) を解釈する純粋関数
+/// コンテキストより前にある `--resolve-paths` などを with 自身のオプションとして扱い、`--` 以降は全てコンテキストとみなす
+pub fn parse_cli_args(args: &[String]) -> Result<CliArgs, String> {
+    let mut cli = CliArgs::default();
+    let mut env = Vec::new();
+    let mut rest = args;
+
+    while let Some(first) = rest.first() {
+        match first.as_str() {
+            "--resolve-paths" => cli.resolve_paths = true,
+            "--quiet" | "-q" => cli.quiet = true,
+            "--no-expand" => cli.no_expand = true,
+            "--print-stack" => cli.print_stack = true,
+            "--record" | "--replay" | "--log" => {
+                let path = rest
+                    .get(1)
+                    .ok_or_else(|| format!("{} requires a file path", first))?;
+                let path = Some(PathBuf::from(path));
+                match first.as_str() {
+                    "--record" => cli.record = path,
+                    "--log" => cli.log = path,
+                    _ => cli.replay = path,
+                }
+                rest = &rest[1..];
+            }
+            "--prompt-command" => {
+                let command = rest.get(1).ok_or("--prompt-command requires a command")?;
+                cli.prompt_command = Some(command.clone());
+                rest = &rest[1..];
+            }
+            "--env" => {
+                let spec = rest.get(1).ok_or("--env requires KEY=VALUE")?;
+                env.push(parse_env_assignment(spec)?);
+                rest = &rest[1..];
+            }
+            arg if arg.starts_with("--env=") => {
+                env.push(parse_env_assignment(&arg["--env=".len()..])?);
+            }
+            "--" => {
+                rest = &rest[1..];
+                break;
+            }
+            _ => break,
+        }
+        rest = &rest[1..];
+    }
+
+    let mut joined_args = rest.join(" ");
+    if !cli.no_expand {
+        joined_args = protect_substitutions(&joined_args);
+    }
+    // 閉じていないクォートがあれば分割し直さず、シェルが分けた引数をそのまま使う
+    let split_args = match shell_words::split(&joined_args) {
+        Ok(split) => split,
+        Err(e) => {
+            cli.warnings.push(format!(
+                "could not split the context arguments ({}); using them as given",
+                e
+            ));
+            rest.to_vec()
+        }
+    };
+    // `with ""` や `with " "` は指定し忘れと区別できないので、コンテキストなしで起動することを知らせる
+    if split_args.is_empty() && !rest.is_empty() {
+        cli.warnings
+            .push("empty context given; starting without a context".to_string());
+    }
+    if let Some((program, args)) = split_args.split_first() {
+        cli.context = Some(TargetContext {
+            program: program.clone(),
+            args: args.to_vec(),
+            env,
+        });
+    } else if !env.is_empty() {
+        return Err("--env requires a context command".to_string());
+    }
+    Ok(cli)
+}
+
+/// `$(...)` を空白で分割されないようにシングルクォートで囲む純粋関数
+/// 中身は分割後の引数に `$(...)` のまま残り、expand_context_args で展開する
+fn protect_substitutions(joined: &str) -> String {
+    let mut out = String::with_capacity(joined.len());
+    let mut rest = joined;
+    while let Some(start) = rest.find("$(") {
+        let Some(end) = find_closing_paren(&rest[start + 2..]) else {
+            break;
+        };
+        let end = start + 2 + end + 1;
+        out.push_str(&rest[..start]);
+        out.push_str(&shell_words::quote(&rest[start..end]));
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `$(...)` の中身をシェルで実行し、標準出力 (末尾の改行を除く) を返す
+pub fn run_substitution(command: &str) -> Result<String, String> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let output = Command::new(shell)
+        .args([flag, command])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("'{}' failed ({})", command, output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches(['\n', '\r'])
+        .to_string())
+}
+
+/// 1つの引数の `$VAR` / `${VAR}` / `$(...)` を展開する
+/// 未定義の変数・閉じていない `$(`・失敗したコマンドは Err にする
+fn expand_context_arg(
+    arg: &str,
+    lookup: &impl Fn(&str) -> Option<String>,
+    run: &impl Fn(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut out = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(inner) = after.strip_prefix('(') {
+            let end = find_closing_paren(inner).ok_or("unclosed '$('")?;
+            out.push_str(&run(&inner[..end])?);
+            rest = &inner[end + 1..];
+        } else if let Some((name, len)) = env_var_name(after) {
+            let value = lookup(name).ok_or_else(|| format!("undefined variable '{}'", name))?;
+            out.push_str(&value);
+            rest = &after[len..];
+        } else {
+            out.push('$');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// コンテキストのプログラム名と引数を起動時に一度だけ展開し、警告の一覧を返す
+/// 展開できなかった引数は元の文字列のまま残す
+pub fn expand_context_args(
+    ctx: &mut TargetContext,
+    run: impl Fn(&str) -> Result<String, String>,
+) -> Vec<String> {
+    let lookup = |name: &str| env::var(name).ok();
+    let mut warnings = Vec::new();
+    for arg in std::iter::once(&mut ctx.program).chain(ctx.args.iter_mut()) {
+        if !arg.contains('$') {
+            continue;
+        }
+        match expand_context_arg(arg, &lookup, &run) {
+            Ok(expanded) => *arg = expanded,
+            Err(msg) => warnings.push(format!("failed to expand '{}': {} (kept as is)", arg, msg)),
+        }
+    }
+    warnings
+}
+
+/// 引数が相対パスなら base 基準の絶対パスに解決する
+/// 誤解決を避けるため、"./" "../" で始まり、実際に存在するものだけを対象にする
+fn resolve_relative_arg(arg: &str, base: &Path) -> Option<PathBuf> {
+    let is_relative = ["./", "../", ".\\", "..\\"]
+        .iter()
+        .any(|prefix| arg.starts_with(prefix));
+    if !is_relative {
+        return None;
+    }
+    // "./" は付けずに結合する (例: "/work/./run.sh" ではなく "/work/run.sh")
+    let trimmed = arg
+        .strip_prefix("./")
+        .or_else(|| arg.strip_prefix(".\\"))
+        .unwrap_or(arg);
+    let path = base.join(trimmed);
+    path.exists().then_some(path)
+}
+
+/// コンテキストのプログラム名と引数のうち、相対パスらしきものを解決する
+pub fn resolve_context_paths(ctx: &mut TargetContext, base: &Path) {
+    for arg in std::iter::once(&mut ctx.program).chain(ctx.args.iter_mut()) {
+        if let Some(path) = resolve_relative_arg(arg, base) {
+            *arg = path.to_string_lossy().to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_cli_args_context() {
+        let cli = parse_cli_args(&strings(&["git", "log"])).unwrap();
+        assert!(!cli.resolve_paths);
+        let ctx = cli.context.unwrap();
+        assert_eq!(ctx.program, "git");
+        assert_eq!(ctx.args, vec!["log"]);
+
+        assert_eq!(parse_cli_args(&[]).unwrap().context, None);
+    }
+
+    #[test]
+    fn test_parse_cli_args_flags() {
+        let cli = parse_cli_args(&strings(&["--resolve-paths", "./run.sh", "dev"])).unwrap();
+        assert!(cli.resolve_paths);
+        assert_eq!(cli.context.unwrap().program, "./run.sh");
+
+        // "--" 以降とコンテキストの後ろはコンテキストの引数
+        let cli = parse_cli_args(&strings(&["--", "--resolve-paths"])).unwrap();
+        assert!(!cli.resolve_paths);
+        assert_eq!(cli.context.unwrap().program, "--resolve-paths");
+
+        let cli = parse_cli_args(&strings(&["npm", "--resolve-paths"])).unwrap();
+        assert!(!cli.resolve_paths);
+
+        let cli = parse_cli_args(&strings(&["-q", "git"])).unwrap();
+        assert!(cli.quiet);
+        assert_eq!(cli.context.unwrap().program, "git");
+
+        let cli = parse_cli_args(&strings(&["--record", "session.json", "git"])).unwrap();
+        assert_eq!(cli.record, Some(PathBuf::from("session.json")));
+        assert_eq!(cli.context.unwrap().program, "git");
+
+        let cli = parse_cli_args(&strings(&["--replay", "session.json"])).unwrap();
+        assert_eq!(cli.replay, Some(PathBuf::from("session.json")));
+        assert_eq!(cli.context, None);
+        assert!(parse_cli_args(&strings(&["--record"])).is_err());
+
+        let cli = parse_cli_args(&strings(&["--log", "with.log", "git"])).unwrap();
+        assert_eq!(cli.log, Some(PathBuf::from("with.log")));
+        assert_eq!(cli.context.unwrap().program, "git");
+        assert!(parse_cli_args(&strings(&["--log"])).is_err());
+
+        let cli =
+            parse_cli_args(&strings(&["--prompt-command", "starship prompt", "git"])).unwrap();
+        assert_eq!(cli.prompt_command.as_deref(), Some("starship prompt"));
+        assert_eq!(cli.context.unwrap().program, "git");
+        assert!(parse_cli_args(&strings(&["--prompt-command"])).is_err());
+
+        let cli = parse_cli_args(&strings(&["--print-stack"])).unwrap();
+        assert!(cli.print_stack);
+        assert_eq!(cli.context, None);
+    }
+
+    #[test]
+    fn test_parse_cli_args_warns_on_blank_or_unsplittable_context() {
+        // 空白だけ・空文字はコンテキストなし (案内を出す)
+        for args in [&[" "][..], &[""], &["", "  "]] {
+            let cli = parse_cli_args(&strings(args)).unwrap();
+            assert_eq!(cli.context, None);
+            assert_eq!(
+                cli.warnings,
+                vec!["empty context given; starting without a context"]
+            );
+        }
+        assert!(parse_cli_args(&[]).unwrap().warnings.is_empty());
+        assert!(parse_cli_args(&strings(&["--env", "A=1", " "])).is_err());
+
+        // 閉じていないクォートは警告して、引数をそのまま使う
+        let cli = parse_cli_args(&strings(&["git", "commit", "-m", "it's"])).unwrap();
+        let ctx = cli.context.unwrap();
+        assert_eq!(ctx.program, "git");
+        assert_eq!(ctx.args, vec!["commit", "-m", "it's"]);
+        assert_eq!(cli.warnings.len(), 1);
+        assert!(cli.warnings[0].starts_with("could not split the context arguments"));
+    }
+
+    #[test]
+    fn test_parse_cli_args_env() {
+        let cli = parse_cli_args(&strings(&[
+            "--env",
+            "RUST_LOG=debug",
+            "--env=A=b=c",
+            "cargo",
+            "run",
+        ]))
+        .unwrap();
+        let ctx = cli.context.unwrap();
+        assert_eq!(ctx.program, "cargo");
+        assert_eq!(
+            ctx.env,
+            vec![
+                ("RUST_LOG".to_string(), "debug".to_string()),
+                ("A".to_string(), "b=c".to_string()),
+            ]
+        );
+
+        // 不正な形式はエラー
+        assert!(parse_cli_args(&strings(&["--env", "RUST_LOG", "cargo"])).is_err());
+        assert!(parse_cli_args(&strings(&["--env", "=debug", "cargo"])).is_err());
+        assert!(parse_cli_args(&strings(&["--env"])).is_err());
+        assert!(parse_cli_args(&strings(&["--env", "A=1"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_cli_args_keeps_substitution_together() {
+        let cli = parse_cli_args(&strings(&[
+            "kubectl",
+            "--context",
+            "$(kubectl config view)",
+        ]))
+        .unwrap();
+        assert!(!cli.no_expand);
+        assert_eq!(
+            cli.context.unwrap().args,
+            vec!["--context", "$(kubectl config view)"]
+        );
+
+        // --no-expand なら従来どおり空白で分ける
+        let cli = parse_cli_args(&strings(&["--no-expand", "echo", "$(a b)"])).unwrap();
+        assert!(cli.no_expand);
+        assert_eq!(cli.context.unwrap().args, vec!["$(a", "b)"]);
+    }
+
+    #[test]
+    fn test_expand_context_arg() {
+        let lookup = |name: &str| (name == "USER").then(|| "alice".to_string());
+        let run = |command: &str| match command {
+            "kubectl config current-context" => Ok("prod".to_string()),
+            _ => Err("failed".to_string()),
+        };
+        assert_eq!(
+            expand_context_arg("--context=$(kubectl config current-context)", &lookup, &run),
+            Ok("--context=prod".to_string())
+        );
+        assert_eq!(
+            expand_context_arg("/home/${USER}/$USER.txt", &lookup, &run),
+            Ok("/home/alice/alice.txt".to_string())
+        );
+        assert_eq!(
+            expand_context_arg("$5 $", &lookup, &run),
+            Ok("$5 $".to_string())
+        );
+
+        assert!(expand_context_arg("$(false)", &lookup, &run).is_err());
+        assert!(expand_context_arg("$(unclosed", &lookup, &run).is_err());
+        assert!(expand_context_arg("$UNDEFINED", &lookup, &run).is_err());
+    }
+
+    #[test]
+    fn test_expand_context_args_keeps_failed_arg() {
+        let mut ctx = TargetContext {
+            program: "kubectl".to_string(),
+            args: strings(&["--context", "$(false)", "get"]),
+            ..Default::default()
+        };
+        let warnings = expand_context_args(&mut ctx, |_| Err("failed".to_string()));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(ctx.args, vec!["--context", "$(false)", "get"]);
+    }
+
+    #[test]
+    fn test_resolve_context_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("script.sh"), "").unwrap();
+
+        let mut ctx = TargetContext {
+            program: "./script.sh".to_string(),
+            args: strings(&["run", "./missing.txt", "src/lib.rs"]),
+            ..Default::default()
+        };
+        resolve_context_paths(&mut ctx, tmp.path());
+
+        assert_eq!(ctx.program, tmp.path().join("script.sh").to_string_lossy());
+        // 存在しないもの・"./" で始まらないものはそのまま
+        assert_eq!(ctx.args, strings(&["run", "./missing.txt", "src/lib.rs"]));
+    }
+}
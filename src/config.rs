@@ -0,0 +1,838 @@
+use crate::context::DirDisplay;
+use crate::prompt::ContextDisplay;
+use crate::stats::SubcommandOrder;
+use crate::subcommands::{self, CommandSpec, SubcommandTable};
+use crate::theme::Background;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+/// 設定ファイル (~/.withrc) の内容
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub prompt: PromptConfig,
+    pub summary: SummaryConfig,
+    pub title: TitleConfig,
+    pub terminal: TerminalConfig,
+    pub dir_env: DirEnvConfig,
+    pub cd: CdConfig,
+    pub completion: CompletionConfig,
+    pub jobs: JobsConfig,
+    pub context: ContextConfig,
+    pub git: GitConfig,
+    pub record: RecordConfig,
+    pub theme: ThemeConfig,
+    /// プログラムごとのコンテキスト定義 (例: [contexts.cargo])
+    pub contexts: BTreeMap<String, ContextDefinition>,
+    /// `cd @name` で移動できるディレクトリのブックマーク (例: work = "~/work")
+    pub bookmarks: BTreeMap<String, String>,
+    /// 複数のコマンドを順に実行するマクロ (例: [macros.deploy])
+    pub macros: BTreeMap<String, MacroDefinition>,
+    /// 補完・ハイライトに使うサブコマンドの追加定義 (例: make = ["build", "test"])
+    pub subcommands: BTreeMap<String, SubcommandDefinition>,
+}
+
+impl Config {
+    /// program のコンテキストに設定する環境変数 (定義が無ければ空)
+    pub fn context_env(&self, program: &str) -> Vec<(String, String)> {
+        self.contexts
+            .get(program)
+            .map(|def| def.env.clone().into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// program のコンテキストのサブコマンドテンプレート (定義が無ければ空)
+    pub fn context_templates(&self, program: &str) -> BTreeMap<String, String> {
+        self.contexts
+            .get(program)
+            .map(|def| def.templates.clone())
+            .unwrap_or_default()
+    }
+
+    /// 組み込みのサブコマンド定義に `[subcommands]` の定義をマージした表
+    pub fn subcommand_table(&self) -> SubcommandTable {
+        merged_subcommand_table(&self.subcommands)
+    }
+
+    /// `@name` または `@name/sub` をブックマークのパスに解決する (未登録なら None)
+    /// パス先頭の `~` はホームディレクトリに展開する
+    pub fn bookmark_path(&self, target: &str) -> Option<PathBuf> {
+        let target = target.strip_prefix('@')?;
+        let (name, rest) = target.split_once('/').unwrap_or((target, ""));
+        let path = self.bookmarks.get(name)?;
+        let base = match path.strip_prefix('~') {
+            Some(after) if after.is_empty() || after.starts_with('/') => {
+                env::home_dir()?.join(after.trim_start_matches('/'))
+            }
+            _ => PathBuf::from(path),
+        };
+        Some(if rest.is_empty() {
+            base
+        } else {
+            base.join(rest)
+        })
+    }
+
+    /// `@name` の name を前方一致で含むブックマークを `@name/sub` の形で返す (名前順)
+    /// bookmark_path で見つからないときに、移動先の候補を示すのに使う
+    pub fn bookmark_matches(&self, target: &str) -> Vec<String> {
+        let Some(target) = target.strip_prefix('@') else {
+            return vec![];
+        };
+        let (prefix, rest) = match target.split_once('/') {
+            Some((prefix, rest)) => (prefix, format!("/{}", rest)),
+            None => (target, String::new()),
+        };
+        self.bookmarks
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| format!("@{}{}", name, rest))
+            .collect()
+    }
+}
+
+/// プロンプトの見た目に関する設定
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PromptConfig {
+    /// ディレクトリ情報の開き括弧 (例: "(")
+    pub open: String,
+    /// ディレクトリ情報の閉じ括弧 (例: ")")
+    pub close: String,
+    /// dir と branch の区切り (例: ": ")
+    pub separator: String,
+    /// 入力位置を示すプロンプト記号 (例: "> ")
+    pub symbol: String,
+    /// プロンプトのテンプレート (例: "({dir}) [{kube}] {cmd}> ")
+    /// 未設定なら open/close/separator/symbol による標準の並びを使う (環境変数 WITH_PROMPT が優先)
+    pub format: Option<String>,
+    /// コンテキスト部分の表示方法 ("always" / "auto" / "icon")
+    pub context_display: ContextDisplay,
+    /// context_display = "icon" のときに表示するアイコン
+    pub context_icon: String,
+    /// ディレクトリ部分の表示方法 ("name" / "relative")
+    pub dir_display: DirDisplay,
+    /// 設定するとこのコマンドの標準出力をプロンプトにする (例: "starship prompt")
+    /// 失敗したときは内蔵のプロンプトを使う
+    pub command: Option<String>,
+    /// 未コミットの変更があるときにブランチ名の後ろに付ける印 (例: "*" で "main*")。空なら付けない
+    pub dirty_marker: String,
+    /// true ならブランチ名の後ろに追跡先より進んだ/遅れたコミット数を付ける (例: "main ↑2↓1")
+    pub ahead_behind: bool,
+    /// 直前のコマンドが失敗したときに終了コードの前に付ける記号 (例: "✗" で "✗1")。空なら表示しない
+    pub status_symbol: String,
+}
+
+impl Default for PromptConfig {
+    fn default() -> Self {
+        Self {
+            open: "(".to_string(),
+            close: ")".to_string(),
+            separator: ": ".to_string(),
+            symbol: "> ".to_string(),
+            format: None,
+            context_display: ContextDisplay::Always,
+            context_icon: "⚙".to_string(),
+            dir_display: DirDisplay::Name,
+            command: None,
+            dirty_marker: String::new(),
+            ahead_behind: false,
+            status_symbol: "✗".to_string(),
+        }
+    }
+}
+
+/// 実行後の要約行 (成否・所要時間) の設定
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SummaryConfig {
+    /// true なら外部コマンド実行後に要約行を表示する
+    pub enabled: bool,
+    /// true なら終了時に実行コマンド数・合計所要時間・失敗数を表示する
+    pub session: bool,
+}
+
+/// ターミナルタイトルの設定
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TitleConfig {
+    /// false ならターミナルタイトルを変更しない
+    pub enabled: bool,
+}
+
+impl Default for TitleConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// 端末への通知の設定
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TerminalConfig {
+    /// false なら起動時と cd 後に OSC 7 でカレントディレクトリを知らせない
+    pub report_cwd: bool,
+}
+
+impl Default for TerminalConfig {
+    fn default() -> Self {
+        Self { report_cwd: true }
+    }
+}
+
+/// 補完の設定
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CompletionConfig {
+    /// true なら未登録ツールのサブコマンドを `<tool> --help` の出力から学習する
+    pub learn_from_help: bool,
+    /// 拡張子で絞り込むファイル補完のルール (組み込みのルールに追加される)
+    pub extension_rules: Vec<ExtensionRule>,
+    /// ファイル走査や `--help` の実行を待つミリ秒 (過ぎたら候補なしで戻る。0 なら待ち続ける)
+    pub timeout_ms: u64,
+    /// サブコマンド候補の先頭に出す順序 ("recent" / "frequency")
+    pub subcommand_order: SubcommandOrder,
+    /// true ならサブコマンドを部分列でも補完する (`cmt` -> `commit`)
+    pub fuzzy: bool,
+    /// true なら `~/` で始まるパスの補完結果をホームディレクトリに展開したパスで置き換える
+    pub expand_tilde: bool,
+}
+
+impl Default for CompletionConfig {
+    fn default() -> Self {
+        Self {
+            learn_from_help: true,
+            extension_rules: vec![],
+            timeout_ms: 200,
+            subcommand_order: SubcommandOrder::Recent,
+            fuzzy: false,
+            expand_tilde: false,
+        }
+    }
+}
+
+/// 特定のサブコマンド/フラグの後でファイル候補を拡張子で絞り込むルール
+/// 例: command = "git", after = "apply", extensions = ["patch", "diff"]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ExtensionRule {
+    pub command: String,
+    /// 直前のトークン、またはサブコマンドがこれに一致したら適用する
+    pub after: String,
+    pub extensions: Vec<String>,
+}
+
+/// バックグラウンドジョブの設定
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// true なら終了時に残っているジョブへ SIGHUP を送る
+    pub hup_on_exit: bool,
+    /// この秒数を過ぎても終わらないコマンドに Ctrl+Z での停止を案内する (0 なら案内しない)
+    pub suspend_hint_secs: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            hup_on_exit: true,
+            suspend_hint_secs: 0,
+        }
+    }
+}
+
+/// `--record` の設定
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RecordConfig {
+    /// `--record` / `--log` で記録しない入力の正規表現 (例: ["(?i)token", "^export "])
+    pub exclude: Vec<String>,
+}
+
+/// コンテキスト適用の設定
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ContextConfig {
+    /// true なら内部コマンドと同名のサブコマンド (例: docker history) をコンテキスト側で実行する
+    pub prefer_subcommands: bool,
+    /// true なら入力引数をコンテキスト引数より前に置く (`with git log` で `-C dir` -> `git -C dir log`)
+    pub input_first: bool,
+    /// true なら未知のサブコマンドで失敗したときに近いサブコマンドを案内する (`commti` -> `commit`)
+    pub suggest_subcommands: bool,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        Self {
+            prefer_subcommands: false,
+            input_first: false,
+            suggest_subcommands: true,
+        }
+    }
+}
+
+/// 設定ファイルでのコンテキスト定義
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ContextDefinition {
+    /// このコンテキストで実行する全コマンドに設定する環境変数
+    pub env: BTreeMap<String, String>,
+    /// サブコマンドテンプレート (例: cm = "commit -m {}" で `cm "msg"` -> `commit -m "msg"`)
+    pub templates: BTreeMap<String, String>,
+}
+
+/// `[subcommands]` の1コマンド分の定義
+/// サブコマンドの一覧だけか、組み込み (subcommands.toml) と同じ形のテーブルで書ける
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum SubcommandDefinition {
+    /// 例: make = ["build", "test", "clean"]
+    List(Vec<String>),
+    /// 例: [subcommands.make] subcommands = [...] / aliases = [...] / nested
+    Spec(CommandSpec),
+}
+
+impl SubcommandDefinition {
+    fn to_spec(&self) -> CommandSpec {
+        match self {
+            SubcommandDefinition::List(subcommands) => CommandSpec {
+                subcommands: subcommands.clone(),
+                ..Default::default()
+            },
+            SubcommandDefinition::Spec(spec) => spec.clone(),
+        }
+    }
+}
+
+/// 組み込みのサブコマンド定義に defs をマージした表
+pub fn merged_subcommand_table(defs: &BTreeMap<String, SubcommandDefinition>) -> SubcommandTable {
+    let mut table = subcommands::builtin().clone();
+    table.extend(
+        defs.iter()
+            .map(|(name, def)| (name.clone(), def.to_spec()))
+            .collect(),
+    );
+    table
+}
+
+/// 設定ファイルでのマクロ定義
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MacroDefinition {
+    /// 順に実行するコマンド行 (入力したときと同じくコンテキストを適用する)
+    pub commands: Vec<String>,
+    /// true なら途中のコマンドが失敗しても残りを実行する (既定は中断)
+    pub keep_going: bool,
+}
+
+/// プロンプト用の git 情報取得の設定
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GitConfig {
+    /// git 情報の取得を打ち切るまでのミリ秒 (0 なら待ち続ける)
+    pub timeout_ms: u64,
+    /// git 情報を取得しないパス (このパス配下も対象)
+    pub skip_paths: Vec<String>,
+    /// true ならネットワークファイルシステム (NFS/SMB など) 上では取得しない
+    pub skip_network_fs: bool,
+    /// detached HEAD で表示するハッシュの長さ
+    pub abbrev_length: usize,
+}
+
+impl Default for GitConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 500,
+            skip_paths: vec![],
+            skip_network_fs: true,
+            abbrev_length: 7,
+        }
+    }
+}
+
+/// 配色の設定
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ThemeConfig {
+    /// 端末の背景 ("auto", "light", "dark")
+    pub background: Background,
+    /// true なら git 管理下かどうかでディレクトリの色を変える
+    pub git_dir_colors: bool,
+    /// git 管理下のディレクトリの色名 (例: "green")
+    pub dir_in_git: Option<String>,
+    /// git 管理外のディレクトリの色名 (既定は "gray")
+    pub dir_outside_git: Option<String>,
+    /// true ならブランチの状態 (clean/dirty/detached) でブランチの色を変える
+    pub branch_state_colors: bool,
+    /// 変更の無いブランチの色名 (既定は通常のブランチ色)
+    pub branch_clean: Option<String>,
+    /// 未コミットの変更があるブランチの色名 (既定は "yellow")
+    pub branch_dirty: Option<String>,
+    /// detached HEAD の色名 (既定は "red")
+    pub branch_detached: Option<String>,
+}
+
+/// cd コマンドの設定
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CdConfig {
+    /// true なら `cd dir1 dir2` のような余分な引数をエラーにする
+    pub strict: bool,
+}
+
+/// cd 時のディレクトリ固有初期化 (.with.dir) の設定
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DirEnvConfig {
+    /// false なら .with.dir を読み込まない
+    pub enabled: bool,
+    /// true ならディレクトリを出たときに環境変数・エイリアスを元に戻す
+    pub restore_on_leave: bool,
+}
+
+impl Default for DirEnvConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            restore_on_leave: true,
+        }
+    }
+}
+
+/// 設定ファイルのパスを返す (~/.withrc)
+pub fn config_path() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".withrc"))
+}
+
+/// 設定ファイルの文字列をパースする純粋関数
+pub fn parse_config(content: &str) -> Result<Config, String> {
+    toml::from_str(content).map_err(|e| e.to_string())
+}
+
+/// 設定ファイルを読み込む
+/// ファイルが無い場合はデフォルト、壊れている場合は警告を出してデフォルトを返す
+pub fn load_config() -> Config {
+    let Some(path) = config_path() else {
+        return Config::default();
+    };
+
+    let Ok(content) = fs::read_to_string(&path) else {
+        return Config::default();
+    };
+
+    match parse_config(&content) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Warning: failed to parse {}: {}", path.display(), e);
+            Config::default()
+        }
+    }
+}
+
+/// 設定ファイルを読み直す (`config edit` の後など)
+/// ファイルが無ければデフォルト、壊れていれば Err を返す
+pub fn reload_config(path: &Path) -> Result<Config, String> {
+    match fs::read_to_string(path) {
+        Ok(content) => parse_config(&content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// 設定を TOML のテーブルに変換する (未設定の Option は含まれない)
+fn to_table(config: &Config) -> Table {
+    Table::try_from(config).unwrap_or_default()
+}
+
+/// ドット区切りのキー (例: "prompt.symbol") で値を探す
+fn lookup<'a>(table: &'a Table, key: &str) -> Option<&'a Value> {
+    let mut parts = key.split('.');
+    let mut value = table.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_table()?.get(part)?;
+    }
+    Some(value)
+}
+
+/// ドット区切りのキーに値を入れる (途中のテーブルが無ければ作る)
+fn insert(table: &mut Table, key: &str, value: Value) -> Result<(), String> {
+    let (parents, last) = match key.rsplit_once('.') {
+        Some((parents, last)) => (parents.split('.').collect(), last),
+        None => (vec![], key),
+    };
+    let mut current = table;
+    for part in parents {
+        current = current
+            .entry(part)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("'{}' is not a table", part))?;
+    }
+    current.insert(last.to_string(), value);
+    Ok(())
+}
+
+/// 設定値を取り出す (`config get`)
+pub fn get_value(config: &Config, key: &str) -> Result<Value, String> {
+    lookup(&to_table(config), key)
+        .cloned()
+        .ok_or_else(|| format!("unknown or unset key '{}'", key))
+}
+
+/// 全ての設定値を (ドット区切りのキー, 値) の一覧にする (`config list`)
+/// テーブルは展開し、配列はそのまま1つの値として扱う
+pub fn list_values(config: &Config) -> Vec<(String, Value)> {
+    fn walk(prefix: &str, table: &Table, out: &mut Vec<(String, Value)>) {
+        for (key, value) in table {
+            let key = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            match value {
+                Value::Table(child) => walk(&key, child, out),
+                _ => out.push((key, value.clone())),
+            }
+        }
+    }
+    let mut values = Vec::new();
+    walk("", &to_table(config), &mut values);
+    values
+}
+
+/// key に raw を設定した新しい設定と、解釈した値を返す (`config set`)
+/// raw は TOML の値 (true / 10 / ["a"] など) として読み、型が合わなければ文字列として試す
+pub fn set_value(config: &Config, key: &str, raw: &str) -> Result<(Config, Value), String> {
+    let mut candidates: Vec<Value> = raw.parse().into_iter().collect();
+    if !matches!(candidates.first(), Some(Value::String(_))) {
+        candidates.push(Value::String(raw.to_string()));
+    }
+
+    let mut error = String::new();
+    for value in candidates {
+        let mut table = to_table(config);
+        insert(&mut table, key, value.clone())?;
+        match table.try_into::<Config>() {
+            // 未知のキーは読み飛ばされるので、変換し直した結果に残っているかで判定する
+            Ok(updated) if lookup(&to_table(&updated), key) == Some(&value) => {
+                return Ok((updated, value));
+            }
+            Ok(_) => return Err(format!("unknown key '{}'", key)),
+            Err(e) => error = e.to_string(),
+        }
+    }
+    Err(format!("invalid value for '{}': {}", key, error.trim()))
+}
+
+/// 変更した設定値を設定ファイルへ書き込む (`config save`)
+/// ファイルにある他の項目はそのまま残す (コメントは保持されない)
+pub fn save_values(path: &Path, values: &[(String, Value)]) -> Result<(), String> {
+    let mut table = read_table(path)?;
+    for (key, value) in values {
+        insert(&mut table, key, value.clone())?;
+    }
+    write_table(path, &table)
+}
+
+/// マクロを設定ファイルへ書き込む (definition が None なら削除する)
+pub fn save_macro(
+    path: &Path,
+    name: &str,
+    definition: Option<&MacroDefinition>,
+) -> Result<(), String> {
+    let mut table = read_table(path)?;
+    let macros = table
+        .entry("macros")
+        .or_insert_with(|| Value::Table(Table::new()))
+        .as_table_mut()
+        .ok_or("'macros' is not a table")?;
+    match definition {
+        Some(definition) => {
+            let value = Value::try_from(definition).map_err(|e| e.to_string())?;
+            macros.insert(name.to_string(), value);
+        }
+        None => {
+            macros.remove(name);
+        }
+    }
+    write_table(path, &table)
+}
+
+/// 設定ファイルを TOML のテーブルとして読む (ファイルが無ければ空)
+fn read_table(path: &Path) -> Result<Table, String> {
+    match fs::read_to_string(path) {
+        Ok(content) => content.parse().map_err(|e: toml::de::Error| e.to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Table::new()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn write_table(path: &Path, table: &Table) -> Result<(), String> {
+    let content = toml::to_string(table).map_err(|e| e.to_string())?;
+    fs::write(path, content).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_empty() {
+        // 空ファイルなら全てデフォルト
+        let config = parse_config("").unwrap();
+        assert_eq!(config.prompt.open, "(");
+        assert_eq!(config.prompt.close, ")");
+        assert_eq!(config.prompt.separator, ": ");
+        assert_eq!(config.prompt.symbol, "> ");
+        assert_eq!(config.prompt.context_display, ContextDisplay::Always);
+        assert_eq!(config.prompt.dir_display, DirDisplay::Name);
+        assert!(!config.summary.enabled);
+        assert!(config.title.enabled);
+        assert!(config.terminal.report_cwd);
+        assert!(config.dir_env.enabled);
+        assert!(config.dir_env.restore_on_leave);
+        assert!(!config.cd.strict);
+        assert!(config.completion.learn_from_help);
+        assert_eq!(config.completion.timeout_ms, 200);
+        assert_eq!(config.completion.subcommand_order, SubcommandOrder::Recent);
+        assert!(config.jobs.hup_on_exit);
+        assert_eq!(config.jobs.suspend_hint_secs, 0);
+        assert!(!config.context.prefer_subcommands);
+        assert!(config.context.suggest_subcommands);
+        assert!(!config.context.input_first);
+        assert_eq!(config.git.timeout_ms, 500);
+        assert!(config.git.skip_paths.is_empty());
+        assert!(config.git.skip_network_fs);
+        assert_eq!(config.git.abbrev_length, 7);
+        assert_eq!(config.theme.background, Background::Auto);
+        assert!(!config.theme.git_dir_colors);
+        assert!(config.contexts.is_empty());
+    }
+
+    #[test]
+    fn test_parse_config_contexts_env() {
+        let content = "[contexts.cargo]\nenv = { RUST_LOG = \"debug\", RUST_BACKTRACE = \"1\" }\n";
+        let config = parse_config(content).unwrap();
+        assert_eq!(
+            config.context_env("cargo"),
+            vec![
+                ("RUST_BACKTRACE".to_string(), "1".to_string()),
+                ("RUST_LOG".to_string(), "debug".to_string()),
+            ]
+        );
+        assert!(config.context_env("git").is_empty());
+    }
+
+    #[test]
+    fn test_parse_config_bookmarks() {
+        let content = "[bookmarks]\nwork = \"/srv/work\"\n";
+        let config = parse_config(content).unwrap();
+        assert_eq!(
+            config.bookmark_path("@work"),
+            Some(PathBuf::from("/srv/work"))
+        );
+        assert_eq!(
+            config.bookmark_path("@work/app/src"),
+            Some(PathBuf::from("/srv/work/app/src"))
+        );
+        assert_eq!(config.bookmark_path("@missing"), None);
+        assert_eq!(config.bookmark_path("work"), None);
+    }
+
+    #[test]
+    fn test_bookmark_matches() {
+        let content =
+            "[bookmarks]\nweb = \"/srv/web\"\nwork = \"/srv/work\"\ndocs = \"/srv/docs\"\n";
+        let config = parse_config(content).unwrap();
+        assert_eq!(config.bookmark_matches("@w"), vec!["@web", "@work"]);
+        assert_eq!(config.bookmark_matches("@wo/app"), vec!["@work/app"]);
+        assert!(config.bookmark_matches("@x").is_empty());
+        assert!(config.bookmark_matches("w").is_empty());
+    }
+
+    #[test]
+    fn test_parse_config_contexts_templates() {
+        let content = "[contexts.git.templates]\ncm = \"commit -m {}\"\n";
+        let config = parse_config(content).unwrap();
+        assert_eq!(
+            config
+                .context_templates("git")
+                .get("cm")
+                .map(|s| s.as_str()),
+            Some("commit -m {}")
+        );
+        assert!(config.context_templates("cargo").is_empty());
+    }
+
+    #[test]
+    fn test_parse_config_theme() {
+        let config = parse_config("[theme]\nbackground = \"light\"\n").unwrap();
+        assert_eq!(config.theme.background, Background::Light);
+        assert!(parse_config("[theme]\nbackground = \"blue\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_config_prompt_partial() {
+        // 指定したキーだけ上書きされ、残りはデフォルトのまま
+        let content = "[prompt]\nopen = \"[\"\nclose = \"]\"\nsymbol = \"❯ \"\n";
+        let config = parse_config(content).unwrap();
+        assert_eq!(config.prompt.open, "[");
+        assert_eq!(config.prompt.close, "]");
+        assert_eq!(config.prompt.separator, ": ");
+        assert_eq!(config.prompt.symbol, "❯ ");
+    }
+
+    #[test]
+    fn test_parse_config_summary() {
+        let config = parse_config("[summary]\nenabled = true\n").unwrap();
+        assert!(config.summary.enabled);
+    }
+
+    #[test]
+    fn test_parse_config_extension_rules() {
+        let content = "[[completion.extension_rules]]\ncommand = \"make\"\nafter = \"-f\"\nextensions = [\"mk\"]\n";
+        let config = parse_config(content).unwrap();
+        assert_eq!(
+            config.completion.extension_rules,
+            vec![ExtensionRule {
+                command: "make".to_string(),
+                after: "-f".to_string(),
+                extensions: vec!["mk".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_config_subcommand_order() {
+        let config = parse_config("[completion]\nsubcommand_order = \"frequency\"\n").unwrap();
+        assert_eq!(
+            config.completion.subcommand_order,
+            SubcommandOrder::Frequency
+        );
+    }
+
+    #[test]
+    fn test_parse_config_invalid() {
+        assert!(parse_config("[prompt\nopen = ").is_err());
+    }
+
+    #[test]
+    fn test_subcommands_merge_over_builtin() {
+        let config = parse_config(
+            "[subcommands]\nmake = [\"build\", \"test\", \"clean\"]\n\n[subcommands.git]\nsubcommands = [\"status\", \"sync\"]\naliases = [\"g\"]\n",
+        )
+        .unwrap();
+        let table = config.subcommand_table();
+        assert_eq!(table.subcommands("make"), vec!["build", "test", "clean"]);
+        // 組み込みの定義は残したまま、足りないものだけ追加する
+        let git = table.subcommands("git");
+        assert!(git.contains(&"commit"));
+        assert_eq!(git.iter().filter(|s| **s == "status").count(), 1);
+        assert_eq!(git.last(), Some(&"sync"));
+        assert_eq!(table.subcommands("g"), git);
+    }
+
+    #[test]
+    fn test_subcommands_invalid_falls_back_to_builtin() {
+        // 型が合わない定義は設定全体のパースエラーになり、読み込み側はデフォルト (組み込みのみ) を使う
+        assert!(parse_config("[subcommands]\nmake = 3\n").is_err());
+        let table = Config::default().subcommand_table();
+        assert_eq!(
+            table.subcommands("git"),
+            subcommands::builtin().subcommands("git")
+        );
+        assert!(table.subcommands("make").is_empty());
+    }
+
+    // --- config get / set / list / save のテスト ---
+
+    #[test]
+    fn test_get_and_list_values() {
+        let config = Config::default();
+        assert_eq!(
+            get_value(&config, "prompt.symbol").unwrap(),
+            Value::String("> ".to_string())
+        );
+        assert_eq!(
+            get_value(&config, "git.timeout_ms").unwrap(),
+            Value::Integer(500)
+        );
+        assert!(get_value(&config, "prompt.unknown").is_err());
+        // 未設定の Option は値を持たない
+        assert!(get_value(&config, "prompt.format").is_err());
+
+        let values = list_values(&config);
+        assert!(values.contains(&("summary.enabled".to_string(), Value::Boolean(false))));
+        assert!(values.iter().all(|(key, _)| key != "prompt"));
+    }
+
+    #[test]
+    fn test_set_value() {
+        let config = Config::default();
+        let (config, value) = set_value(&config, "summary.enabled", "true").unwrap();
+        assert!(config.summary.enabled);
+        assert_eq!(value, Value::Boolean(true));
+
+        // TOML として読めない値は文字列として扱う
+        let (config, _) = set_value(&config, "prompt.symbol", "$ ").unwrap();
+        assert_eq!(config.prompt.symbol, "$ ");
+        // 数値に見えても文字列の項目なら文字列になる
+        let (config, _) = set_value(&config, "prompt.open", "1").unwrap();
+        assert_eq!(config.prompt.open, "1");
+
+        let (config, _) = set_value(&config, "theme.background", "light").unwrap();
+        assert_eq!(config.theme.background, Background::Light);
+        let (config, _) = set_value(&config, "bookmarks.work", "~/work").unwrap();
+        assert_eq!(config.bookmarks["work"], "~/work");
+    }
+
+    #[test]
+    fn test_set_value_invalid() {
+        let config = Config::default();
+        assert!(set_value(&config, "prompt.unknown", "x").is_err());
+        assert!(set_value(&config, "git.timeout_ms", "soon").is_err());
+        assert!(set_value(&config, "theme.background", "blue").is_err());
+        assert!(set_value(&config, "prompt.symbol.x", "1").is_err());
+    }
+
+    #[test]
+    fn test_save_values_keeps_other_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".withrc");
+        fs::write(&path, "[prompt]\nopen = \"[\"\n").unwrap();
+
+        let values = vec![("prompt.symbol".to_string(), Value::String("$ ".to_string()))];
+        save_values(&path, &values).unwrap();
+
+        let config = reload_config(&path).unwrap();
+        assert_eq!(config.prompt.open, "[");
+        assert_eq!(config.prompt.symbol, "$ ");
+        // ファイルが無ければ新しく作る
+        let new_path = tmp.path().join("new.toml");
+        save_values(&new_path, &values).unwrap();
+        assert_eq!(reload_config(&new_path).unwrap().prompt.symbol, "$ ");
+    }
+
+    #[test]
+    fn test_save_macro() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".withrc");
+        fs::write(&path, "[cd]\nstrict = true\n").unwrap();
+
+        let definition = MacroDefinition {
+            commands: vec!["git pull".to_string(), "cargo build".to_string()],
+            keep_going: true,
+        };
+        save_macro(&path, "deploy", Some(&definition)).unwrap();
+        let config = reload_config(&path).unwrap();
+        assert!(config.cd.strict);
+        assert_eq!(config.macros.get("deploy"), Some(&definition));
+
+        save_macro(&path, "deploy", None).unwrap();
+        assert!(reload_config(&path).unwrap().macros.is_empty());
+    }
+}
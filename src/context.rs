@@ -1,24 +1,82 @@
-use std::{fs, option::Option::*, path::Path};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    option::Option::*,
+    path::{MAIN_SEPARATOR_STR, Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Mutex, mpsc},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// プロンプトのディレクトリ部分の表示方法
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DirDisplay {
+    /// base と同じなら "."、それ以外は末端のディレクトリ名 (例: "parser")
+    #[default]
+    Name,
+    /// base 配下なら base からの相対パス (例: "./src/parser")、base の外は末端名
+    Relative,
+}
 
 /// ディレクトリ表示名の解決ロジック
 /// current: 現在のディレクトリ, base: 起動時のディレクトリ
 pub fn resolve_display_dir(current: &Path, base: &Path) -> Option<String> {
+    resolve_display_dir_with(current, base, DirDisplay::Name)
+}
+
+/// 表示方法を指定してディレクトリ表示名を解決する
+/// 相対表示の区切りは OS の表記に合わせる (Windows では ".\src\parser")
+pub fn resolve_display_dir_with(current: &Path, base: &Path, mode: DirDisplay) -> Option<String> {
     if current == base {
-        Some(".".to_string())
-    } else {
-        Some(
-            current
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or(".")
-                .to_string(),
-        )
+        return Some(".".to_string());
     }
+    if mode == DirDisplay::Relative
+        && let Ok(relative) = current.strip_prefix(base)
+    {
+        let parts: Vec<_> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect();
+        return Some(format!(
+            ".{}{}",
+            MAIN_SEPARATOR_STR,
+            parts.join(MAIN_SEPARATOR_STR)
+        ));
+    }
+    Some(
+        current
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(".")
+            .to_string(),
+    )
+}
+
+/// 削除されたディレクトリにいるときの移動先を決める
+/// last (最後に確認できたカレントディレクトリ) の残っている親、無ければ base (起動時のディレクトリ)
+pub fn recover_dir(last: &Path, base: &Path) -> Option<PathBuf> {
+    last.ancestors()
+        .skip(1)
+        .find(|dir| dir.is_dir())
+        .or_else(|| base.is_dir().then_some(base))
+        .map(Path::to_path_buf)
 }
 
 // --- Git branch 取得ロジック---
+/// detached HEAD で表示するハッシュの既定の長さ
+pub const DEFAULT_ABBREV_LENGTH: usize = 7;
+
 /// ファイルの中身からブランチ名またはハッシュを抽出する純粋関数
 fn parse_git_head(content: &str) -> Option<String> {
+    parse_git_head_with(content, DEFAULT_ABBREV_LENGTH)
+}
+
+/// ハッシュの表示長を指定してブランチ名またはハッシュを抽出する純粋関数
+/// SHA-1 (40文字) ・SHA-256 (64文字) のどちらでも、16進数であれば先頭 abbrev 文字を返す
+fn parse_git_head_with(content: &str, abbrev: usize) -> Option<String> {
     let content = content.trim();
 
     // "ref: refs/heads/main" の形式なら "main" を返す
@@ -26,28 +84,40 @@ fn parse_git_head(content: &str) -> Option<String> {
         return Some(branch.to_string());
     }
 
-    // Detached HEAD (ハッシュ値) の場合は先頭7文字を返す
-    if content.len() >= 7 {
-        return Some(content[..7].to_string());
+    // Detached HEAD (ハッシュ値) の場合は先頭を短縮して返す
+    // 16進数でない、または短すぎる内容は壊れた HEAD とみなす
+    let is_hash =
+        content.len() >= DEFAULT_ABBREV_LENGTH && content.chars().all(|c| c.is_ascii_hexdigit());
+    if is_hash {
+        let len = abbrev.clamp(4, content.len());
+        return Some(content[..len].to_string());
     }
 
     None
 }
 
-/// カレントディレクトリから遡って .git/HEAD を探し、ブランチ名を返す
-pub fn get_git_branch(cwd: &Path) -> Option<String> {
+/// worktree / submodule の .git ファイルの内容 ("gitdir: <path>") から参照先のパスを取り出す純粋関数
+fn parse_gitdir_file(content: &str) -> Option<&str> {
+    let path = content.trim().strip_prefix("gitdir:")?.trim();
+    (!path.is_empty()).then_some(path)
+}
+
+/// カレントディレクトリから遡って .git ディレクトリを探す
+/// worktree や submodule のように .git がファイルなら、`gitdir:` が指す先 (相対パスは .git の場所から) を返す
+pub fn find_git_dir(cwd: &Path) -> Option<PathBuf> {
     let mut current = cwd;
 
     loop {
         let git_dir = current.join(".git");
-        let head_path = git_dir.join("HEAD");
 
-        if head_path.exists() {
-            // HEADファイルを読み込む
-            if let Ok(content) = fs::read_to_string(head_path) {
-                return parse_git_head(&content);
-            }
-            return None;
+        if git_dir.is_file() {
+            // 壊れた .git ファイルのときに親のリポジトリのブランチを出さないよう、ここで打ち切る
+            let content = fs::read_to_string(&git_dir).ok()?;
+            let real = current.join(parse_gitdir_file(&content)?);
+            return real.join("HEAD").exists().then_some(real);
+        }
+        if git_dir.join("HEAD").exists() {
+            return Some(git_dir);
         }
 
         match current.parent() {
@@ -58,6 +128,566 @@ pub fn get_git_branch(cwd: &Path) -> Option<String> {
     None
 }
 
+/// ref や config を持つディレクトリ
+/// worktree では HEAD だけが worktree ごとにあり、残りは `commondir` が指す本体のリポジトリにある
+fn git_common_dir(git_dir: &Path) -> PathBuf {
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(content) if !content.trim().is_empty() => git_dir.join(content.trim()),
+        _ => git_dir.to_path_buf(),
+    }
+}
+
+/// カレントディレクトリから遡って .git/HEAD を探し、中身を読み込む
+fn read_git_head(cwd: &Path) -> Option<String> {
+    let git_dir = find_git_dir(cwd)?;
+    fs::read_to_string(git_dir.join("HEAD")).ok()
+}
+
+/// カレントディレクトリから遡って .git/HEAD を探し、ブランチ名を返す
+/// detached HEAD でもタグが指していればタグ名を返す
+pub fn get_git_branch(cwd: &Path) -> Option<String> {
+    let head = read_git_head(cwd)?;
+    detached_head_tag(cwd, &head).or_else(|| parse_git_head(&head))
+}
+
+/// detached HEAD のハッシュ表示長を指定してブランチ名を返す
+pub fn get_git_branch_with(cwd: &Path, abbrev: usize) -> Option<String> {
+    let head = read_git_head(cwd)?;
+    detached_head_tag(cwd, &head).or_else(|| parse_git_head_with(&head, abbrev))
+}
+
+/// 進行中の git の操作 (プロンプトのブランチ名の横に "main|MERGING" のように表示する)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GitOperation {
+    Merging,
+    Rebasing,
+    CherryPicking,
+}
+
+impl GitOperation {
+    pub fn label(self) -> &'static str {
+        match self {
+            GitOperation::Merging => "MERGING",
+            GitOperation::Rebasing => "REBASING",
+            GitOperation::CherryPicking => "CHERRY-PICKING",
+        }
+    }
+}
+
+/// .git ディレクトリ内のファイルの有無だけで進行中の操作を調べる (git は実行しない)
+/// リベース中の衝突では CHERRY_PICK_HEAD も作られるので、リベースを先に見る
+fn detect_git_operation(git_dir: &Path) -> Option<GitOperation> {
+    if git_dir.join("rebase-merge").is_dir() || git_dir.join("rebase-apply").is_dir() {
+        Some(GitOperation::Rebasing)
+    } else if git_dir.join("MERGE_HEAD").is_file() {
+        Some(GitOperation::Merging)
+    } else if git_dir.join("CHERRY_PICK_HEAD").is_file() {
+        Some(GitOperation::CherryPicking)
+    } else {
+        None
+    }
+}
+
+/// カレントディレクトリのリポジトリで進行中の操作を返す (worktree ではその worktree のもの)
+pub fn get_git_operation(cwd: &Path) -> Option<GitOperation> {
+    detect_git_operation(&find_git_dir(cwd)?)
+}
+
+/// ブランチ名と、それを読んだときの HEAD (BranchCache が覚えておく)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedBranch {
+    pub cwd: PathBuf,
+    pub head: PathBuf,
+    pub modified: SystemTime,
+    pub branch: Option<String>,
+}
+
+/// .git を探してブランチ名を読み、HEAD の場所と更新時刻を添えて返す (リポジトリの外なら None)
+pub fn read_cached_branch(cwd: &Path, abbrev: usize) -> Option<CachedBranch> {
+    let head = find_git_dir(cwd)?.join("HEAD");
+    let modified = fs::metadata(&head).and_then(|m| m.modified()).ok()?;
+    Some(CachedBranch {
+        cwd: cwd.to_path_buf(),
+        head,
+        modified,
+        branch: get_git_branch_with(cwd, abbrev),
+    })
+}
+
+/// プロンプトを出すたびに .git を探し直さないよう、直前のディレクトリのブランチ名を覚える
+/// ディレクトリが変わったとき (cd) と HEAD が書き換わったとき (checkout など) は読み直す
+/// リポジトリの外では覚えない (その場で git init されても気付けるように)
+#[derive(Debug, Default)]
+pub struct BranchCache {
+    entry: Option<CachedBranch>,
+}
+
+impl BranchCache {
+    /// cwd のブランチ名を返す。覚えているものが古ければ read で読み直す
+    pub fn get_or_read(
+        &mut self,
+        cwd: &Path,
+        read: impl FnOnce(&Path) -> Option<CachedBranch>,
+    ) -> Option<String> {
+        if let Some(entry) = &self.entry
+            && entry.cwd == cwd
+            && fs::metadata(&entry.head)
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| modified == entry.modified)
+        {
+            return entry.branch.clone();
+        }
+        self.entry = read(cwd);
+        self.entry.as_ref().and_then(|entry| entry.branch.clone())
+    }
+}
+
+/// detached HEAD が指すコミットのタグ名 (ブランチ上やタグが無ければ None)
+fn detached_head_tag(cwd: &Path, head: &str) -> Option<String> {
+    if !is_detached_head(head) {
+        return None;
+    }
+    find_tag_at(&git_common_dir(&find_git_dir(cwd)?), head.trim())
+}
+
+/// hash を指すタグ名を返す (複数あれば名前順で最初のもの)
+/// loose ref は中身のハッシュだけを見るため、注釈付きタグは packed-refs にある場合だけ一致する
+fn find_tag_at(git_dir: &Path, hash: &str) -> Option<String> {
+    let packed = read_packed_refs(git_dir);
+    list_git_refs(git_dir, "refs/tags")
+        .into_iter()
+        .find(|name| {
+            resolve_ref(git_dir, &packed, &format!("refs/tags/{}", name)).as_deref() == Some(hash)
+        })
+}
+
+/// ブランチの状態 (プロンプトのブランチ色の切り替えに使う)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BranchState {
+    Clean,
+    Dirty,
+    Detached,
+}
+
+/// HEAD の内容が detached (ブランチではなくハッシュを指す) かを判定する純粋関数
+fn is_detached_head(content: &str) -> bool {
+    !content.trim().starts_with("ref: ")
+}
+
+/// 作業ツリーに未コミットの変更があるかを返す (git が無い・失敗した場合は None)
+/// 未追跡ファイルは数えない
+pub fn get_git_dirty(cwd: &Path) -> Option<bool> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(cwd)
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then_some(!output.stdout.is_empty())
+}
+
+/// 作業ツリーに未コミットの変更があるか (プロンプトの `main*` の印に使う)
+/// git 管理外や git が無い場合は false
+pub fn git_is_dirty(cwd: &Path) -> bool {
+    get_git_dirty(cwd).unwrap_or(false)
+}
+
+/// ブランチの状態を返す (detached HEAD なら変更の有無は調べない)
+pub fn get_git_branch_state(cwd: &Path) -> Option<BranchState> {
+    if is_detached_head(&read_git_head(cwd)?) {
+        return Some(BranchState::Detached);
+    }
+    match get_git_dirty(cwd)? {
+        true => Some(BranchState::Dirty),
+        false => Some(BranchState::Clean),
+    }
+}
+
+// --- 遅いファイルシステムへの対策 ---
+/// path が skip_paths のいずれかの配下にあるかを判定する純粋関数
+pub fn is_skipped_path(path: &Path, skip_paths: &[String]) -> bool {
+    skip_paths
+        .iter()
+        .filter(|p| !p.is_empty())
+        .any(|p| path.starts_with(p))
+}
+
+/// statfs の f_type がネットワーク/リモートファイルシステムのものかを判定する純粋関数
+#[cfg(target_os = "linux")]
+fn is_network_fs_type(fs_type: i64) -> bool {
+    const NETWORK_FS_TYPES: &[i64] = &[
+        0x6969,     // NFS
+        0x517B,     // SMB
+        0xFF534D42, // CIFS
+        0xFE534D42, // SMB2
+        0x65735546, // FUSE (sshfs など)
+        0x5346414F, // AFS
+        0x73757245, // CODA
+        0x01021997, // 9P
+    ];
+    NETWORK_FS_TYPES.contains(&fs_type)
+}
+
+/// path がネットワークファイルシステム上にあるか (Linux 以外では常に false)
+#[cfg(target_os = "linux")]
+pub fn is_network_fs(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path は NUL 終端済みで、stat は statfs が書き込める領域
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+    is_network_fs_type(stat.f_type as i64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_network_fs(_path: &Path) -> bool {
+    false
+}
+
+/// f を別スレッドで実行し、timeout 以内に終わらなければ None を返す
+/// 打ち切ったスレッドは終わるまで裏で走らせたままにする
+pub fn run_with_timeout<T, F>(timeout: Duration, f: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Option<T> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+    rx.recv_timeout(timeout).ok().flatten()
+}
+
+// --- .git/config 解析ロジック ---
+/// .git/config の1エントリ (例: [branch "main"] remote = origin)
+#[derive(Debug, PartialEq)]
+struct GitConfigEntry {
+    section: String,
+    subsection: Option<String>,
+    key: String,
+    value: String,
+}
+
+/// .git/config (INI 風) の内容をエントリの列に変換する純粋関数
+/// セクション見出しだけでキーの無いセクションも、key を空にして残す
+fn parse_git_config(content: &str) -> Vec<GitConfigEntry> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+    let mut subsection = None;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(inner) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            // [remote "origin"] -> section="remote", subsection="origin"
+            let (name, sub) = match inner.split_once(' ') {
+                Some((name, sub)) => (name, Some(sub.trim().trim_matches('"').to_string())),
+                None => (inner, None),
+            };
+            section = name.trim().to_lowercase();
+            subsection = sub;
+            entries.push(GitConfigEntry {
+                section: section.clone(),
+                subsection: subsection.clone(),
+                key: String::new(),
+                value: String::new(),
+            });
+            continue;
+        }
+
+        if let Some((key, value)) = line.split_once('=') {
+            entries.push(GitConfigEntry {
+                section: section.clone(),
+                subsection: subsection.clone(),
+                key: key.trim().to_lowercase(),
+                value: value.trim().to_string(),
+            });
+        }
+    }
+    entries
+}
+
+/// .git/config の内容から `[remote "..."]` セクションのリモート名を抽出する純粋関数
+fn parse_git_remotes(content: &str) -> Vec<String> {
+    let mut remotes: Vec<String> = Vec::new();
+    for entry in parse_git_config(content) {
+        if entry.section == "remote"
+            && let Some(name) = entry.subsection
+            && !remotes.contains(&name)
+        {
+            remotes.push(name);
+        }
+    }
+    remotes
+}
+
+/// ブランチの追跡先 ([branch "main"] の remote と merge)
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitUpstream {
+    pub remote: String,
+    /// 追跡先のブランチ名 (refs/heads/ を除いたもの)
+    pub branch: String,
+}
+
+impl GitUpstream {
+    /// 表示用の名前 (例: "origin/main")
+    pub fn display_name(&self) -> String {
+        format!("{}/{}", self.remote, self.branch)
+    }
+}
+
+/// .git/config の内容から指定ブランチの追跡先を取り出す純粋関数
+fn parse_branch_upstream(content: &str, branch: &str) -> Option<GitUpstream> {
+    let mut remote = None;
+    let mut merge = None;
+
+    for entry in parse_git_config(content) {
+        if entry.section != "branch" || entry.subsection.as_deref() != Some(branch) {
+            continue;
+        }
+        match entry.key.as_str() {
+            "remote" => remote = Some(entry.value),
+            "merge" => merge = Some(entry.value),
+            _ => {}
+        }
+    }
+
+    let merge = merge?;
+    Some(GitUpstream {
+        remote: remote?,
+        branch: merge
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&merge)
+            .to_string(),
+    })
+}
+
+/// 現在のブランチ (detached HEAD なら None) の追跡先を返す
+pub fn get_git_upstream(cwd: &Path) -> Option<GitUpstream> {
+    let git_dir = find_git_dir(cwd)?;
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let branch = head.trim().strip_prefix("ref: refs/heads/")?;
+
+    let config = fs::read_to_string(git_common_dir(&git_dir).join("config")).ok()?;
+    parse_branch_upstream(&config, branch)
+}
+
+/// `git rev-list --left-right --count` の出力 (例: "2\t1") を (ahead, behind) にする純粋関数
+fn parse_ahead_behind(output: &str) -> Option<(usize, usize)> {
+    let mut counts = output.split_whitespace().map(|n| n.parse().ok());
+    match (counts.next()??, counts.next()??, counts.next()) {
+        (ahead, behind, None) => Some((ahead, behind)),
+        _ => None,
+    }
+}
+
+/// 追跡先より進んでいる・遅れているコミット数 (ahead, behind) を返す
+/// 追跡先が設定されていない場合は git を呼ばずに None
+pub fn get_git_ahead_behind(cwd: &Path) -> Option<(usize, usize)> {
+    let upstream = get_git_upstream(cwd)?;
+    // 追跡先と同じコミットを指していれば git を呼ばずに済む
+    if let Some(git_dir) = find_git_dir(cwd)
+        && let Some((local, remote)) = resolve_upstream_hashes(&git_dir, &upstream)
+        && local == remote
+    {
+        return Some((0, 0));
+    }
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(cwd)
+        .args(["rev-list", "--left-right", "--count", "HEAD...@{upstream}"])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_ahead_behind(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// 現在のブランチと追跡先が指すコミットのハッシュ (どちらかが解決できなければ None)
+fn resolve_upstream_hashes(git_dir: &Path, upstream: &GitUpstream) -> Option<(String, String)> {
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let local = head.trim().strip_prefix("ref: ")?;
+    // remote = "." は同じリポジトリのブランチを追跡している
+    let remote = if upstream.remote == "." {
+        format!("refs/heads/{}", upstream.branch)
+    } else {
+        format!("refs/remotes/{}/{}", upstream.remote, upstream.branch)
+    };
+    let common = git_common_dir(git_dir);
+    let packed = read_packed_refs(&common);
+    Some((
+        resolve_ref(&common, &packed, local)?,
+        resolve_ref(&common, &packed, &remote)?,
+    ))
+}
+
+// --- Git ref 列挙ロジック ---
+/// packed-refs の内容を ref 名 -> ハッシュの表にする純粋関数
+/// 注釈付きタグは直後の "^<hash>" 行 (タグが指すコミット) のハッシュを使う
+fn parse_packed_refs(content: &str) -> BTreeMap<String, String> {
+    let mut refs = BTreeMap::new();
+    let mut last_ref: Option<String> = None;
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(peeled) = line.strip_prefix('^') {
+            if let Some(name) = last_ref.take() {
+                refs.insert(name, peeled.to_string());
+            }
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(hash), Some(name)) = (fields.next(), fields.next()) else {
+            last_ref = None;
+            continue;
+        };
+        refs.insert(name.to_string(), hash.to_string());
+        last_ref = Some(name.to_string());
+    }
+    refs
+}
+
+/// .git/packed-refs を読む (無ければ空の表)
+fn read_packed_refs(git_dir: &Path) -> BTreeMap<String, String> {
+    fs::read_to_string(git_dir.join("packed-refs"))
+        .map(|content| parse_packed_refs(&content))
+        .unwrap_or_default()
+}
+
+/// ref (例: "refs/heads/main") が指すハッシュを返す
+/// gc 後や clone 直後は loose ref のファイルが無いので packed-refs で補う
+fn resolve_ref(git_dir: &Path, packed: &BTreeMap<String, String>, name: &str) -> Option<String> {
+    match fs::read_to_string(git_dir.join(name)) {
+        Ok(content) => Some(content.trim().to_string()).filter(|hash| !hash.is_empty()),
+        Err(_) => packed.get(name).cloned(),
+    }
+}
+
+/// packed-refs の内容から指定プレフィックス配下の ref 名を抽出する純粋関数
+/// 例: prefix = "refs/tags/" -> "v1.0.0"
+fn parse_packed_ref_names(content: &str, prefix: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter_map(|name| name.strip_prefix(prefix))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// refs ディレクトリ配下のファイルを再帰的に集め、相対パスを ref 名として返す
+fn collect_loose_refs(dir: &Path, prefix: &str, out: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = entry.path();
+        let ref_name = format!("{}{}", prefix, name);
+
+        if path.is_dir() {
+            // "feature/new-ui" のようなスラッシュ入りの ref
+            collect_loose_refs(&path, &format!("{}/", ref_name), out);
+        } else {
+            out.push(ref_name);
+        }
+    }
+}
+
+/// loose ref と packed-refs の両方から ref 名を列挙する (重複除去・ソート済み)
+fn list_git_refs(git_dir: &Path, namespace: &str) -> Vec<String> {
+    let mut refs = Vec::new();
+    collect_loose_refs(&git_dir.join(namespace), "", &mut refs);
+
+    if let Ok(content) = fs::read_to_string(git_dir.join("packed-refs")) {
+        refs.extend(parse_packed_ref_names(&content, &format!("{}/", namespace)));
+    }
+
+    refs.sort();
+    refs.dedup();
+    refs
+}
+
+/// ローカルブランチ名の一覧を返す
+pub fn list_git_branches(cwd: &Path) -> Vec<String> {
+    find_git_dir(cwd)
+        .map(|git_dir| list_git_refs(&git_common_dir(&git_dir), "refs/heads"))
+        .unwrap_or_default()
+}
+
+/// タグ名の一覧を返す
+pub fn list_git_tags(cwd: &Path) -> Vec<String> {
+    find_git_dir(cwd)
+        .map(|git_dir| list_git_refs(&git_common_dir(&git_dir), "refs/tags"))
+        .unwrap_or_default()
+}
+
+/// .git/config に登録されたリモート名の一覧を返す
+pub fn list_git_remotes(cwd: &Path) -> Vec<String> {
+    find_git_dir(cwd)
+        .and_then(|git_dir| fs::read_to_string(git_common_dir(&git_dir).join("config")).ok())
+        .map(|content| parse_git_remotes(&content))
+        .unwrap_or_default()
+}
+
+// --- クラウドコンテキスト取得ロジック ---
+/// kubeconfig の内容からトップレベルの current-context を取り出す純粋関数
+/// YAML 全体はパースせず、インデントの無い `current-context:` 行だけを見る
+fn parse_kube_current_context(content: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        let value = line.strip_prefix("current-context:")?.trim();
+        let value = value.trim_matches(|c| c == '"' || c == '\'');
+        (!value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// kubeconfig のパス (kubeconfig ($KUBECONFIG の値) の先頭、なければ ~/.kube/config)
+fn kubeconfig_path(kubeconfig: Option<&str>) -> Option<PathBuf> {
+    if let Some(paths) = kubeconfig
+        && let Some(first) = env::split_paths(paths).next()
+    {
+        return Some(first);
+    }
+    env::home_dir().map(|home| home.join(".kube").join("config"))
+}
+
+/// kubeconfig の読み取り結果のキャッシュ (パス・更新時刻が同じなら再読込しない)
+static KUBE_CACHE: Mutex<Option<(PathBuf, SystemTime, Option<String>)>> = Mutex::new(None);
+
+/// 現在の kube-context を返す (kubeconfig が無い/未設定なら None)
+/// kubeconfig には $KUBECONFIG の値を渡す
+pub fn get_kube_context(kubeconfig: Option<&str>) -> Option<String> {
+    let path = kubeconfig_path(kubeconfig)?;
+    let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+
+    let mut cache = KUBE_CACHE.lock().ok()?;
+    if let Some((cached_path, cached_time, value)) = cache.as_ref()
+        && *cached_path == path
+        && *cached_time == modified
+    {
+        return value.clone();
+    }
+
+    let value = fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| parse_kube_current_context(&content));
+    *cache = Some((path, modified, value.clone()));
+    value
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -84,6 +714,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_display_dir_relative_mode() {
+        let base = std::path::PathBuf::from("/home/user/project");
+        let relative = |current: &str| {
+            resolve_display_dir_with(Path::new(current), &base, DirDisplay::Relative).unwrap()
+        };
+
+        assert_eq!(relative("/home/user/project"), ".");
+        assert_eq!(
+            relative("/home/user/project/src/parser"),
+            format!(".{0}src{0}parser", MAIN_SEPARATOR_STR)
+        );
+        // base の外は末端名のまま ("project2" のような前方一致も配下とはみなさない)
+        assert_eq!(relative("/home/user/other"), "other");
+        assert_eq!(relative("/home/user/project2"), "project2");
+    }
+
     #[test]
     fn test_parse_git_head_branch() {
         let content = "ref: refs/heads/main\n";
@@ -96,6 +743,317 @@ mod tests {
         assert_eq!(parse_git_head(content), Some("a1b2c3d".to_string()));
     }
 
+    #[test]
+    fn test_is_detached_head() {
+        assert!(is_detached_head(
+            "a1b2c3d4e5f67890abcdef1234567890abcdef12\n"
+        ));
+        assert!(!is_detached_head("ref: refs/heads/main\n"));
+    }
+
+    #[test]
+    fn test_get_git_branch_state_detached_and_outside() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(get_git_branch_state(tmp.path()), None);
+
+        // detached なら git を呼ばずに判定できる
+        let git_dir = tmp.path().join(".git");
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::write(
+            git_dir.join("HEAD"),
+            "a1b2c3d4e5f67890abcdef1234567890abcdef12\n",
+        )
+        .unwrap();
+        assert_eq!(
+            get_git_branch_state(tmp.path()),
+            Some(BranchState::Detached)
+        );
+    }
+
+    #[test]
+    fn test_git_is_dirty() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(!git_is_dirty(tmp.path()));
+
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .arg("-C")
+                .arg(tmp.path())
+                .args(["-c", "user.name=with", "-c", "user.email=with@example.com"])
+                .args(["-c", "commit.gpgsign=false"])
+                .args(args)
+                .output()
+        };
+        // git が無い環境では確かめられない
+        if git(&["init", "-q"]).is_err() {
+            return;
+        }
+        fs::write(tmp.path().join("README.md"), "hello\n").unwrap();
+        git(&["add", "README.md"]).unwrap();
+        git(&["commit", "-q", "-m", "init"]).unwrap();
+        assert!(!git_is_dirty(tmp.path()));
+
+        fs::write(tmp.path().join("README.md"), "changed\n").unwrap();
+        assert!(git_is_dirty(tmp.path()));
+    }
+
+    #[test]
+    fn test_parse_packed_refs() {
+        let content = "# pack-refs with: peeled fully-peeled sorted\n\
+            1111111111111111111111111111111111111111 refs/heads/main\n\
+            1111111111111111111111111111111111111111 refs/tags/v1.0.0\n\
+            2222222222222222222222222222222222222222 refs/tags/v1.1.0\n\
+            ^1111111111111111111111111111111111111111\n\
+            3333333333333333333333333333333333333333 refs/remotes/origin/main\n";
+        let refs = parse_packed_refs(content);
+        assert_eq!(refs.len(), 4);
+        assert_eq!(
+            refs["refs/heads/main"],
+            "1111111111111111111111111111111111111111"
+        );
+        // 注釈付きタグはタグが指すコミットのハッシュになる
+        assert_eq!(
+            refs["refs/tags/v1.1.0"],
+            "1111111111111111111111111111111111111111"
+        );
+        assert_eq!(
+            refs["refs/remotes/origin/main"],
+            "3333333333333333333333333333333333333333"
+        );
+        assert!(parse_packed_refs("# only a comment\n").is_empty());
+    }
+
+    #[test]
+    fn test_resolve_ref_falls_back_to_packed_refs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path().join(".git");
+        fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+        fs::write(
+            git_dir.join("packed-refs"),
+            "# pack-refs with: peeled\n\
+             1111111111111111111111111111111111111111 refs/heads/main\n\
+             2222222222222222222222222222222222222222 refs/heads/dev\n",
+        )
+        .unwrap();
+        // loose ref があればそちらが新しい
+        fs::write(
+            git_dir.join("refs/heads/dev"),
+            "4444444444444444444444444444444444444444\n",
+        )
+        .unwrap();
+
+        let packed = read_packed_refs(&git_dir);
+        assert_eq!(
+            resolve_ref(&git_dir, &packed, "refs/heads/main").as_deref(),
+            Some("1111111111111111111111111111111111111111")
+        );
+        assert_eq!(
+            resolve_ref(&git_dir, &packed, "refs/heads/dev").as_deref(),
+            Some("4444444444444444444444444444444444444444")
+        );
+        assert_eq!(resolve_ref(&git_dir, &packed, "refs/heads/none"), None);
+
+        // 追跡先も packed-refs だけにあれば解決できる
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let upstream = GitUpstream {
+            remote: ".".to_string(),
+            branch: "main".to_string(),
+        };
+        let (local, remote) = resolve_upstream_hashes(&git_dir, &upstream).unwrap();
+        assert_eq!(local, remote);
+    }
+
+    #[test]
+    fn test_get_git_branch_prefers_peeled_tag() {
+        let hash = "a1b2c3d4e5f67890abcdef1234567890abcdef12";
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path().join(".git");
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::write(git_dir.join("HEAD"), format!("{}\n", hash)).unwrap();
+        fs::write(
+            git_dir.join("packed-refs"),
+            format!(
+                "# pack-refs with: peeled fully-peeled sorted\n\
+                 ffffffffffffffffffffffffffffffffffffffff refs/tags/v2.0.0\n\
+                 ^{}\n",
+                hash
+            ),
+        )
+        .unwrap();
+        assert_eq!(get_git_branch(tmp.path()), Some("v2.0.0".to_string()));
+    }
+
+    #[test]
+    fn test_get_git_branch_prefers_tag_on_detached_head() {
+        let hash = "a1b2c3d4e5f67890abcdef1234567890abcdef12";
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path().join(".git");
+        fs::create_dir_all(git_dir.join("refs/tags/release")).unwrap();
+        fs::write(git_dir.join("HEAD"), format!("{}\n", hash)).unwrap();
+
+        // タグが無ければ短縮ハッシュ
+        assert_eq!(get_git_branch(tmp.path()), Some("a1b2c3d".to_string()));
+
+        fs::write(
+            git_dir.join("refs/tags/other"),
+            "ffffffffffffffffffffffffffffffffffffffff\n",
+        )
+        .unwrap();
+        fs::write(
+            git_dir.join("refs/tags/release/v1.2.3"),
+            format!("{}\n", hash),
+        )
+        .unwrap();
+        assert_eq!(
+            get_git_branch(tmp.path()),
+            Some("release/v1.2.3".to_string())
+        );
+
+        // packed-refs のタグも見る (名前順で最初のもの)
+        fs::write(
+            git_dir.join("packed-refs"),
+            format!("{} refs/tags/v1.2.3\n", hash),
+        )
+        .unwrap();
+        assert_eq!(
+            get_git_branch_with(tmp.path(), 12),
+            Some("release/v1.2.3".to_string())
+        );
+        fs::remove_file(git_dir.join("refs/tags/release/v1.2.3")).unwrap();
+        assert_eq!(get_git_branch(tmp.path()), Some("v1.2.3".to_string()));
+    }
+
+    #[test]
+    fn test_branch_cache_skips_reread() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path().join(".git");
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let other = tempfile::tempdir().unwrap();
+
+        let mut cache = BranchCache::default();
+        let reads = std::cell::Cell::new(0);
+        let read = |cwd: &Path| {
+            reads.set(reads.get() + 1);
+            read_cached_branch(cwd, DEFAULT_ABBREV_LENGTH)
+        };
+
+        assert_eq!(
+            cache.get_or_read(tmp.path(), read),
+            Some("main".to_string())
+        );
+        // 同じディレクトリなら読み直さない
+        assert_eq!(
+            cache.get_or_read(tmp.path(), read),
+            Some("main".to_string())
+        );
+        assert_eq!(reads.get(), 1);
+
+        // HEAD が書き換わったら読み直す (更新時刻は確実に変わるよう明示する)
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/dev\n").unwrap();
+        let head = fs::File::options()
+            .write(true)
+            .open(git_dir.join("HEAD"))
+            .unwrap();
+        head.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(cache.get_or_read(tmp.path(), read), Some("dev".to_string()));
+        assert_eq!(reads.get(), 2);
+
+        // ディレクトリが変わったら読み直し、リポジトリの外は覚えない
+        assert_eq!(cache.get_or_read(other.path(), read), None);
+        assert_eq!(cache.get_or_read(other.path(), read), None);
+        assert_eq!(reads.get(), 4);
+    }
+
+    #[test]
+    fn test_parse_gitdir_file() {
+        assert_eq!(
+            parse_gitdir_file("gitdir: /repo/.git/worktrees/feature\n"),
+            Some("/repo/.git/worktrees/feature")
+        );
+        assert_eq!(
+            parse_gitdir_file("gitdir: ../.git/modules/lib\n"),
+            Some("../.git/modules/lib")
+        );
+        assert_eq!(parse_gitdir_file("ref: refs/heads/main\n"), None);
+        assert_eq!(parse_gitdir_file("gitdir:\n"), None);
+    }
+
+    #[test]
+    fn test_get_git_operation() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path().join(".git");
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        assert_eq!(get_git_operation(tmp.path()), None);
+
+        fs::write(git_dir.join("CHERRY_PICK_HEAD"), "abc\n").unwrap();
+        assert_eq!(
+            get_git_operation(tmp.path()),
+            Some(GitOperation::CherryPicking)
+        );
+        fs::write(git_dir.join("MERGE_HEAD"), "abc\n").unwrap();
+        assert_eq!(get_git_operation(tmp.path()), Some(GitOperation::Merging));
+        // リベースの途中で作られた CHERRY_PICK_HEAD よりリベースを優先する
+        fs::create_dir(git_dir.join("rebase-merge")).unwrap();
+        assert_eq!(get_git_operation(tmp.path()), Some(GitOperation::Rebasing));
+        assert_eq!(GitOperation::Rebasing.label(), "REBASING");
+    }
+
+    #[test]
+    fn test_get_git_branch_in_worktree() {
+        // 本体のリポジトリと、絶対パスの gitdir を持つ worktree
+        let tmp = tempfile::tempdir().unwrap();
+        let main_git = tmp.path().join("repo/.git");
+        let worktree_git = main_git.join("worktrees/feature");
+        fs::create_dir_all(&worktree_git).unwrap();
+        fs::create_dir_all(main_git.join("refs/heads")).unwrap();
+        fs::write(main_git.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(main_git.join("refs/heads/main"), "abc\n").unwrap();
+        fs::write(worktree_git.join("HEAD"), "ref: refs/heads/feature\n").unwrap();
+        fs::write(worktree_git.join("commondir"), "../..\n").unwrap();
+
+        let worktree = tmp.path().join("feature");
+        fs::create_dir_all(worktree.join("src")).unwrap();
+        fs::write(
+            worktree.join(".git"),
+            format!("gitdir: {}\n", worktree_git.display()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            get_git_branch(&worktree.join("src")),
+            Some("feature".to_string())
+        );
+        // ブランチの一覧は本体のリポジトリから読む
+        assert_eq!(list_git_branches(&worktree), vec!["main"]);
+        // 本体のディレクトリはこれまで通り
+        assert_eq!(
+            get_git_branch(&tmp.path().join("repo")),
+            Some("main".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_git_branch_in_submodule() {
+        // 相対パスの gitdir を持つ submodule (.git/modules/<name> に実体がある)
+        let tmp = tempfile::tempdir().unwrap();
+        let module_git = tmp.path().join(".git/modules/lib");
+        fs::create_dir_all(&module_git).unwrap();
+        fs::write(tmp.path().join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(module_git.join("HEAD"), "ref: refs/heads/develop\n").unwrap();
+
+        let lib = tmp.path().join("lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(lib.join(".git"), "gitdir: ../.git/modules/lib\n").unwrap();
+        assert_eq!(get_git_branch(&lib), Some("develop".to_string()));
+
+        // 参照先が無ければ親のリポジトリのブランチは出さない
+        fs::write(lib.join(".git"), "gitdir: ../.git/modules/missing\n").unwrap();
+        assert_eq!(get_git_branch(&lib), None);
+    }
+
     #[test]
     fn test_parse_git_head_invalid() {
         let content = "short";
@@ -131,6 +1089,40 @@ mod tests {
         assert_eq!(parse_git_head(content), None);
     }
 
+    #[test]
+    fn test_parse_git_head_sha256() {
+        // SHA-256 リポジトリの 64 文字のハッシュ
+        let content = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08\n";
+        assert_eq!(parse_git_head(content), Some("9f86d08".to_string()));
+    }
+
+    #[test]
+    fn test_parse_git_head_non_hex() {
+        // 16進数でなければ長さが足りていても None
+        assert_eq!(parse_git_head("not-a-hash-at-all"), None);
+        assert_eq!(
+            parse_git_head("zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_git_head_custom_abbrev() {
+        let sha1 = "a1b2c3d4e5f67890abcdef1234567890abcdef12";
+        assert_eq!(
+            parse_git_head_with(sha1, 12),
+            Some("a1b2c3d4e5f6".to_string())
+        );
+        // ハッシュより長い指定は全体、短すぎる指定は 4 文字に丸める
+        assert_eq!(parse_git_head_with(sha1, 100), Some(sha1.to_string()));
+        assert_eq!(parse_git_head_with(sha1, 1), Some("a1b2".to_string()));
+        // ブランチ名は短縮しない
+        assert_eq!(
+            parse_git_head_with("ref: refs/heads/main", 2),
+            Some("main".to_string())
+        );
+    }
+
     #[test]
     fn test_parse_git_head_empty() {
         // 空文字の場合
@@ -172,4 +1164,238 @@ mod tests {
         let result = resolve_display_dir(&current, &base);
         assert!(result.is_some());
     }
+
+    #[test]
+    fn test_recover_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("project");
+        let parent = tmp.path().join("project/build");
+        fs::create_dir_all(&parent).unwrap();
+
+        // 消えたディレクトリの、残っている一番近い親へ移る
+        assert_eq!(
+            recover_dir(&parent.join("out/debug"), &base),
+            Some(parent.clone())
+        );
+        // 親が残っていなければ起動時のディレクトリへ戻る
+        assert_eq!(
+            recover_dir(Path::new("relative/removed"), &base),
+            Some(base.clone())
+        );
+        assert_eq!(
+            recover_dir(Path::new("relative/removed"), &tmp.path().join("gone")),
+            None
+        );
+    }
+
+    // --- ref 列挙のテスト ---
+
+    #[test]
+    fn test_parse_git_remotes() {
+        let content = "[core]\n\tbare = false\n[remote \"origin\"]\n\turl = git@example.com:a/b.git\n[branch \"main\"]\n\tremote = origin\n[remote \"upstream\"]\n";
+        assert_eq!(parse_git_remotes(content), vec!["origin", "upstream"]);
+    }
+
+    #[test]
+    fn test_parse_git_remotes_none() {
+        let content = "[core]\n\tbare = false\n";
+        assert!(parse_git_remotes(content).is_empty());
+    }
+
+    #[test]
+    fn test_parse_packed_ref_names() {
+        let content = "# pack-refs with: peeled fully-peeled sorted\n\
+            1111111111111111111111111111111111111111 refs/heads/main\n\
+            2222222222222222222222222222222222222222 refs/tags/v1.0.0\n\
+            ^3333333333333333333333333333333333333333\n\
+            4444444444444444444444444444444444444444 refs/remotes/origin/main\n";
+        assert_eq!(
+            parse_packed_ref_names(content, "refs/tags/"),
+            vec!["v1.0.0"]
+        );
+        assert_eq!(parse_packed_ref_names(content, "refs/heads/"), vec!["main"]);
+    }
+
+    #[test]
+    fn test_list_git_refs_from_fixture() {
+        // loose ref と packed-refs が混在するリポジトリ
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path().join(".git");
+        fs::create_dir_all(git_dir.join("refs/heads/feature")).unwrap();
+        fs::create_dir_all(git_dir.join("refs/tags")).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(git_dir.join("refs/heads/main"), "abc\n").unwrap();
+        fs::write(git_dir.join("refs/heads/feature/login"), "abc\n").unwrap();
+        fs::write(git_dir.join("refs/tags/v0.1.0"), "abc\n").unwrap();
+        fs::write(
+            git_dir.join("packed-refs"),
+            "abc refs/heads/main\nabc refs/tags/v0.2.0\n",
+        )
+        .unwrap();
+        fs::write(git_dir.join("config"), "[remote \"origin\"]\n").unwrap();
+
+        // サブディレクトリからでも親の .git を見つける
+        let sub = tmp.path().join("src");
+        fs::create_dir_all(&sub).unwrap();
+
+        assert_eq!(list_git_branches(&sub), vec!["feature/login", "main"]);
+        assert_eq!(list_git_tags(&sub), vec!["v0.1.0", "v0.2.0"]);
+        assert_eq!(list_git_remotes(&sub), vec!["origin"]);
+    }
+
+    #[test]
+    fn test_list_git_refs_outside_repo() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(list_git_branches(tmp.path()).is_empty());
+        assert!(list_git_remotes(tmp.path()).is_empty());
+    }
+
+    // --- クラウドコンテキストのテスト ---
+
+    #[test]
+    fn test_parse_kube_current_context() {
+        let content = "apiVersion: v1\nclusters: []\ncontexts:\n- name: dev\n  context:\n    current-context: nested\ncurrent-context: prod-cluster\nkind: Config\n";
+        assert_eq!(
+            parse_kube_current_context(content),
+            Some("prod-cluster".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_kube_current_context_quoted() {
+        let content = "current-context: \"my-ctx\"\n";
+        assert_eq!(
+            parse_kube_current_context(content),
+            Some("my-ctx".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_kube_current_context_missing() {
+        assert_eq!(parse_kube_current_context("apiVersion: v1\n"), None);
+        assert_eq!(parse_kube_current_context("current-context: \"\"\n"), None);
+    }
+
+    // --- .git/config 解析のテスト ---
+
+    const SAMPLE_GIT_CONFIG: &str = "[core]\n\trepositoryformatversion = 0\n[remote \"origin\"]\n\turl = git@example.com:a/b.git\n\tfetch = +refs/heads/*:refs/remotes/origin/*\n[branch \"main\"]\n\tremote = origin\n\tmerge = refs/heads/main\n[branch \"feature/x\"]\n\tremote = upstream\n\tmerge = refs/heads/develop\n[branch \"local-only\"]\n";
+
+    #[test]
+    fn test_parse_git_config_entries() {
+        let entries = parse_git_config(SAMPLE_GIT_CONFIG);
+        assert!(entries.contains(&GitConfigEntry {
+            section: "branch".to_string(),
+            subsection: Some("main".to_string()),
+            key: "merge".to_string(),
+            value: "refs/heads/main".to_string(),
+        }));
+    }
+
+    #[test]
+    fn test_parse_branch_upstream() {
+        let upstream = parse_branch_upstream(SAMPLE_GIT_CONFIG, "main").unwrap();
+        assert_eq!(upstream.display_name(), "origin/main");
+
+        // スラッシュ入りのブランチ名・別名の追跡先
+        let upstream = parse_branch_upstream(SAMPLE_GIT_CONFIG, "feature/x").unwrap();
+        assert_eq!(upstream.remote, "upstream");
+        assert_eq!(upstream.branch, "develop");
+    }
+
+    #[test]
+    fn test_parse_branch_upstream_none() {
+        // 追跡設定の無いブランチ・存在しないブランチ
+        assert_eq!(parse_branch_upstream(SAMPLE_GIT_CONFIG, "local-only"), None);
+        assert_eq!(parse_branch_upstream(SAMPLE_GIT_CONFIG, "nope"), None);
+    }
+
+    #[test]
+    fn test_parse_ahead_behind() {
+        assert_eq!(parse_ahead_behind("2\t1\n"), Some((2, 1)));
+        assert_eq!(parse_ahead_behind("0\t0\n"), Some((0, 0)));
+        assert_eq!(parse_ahead_behind(""), None);
+        assert_eq!(parse_ahead_behind("2"), None);
+        assert_eq!(parse_ahead_behind("a\tb"), None);
+    }
+
+    #[test]
+    fn test_get_git_ahead_behind() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .arg("-C")
+                .arg(tmp.path())
+                .args(["-c", "user.name=with", "-c", "user.email=with@example.com"])
+                .args(["-c", "commit.gpgsign=false"])
+                .args(args)
+                .output()
+        };
+        // git が無い環境では確かめられない
+        if git(&["init", "-q", "-b", "base"]).is_err() {
+            return;
+        }
+        git(&["commit", "-q", "--allow-empty", "-m", "base"]).unwrap();
+        git(&["checkout", "-q", "-b", "main"]).unwrap();
+        // 追跡先が無ければ表示しない
+        assert_eq!(get_git_ahead_behind(tmp.path()), None);
+
+        // 同じリポジトリの base を追跡し、2コミット進める
+        git(&["branch", "-q", "--set-upstream-to=base"]).unwrap();
+        git(&["commit", "-q", "--allow-empty", "-m", "one"]).unwrap();
+        git(&["commit", "-q", "--allow-empty", "-m", "two"]).unwrap();
+        assert_eq!(get_git_ahead_behind(tmp.path()), Some((2, 0)));
+    }
+
+    #[test]
+    fn test_get_git_upstream_from_fixture() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path().join(".git");
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(git_dir.join("config"), SAMPLE_GIT_CONFIG).unwrap();
+
+        let upstream = get_git_upstream(tmp.path()).unwrap();
+        assert_eq!(upstream.display_name(), "origin/main");
+
+        // detached HEAD では追跡先なし
+        fs::write(
+            git_dir.join("HEAD"),
+            "a1b2c3d4e5f67890abcdef1234567890abcdef12\n",
+        )
+        .unwrap();
+        assert_eq!(get_git_upstream(tmp.path()), None);
+    }
+
+    // --- 遅いファイルシステム対策のテスト ---
+
+    #[test]
+    fn test_is_skipped_path() {
+        let skip = vec!["/mnt/nas".to_string(), String::new()];
+        assert!(is_skipped_path(Path::new("/mnt/nas/project"), &skip));
+        assert!(!is_skipped_path(Path::new("/mnt/nas2"), &skip));
+        assert!(!is_skipped_path(Path::new("/home/user"), &skip));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_is_network_fs_type() {
+        assert!(is_network_fs_type(0x6969));
+        assert!(!is_network_fs_type(0xEF53)); // ext4
+        assert!(!is_network_fs(Path::new("/nonexistent/path")));
+    }
+
+    #[test]
+    fn test_run_with_timeout() {
+        assert_eq!(
+            run_with_timeout(Duration::from_secs(5), || Some(1)),
+            Some(1)
+        );
+
+        // 時間内に終わらなければ待たずに None
+        let slow = run_with_timeout(Duration::from_millis(10), || {
+            thread::sleep(Duration::from_millis(500));
+            Some(2)
+        });
+        assert_eq!(slow, None);
+    }
 }
@@ -0,0 +1,255 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// ディレクトリ固有の初期化ファイル名
+pub const DIR_FILE_NAME: &str = ".with.dir";
+
+/// 許可済みディレクトリを記録するファイルのパスを返す (~/.with_allowed_dirs)
+fn allowed_list_path() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".with_allowed_dirs"))
+}
+
+/// .with.dir の内容から実行するコマンド行を取り出す純粋関数
+/// 空行と "#" で始まるコメント行は無視する
+pub fn parse_dir_file(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// 許可リストの内容に dir が含まれているかを判定する純粋関数
+fn is_allowed_in(list: &str, dir: &Path) -> bool {
+    list.lines().any(|line| Path::new(line.trim()) == dir)
+}
+
+/// dir が許可済みかどうか
+fn is_allowed(dir: &Path) -> bool {
+    allowed_list_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .is_some_and(|list| is_allowed_in(&list, dir))
+}
+
+/// dir を許可リストに追記する
+fn allow(dir: &Path) {
+    let Some(path) = allowed_list_path() else {
+        return;
+    };
+
+    let result = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| writeln!(file, "{}", dir.display()));
+
+    if let Err(e) = result {
+        eprintln!("Warning: failed to record allowed directory: {}", e);
+    }
+}
+
+/// 未許可のディレクトリなら実行してよいかユーザーに確認する
+fn confirm(dir: &Path, lines: &[String]) -> bool {
+    println!("{} found in {}:", DIR_FILE_NAME, dir.display());
+    for line in lines {
+        println!("  {}", line);
+    }
+    print!("Allow and run these commands? [y/N] ");
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// 現在適用中のディレクトリ設定
+/// export した変数・エイリアスの元の値を覚えておき、ディレクトリを出たときに戻せるようにする
+#[derive(Default)]
+pub struct DirEnv {
+    active: Option<PathBuf>,
+    saved: Vec<(String, Option<String>)>,
+    saved_aliases: Vec<(String, Option<String>)>,
+}
+
+/// key の上書き前の値を (まだ記録していなければ) 記録する
+fn remember(saved: &mut Vec<(String, Option<String>)>, map: &BTreeMap<String, String>, key: &str) {
+    if !saved.iter().any(|(k, _)| k == key) {
+        saved.push((key.to_string(), map.get(key).cloned()));
+    }
+}
+
+/// 記録しておいた値に map を戻す (元が無ければ取り除く)
+fn restore_saved(saved: &mut Vec<(String, Option<String>)>, map: &mut BTreeMap<String, String>) {
+    for (key, value) in saved.drain(..) {
+        match value {
+            Some(v) => map.insert(key, v),
+            None => map.remove(&key),
+        };
+    }
+}
+
+impl DirEnv {
+    /// cd 後に呼ばれ、.with.dir を読み込んで実行すべきコマンド行を返す
+    /// restore_on_leave が true なら、適用中ディレクトリの外へ出た時点で exported と aliases を戻す
+    pub fn on_change_dir(
+        &mut self,
+        exported: &mut BTreeMap<String, String>,
+        aliases: &mut BTreeMap<String, String>,
+        cwd: &Path,
+        restore_on_leave: bool,
+    ) -> Vec<String> {
+        if let Some(active) = &self.active
+            && !cwd.starts_with(active)
+        {
+            if restore_on_leave {
+                restore_saved(&mut self.saved, exported);
+                restore_saved(&mut self.saved_aliases, aliases);
+            }
+            self.active = None;
+        }
+
+        let Ok(content) = fs::read_to_string(cwd.join(DIR_FILE_NAME)) else {
+            return vec![];
+        };
+        let lines = parse_dir_file(&content);
+        if lines.is_empty() {
+            return vec![];
+        }
+
+        if !is_allowed(cwd) {
+            if !confirm(cwd, &lines) {
+                println!("Skipped {}", DIR_FILE_NAME);
+                return vec![];
+            }
+            allow(cwd);
+        }
+
+        self.active = Some(cwd.to_path_buf());
+        lines
+    }
+
+    /// exported に変数をセットし、上書き前の値を記録する
+    pub fn set_var(&mut self, exported: &mut BTreeMap<String, String>, key: &str, value: &str) {
+        if self.active.is_some() {
+            remember(&mut self.saved, exported, key);
+        }
+        exported.insert(key.to_string(), value.to_string());
+    }
+
+    /// aliases にエイリアスを定義し、上書き前の定義を記録する
+    pub fn set_alias(
+        &mut self,
+        aliases: &mut BTreeMap<String, String>,
+        name: &str,
+        expansion: &str,
+    ) {
+        if self.active.is_some() {
+            remember(&mut self.saved_aliases, aliases, name);
+        }
+        aliases.insert(name.to_string(), expansion.to_string());
+    }
+
+    /// aliases からエイリアスを取り除き、元の定義を記録する (定義が無ければ false)
+    pub fn unalias(&mut self, aliases: &mut BTreeMap<String, String>, name: &str) -> bool {
+        if self.active.is_some() && aliases.contains_key(name) {
+            remember(&mut self.saved_aliases, aliases, name);
+        }
+        aliases.remove(name).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dir_file_skips_comments() {
+        let content = "# project env\nexport RUST_LOG=debug\n\n  export A=1  \n";
+        assert_eq!(
+            parse_dir_file(content),
+            vec!["export RUST_LOG=debug", "export A=1"]
+        );
+    }
+
+    #[test]
+    fn test_is_allowed_in() {
+        let list = "/home/user/a\n/home/user/b\n";
+        assert!(is_allowed_in(list, Path::new("/home/user/b")));
+        assert!(!is_allowed_in(list, Path::new("/home/user")));
+    }
+
+    #[test]
+    fn test_dir_env_restore_on_leave() {
+        let tmp = tempfile::tempdir().unwrap();
+        let key = "WITH_TEST_DIR_ENV_RESTORE";
+
+        let mut dir_env = DirEnv {
+            active: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        };
+        let mut exported = BTreeMap::from([("RUST_LOG".to_string(), "info".to_string())]);
+        dir_env.set_var(&mut exported, key, "inside");
+        dir_env.set_var(&mut exported, "RUST_LOG", "debug");
+        assert_eq!(exported.get(key).map(String::as_str), Some("inside"));
+
+        // ディレクトリの外 (.with.dir の無い場所) へ出たら元に戻る
+        let outside = tempfile::tempdir().unwrap();
+        let lines =
+            dir_env.on_change_dir(&mut exported, &mut BTreeMap::new(), outside.path(), true);
+        assert!(lines.is_empty());
+        assert_eq!(exported.get(key), None);
+        assert_eq!(exported.get("RUST_LOG").map(String::as_str), Some("info"));
+    }
+
+    #[test]
+    fn test_dir_env_restores_aliases_on_leave() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut dir_env = DirEnv {
+            active: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        };
+        let mut aliases = BTreeMap::from([
+            ("ll".to_string(), "ls -l".to_string()),
+            ("gs".to_string(), "git status".to_string()),
+        ]);
+        dir_env.set_alias(&mut aliases, "ll", "ls -la");
+        dir_env.set_alias(&mut aliases, "t", "cargo test");
+        assert!(dir_env.unalias(&mut aliases, "gs"));
+        assert!(!dir_env.unalias(&mut aliases, "nope"));
+        assert_eq!(aliases.get("ll").map(String::as_str), Some("ls -la"));
+
+        let outside = tempfile::tempdir().unwrap();
+        dir_env.on_change_dir(&mut BTreeMap::new(), &mut aliases, outside.path(), true);
+        assert_eq!(
+            aliases,
+            BTreeMap::from([
+                ("ll".to_string(), "ls -l".to_string()),
+                ("gs".to_string(), "git status".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_dir_env_keep_on_leave() {
+        let tmp = tempfile::tempdir().unwrap();
+        let key = "WITH_TEST_DIR_ENV_KEEP";
+
+        let mut dir_env = DirEnv {
+            active: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        };
+        let mut exported = BTreeMap::new();
+        dir_env.set_var(&mut exported, key, "inside");
+
+        let outside = tempfile::tempdir().unwrap();
+        dir_env.on_change_dir(&mut exported, &mut BTreeMap::new(), outside.path(), false);
+        assert_eq!(exported.get(key).map(String::as_str), Some("inside"));
+    }
+}
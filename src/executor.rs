@@ -1,5 +1,16 @@
+use crate::parser::{OutputRedirect, Redirects, TargetContext, TeeOutput};
+use crate::with_helper::{COLOR_GREEN, COLOR_RED, STYLE_RESET};
+use std::collections::BTreeMap;
 use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
 use std::process;
+#[cfg(unix)]
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 #[cfg(target_os = "windows")]
 fn resolve_program(program: &str) -> String {
@@ -14,40 +25,91 @@ fn resolve_program(program: &str) -> String {
     program.to_string()
 }
 
-/// 次のプロセスに渡すスタック文字列を計算する純粋関数
+/// 名前がシェルのエイリアス/関数によくある形かを判定する純粋関数
+/// (例: "gco", "gst", "ll" のような2~4文字の英小文字、または "g-" のような記号入り)
+fn looks_like_shell_alias(name: &str) -> bool {
+    if name.contains('/') || name.contains('\\') {
+        return false;
+    }
+    let short_lower = (2..=4).contains(&name.len()) && name.chars().all(|c| c.is_ascii_lowercase());
+    let has_symbol = name.chars().any(|c| matches!(c, '-' | '_' | '.')) && name.len() <= 4;
+    short_lower || has_symbol
+}
+
+/// PATH に見つからないコンテキストコマンドの案内文を組み立てる純粋関数
+/// shell はユーザーのログインシェル ($SHELL) で、案内するコマンド例に使う
+pub fn missing_program_message(program: &str, shell: Option<&str>) -> String {
+    let mut message = format!("Warning: '{}' was not found in PATH.", program);
+    if looks_like_shell_alias(program) {
+        message.push_str(&format!(
+            "\n'{}' may be a shell alias or function. Aliases and functions defined in your shell \
+             are not available inside with.\nUse the real command instead (e.g. `with git` rather than `with gco`), \
+             or run it through your shell: !{} -ic '{}'",
+            program,
+            shell.unwrap_or("bash"),
+            program
+        ));
+    }
+    message
+}
+
+/// コンテキストコマンドが実行可能か (PATH 上またはパス指定で存在するか) を確かめる
+pub fn program_exists(program: &str) -> bool {
+    which::which(program).is_ok()
+}
+
+/// WITH_CONTEXT_STACK を階層ごとに分ける純粋関数 (None はコンテキストなしの with)
+/// 新形式は JSON 配列 (例: `["git",null,"cargo"]`)。旧形式 (例: "git//cargo") は "/" で区切り、空の要素をコンテキストなしとみなす
+pub fn parse_context_stack(stack: &str) -> Vec<Option<String>> {
+    if stack.starts_with('[')
+        && let Ok(levels) = serde_json::from_str(stack)
+    {
+        return levels;
+    }
+    stack
+        .split('/')
+        .map(|level| (!level.is_empty()).then(|| level.to_string()))
+        .collect()
+}
+
+/// スタックをプロンプトに表示する形にする純粋関数 (例: ["git", None] -> "git/")
+pub fn format_context_stack(levels: &[Option<String>]) -> String {
+    levels
+        .iter()
+        .map(|level| level.as_deref().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// 次のプロセスに渡すスタック文字列 (JSON 配列) を計算する純粋関数
 ///
-/// * `parent_stack`: 親プロセスから受け取ったスタック (例: "git", "")。NoneならRoot。
-/// * `current_ctx`: 現在実行中のコンテキスト (例: "cargo")。Noneならwith単体。
+/// * `parent_stack`: 親プロセスから受け取ったスタック (旧形式も読む)。NoneならRoot。
+/// * `current_ctx`: 現在実行中のコンテキスト (例: "cargo")。Noneならwith単体 (null として積む)。
 fn compute_next_stack(parent_stack: Option<&str>, current_ctx: Option<&str>) -> String {
-    match parent_stack {
-        Some(parent) => {
-            // 既に親がいる場合
-            if let Some(ctx) = current_ctx {
-                // 親スタック + "/" + 自分 (例: "git" + "cargo" -> "git/cargo")
-                // 親が空文字("")の場合も "/cargo" となり、空階層が表現される
-                format!("{}/{}", parent, ctx)
-            } else {
-                // 自分はコンテキストなし (例: "git" + None -> "git/")
-                format!("{}/", parent)
-            }
-        }
-        None => {
-            // 親がいない (Root) 場合
-            if let Some(ctx) = current_ctx {
-                // 自分のコンテキストを起点にする (例: "git")
-                ctx.to_string()
-            } else {
-                // 自分もなしなら空文字 (例: "")
-                // これにより、子は「親はいるが空(Root直下)」と認識できる
-                String::new()
-            }
-        }
+    let mut levels = parent_stack.map(parse_context_stack).unwrap_or_default();
+    levels.push(current_ctx.map(|ctx| ctx.to_string()));
+    serde_json::to_string(&levels).unwrap_or_default()
+}
+
+/// 実行結果の要約行を組み立てる純粋関数 (例: "✓ git status (0.12s)")
+pub fn format_summary(command: &str, success: bool, elapsed: Duration, color: bool) -> String {
+    let mark = if success { "✓" } else { "✗" };
+    let body = format!("{} {} ({:.2}s)", mark, command, elapsed.as_secs_f64());
+
+    if !color {
+        return body;
     }
+    let color_code = if success { COLOR_GREEN } else { COLOR_RED };
+    format!("{}{}{}", color_code, body, STYLE_RESET)
 }
 
-// --- コマンド実行処理 ---
-/// 指定されたプログラムを子プロセスとして実行する関数
-pub fn execute_child_process(program: &str, args: Vec<String>, current_context_prog: Option<&str>) {
+/// 子プロセス用の Command を組み立てる (WITH_CONTEXT_STACK の引き継ぎ・`export` した変数・コンテキストの環境変数を含む)
+fn build_command(
+    program: &str,
+    args: Vec<String>,
+    current_context: Option<&TargetContext>,
+    exported: &BTreeMap<String, String>,
+) -> process::Command {
     let program_path = resolve_program(program);
 
     let mut command = process::Command::new(program_path);
@@ -58,33 +120,888 @@ pub fn execute_child_process(program: &str, args: Vec<String>, current_context_p
     let parent_stack_str = parent_stack_opt.as_deref();
 
     // 次のスタックを計算
+    let current_context_prog = current_context.map(|ctx| ctx.program.as_str());
     let new_stack = compute_next_stack(parent_stack_str, current_context_prog);
 
     // 環境変数をセット
     command.env("WITH_CONTEXT_STACK", new_stack);
+    command.envs(exported);
+    if let Some(ctx) = current_context {
+        command.envs(ctx.env.iter().map(|(k, v)| (k, v)));
+    }
+    command
+}
+
+/// 指定されたプログラムをバックグラウンドで起動する (終了を待たない)
+/// 端末入力と Ctrl+C を取り合わないよう、stdin を切り離し別プロセスグループで起動する
+pub fn spawn_background(
+    program: &str,
+    args: Vec<String>,
+    current_context: Option<&TargetContext>,
+    exported: &BTreeMap<String, String>,
+) -> std::io::Result<process::Child> {
+    let mut command = build_command(program, args, current_context, exported);
+    command.stdin(process::Stdio::null());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    command.spawn()
+}
+
+/// 子プロセスが色を付けたまま終了しても次のプロンプトに漏れないよう、文字装飾をリセットする
+/// 出力は端末を直接継承させたまま、終了後にリセットを1回書き込むだけにする
+fn reset_terminal_style() {
+    let mut stdout = io::stdout();
+    if stdout.is_terminal() && env::var_os("NO_COLOR").is_none() {
+        let _ = stdout.write_all(STYLE_RESET.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+/// 子プロセスの実行前の端末設定を覚えておき、終了後に戻す
+/// 対話コマンドが raw モードのまま異常終了しても、readline の入力が壊れないようにする
+pub struct TerminalGuard {
+    #[cfg(unix)]
+    saved: Option<libc::termios>,
+}
+
+impl TerminalGuard {
+    #[cfg(unix)]
+    pub fn capture() -> Self {
+        if !io::stdin().is_terminal() {
+            return Self { saved: None };
+        }
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: 標準入力の fd に対して端末設定を読み出すだけ
+        let ok = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } == 0;
+        Self {
+            saved: ok.then_some(termios),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn capture() -> Self {
+        Self {}
+    }
+
+    /// capture した端末設定に書き戻し、文字装飾をリセットする (何度呼んでもよい)
+    pub fn restore(&self) {
+        #[cfg(unix)]
+        if let Some(termios) = &self.saved {
+            // SAFETY: capture で読み出した設定をそのまま書き戻す
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+            }
+        }
+        reset_terminal_style();
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// シグナルで終了したときの終了コードの基準 (シェルと同じく 128 + シグナル番号)
+const SIGNAL_EXIT_BASE: i32 = 128;
+
+/// シグナル番号の名前を返す純粋関数 (例: 11 -> "SIGSEGV")。主なものだけ
+#[cfg(unix)]
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGUSR2 => "SIGUSR2",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// シグナル終了の表示文を組み立てる純粋関数 (例: "terminated by signal 11 (SIGSEGV)")
+#[cfg(unix)]
+pub fn format_signal_exit(signal: i32) -> String {
+    match signal_name(signal) {
+        Some(name) => format!("terminated by signal {} ({})", signal, name),
+        None => format!("terminated by signal {}", signal),
+    }
+}
+
+/// Windows の異常終了コード (NTSTATUS のエラー値) なら表示文を組み立てる純粋関数
+/// 例: -1073741819 -> "terminated abnormally (exit code 0xC0000005)"
+pub fn format_abnormal_exit(code: i32) -> Option<String> {
+    let code = code as u32;
+    (code >= 0xC000_0000).then(|| format!("terminated abnormally (exit code 0x{:08X})", code))
+}
+
+/// シグナルで終了したことを表示し、128 + シグナル番号を終了コードにする
+/// Ctrl+C (SIGINT) とパイプの切断 (SIGPIPE) はよくある終わり方なので表示しない
+#[cfg(unix)]
+fn signal_exit(signal: i32) -> i32 {
+    if !matches!(signal, libc::SIGINT | libc::SIGPIPE) {
+        eprintln!("with: {}", format_signal_exit(signal));
+    }
+    SIGNAL_EXIT_BASE + signal
+}
+
+/// 子プロセスの終了状態から終了コードを求める (異常終了はコードをそのまま表示する)
+#[cfg(not(unix))]
+fn exit_status_code(status: process::ExitStatus) -> Option<i32> {
+    let code = status.code();
+    if let Some(message) = code.and_then(format_abnormal_exit) {
+        eprintln!("with: {}", message);
+    }
+    code
+}
+
+/// ネストした with が `exit` で全て終了するときの終了コード
+pub const EXIT_ALL_CODE: i32 = 127;
+
+/// フォアグラウンドで実行した結果
+#[derive(Debug)]
+pub enum Foreground {
+    /// 終了した (起動に失敗した場合は None。シグナルで終了した場合は 128 + シグナル番号)
+    Exited(Option<i32>),
+    /// Ctrl+Z で停止した (ジョブ一覧に登録し、`fg` / `bg` で再開する)
+    /// パイプラインなら child が最後の段、upstream がそれより前の段 (全段が同じプロセスグループ)
+    Stopped {
+        child: process::Child,
+        upstream: Vec<process::Child>,
+    },
+    /// 子の with が全終了 (EXIT_ALL_CODE) で終わった (呼び出し側も後を追って終了する)
+    ExitAll,
+}
+
+impl Foreground {
+    /// 終了コードから結果を作る (EXIT_ALL_CODE なら ExitAll)
+    pub fn exited(code: Option<i32>) -> Self {
+        if code == Some(EXIT_ALL_CODE) {
+            Self::ExitAll
+        } else {
+            Self::Exited(code)
+        }
+    }
+
+    /// 終了コード (停止した場合は None)
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            Self::Exited(code) => *code,
+            Self::Stopped { .. } => None,
+            Self::ExitAll => Some(EXIT_ALL_CODE),
+        }
+    }
+}
+
+/// 子プロセスを待った結果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WaitResult {
+    Exited(Option<i32>),
+    Stopped,
+}
+
+/// 子だけを Ctrl+Z で止められるか (自分が端末のフォアグラウンドにいるときだけジョブ制御する)
+#[cfg(unix)]
+fn job_control_enabled() -> bool {
+    // SAFETY: 端末のフォアグラウンドのプロセスグループと自分のプロセスグループを読むだけ
+    io::stdin().is_terminal() && unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() }
+}
+
+/// ジョブ制御は Unix のみ対応
+#[cfg(not(unix))]
+fn job_control_enabled() -> bool {
+    false
+}
+
+/// 端末のフォアグラウンドを pgid のプロセスグループにする
+/// バックグラウンドから呼ぶと SIGTTOU で止まるため、その間だけブロックする (fork 後の子からも呼ぶ)
+#[cfg(unix)]
+fn set_terminal_foreground(pgid: libc::pid_t) {
+    // SAFETY: シグナルマスクと端末のプロセスグループの操作のみ (いずれも async-signal-safe)
+    unsafe {
+        let mut block: libc::sigset_t = std::mem::zeroed();
+        let mut saved: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut block);
+        libc::sigaddset(&mut block, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &block, &mut saved);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &saved, std::ptr::null_mut());
+    }
+}
+
+/// 子を別のプロセスグループで起動し、端末のフォアグラウンドにする
+/// leader が None なら子自身がリーダーになり、Some ならそのプロセスグループ (パイプラインの先頭の段) に入る
+#[cfg(unix)]
+fn enter_foreground_group(command: &mut process::Command, leader: Option<u32>) {
+    use std::os::unix::process::CommandExt;
+    let pgid = leader.map_or(0, |pid| pid as libc::pid_t);
+    // SAFETY: fork 後の子では async-signal-safe な関数だけを呼ぶ
+    unsafe {
+        command.pre_exec(move || {
+            libc::setpgid(0, pgid);
+            set_terminal_foreground(libc::getpgrp());
+            Ok(())
+        });
+    }
+}
+
+/// waitpid で得た状態を終了・停止に読み替える
+#[cfg(unix)]
+fn wait_result(status: libc::c_int) -> WaitResult {
+    if libc::WIFSTOPPED(status) {
+        return WaitResult::Stopped;
+    }
+    if libc::WIFSIGNALED(status) {
+        return WaitResult::Exited(Some(signal_exit(libc::WTERMSIG(status))));
+    }
+    WaitResult::Exited(libc::WIFEXITED(status).then(|| libc::WEXITSTATUS(status)))
+}
+
+/// 終了するか Ctrl+Z で停止するまで待つ
+#[cfg(unix)]
+fn wait_untraced(child: &process::Child) -> io::Result<WaitResult> {
+    let pid = child.id() as libc::pid_t;
+    loop {
+        let mut status = 0;
+        // SAFETY: 自分が起動した子プロセスを待つだけ
+        if unsafe { libc::waitpid(pid, &mut status, libc::WUNTRACED) } == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        return Ok(wait_result(status));
+    }
+}
+
+/// プロセスグループの子が全員終了するか、どれかが Ctrl+Z で停止するまで待つ
+/// 終了コードは last (パイプラインの最後の段) のものを返す
+#[cfg(unix)]
+fn wait_group(pgid: libc::pid_t, last: libc::pid_t) -> io::Result<WaitResult> {
+    let mut code = None;
+    loop {
+        let mut status = 0;
+        // SAFETY: 自分が起動した子プロセス (のプロセスグループ) を待つだけ
+        let pid = unsafe { libc::waitpid(-pgid, &mut status, libc::WUNTRACED) };
+        if pid == -1 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // 待つ子が残っていない
+                Some(libc::ECHILD) => return Ok(WaitResult::Exited(code)),
+                _ => return Err(err),
+            }
+        }
+        match wait_result(status) {
+            WaitResult::Stopped => return Ok(WaitResult::Stopped),
+            WaitResult::Exited(exited) if pid == last => code = exited,
+            WaitResult::Exited(_) => {}
+        }
+    }
+}
+
+/// 端末を子のプロセスグループに渡して全段を待ち、終了・停止したら取り戻す
+/// 自分と同じプロセスグループの子 (ジョブ制御せずに起動したもの) は1つずつ待つ
+#[cfg(unix)]
+fn wait_foreground(children: &[&process::Child], job_control: bool) -> io::Result<WaitResult> {
+    let (Some(leader), Some(last)) = (children.first(), children.last()) else {
+        return Ok(WaitResult::Exited(None));
+    };
+    // SAFETY: 自分と子のプロセスグループ ID を読むだけ
+    let shares_group = children
+        .iter()
+        .any(|child| unsafe { libc::getpgid(child.id() as libc::pid_t) == libc::getpgrp() });
+    if !job_control || shares_group {
+        let mut result = Ok(WaitResult::Exited(None));
+        for child in children {
+            result = wait_untraced(child);
+            if !matches!(result, Ok(WaitResult::Exited(_))) {
+                break;
+            }
+        }
+        return result;
+    }
+    let pgid = leader.id() as libc::pid_t;
+    set_terminal_foreground(pgid);
+    let result = wait_group(pgid, last.id() as libc::pid_t);
+    // SAFETY: 自分のプロセスグループ ID を読むだけ
+    set_terminal_foreground(unsafe { libc::getpgrp() });
+    result
+}
+
+#[cfg(not(unix))]
+fn wait_foreground(children: &mut [process::Child]) -> io::Result<WaitResult> {
+    let mut code = None;
+    for child in children.iter_mut() {
+        code = exit_status_code(child.wait()?);
+    }
+    Ok(WaitResult::Exited(code))
+}
+
+/// 起動した全段 (単独のコマンドなら1つ) をフォアグラウンドで待つ
+/// 終了したら最後の段の終了コードを、Ctrl+Z で止まったらジョブにできるよう全段を返す
+fn wait_stages(
+    mut children: Vec<process::Child>,
+    job_control: bool,
+    suspend_hint: Option<Duration>,
+) -> Foreground {
+    #[cfg(unix)]
+    let result = {
+        let _hint = suspend_hint.filter(|_| job_control).map(spawn_suspend_hint);
+        let stages: Vec<&process::Child> = children.iter().collect();
+        wait_foreground(&stages, job_control)
+    };
+    #[cfg(not(unix))]
+    let result = {
+        let _ = (job_control, suspend_hint);
+        wait_foreground(&mut children)
+    };
+
+    match result {
+        Ok(WaitResult::Exited(code)) => Foreground::exited(code),
+        Ok(WaitResult::Stopped) => match children.pop() {
+            Some(child) => Foreground::Stopped {
+                child,
+                upstream: children,
+            },
+            None => Foreground::Exited(None),
+        },
+        Err(e) => {
+            eprintln!("Error waiting for process: {}", e);
+            Foreground::Exited(None)
+        }
+    }
+}
+
+/// 一定時間たっても終わらないコマンドに、Ctrl+Z でバックグラウンドへ回せることを案内する
+/// 返り値を drop すると (先にコマンドが終われば) 案内は出さない
+#[cfg(unix)]
+fn spawn_suspend_hint(after: Duration) -> mpsc::Sender<()> {
+    let (tx, rx) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        if rx.recv_timeout(after) == Err(mpsc::RecvTimeoutError::Timeout) {
+            eprintln!(
+                "\r\n[with] still running: press Ctrl+Z to suspend it, then `bg` to continue in the background"
+            );
+        }
+    });
+    tx
+}
+
+// --- コマンド実行処理 ---
+/// 指定されたプログラムを子プロセスとして実行する関数
+/// Unix では子を別のプロセスグループにして端末を渡し、Ctrl+Z で子だけを停止できるようにする
+/// suspend_hint が指定されていれば、その時間を過ぎても終わらないときに Ctrl+Z を案内する
+pub fn execute_child_process(
+    program: &str,
+    args: Vec<String>,
+    current_context: Option<&TargetContext>,
+    exported: &BTreeMap<String, String>,
+    suspend_hint: Option<Duration>,
+) -> Foreground {
+    execute_with_redirects(
+        program,
+        args,
+        current_context,
+        exported,
+        &Redirects::default(),
+        suspend_hint,
+    )
+}
+
+/// リダイレクト先のファイルを開く (`>>` なら追記、`>` なら上書き)
+fn open_output(output: &OutputRedirect) -> io::Result<File> {
+    if output.append {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&output.path)
+    } else {
+        File::create(&output.path)
+    }
+}
+
+/// 標準入出力をファイルにつなぎ替えて子プロセスを実行する (`cmd > file` など)
+/// ファイルを開けなければコマンドを起動せずにエラーを表示する。それ以外は execute_child_process と同じ
+pub fn execute_with_redirects(
+    program: &str,
+    args: Vec<String>,
+    current_context: Option<&TargetContext>,
+    exported: &BTreeMap<String, String>,
+    redirects: &Redirects,
+    suspend_hint: Option<Duration>,
+) -> Foreground {
+    let command = build_command(program, args, current_context, exported);
+    run_foreground(command, program, redirects, suspend_hint)
+}
+
+/// 作業ディレクトリを指定して子プロセスを実行する (`in <dir> <cmd>`)
+/// with 自身のカレントディレクトリは変えない。それ以外は execute_child_process と同じ
+pub fn execute_in_dir(
+    program: &str,
+    args: Vec<String>,
+    current_context: Option<&TargetContext>,
+    exported: &BTreeMap<String, String>,
+    dir: &Path,
+    suspend_hint: Option<Duration>,
+) -> Foreground {
+    let mut command = build_command(program, args, current_context, exported);
+    command.current_dir(dir);
+    run_foreground(command, program, &Redirects::default(), suspend_hint)
+}
+
+/// 組み立てたコマンドをフォアグラウンドで実行し、終了か停止まで待つ
+fn run_foreground(
+    mut command: process::Command,
+    program: &str,
+    redirects: &Redirects,
+    suspend_hint: Option<Duration>,
+) -> Foreground {
+    // 対話コマンド (python, psql など) が端末を直接読めるよう、標準入出力を明示的に継承する
+    // 実行中は readline が入力を読まないので、子の Ctrl+D (EOF) は子だけに届く
+    command
+        .stdin(process::Stdio::inherit())
+        .stdout(process::Stdio::inherit())
+        .stderr(process::Stdio::inherit());
+    if let Some(path) = &redirects.input {
+        match File::open(path) {
+            Ok(file) => {
+                command.stdin(file);
+            }
+            Err(e) => {
+                eprintln!("with: {}: {}", path, e);
+                return Foreground::Exited(None);
+            }
+        }
+    }
+    if let Some(output) = &redirects.output {
+        match open_output(output) {
+            Ok(file) => {
+                command.stdout(file);
+            }
+            Err(e) => {
+                eprintln!("with: {}: {}", output.path, e);
+                return Foreground::Exited(None);
+            }
+        }
+    }
+    let guard = TerminalGuard::capture();
+
+    let job_control = job_control_enabled();
+    #[cfg(unix)]
+    if job_control {
+        enter_foreground_group(&mut command, None);
+    }
 
     // spawn() でプロセスを開始
-    match command.spawn() {
-        Ok(mut child) => {
-            // wait() で子プロセスの終了を待機する
-            match child.wait() {
-                Ok(status) => {
-                    // 子プロセスが「全終了(127)」で死んだ場合、自分も後を追う
-                    if let Some(code) = status.code()
-                        && code == 127
-                    {
-                        process::exit(127);
-                    }
-                }
+    let child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to execute command '{}': {}", program, e);
+            return Foreground::Exited(None);
+        }
+    };
+
+    let result = wait_stages(vec![child], job_control, suspend_hint);
+    drop(guard);
+    result
+}
+
+/// 停止中のジョブに SIGCONT を送って再開させる (`bg`)
+/// パイプラインは先頭の段 (プロセスグループのリーダー) を渡すと全段に届く
+#[cfg(unix)]
+pub fn continue_job(child: &process::Child) -> io::Result<()> {
+    let pid = child.id() as libc::pid_t;
+    // SAFETY: 自分が起動した子プロセス (のプロセスグループ) にシグナルを送るだけ
+    // 子がプロセスグループのリーダーでなければ本人にだけ送る
+    let ok = unsafe { libc::kill(-pid, libc::SIGCONT) == 0 || libc::kill(pid, libc::SIGCONT) == 0 };
+    if ok {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// 停止中・バックグラウンドのジョブ (パイプラインなら先頭から順に全段) に端末を渡して再開し、
+/// 終了か再停止まで待つ (`fg`)
+#[cfg(unix)]
+pub fn resume_foreground(children: &[&process::Child]) -> io::Result<WaitResult> {
+    let Some(leader) = children.first() else {
+        return Ok(WaitResult::Exited(None));
+    };
+    let guard = TerminalGuard::capture();
+    let job_control = job_control_enabled();
+    if job_control {
+        set_terminal_foreground(leader.id() as libc::pid_t);
+    }
+    let result = continue_job(leader).and_then(|()| wait_foreground(children, job_control));
+    drop(guard);
+    result
+}
+
+/// ジョブ制御は Unix のみ対応
+#[cfg(not(unix))]
+pub fn continue_job(_child: &process::Child) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "job control is not supported on Windows",
+    ))
+}
+
+/// ジョブ制御は Unix のみ対応
+#[cfg(not(unix))]
+pub fn resume_foreground(children: &[&process::Child]) -> io::Result<WaitResult> {
+    children
+        .iter()
+        .try_for_each(|child| continue_job(child))
+        .map(|()| WaitResult::Stopped)
+}
+
+/// tee の書き写し先 (エラー表示に使う名前と出力先)
+type TeeSinks = Vec<(String, Box<dyn Write + Send>)>;
+
+/// 入力を out と各ファイルへ書き写す
+/// ファイルへの書き込みに失敗したら警告を出してそのファイルだけ外し、画面出力は続ける
+fn tee_stream<R: Read, W: Write>(
+    mut input: R,
+    out: &mut W,
+    sinks: &mut TeeSinks,
+) -> io::Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        out.write_all(&buf[..n])?;
+        out.flush()?;
+
+        sinks.retain_mut(|(name, sink)| match sink.write_all(&buf[..n]) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("tee: {}: {}", name, e);
+                false
+            }
+        });
+    }
+    for (name, sink) in sinks.iter_mut() {
+        if let Err(e) = sink.flush() {
+            eprintln!("tee: {}: {}", name, e);
+        }
+    }
+    Ok(())
+}
+
+/// 子の出力を画面 (to_stderr なら標準エラー出力) と sinks へ書き写すスレッドを起動する
+/// 子が Ctrl+Z で停止してジョブになっても書き写しを続けられるよう、呼び出し元からは切り離しておく
+fn spawn_tee<R: Read + Send + 'static>(
+    input: R,
+    to_stderr: bool,
+    mut sinks: TeeSinks,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let result = if to_stderr {
+            tee_stream(input, &mut io::stderr(), &mut sinks)
+        } else {
+            tee_stream(input, &mut io::stdout(), &mut sinks)
+        };
+        if let Err(e) = result {
+            eprintln!("tee: {}", e);
+        }
+    })
+}
+
+/// 書き写しスレッドが出力を流し終えるまで待つ (停止してジョブになったときは待たずに書き写しを続けさせる)
+fn join_tees(result: &Foreground, tees: Vec<thread::JoinHandle<()>>) {
+    if matches!(result, Foreground::Stopped { .. }) {
+        return;
+    }
+    for tee in tees {
+        let _ = tee.join();
+    }
+}
+
+/// 書き写しスレッドと共有する取り込み先
+/// take した後の書き込みは捨てる (停止したジョブの出力を取り込み続けないようにする)
+#[derive(Clone)]
+struct SharedBuffer(Arc<Mutex<Option<Vec<u8>>>>);
+
+impl SharedBuffer {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(Some(Vec::new()))))
+    }
+
+    /// 取り込んだ分を取り出し、以降は取り込まない
+    fn take(&self) -> Vec<u8> {
+        self.0
+            .lock()
+            .ok()
+            .and_then(|mut buf| buf.take())
+            .unwrap_or_default()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Ok(mut inner) = self.0.lock()
+            && let Some(inner) = inner.as_mut()
+        {
+            inner.extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// tee の出力先ファイルを開く (開けないファイルは警告を出して除外する)
+fn open_tee_files(files: &[String], append: bool) -> TeeSinks {
+    files
+        .iter()
+        .filter_map(|name| {
+            let result = if append {
+                OpenOptions::new().create(true).append(true).open(name)
+            } else {
+                File::create(name)
+            };
+            match result {
+                Ok(file) => Some((name.clone(), Box::new(file) as Box<dyn Write + Send>)),
                 Err(e) => {
-                    eprintln!("Error waiting for process: {}", e);
+                    eprintln!("tee: {}: {}", name, e);
+                    None
                 }
             }
-        }
+        })
+        .collect()
+}
+
+/// 子プロセスの標準出力を画面とファイルの両方へ流しながら実行する
+/// 終了コード・Ctrl+Z での停止の扱いは execute_child_process と同じ
+pub fn execute_with_tee(
+    program: &str,
+    args: Vec<String>,
+    current_context: Option<&TargetContext>,
+    exported: &BTreeMap<String, String>,
+    files: &[String],
+    append: bool,
+    suspend_hint: Option<Duration>,
+) -> Foreground {
+    let sinks = open_tee_files(files, append);
+    execute_with_sinks(
+        program,
+        args,
+        current_context,
+        exported,
+        sinks,
+        None,
+        suspend_hint,
+    )
+}
+
+/// 取り込んだ子プロセスの出力 (バイナリの可能性があるのでバイト列のまま持つ)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CapturedOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// 子プロセスの標準出力・標準エラー出力を画面に流しながら取り込み、(結果, 出力) を返す
+/// `--record` / `--log` 用。Ctrl+Z で停止したら、それまでに取り込んだ分を返す
+pub fn execute_capturing(
+    program: &str,
+    args: Vec<String>,
+    current_context: Option<&TargetContext>,
+    exported: &BTreeMap<String, String>,
+    suspend_hint: Option<Duration>,
+) -> (Foreground, CapturedOutput) {
+    let mut captured = CapturedOutput::default();
+    let result = execute_with_sinks(
+        program,
+        args,
+        current_context,
+        exported,
+        Vec::new(),
+        Some(&mut captured),
+        suspend_hint,
+    );
+    (result, captured)
+}
+
+/// 標準出力を画面と sinks へ書き写しながら実行する
+/// capture を渡すと標準出力と標準エラー出力を (画面へも流しつつ) 取り込む
+/// 書き写しは別スレッドで行い、子は (ジョブ制御できれば) 端末のフォアグラウンドのプロセスグループで動かす
+fn execute_with_sinks(
+    program: &str,
+    args: Vec<String>,
+    current_context: Option<&TargetContext>,
+    exported: &BTreeMap<String, String>,
+    mut sinks: TeeSinks,
+    capture: Option<&mut CapturedOutput>,
+    suspend_hint: Option<Duration>,
+) -> Foreground {
+    let mut command = build_command(program, args, current_context, exported);
+    command.stdout(process::Stdio::piped());
+    if capture.is_some() {
+        command.stderr(process::Stdio::piped());
+    }
+    let guard = TerminalGuard::capture();
+
+    let job_control = job_control_enabled();
+    #[cfg(unix)]
+    if job_control {
+        enter_foreground_group(&mut command, None);
+    }
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
         Err(e) => {
             eprintln!("Failed to execute command '{}': {}", program, e);
+            return Foreground::Exited(None);
         }
+    };
+
+    let buffers = capture
+        .is_some()
+        .then(|| (SharedBuffer::new(), SharedBuffer::new()));
+    let mut tees = Vec::new();
+    if let Some((stdout, _)) = &buffers {
+        sinks.push(("record".to_string(), Box::new(stdout.clone())));
+    }
+    if let Some(output) = child.stdout.take() {
+        tees.push(spawn_tee(output, false, sinks));
+    }
+    if let (Some(output), Some((_, stderr))) = (child.stderr.take(), &buffers) {
+        let sinks: TeeSinks = vec![("record".to_string(), Box::new(stderr.clone()))];
+        tees.push(spawn_tee(output, true, sinks));
     }
+
+    let result = wait_stages(vec![child], job_control, suspend_hint);
+    join_tees(&result, tees);
+    drop(guard);
+    if let (Some(captured), Some((stdout, stderr))) = (capture, buffers) {
+        captured.stdout = stdout.take();
+        captured.stderr = stderr.take();
+    }
+    result
+}
+
+/// パイプでつないだコマンドを実行し、最後の段の終了コードを返す
+/// `export` した変数は全段に、コンテキスト (環境変数・スタック) と入力のつなぎ替えは先頭の段に、出力のつなぎ替えは最後の段に適用する
+/// ファイルを開けなければどの段も起動しない
+/// tee・capture を渡すと、(ファイルへのつなぎ替えが無ければ) 最後の段の標準出力を画面に流しながら
+/// tee のファイルへ書き写し、capture に取り込む
+/// 全段を先頭の段のプロセスグループに入れるので、Ctrl+Z で全段をまとめて停止できる
+pub fn execute_pipeline(
+    stages: Vec<(String, Vec<String>)>,
+    current_context: Option<&TargetContext>,
+    exported: &BTreeMap<String, String>,
+    redirects: &Redirects,
+    tee: Option<&TeeOutput>,
+    capture: Option<&mut Vec<u8>>,
+    suspend_hint: Option<Duration>,
+) -> Foreground {
+    let mut input = match redirects.input.as_ref().map(File::open).transpose() {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("with: {}: {}", redirects.input.as_deref().unwrap_or(""), e);
+            return Foreground::Exited(None);
+        }
+    };
+    let mut output = match redirects.output.as_ref().map(open_output).transpose() {
+        Ok(output) => output,
+        Err(e) => {
+            let path = redirects.output.as_ref().map_or("", |o| o.path.as_str());
+            eprintln!("with: {}: {}", path, e);
+            return Foreground::Exited(None);
+        }
+    };
+    let copy_output = output.is_none() && (tee.is_some() || capture.is_some());
+    let guard = TerminalGuard::capture();
+    let job_control = job_control_enabled();
+    let last = stages.len().saturating_sub(1);
+    let mut children: Vec<process::Child> = Vec::new();
+    let mut prev_stdout: Option<process::ChildStdout> = None;
+    let mut failed = false;
+
+    for (i, (program, args)) in stages.into_iter().enumerate() {
+        let context = if i == 0 { current_context } else { None };
+        let mut command = build_command(&program, args, context, exported);
+        if let Some(stdout) = prev_stdout.take() {
+            command.stdin(stdout);
+        } else if let Some(file) = input.take() {
+            command.stdin(file);
+        }
+        if i < last {
+            command.stdout(process::Stdio::piped());
+        } else if let Some(file) = output.take() {
+            command.stdout(file);
+        } else if copy_output {
+            command.stdout(process::Stdio::piped());
+        }
+        #[cfg(unix)]
+        if job_control {
+            enter_foreground_group(&mut command, children.first().map(process::Child::id));
+        }
+        match command.spawn() {
+            Ok(mut child) => {
+                prev_stdout = child.stdout.take();
+                children.push(child);
+            }
+            Err(e) => {
+                eprintln!("Failed to execute command '{}': {}", program, e);
+                failed = true;
+                break;
+            }
+        }
+    }
+    let buffer = capture.is_some().then(SharedBuffer::new);
+    let mut tees = Vec::new();
+    if !failed
+        && copy_output
+        && let Some(stdout) = prev_stdout.take()
+    {
+        let mut sinks = tee
+            .map(|tee| open_tee_files(&tee.files, tee.append))
+            .unwrap_or_default();
+        if let Some(buffer) = &buffer {
+            sinks.push(("record".to_string(), Box::new(buffer.clone())));
+        }
+        tees.push(spawn_tee(stdout, false, sinks));
+    }
+    // 途中で起動に失敗した場合、前段は書き込み先を失って終了する
+    drop(prev_stdout);
+
+    let result = wait_stages(children, job_control, suspend_hint);
+    join_tees(&result, tees);
+    drop(guard);
+    if let (Some(capture), Some(buffer)) = (capture, buffer) {
+        capture.extend_from_slice(&buffer.take());
+    }
+
+    if failed && !matches!(result, Foreground::Stopped { .. }) {
+        return Foreground::Exited(None);
+    }
+    result
 }
 
 // --- テスト ---
@@ -96,58 +1013,609 @@ mod tests {
 
     #[test]
     fn test_stack_root_with_context() {
-        // Root: with git -> child stack should be "git"
+        // Root: with git -> child stack should be ["git"]
         let res = compute_next_stack(None, Some("git"));
-        assert_eq!(res, "git");
+        assert_eq!(res, r#"["git"]"#);
     }
 
     #[test]
     fn test_stack_root_no_context() {
-        // Root: with -> child stack should be "" (Empty Root)
+        // Root: with -> child stack should be [null] (Empty Root)
         let res = compute_next_stack(None, None);
-        assert_eq!(res, "");
+        assert_eq!(res, "[null]");
     }
 
     #[test]
     fn test_stack_nested_context() {
-        // L1: git -> rc cargo -> "git/cargo"
-        let res = compute_next_stack(Some("git"), Some("cargo"));
-        assert_eq!(res, "git/cargo");
+        // L1: git -> rc cargo -> ["git","cargo"]
+        let res = compute_next_stack(Some(r#"["git"]"#), Some("cargo"));
+        assert_eq!(res, r#"["git","cargo"]"#);
     }
 
     #[test]
     fn test_stack_nested_no_context() {
-        // L1: git -> rc -> "git/"
-        let res = compute_next_stack(Some("git"), None);
-        assert_eq!(res, "git/");
+        // L1: git -> rc -> ["git",null]
+        let res = compute_next_stack(Some(r#"["git"]"#), None);
+        assert_eq!(res, r#"["git",null]"#);
+    }
+
+    #[test]
+    fn test_stack_keeps_slash_in_program() {
+        // "/" を含むコンテキスト (./run.sh) も1階層として扱える
+        let res = compute_next_stack(Some("[null]"), Some("./run.sh"));
+        assert_eq!(res, r#"[null,"./run.sh"]"#);
+        assert_eq!(
+            parse_context_stack(&res),
+            vec![None, Some("./run.sh".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_stack_reads_legacy_format() {
+        // 旧形式 ("/" 区切り、空はコンテキストなし) を受け取っても新形式で引き継ぐ
+        assert_eq!(
+            compute_next_stack(Some("git"), Some("cargo")),
+            r#"["git","cargo"]"#
+        );
+        assert_eq!(compute_next_stack(Some(""), Some("git")), r#"[null,"git"]"#);
+        assert_eq!(compute_next_stack(Some("/"), None), "[null,null,null]");
+        assert_eq!(
+            parse_context_stack("git/cargo/bun"),
+            vec![
+                Some("git".to_string()),
+                Some("cargo".to_string()),
+                Some("bun".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_context_stack() {
+        assert_eq!(
+            format_context_stack(&parse_context_stack(r#"["git","cargo"]"#)),
+            "git/cargo"
+        );
+        assert_eq!(
+            format_context_stack(&parse_context_stack(r#"["git",null]"#)),
+            "git/"
+        );
+        assert_eq!(format_context_stack(&parse_context_stack("[null]")), "");
+        assert_eq!(format_context_stack(&parse_context_stack("git/")), "git/");
+    }
+
+    // --- missing_program_message のテスト ---
+
+    #[test]
+    fn test_looks_like_shell_alias() {
+        assert!(looks_like_shell_alias("gco"));
+        assert!(looks_like_shell_alias("ll"));
+        assert!(looks_like_shell_alias("g-"));
+        assert!(!looks_like_shell_alias("terraform"));
+        assert!(!looks_like_shell_alias("./gco"));
+    }
+
+    #[test]
+    fn test_missing_program_message() {
+        let alias = missing_program_message("gco", Some("/bin/zsh"));
+        assert!(alias.contains("not found in PATH"));
+        assert!(alias.contains("shell alias or function"));
+        assert!(alias.contains("!/bin/zsh -ic 'gco'"));
+
+        // エイリアスらしくない名前には推測を付けない
+        let other = missing_program_message("my-long-tool", None);
+        assert!(!other.contains("alias"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_program_exists() {
+        assert!(program_exists("sh"));
+        assert!(!program_exists("non_existent_command_12345aaaaaaaa"));
+    }
+
+    // --- format_summary のテスト ---
+
+    #[test]
+    fn test_summary_success_plain() {
+        let res = format_summary("git status", true, Duration::from_millis(120), false);
+        assert_eq!(res, "✓ git status (0.12s)");
+    }
+
+    #[test]
+    fn test_summary_failure_plain() {
+        let res = format_summary("cargo test", false, Duration::from_secs(3), false);
+        assert_eq!(res, "✗ cargo test (3.00s)");
+    }
+
+    #[test]
+    fn test_summary_colored() {
+        let ok = format_summary("ls", true, Duration::ZERO, true);
+        assert!(ok.starts_with(COLOR_GREEN));
+        assert!(ok.ends_with(STYLE_RESET));
+
+        let ng = format_summary("ls", false, Duration::ZERO, true);
+        assert!(ng.starts_with(COLOR_RED));
+    }
+
+    // --- build_command のテスト ---
+
+    #[test]
+    fn test_build_command_applies_context_env() {
+        let ctx = TargetContext {
+            program: "cargo".to_string(),
+            args: vec![],
+            env: vec![("RUST_LOG".to_string(), "debug".to_string())],
+        };
+        let command = build_command(
+            "cargo",
+            vec!["run".to_string()],
+            Some(&ctx),
+            &BTreeMap::new(),
+        );
+        let envs: Vec<_> = command.get_envs().collect();
+        assert!(envs.contains(&(
+            std::ffi::OsStr::new("RUST_LOG"),
+            Some(std::ffi::OsStr::new("debug"))
+        )));
+    }
+
+    #[test]
+    fn test_build_command_applies_exported_env() {
+        // export した変数はコンテキストが無くても渡し、コンテキストの環境変数が優先する
+        let exported = BTreeMap::from([
+            ("WITH_TEST_EXPORTED".to_string(), "1".to_string()),
+            ("RUST_LOG".to_string(), "info".to_string()),
+        ]);
+        let command = build_command("true", vec![], None, &exported);
+        let envs: Vec<_> = command.get_envs().collect();
+        assert!(envs.contains(&(
+            std::ffi::OsStr::new("WITH_TEST_EXPORTED"),
+            Some(std::ffi::OsStr::new("1"))
+        )));
+
+        let ctx = TargetContext {
+            program: "cargo".to_string(),
+            args: vec![],
+            env: vec![("RUST_LOG".to_string(), "debug".to_string())],
+        };
+        let command = build_command("cargo", vec![], Some(&ctx), &exported);
+        let envs: Vec<_> = command.get_envs().collect();
+        assert!(envs.contains(&(
+            std::ffi::OsStr::new("RUST_LOG"),
+            Some(std::ffi::OsStr::new("debug"))
+        )));
+    }
+
+    // --- TerminalGuard のテスト ---
+
+    #[test]
+    fn test_terminal_guard_without_tty() {
+        // 端末でない stdin (テスト実行時) では何も保存せず、戻すときも失敗しない
+        let guard = TerminalGuard::capture();
+        #[cfg(unix)]
+        if !io::stdin().is_terminal() {
+            assert!(guard.saved.is_none());
+        }
+        drop(guard);
+    }
+
+    // --- tee_stream のテスト ---
+
+    /// 常に書き込みに失敗する出力先
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_with_redirects_truncate_and_append() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("out.txt");
+        let redirects = |append| Redirects {
+            output: Some(OutputRedirect {
+                path: path.to_string_lossy().to_string(),
+                append,
+            }),
+            ..Default::default()
+        };
+        let echo = |text: &str, append| {
+            execute_with_redirects(
+                "echo",
+                vec![text.to_string()],
+                None,
+                &BTreeMap::new(),
+                &redirects(append),
+                None,
+            )
+            .code()
+        };
+
+        assert_eq!(echo("first", false), Some(0));
+        assert_eq!(echo("second", true), Some(0));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+
+        assert_eq!(echo("third", false), Some(0));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "third\n");
+    }
+
+    #[test]
+    fn test_execute_with_redirects_unopenable_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let redirects = Redirects {
+            output: Some(OutputRedirect {
+                path: tmp
+                    .path()
+                    .join("missing/out.txt")
+                    .to_string_lossy()
+                    .to_string(),
+                append: false,
+            }),
+            ..Default::default()
+        };
+        // 開けなければコマンドを起動しない
+        let result = execute_with_redirects(
+            "with-no-such-program",
+            vec![],
+            None,
+            &BTreeMap::new(),
+            &redirects,
+            None,
+        );
+        assert_eq!(result.code(), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_with_redirects_input() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("input.txt");
+        std::fs::write(&path, "hello\n").unwrap();
+        let grep = |pattern: &str, input: String| {
+            let redirects = Redirects {
+                input: Some(input),
+                ..Default::default()
+            };
+            let args = vec!["-q".to_string(), pattern.to_string()];
+            execute_with_redirects("grep", args, None, &BTreeMap::new(), &redirects, None).code()
+        };
+
+        // ファイルの中身が標準入力として読まれる
+        let input = path.to_string_lossy().to_string();
+        assert_eq!(grep("hello", input.clone()), Some(0));
+        assert_eq!(grep("bye", input), Some(1));
+
+        // 存在しないファイルならコマンドを起動しない
+        let missing = tmp.path().join("missing.txt").to_string_lossy().to_string();
+        assert_eq!(grep("hello", missing), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_child_process_returns_exit_code() {
+        let run = |script: &str| {
+            execute_child_process(
+                "sh",
+                vec!["-c".to_string(), script.to_string()],
+                None,
+                &BTreeMap::new(),
+                None,
+            )
+            .code()
+        };
+        assert_eq!(run("exit 3"), Some(3));
+        assert_eq!(run("true"), Some(0));
+        // 子の with の全終了は呼び出し側に返す (ここでプロセスを終了しない)
+        assert!(matches!(
+            execute_child_process(
+                "sh",
+                vec!["-c".to_string(), "exit 127".to_string()],
+                None,
+                &BTreeMap::new(),
+                None,
+            ),
+            Foreground::ExitAll
+        ));
     }
 
     #[test]
-    fn test_stack_from_empty_root_with_context() {
-        // L1: "" (from Root 'with') -> rc git -> "/git"
-        let res = compute_next_stack(Some(""), Some("git"));
-        assert_eq!(res, "/git");
+    #[cfg(unix)]
+    fn test_execute_pipeline_redirects_last_stage_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("out.txt").to_string_lossy().to_string();
+        let stages = || {
+            vec![
+                ("echo".to_string(), vec!["x".to_string()]),
+                ("tr".to_string(), vec!["x".to_string(), "y".to_string()]),
+            ]
+        };
+        let redirects = |append| Redirects {
+            input: None,
+            output: Some(OutputRedirect {
+                path: path.clone(),
+                append,
+            }),
+        };
+
+        assert_eq!(
+            execute_pipeline(
+                stages(),
+                None,
+                &BTreeMap::new(),
+                &redirects(false),
+                None,
+                None,
+                None
+            )
+            .code(),
+            Some(0)
+        );
+        assert_eq!(
+            execute_pipeline(
+                stages(),
+                None,
+                &BTreeMap::new(),
+                &redirects(true),
+                None,
+                None,
+                None
+            )
+            .code(),
+            Some(0)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "y\ny\n");
+        assert_eq!(
+            execute_pipeline(
+                stages(),
+                None,
+                &BTreeMap::new(),
+                &redirects(false),
+                None,
+                None,
+                None
+            )
+            .code(),
+            Some(0)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "y\n");
+
+        // 開けないファイルならどの段も起動しない
+        let missing = Redirects {
+            input: None,
+            output: Some(OutputRedirect {
+                path: tmp.path().join("no/out.txt").to_string_lossy().to_string(),
+                append: false,
+            }),
+        };
+        assert_eq!(
+            execute_pipeline(stages(), None, &BTreeMap::new(), &missing, None, None, None).code(),
+            None
+        );
     }
 
     #[test]
-    fn test_stack_from_empty_root_no_context() {
-        // L1: "" (from Root 'with') -> rc -> "/"
-        let res = compute_next_stack(Some(""), None);
-        assert_eq!(res, "/");
+    #[cfg(unix)]
+    fn test_execute_pipeline_reads_first_stage_input() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in.txt");
+        let output = tmp.path().join("out.txt");
+        std::fs::write(&input, "hello\n").unwrap();
+        let stages = vec![
+            ("cat".to_string(), vec![]),
+            ("tr".to_string(), vec!["a-z".to_string(), "A-Z".to_string()]),
+        ];
+        let redirects = Redirects {
+            input: Some(input.to_string_lossy().to_string()),
+            output: Some(OutputRedirect {
+                path: output.to_string_lossy().to_string(),
+                append: false,
+            }),
+        };
+        assert_eq!(
+            execute_pipeline(
+                stages.clone(),
+                None,
+                &BTreeMap::new(),
+                &redirects,
+                None,
+                None,
+                None
+            )
+            .code(),
+            Some(0)
+        );
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "HELLO\n");
+
+        // 入力が無ければどの段も起動しない
+        let missing = Redirects {
+            input: Some(tmp.path().join("missing.txt").to_string_lossy().to_string()),
+            output: None,
+        };
+        assert_eq!(
+            execute_pipeline(stages, None, &BTreeMap::new(), &missing, None, None, None).code(),
+            None
+        );
     }
 
     #[test]
-    fn test_stack_deep_nesting() {
-        // L2: "git/cargo" -> rc bun -> "git/cargo/bun"
-        let res = compute_next_stack(Some("git/cargo"), Some("bun"));
-        assert_eq!(res, "git/cargo/bun");
+    #[cfg(unix)]
+    fn test_execute_pipeline_captures_last_stage_output() {
+        let stages = vec![
+            ("echo".to_string(), vec!["hello".to_string()]),
+            ("tr".to_string(), vec!["a-z".to_string(), "A-Z".to_string()]),
+        ];
+        let mut captured = Vec::new();
+        assert_eq!(
+            execute_pipeline(
+                stages,
+                None,
+                &BTreeMap::new(),
+                &Redirects::default(),
+                None,
+                Some(&mut captured),
+                None
+            )
+            .code(),
+            Some(0)
+        );
+        assert_eq!(captured, b"HELLO\n");
     }
 
     #[test]
-    fn test_stack_deep_empty_nesting() {
-        // L2: "/" -> rc -> "//"
-        let res = compute_next_stack(Some("/"), None);
-        assert_eq!(res, "//");
+    #[cfg(unix)]
+    fn test_execute_pipeline_tees_last_stage_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("out.log").to_string_lossy().to_string();
+        let stages = vec![
+            ("echo".to_string(), vec!["hello".to_string()]),
+            ("tr".to_string(), vec!["a-z".to_string(), "A-Z".to_string()]),
+        ];
+        let tee = TeeOutput {
+            files: vec![path.clone()],
+            append: false,
+        };
+        let mut captured = Vec::new();
+        assert_eq!(
+            execute_pipeline(
+                stages,
+                None,
+                &BTreeMap::new(),
+                &Redirects::default(),
+                Some(&tee),
+                Some(&mut captured),
+                None
+            )
+            .code(),
+            Some(0)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "HELLO\n");
+        assert_eq!(captured, b"HELLO\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_pipeline_returns_last_status() {
+        let stage = |program: &str| (program.to_string(), vec![]);
+        let none = Redirects::default();
+        assert_eq!(
+            execute_pipeline(
+                vec![stage("true"), stage("false")],
+                None,
+                &BTreeMap::new(),
+                &none,
+                None,
+                None,
+                None
+            )
+            .code(),
+            Some(1)
+        );
+        assert_eq!(
+            execute_pipeline(
+                vec![stage("false"), stage("true")],
+                None,
+                &BTreeMap::new(),
+                &none,
+                None,
+                None,
+                None
+            )
+            .code(),
+            Some(0)
+        );
+        assert_eq!(
+            execute_pipeline(
+                vec![stage("true"), stage("with-no-such-program"), stage("true")],
+                None,
+                &BTreeMap::new(),
+                &none,
+                None,
+                None,
+                None
+            )
+            .code(),
+            None
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_format_signal_exit() {
+        assert_eq!(
+            format_signal_exit(libc::SIGSEGV),
+            format!("terminated by signal {} (SIGSEGV)", libc::SIGSEGV)
+        );
+        assert_eq!(format_signal_exit(9), "terminated by signal 9 (SIGKILL)");
+        assert_eq!(format_signal_exit(64), "terminated by signal 64");
+    }
+
+    #[test]
+    fn test_format_abnormal_exit() {
+        assert_eq!(
+            format_abnormal_exit(0xC000_0005_u32 as i32).as_deref(),
+            Some("terminated abnormally (exit code 0xC0000005)")
+        );
+        assert_eq!(format_abnormal_exit(1), None);
+        assert_eq!(format_abnormal_exit(0), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_signal_exit_becomes_128_plus_signal() {
+        let sh = |script: &str| vec!["-c".to_string(), script.to_string()];
+        let (result, _) =
+            execute_capturing("sh", sh("kill -SEGV $$"), None, &BTreeMap::new(), None);
+        assert_eq!(result.code(), Some(128 + libc::SIGSEGV));
+        assert_eq!(
+            execute_pipeline(
+                vec![("sh".to_string(), sh("kill -TERM $$"))],
+                None,
+                &BTreeMap::new(),
+                &Redirects::default(),
+                None,
+                None,
+                None
+            )
+            .code(),
+            Some(128 + libc::SIGTERM)
+        );
+    }
+
+    #[test]
+    fn test_tee_stream_writes_to_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("out.txt");
+        let name = path.to_string_lossy().to_string();
+
+        let mut out = Vec::new();
+        let mut sinks = open_tee_files(std::slice::from_ref(&name), false);
+        tee_stream(&b"hello\n"[..], &mut out, &mut sinks).unwrap();
+        drop(sinks);
+        assert_eq!(out, b"hello\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello\n");
+
+        // -a なら追記、無しなら上書き
+        let mut sinks = open_tee_files(std::slice::from_ref(&name), true);
+        tee_stream(&b"world\n"[..], &mut Vec::new(), &mut sinks).unwrap();
+        drop(sinks);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello\nworld\n");
+    }
+
+    #[test]
+    fn test_tee_stream_continues_on_write_error() {
+        let mut out = Vec::new();
+        let mut sinks: TeeSinks = vec![("broken".to_string(), Box::new(FailingWriter))];
+        tee_stream(&b"still shown"[..], &mut out, &mut sinks).unwrap();
+
+        // 画面出力は続き、失敗したファイルは外される
+        assert_eq!(out, b"still shown");
+        assert!(sinks.is_empty());
     }
 
     // --- resolve_program のテスト (既存) ---
@@ -0,0 +1,681 @@
+use crate::with_helper::{COLOR_RED, STYLE_BOLD, STYLE_RESET};
+use regex::{Regex, RegexBuilder};
+use rustyline::{error::ReadlineError, history::SearchDirection};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// 上矢印で呼び出す入力履歴を保存するファイルのパスを返す (~/.with_history)
+pub fn history_file_path() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".with_history"))
+}
+
+/// `history` の ID 付きの履歴を保存するファイルのパスを返す (~/.with_history_ids)
+pub fn history_ids_file_path() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".with_history_ids"))
+}
+
+/// `history pin` でピン留めしたコマンドを保存するファイルのパスを返す (~/.with_pins)
+pub fn pins_file_path() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".with_pins"))
+}
+
+/// ピン留めしたコマンドを読み込む (1行に1件。ファイルが無ければ空)
+pub fn load_pins(path: &Path) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(content) => content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect(),
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("Warning: failed to read {}: {}", path.display(), e);
+            }
+            Vec::new()
+        }
+    }
+}
+
+/// ピン留めしたコマンドを保存する
+pub fn save_pins(path: &Path, pins: &[String]) {
+    let mut content = pins.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+    if let Err(e) = fs::write(path, content) {
+        eprintln!("Warning: failed to save {}: {}", path.display(), e);
+    }
+}
+
+/// 前回までの入力履歴を読み込む
+/// ファイルが無ければ何もせず、読めない・壊れているときは警告を出して空のまま始める
+pub fn load_line_history(history: &mut impl rustyline::history::History, path: &Path) {
+    match history.load(path) {
+        Ok(()) => {}
+        Err(ReadlineError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            eprintln!("Warning: failed to read {}: {}", path.display(), e);
+            let _ = history.clear();
+        }
+    }
+}
+
+/// 入力履歴を保存する (保存先のディレクトリが無ければ作る)
+pub fn save_line_history(history: &mut impl rustyline::history::History, path: &Path) {
+    if let Some(parent) = path.parent()
+        && let Err(e) = fs::create_dir_all(parent)
+    {
+        eprintln!("Warning: failed to create {}: {}", parent.display(), e);
+        return;
+    }
+    if let Err(e) = history.save(path) {
+        eprintln!("Warning: failed to save {}: {}", path.display(), e);
+    }
+}
+
+/// 履歴の1件
+/// id は追加時に振る不変の番号で、`!<id>` で同じコマンドを呼び出せる
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryEntry {
+    pub id: usize,
+    pub line: String,
+    /// 入力したときのコンテキストのプログラム名 (例: "git")
+    pub context: Option<String>,
+    /// 入力した日時 (保存されていなかった履歴では None)
+    pub time: Option<SystemTime>,
+}
+
+/// セッションの入力履歴
+/// 削除 (`history clear`) しても番号は巻き戻さず、消えた ID を別のコマンドに再利用しない
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<HistoryEntry>,
+    /// これまでに振った最大の ID
+    last_id: usize,
+    /// 保存しておいた履歴から復元した時点の last_id (これより大きい ID がこのセッションで追加した分)
+    restored_last_id: usize,
+    /// clear した回数 (上矢印の履歴を作り直すきっかけにする)
+    generation: usize,
+    /// ピン留めしたコマンド (ピン留めした順。`!p<n>` の n は 1 始まりの位置)
+    pinned: Vec<String>,
+}
+
+impl History {
+    /// 保存しておいた履歴から復元する
+    /// last_id には削除済みのものも含めて振った最大の ID を渡す (エントリ側が大きければそちらを使う)
+    pub fn restore(entries: Vec<HistoryEntry>, last_id: usize) -> Self {
+        let max_id = entries.iter().map(|e| e.id).max().unwrap_or(0);
+        let last_id = last_id.max(max_id);
+        Self {
+            entries,
+            last_id,
+            restored_last_id: last_id,
+            generation: 0,
+            pinned: Vec::new(),
+        }
+    }
+
+    /// 追加する (空行と直前と同じ行は追加しない)
+    pub fn push(&mut self, line: &str) {
+        self.push_in(line, None);
+    }
+
+    /// 入力したときのコンテキストと日時を添えて追加する
+    pub fn push_in(&mut self, line: &str, context: Option<String>) {
+        if line.is_empty() || self.last().is_some_and(|e| e.line == line) {
+            return;
+        }
+        self.last_id += 1;
+        self.entries.push(HistoryEntry {
+            id: self.last_id,
+            line: line.to_string(),
+            context,
+            time: Some(SystemTime::now()),
+        });
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    /// 入力行だけを古い順に返す
+    pub fn lines(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.entries.iter().map(|e| e.line.as_str())
+    }
+
+    pub fn last(&self) -> Option<&HistoryEntry> {
+        self.entries.last()
+    }
+
+    /// ID で探す
+    pub fn get(&self, id: usize) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    /// これまでに振った最大の ID (永続化するときに保存する)
+    pub fn last_id(&self) -> usize {
+        self.last_id
+    }
+
+    /// 全て削除する (ID は巻き戻さない。ピン留めしたコマンドの履歴は残す)
+    pub fn clear(&mut self) {
+        let pinned = &self.pinned;
+        self.entries.retain(|e| pinned.contains(&e.line));
+        self.generation += 1;
+    }
+
+    /// clear するたびに変わる番号
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    /// ID の履歴をピン留めし、ピンの番号を返す (ピン留め済みならその番号)
+    pub fn pin(&mut self, id: usize) -> Result<usize, String> {
+        let line = self
+            .get(id)
+            .ok_or_else(|| format!("history pin: {}: no such entry", id))?
+            .line
+            .clone();
+        if let Some(number) = self.pin_number(&line) {
+            return Ok(number);
+        }
+        self.pinned.push(line);
+        Ok(self.pinned.len())
+    }
+
+    /// 番号のピンを外し、外したコマンドを返す (後ろのピンの番号は1つずつ詰まる)
+    pub fn unpin(&mut self, number: usize) -> Option<String> {
+        (1..=self.pinned.len())
+            .contains(&number)
+            .then(|| self.pinned.remove(number - 1))
+    }
+
+    pub fn pinned(&self) -> &[String] {
+        &self.pinned
+    }
+
+    /// 保存しておいたピンを復元する
+    pub fn set_pinned(&mut self, pinned: Vec<String>) {
+        self.pinned = pinned;
+    }
+
+    /// コマンドのピンの番号 (ピン留めしていなければ None)
+    pub fn pin_number(&self, line: &str) -> Option<usize> {
+        self.pinned.iter().position(|p| p == line).map(|i| i + 1)
+    }
+}
+
+/// 保存する ID 付きの履歴の件数の上限 (古いものから捨てる。ID は last_id で巻き戻さない)
+const SAVED_HISTORY_LIMIT: usize = 1000;
+
+/// ID 付きの履歴のファイル形式
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct SavedHistory {
+    last_id: usize,
+    entries: Vec<HistoryEntry>,
+}
+
+/// ID 付きの履歴のファイルを読む
+/// ファイルが無ければ空、読めない・壊れているときは警告を出して空として扱う
+fn read_saved_history(path: &Path) -> SavedHistory {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("Warning: failed to read {}: {}", path.display(), e);
+            }
+            return SavedHistory::default();
+        }
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        eprintln!("Warning: failed to read {}: {}", path.display(), e);
+        SavedHistory::default()
+    })
+}
+
+/// 前回までの ID 付きの履歴を読み込む (ピンは含まない)
+/// ファイルが無ければ空、読めない・壊れているときは警告を出して空のまま始める
+pub fn load_history(path: &Path) -> History {
+    let saved = read_saved_history(path);
+    History::restore(saved.entries, saved.last_id)
+}
+
+/// 保存先の履歴 (復元した後に他のセッションが保存した分を含む) にこのセッションの履歴を重ねる純粋関数
+/// 他のセッションが振った ID と重ならないよう、このセッションで追加した分は保存先の last_id の続きへずらす
+/// 復元した時点で既にあった履歴のうち、このセッションで消したもの (`history clear`) は除く
+fn merge_history(saved: SavedHistory, history: &History) -> SavedHistory {
+    let base = history.restored_last_id;
+    let offset = saved.last_id.saturating_sub(base);
+    let mut entries: Vec<HistoryEntry> = saved
+        .entries
+        .into_iter()
+        .filter(|e| e.id > base || history.get(e.id).is_some())
+        .collect();
+    entries.extend(
+        history
+            .entries()
+            .iter()
+            .filter(|e| e.id > base)
+            .map(|e| HistoryEntry {
+                id: e.id + offset,
+                ..e.clone()
+            }),
+    );
+    SavedHistory {
+        last_id: history.last_id() + offset,
+        entries,
+    }
+}
+
+/// ID 付きの履歴を保存し、保存した内容 (他のセッションの分を重ねたもの) を返す
+/// 保存先のディレクトリが無ければ作る
+pub fn save_history(path: &Path, history: &History) -> History {
+    let merged = merge_history(read_saved_history(path), history);
+    let entries = &merged.entries;
+    let saved = SavedHistory {
+        last_id: merged.last_id,
+        entries: entries[entries.len().saturating_sub(SAVED_HISTORY_LIMIT)..].to_vec(),
+    };
+    let merged = History::restore(merged.entries, merged.last_id);
+    if let Some(parent) = path.parent()
+        && let Err(e) = fs::create_dir_all(parent)
+    {
+        eprintln!("Warning: failed to create {}: {}", parent.display(), e);
+        return merged;
+    }
+    let result = serde_json::to_string(&saved)
+        .map_err(|e| e.to_string())
+        .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        eprintln!("Warning: failed to save {}: {}", path.display(), e);
+    }
+    merged
+}
+
+/// 上矢印の入力履歴と `history` の ID 付きの履歴の保存先
+/// 2つがずれないよう、読み込みと保存は必ずここでまとめて行う
+#[derive(Debug, Clone)]
+pub struct HistoryFiles {
+    pub lines: PathBuf,
+    pub ids: PathBuf,
+}
+
+impl HistoryFiles {
+    /// ホームディレクトリ直下 (~/.with_history と ~/.with_history_ids)
+    pub fn in_home() -> Option<Self> {
+        Some(Self {
+            lines: history_file_path()?,
+            ids: history_ids_file_path()?,
+        })
+    }
+
+    /// 両方の履歴を読み込む。入力履歴は ID 付きの履歴から作り直して中身を揃える
+    /// ID 付きの履歴がまだ無ければ (以前の版からの移行)、入力履歴の各行に ID を振る
+    pub fn load(&self, line_history: &mut impl rustyline::history::History) -> History {
+        if !self.ids.exists() {
+            load_line_history(line_history, &self.lines);
+            let mut history = History::default();
+            for i in 0..line_history.len() {
+                if let Ok(Some(found)) = line_history.get(i, SearchDirection::Forward) {
+                    history.push(&found.entry);
+                }
+            }
+            return history;
+        }
+        let history = load_history(&self.ids);
+        let _ = line_history.clear();
+        for line in history.lines() {
+            let _ = line_history.add(line);
+        }
+        history
+    }
+
+    /// 両方の履歴を保存する
+    /// 並行して動く with が先に保存した分も残るよう、入力履歴も重ねた後の ID 付きの履歴から作り直す
+    pub fn save(&self, line_history: &mut impl rustyline::history::History, history: &History) {
+        let merged = save_history(&self.ids, history);
+        let _ = line_history.clear();
+        for line in merged.lines() {
+            let _ = line_history.add(line);
+        }
+        save_line_history(line_history, &self.lines);
+    }
+}
+
+/// 履歴検索のパターンを組み立てる
+/// use_regex が false なら部分一致 (記号はそのままの文字として扱う)
+pub fn build_search_regex(
+    pattern: &str,
+    use_regex: bool,
+    ignore_case: bool,
+) -> Result<Regex, String> {
+    let source = if use_regex {
+        pattern.to_string()
+    } else {
+        regex::escape(pattern)
+    };
+    RegexBuilder::new(&source)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// 履歴の表示行を組み立てる純粋関数 (例: "  3: git status")
+/// color が true ならマッチ部分を強調する
+pub fn format_history_line(
+    number: usize,
+    entry: &str,
+    regex: Option<&Regex>,
+    color: bool,
+) -> String {
+    let body = match regex {
+        Some(re) if color => re
+            .replace_all(entry, |caps: &regex::Captures| {
+                format!("{}{}{}{}", STYLE_BOLD, COLOR_RED, &caps[0], STYLE_RESET)
+            })
+            .into_owned(),
+        _ => entry.to_string(),
+    };
+    format!("{: >3}: {}", number, body)
+}
+
+/// 正規表現にマッチする履歴を (ID, 内容) で返す純粋関数
+/// ID は `!<id>` でそのまま使える
+pub fn search_history<'a>(history: &'a History, regex: &Regex) -> Vec<(usize, &'a str)> {
+    history
+        .entries()
+        .iter()
+        .filter(|entry| regex.is_match(&entry.line))
+        .map(|entry| (entry.id, entry.line.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> History {
+        let mut history = History::default();
+        for line in [
+            "git status",
+            "git commit -m fix",
+            "ls",
+            "GIT push",
+            "git push",
+        ] {
+            history.push(line);
+        }
+        history
+    }
+
+    // --- 履歴の ID のテスト ---
+
+    #[test]
+    fn test_history_push_assigns_ids() {
+        let mut history = History::default();
+        history.push("status");
+        history.push("status");
+        history.push("");
+        history.push("log");
+        assert_eq!(
+            history
+                .entries()
+                .iter()
+                .map(|e| (e.id, e.line.as_str()))
+                .collect::<Vec<_>>(),
+            [(1, "status"), (2, "log")]
+        );
+        assert!(history.entries().iter().all(|e| e.time.is_some()));
+        assert_eq!(history.get(2).map(|e| e.line.as_str()), Some("log"));
+    }
+
+    #[test]
+    fn test_history_push_in_context() {
+        let mut history = History::default();
+        history.push_in("status", Some("git".to_string()));
+        assert_eq!(history.last().unwrap().context.as_deref(), Some("git"));
+    }
+
+    #[test]
+    fn test_history_clear_does_not_reuse_ids() {
+        let mut history = history();
+        history.clear();
+        assert!(history.entries().is_empty());
+        history.push("ls");
+        assert_eq!(history.last().map(|e| e.id), Some(6));
+        assert!(history.get(1).is_none());
+    }
+
+    // --- ピン留めのテスト ---
+
+    #[test]
+    fn test_history_pin() {
+        let mut history = history();
+        assert_eq!(history.pin(5), Ok(1));
+        assert_eq!(history.pin(1), Ok(2));
+        // ピン留め済みなら同じ番号
+        assert_eq!(history.pin(5), Ok(1));
+        assert!(history.pin(9).is_err());
+        assert_eq!(history.pinned(), ["git push", "git status"]);
+        assert_eq!(history.pin_number("git status"), Some(2));
+
+        assert_eq!(history.unpin(1), Some("git push".to_string()));
+        assert_eq!(history.unpin(3), None);
+        assert_eq!(history.pin_number("git status"), Some(1));
+    }
+
+    #[test]
+    fn test_history_clear_keeps_pinned() {
+        let mut history = history();
+        history.pin(2).unwrap();
+        let generation = history.generation();
+        history.clear();
+        assert_eq!(history.lines().collect::<Vec<_>>(), ["git commit -m fix"]);
+        assert_eq!(history.get(2).map(|e| e.id), Some(2));
+        // 残った履歴が空でなくても、clear したことは分かる
+        assert_ne!(history.generation(), generation);
+    }
+
+    #[test]
+    fn test_save_and_load_pins() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".with_pins");
+        assert!(load_pins(&path).is_empty());
+
+        let pins = vec![
+            "git status".to_string(),
+            "cargo test -- --nocapture".to_string(),
+        ];
+        save_pins(&path, &pins);
+        assert_eq!(load_pins(&path), pins);
+    }
+
+    #[test]
+    fn test_history_restore() {
+        let entries = vec![HistoryEntry {
+            id: 3,
+            line: "git push".to_string(),
+            ..Default::default()
+        }];
+        // 削除済みの ID (5 まで) も再利用しない
+        let mut history = History::restore(entries.clone(), 5);
+        history.push("ls");
+        assert_eq!(history.last().map(|e| e.id), Some(6));
+
+        let mut history = History::restore(entries, 0);
+        history.push("ls");
+        assert_eq!(history.last().map(|e| e.id), Some(4));
+    }
+
+    #[test]
+    fn test_save_and_load_history_keeps_ids() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("nested").join(".with_history_ids");
+        assert!(load_history(&path).entries().is_empty());
+
+        let mut history = history();
+        history.push_in("status", Some("git".to_string()));
+        history.clear();
+        history.push("ls -a");
+        save_history(&path, &history);
+
+        // 次のセッションでも同じ ID で呼び出せ、削除済みの ID は再利用しない
+        let mut reloaded = load_history(&path);
+        assert_eq!(reloaded.entries(), history.entries());
+        assert_eq!(reloaded.get(7).map(|e| e.line.as_str()), Some("ls -a"));
+        reloaded.push("pwd");
+        assert_eq!(reloaded.last().map(|e| e.id), Some(8));
+
+        // 壊れたファイルは空から始める
+        fs::write(&path, "not json").unwrap();
+        assert!(load_history(&path).entries().is_empty());
+    }
+
+    #[test]
+    fn test_save_history_merges_other_sessions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".with_history_ids");
+        let mut first = History::default();
+        first.push("git status");
+        first.push("cargo build");
+        save_history(&path, &first);
+
+        // 2つのセッションが同じファイルから始め、それぞれ追加して保存する
+        let mut a = load_history(&path);
+        let mut b = load_history(&path);
+        a.push("ls");
+        b.clear();
+        b.push("pwd");
+        b.push("make");
+        save_history(&path, &a);
+        let merged = save_history(&path, &b);
+
+        // 先に保存した分は残り、後から保存した分は ID をずらして続ける (b が消した分は消える)
+        let entries: Vec<_> = merged
+            .entries()
+            .iter()
+            .map(|e| (e.id, e.line.as_str()))
+            .collect();
+        assert_eq!(entries, [(3, "ls"), (4, "pwd"), (5, "make")]);
+        assert_eq!(merged.last_id(), 5);
+        assert_eq!(load_history(&path).entries(), merged.entries());
+    }
+
+    #[test]
+    fn test_history_files_keep_both_histories_in_sync() {
+        use rustyline::history::{DefaultHistory, History as _};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let files = HistoryFiles {
+            lines: tmp.path().join(".with_history"),
+            ids: tmp.path().join(".with_history_ids"),
+        };
+        // 以前の版が残した入力履歴だけがあれば、その行に ID を振る
+        let mut old = DefaultHistory::new();
+        old.add("git status").unwrap();
+        old.add("cargo build").unwrap();
+        save_line_history(&mut old, &files.lines);
+
+        let mut line_history = DefaultHistory::new();
+        let mut history = files.load(&mut line_history);
+        assert_eq!(history.get(2).map(|e| e.line.as_str()), Some("cargo build"));
+
+        history.push("ls");
+        line_history.add("ls").unwrap();
+        files.save(&mut line_history, &history);
+
+        // 次のセッションでは上矢印の履歴と `history` が同じ内容になる
+        let mut line_history = DefaultHistory::new();
+        let history = files.load(&mut line_history);
+        let lines: Vec<_> = line_history.iter().map(String::as_str).collect();
+        assert_eq!(lines, history.lines().collect::<Vec<_>>());
+        assert_eq!(lines, ["git status", "cargo build", "ls"]);
+        assert_eq!(history.get(3).map(|e| e.line.as_str()), Some("ls"));
+    }
+
+    // --- 履歴検索のテスト ---
+
+    #[test]
+    fn test_search_history_regex_keeps_numbers() {
+        let re = build_search_regex("^git (commit|push)", true, false).unwrap();
+        assert_eq!(
+            search_history(&history(), &re),
+            vec![(2, "git commit -m fix"), (5, "git push")]
+        );
+    }
+
+    #[test]
+    fn test_search_history_ignore_case() {
+        let re = build_search_regex("^git push", true, true).unwrap();
+        assert_eq!(
+            search_history(&history(), &re),
+            vec![(4, "GIT push"), (5, "git push")]
+        );
+    }
+
+    #[test]
+    fn test_search_history_plain_text() {
+        // 部分一致モードでは記号も文字として扱う
+        let re = build_search_regex("-m", false, false).unwrap();
+        assert_eq!(
+            search_history(&history(), &re),
+            vec![(2, "git commit -m fix")]
+        );
+        assert!(build_search_regex("(", false, false).is_ok());
+    }
+
+    #[test]
+    fn test_build_search_regex_invalid() {
+        assert!(build_search_regex("(unclosed", true, false).is_err());
+    }
+
+    #[test]
+    fn test_format_history_line_highlight() {
+        let re = build_search_regex("push", true, false).unwrap();
+        assert_eq!(
+            format_history_line(5, "git push", Some(&re), false),
+            "  5: git push"
+        );
+        let colored = format_history_line(5, "git push", Some(&re), true);
+        assert!(colored.contains(&format!("{}{}push{}", STYLE_BOLD, COLOR_RED, STYLE_RESET)));
+    }
+
+    #[test]
+    fn test_line_history_round_trip() {
+        use rustyline::history::{DefaultHistory, History as _};
+
+        let tmp = tempfile::tempdir().unwrap();
+        // 保存先のディレクトリが無ければ作る
+        let path = tmp.path().join("nested").join(".with_history");
+
+        let mut history = DefaultHistory::new();
+        history.add("git status").unwrap();
+        history.add("cargo build").unwrap();
+        save_line_history(&mut history, &path);
+
+        let mut reloaded = DefaultHistory::new();
+        load_line_history(&mut reloaded, &path);
+        let lines: Vec<_> = reloaded.iter().cloned().collect();
+        assert_eq!(lines, ["git status", "cargo build"]);
+    }
+
+    #[test]
+    fn test_load_line_history_missing_or_unreadable() {
+        use rustyline::history::{DefaultHistory, History as _};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let mut history = DefaultHistory::new();
+        load_line_history(&mut history, &tmp.path().join("missing"));
+        assert!(history.is_empty());
+
+        // ディレクトリは履歴ファイルとして読めないが、空のまま始める
+        load_line_history(&mut history, tmp.path());
+        assert!(history.is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+use std::process::Child;
+
+/// バックグラウンドで実行中のジョブ
+pub struct Job {
+    pub id: usize,
+    pub command: String,
+    /// Ctrl+Z で停止中か (`bg` / `fg` で再開する)
+    pub stopped: bool,
+    /// 終了コードを返すプロセス (パイプラインなら最後の段)
+    pub child: Child,
+    /// パイプラインの前の段 (child と同じプロセスグループで、先頭がリーダー)
+    pub upstream: Vec<Child>,
+}
+
+impl Job {
+    /// シグナルを送る先 (パイプラインなら先頭の段。プロセスグループのリーダー)
+    pub fn leader(&self) -> &Child {
+        self.upstream.first().unwrap_or(&self.child)
+    }
+
+    /// 全段のプロセス (先頭の段から順に)
+    pub fn processes(&self) -> Vec<&Child> {
+        self.upstream.iter().chain([&self.child]).collect()
+    }
+
+    /// ジョブ一覧に表示する状態
+    pub fn state(&self) -> &'static str {
+        if self.stopped { "Stopped" } else { "Running" }
+    }
+}
+
+/// バックグラウンドジョブの一覧
+#[derive(Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+    next_id: usize,
+}
+
+impl JobTable {
+    /// ジョブを登録し、ジョブ番号 (%n) を返す
+    pub fn add(&mut self, command: String, child: Child) -> usize {
+        self.push(command, child, Vec::new(), false)
+    }
+
+    /// Ctrl+Z で停止したフォアグラウンドのコマンドを登録し、ジョブ番号を返す
+    /// パイプラインなら upstream に最後の段より前の段を渡す
+    pub fn add_stopped(&mut self, command: String, child: Child, upstream: Vec<Child>) -> usize {
+        self.push(command, child, upstream, true)
+    }
+
+    fn push(
+        &mut self,
+        command: String,
+        child: Child,
+        upstream: Vec<Child>,
+        stopped: bool,
+    ) -> usize {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.jobs.push(Job {
+            id,
+            command,
+            stopped,
+            child,
+            upstream,
+        });
+        id
+    }
+
+    /// ジョブを探す (id が None なら最後に追加したジョブ)
+    pub fn get_mut(&mut self, id: Option<usize>) -> Option<&mut Job> {
+        match id {
+            Some(id) => self.jobs.iter_mut().find(|job| job.id == id),
+            None => self.jobs.last_mut(),
+        }
+    }
+
+    /// 終了したジョブを一覧から取り除く (`fg` で待ち終えたときなど)
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        let idx = self.jobs.iter().position(|job| job.id == id)?;
+        let job = self.jobs.remove(idx);
+        if self.jobs.is_empty() {
+            self.next_id = 0;
+        }
+        Some(job)
+    }
+
+    /// 終了したジョブを一覧から取り除き、(番号, コマンド, 終了コード) を返す
+    pub fn reap(&mut self) -> Vec<(usize, String, Option<i32>)> {
+        let mut finished = Vec::new();
+        self.jobs.retain_mut(|job| match job.child.try_wait() {
+            Ok(Some(status)) => {
+                for stage in job.upstream.iter_mut() {
+                    let _ = stage.try_wait();
+                }
+                finished.push((job.id, job.command.clone(), status.code()));
+                false
+            }
+            Ok(None) => true,
+            Err(_) => false,
+        });
+
+        // 空になったら番号を振り直す
+        if self.jobs.is_empty() {
+            self.next_id = 0;
+        }
+        finished
+    }
+
+    /// 実行中のジョブ一覧
+    pub fn running(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    /// ジョブを親から切り離す (終了時の確認・SIGHUP の対象外にする)
+    /// id が None なら最後に追加したジョブを対象にする
+    pub fn disown(&mut self, id: Option<usize>) -> Option<Job> {
+        let idx = match id {
+            Some(id) => self.jobs.iter().position(|job| job.id == id)?,
+            None => self.jobs.len().checked_sub(1)?,
+        };
+        Some(self.jobs.remove(idx))
+    }
+
+    /// 実行中の全ジョブにハングアップを送る (Windows では強制終了)
+    pub fn hangup_all(&mut self) {
+        for job in self.jobs.iter_mut() {
+            for process in job.upstream.iter_mut().chain([&mut job.child]) {
+                hangup(process, job.stopped);
+            }
+        }
+        self.jobs.clear();
+    }
+}
+
+#[cfg(unix)]
+fn hangup(child: &mut Child, stopped: bool) {
+    // SAFETY: 自分が起動した子プロセスの PID に対してシグナルを送るだけ
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGHUP);
+        // 停止中のプロセスは再開させないと SIGHUP を処理できない
+        if stopped {
+            libc::kill(child.id() as libc::pid_t, libc::SIGCONT);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn hangup(child: &mut Child, _stopped: bool) {
+    let _ = child.kill();
+}
+
+/// ジョブ一覧の表示行を組み立てる純粋関数 (例: "[1]  Running  sleep 10")
+pub fn format_job(id: usize, state: &str, command: &str) -> String {
+    format!("[{}]  {:<8} {}", id, state, command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_format_job() {
+        assert_eq!(
+            format_job(1, "Running", "sleep 10"),
+            "[1]  Running  sleep 10"
+        );
+        assert_eq!(format_job(12, "Done", "make"), "[12]  Done     make");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_job_table_add_and_reap() {
+        let mut table = JobTable::default();
+        let child = Command::new("true").spawn().unwrap();
+        let id = table.add("true".to_string(), child);
+        assert_eq!(id, 1);
+
+        // 終了するまで待ってから回収する
+        let mut finished = Vec::new();
+        for _ in 0..100 {
+            finished = table.reap();
+            if !finished.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(finished, vec![(1, "true".to_string(), Some(0))]);
+        assert!(table.running().is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_job_table_disown() {
+        let mut table = JobTable::default();
+        let child = Command::new("sleep").arg("5").spawn().unwrap();
+        table.add("sleep 5".to_string(), child);
+
+        let mut job = table.disown(None).unwrap();
+        assert!(table.running().is_empty());
+        assert!(table.disown(Some(1)).is_none());
+
+        let _ = job.child.kill();
+        let _ = job.child.wait();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_job_table_get_and_remove() {
+        let mut table = JobTable::default();
+        let first = Command::new("sleep").arg("5").spawn().unwrap();
+        let second = Command::new("sleep").arg("5").spawn().unwrap();
+        table.add("sleep 5".to_string(), first);
+        let id = table.add_stopped("vim".to_string(), second, Vec::new());
+
+        assert_eq!(table.get_mut(None).unwrap().state(), "Stopped");
+        assert_eq!(table.get_mut(Some(1)).unwrap().state(), "Running");
+        assert!(table.get_mut(Some(3)).is_none());
+
+        let mut job = table.remove(id).unwrap();
+        assert_eq!(table.running().len(), 1);
+        table.hangup_all();
+
+        let _ = job.child.kill();
+        let _ = job.child.wait();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_job_table_pipeline_job() {
+        let mut table = JobTable::default();
+        let first = Command::new("sleep").arg("5").spawn().unwrap();
+        let last = Command::new("sleep").arg("5").spawn().unwrap();
+        let (first_pid, last_pid) = (first.id(), last.id());
+        table.add_stopped("sleep 5 | sleep 5".to_string(), last, vec![first]);
+
+        // シグナルは先頭の段へ、終了コードは最後の段から取る
+        let job = table.get_mut(None).unwrap();
+        assert_eq!(job.leader().id(), first_pid);
+        let pids: Vec<u32> = job.processes().iter().map(|child| child.id()).collect();
+        assert_eq!(pids, vec![first_pid, last_pid]);
+
+        let mut job = table.remove(1).unwrap();
+        let _ = job.child.kill();
+        let _ = job.child.wait();
+        for stage in job.upstream.iter_mut() {
+            let _ = stage.kill();
+            let _ = stage.wait();
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_job_table_hangup_all() {
+        let mut table = JobTable::default();
+        let child = Command::new("sleep").arg("5").spawn().unwrap();
+        table.add("sleep 5".to_string(), child);
+
+        table.hangup_all();
+        assert!(table.running().is_empty());
+    }
+}
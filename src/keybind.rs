@@ -0,0 +1,139 @@
+use rustyline::{Cmd, EditMode, KeyCode, KeyEvent, Modifiers, Movement};
+
+/// ヘルプの `Keyboard Shortcuts` に表示するキー操作
+/// cmd を持つものは with が rustyline に登録し、None は rustyline 標準の操作
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyBinding {
+    pub key: KeyEvent,
+    pub description: &'static str,
+    pub cmd: Option<Cmd>,
+}
+
+impl KeyBinding {
+    fn builtin(key: KeyEvent, description: &'static str) -> Self {
+        Self {
+            key,
+            description,
+            cmd: None,
+        }
+    }
+
+    fn custom(key: KeyEvent, description: &'static str, cmd: Cmd) -> Self {
+        Self {
+            key,
+            description,
+            cmd: Some(cmd),
+        }
+    }
+}
+
+/// 編集モードごとに有効なキー操作を返す
+/// バインドを追加するときはここに足せば、登録 (run_repl) とヘルプの両方に反映される
+pub fn key_bindings(mode: EditMode) -> Vec<KeyBinding> {
+    let mut bindings = vec![
+        KeyBinding::builtin(KeyEvent::ctrl('C'), "Cancel input / Interrupt process"),
+        KeyBinding::builtin(KeyEvent::ctrl('D'), "Exit (EOF)"),
+        KeyBinding::builtin(
+            KeyEvent(KeyCode::Tab, Modifiers::NONE),
+            "Complete commands, arguments and files",
+        ),
+    ];
+    match mode {
+        // Esc はノーマルモードへの切り替えに使うので上書きしない
+        EditMode::Vi => bindings.push(KeyBinding::builtin(
+            KeyEvent(KeyCode::Esc, Modifiers::NONE),
+            "Switch to normal mode",
+        )),
+        _ => bindings.extend([
+            // Windows ライクな挙動
+            KeyBinding::custom(
+                KeyEvent(KeyCode::Esc, Modifiers::NONE),
+                "Clear the whole input line",
+                Cmd::Kill(Movement::WholeLine),
+            ),
+            KeyBinding::builtin(KeyEvent::ctrl('L'), "Clear the screen"),
+            KeyBinding::builtin(KeyEvent::ctrl('R'), "Search history backward"),
+        ]),
+    }
+    bindings
+}
+
+/// キーの表示名を組み立てる純粋関数 (例: "Ctrl + C", "Esc")
+pub fn key_label(key: &KeyEvent) -> String {
+    let KeyEvent(code, modifiers) = *key;
+    let mut label = String::new();
+    if modifiers.contains(Modifiers::CTRL) {
+        label.push_str("Ctrl + ");
+    }
+    if modifiers.contains(Modifiers::ALT) {
+        label.push_str("Alt + ");
+    }
+    if modifiers.contains(Modifiers::SHIFT) {
+        label.push_str("Shift + ");
+    }
+    match code {
+        KeyCode::Char(c) => label.extend(c.to_uppercase()),
+        KeyCode::Esc => label.push_str("Esc"),
+        KeyCode::Tab | KeyCode::BackTab => label.push_str("Tab"),
+        KeyCode::Enter => label.push_str("Enter"),
+        KeyCode::Backspace => label.push_str("Backspace"),
+        KeyCode::Delete => label.push_str("Delete"),
+        KeyCode::Up => label.push_str("Up"),
+        KeyCode::Down => label.push_str("Down"),
+        KeyCode::Left => label.push_str("Left"),
+        KeyCode::Right => label.push_str("Right"),
+        KeyCode::Home => label.push_str("Home"),
+        KeyCode::End => label.push_str("End"),
+        KeyCode::F(n) => label.push_str(&format!("F{}", n)),
+        other => label.push_str(&format!("{:?}", other)),
+    }
+    label
+}
+
+/// ヘルプに並べる行 (例: "  Ctrl + C          Cancel input / Interrupt process")
+pub fn format_key_bindings(bindings: &[KeyBinding]) -> Vec<String> {
+    bindings
+        .iter()
+        .map(|binding| format!("  {:<18}{}", key_label(&binding.key), binding.description))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_label() {
+        assert_eq!(key_label(&KeyEvent::ctrl('c')), "Ctrl + C");
+        assert_eq!(key_label(&KeyEvent(KeyCode::Esc, Modifiers::NONE)), "Esc");
+        assert_eq!(key_label(&KeyEvent(KeyCode::Tab, Modifiers::NONE)), "Tab");
+        assert_eq!(key_label(&KeyEvent::alt('f')), "Alt + F");
+        assert_eq!(key_label(&KeyEvent(KeyCode::F(5), Modifiers::NONE)), "F5");
+    }
+
+    #[test]
+    fn test_key_bindings_follow_edit_mode() {
+        let esc = KeyEvent(KeyCode::Esc, Modifiers::NONE);
+        let emacs = key_bindings(EditMode::Emacs);
+        let esc_emacs = emacs.iter().find(|b| b.key == esc).unwrap();
+        assert_eq!(esc_emacs.cmd, Some(Cmd::Kill(Movement::WholeLine)));
+        assert!(emacs.iter().any(|b| b.key == KeyEvent::ctrl('L')));
+
+        // vi モードでは Esc を上書きしない
+        let vi = key_bindings(EditMode::Vi);
+        let esc_vi = vi.iter().find(|b| b.key == esc).unwrap();
+        assert_eq!(esc_vi.cmd, None);
+        assert!(vi.iter().all(|b| b.cmd.is_none()));
+    }
+
+    #[test]
+    fn test_format_key_bindings() {
+        let lines = format_key_bindings(&key_bindings(EditMode::Emacs));
+        assert_eq!(
+            lines[0],
+            "  Ctrl + C          Cancel input / Interrupt process"
+        );
+        assert!(lines.contains(&"  Esc               Clear the whole input line".to_string()));
+        assert_eq!(lines.len(), key_bindings(EditMode::Emacs).len());
+    }
+}
@@ -0,0 +1,22 @@
+//! with の REPL を構成するモジュール群
+//! バイナリ (main.rs) は入力の受け取りと表示だけを担い、1行ごとの処理は repl::run_line に委ねる
+
+pub mod async_lookup;
+pub mod cli;
+pub mod config;
+pub mod context;
+pub mod dir_env;
+pub mod executor;
+pub mod history;
+pub mod jobs;
+pub mod keybind;
+pub mod parser;
+pub mod prompt;
+pub mod record;
+pub mod repl;
+pub mod select;
+pub mod stats;
+pub mod subcommands;
+pub mod theme;
+pub mod title;
+pub mod with_helper;
@@ -1,164 +1,300 @@
-mod context;
-mod executor;
-mod parser;
-mod with_helper;
-
-use context::*;
-use executor::execute_child_process;
-use parser::*;
 use rustyline::{
-    Cmd, CompletionType, Config, Editor, KeyCode, Modifiers, Movement, Result, error::ReadlineError,
+    CompletionType, Config, Editor, Result, error::ReadlineError, history::DefaultHistory,
 };
 use std::{
-    env, eprintln, format,
-    option::Option::{None, Some},
+    env, eprintln,
+    io::{self, IsTerminal, Write},
+    option::Option::Some,
     path::{Path, PathBuf},
     println, process,
     result::Result::Ok,
+    thread,
+    time::Instant,
 };
-use with_helper::WithHelper;
-
-fn print_help() {
-    println!("With - Command Wrapper Tool");
-    println!();
-    println!("Usage:");
-    println!("  <command> [args]  Execute command in the target context");
-    println!("  cd <path>         Change current directory");
-    println!("  ! <command>       Execute external command (e.g. !ls, !vim)");
-    println!("  clear/cls         Clear the screen");
-    println!("  help              Show this help message");
-    println!("  pwd               Show current pass");
-    println!("  history           Show command history");
-    println!("  exit/quit (e/q)   Exit the application");
-    println!();
-    println!("Keyboard Shortcuts:");
-    println!("  Ctrl + C          Cancel input / Interrupt process");
-    println!("  Ctrl + D          Exit (EOF)");
-    println!("  Tab               File completion");
+use with::cli::{expand_context_args, parse_cli_args, resolve_context_paths, run_substitution};
+use with::config;
+use with::context::recover_dir;
+use with::executor::{
+    EXIT_ALL_CODE, TerminalGuard, missing_program_message, parse_context_stack, program_exists,
+};
+use with::history::{HistoryFiles, load_pins, pins_file_path};
+use with::jobs::{JobTable, format_job};
+use with::keybind::key_bindings;
+use with::parser::TargetContext;
+use with::prompt::{Prompt, SystemPromptProvider, external_prompt, override_format, render_prompt};
+use with::record::{Recorder, SessionLog, load_recording, replay};
+use with::repl::{ReplState, StepResult, run_line};
+use with::stats::preferred_subcommands;
+use with::theme::{Theme, enable_ansi_support};
+use with::title::{context_title, report_cwd, set_title};
+use with::with_helper::{WithHelper, detect_color};
+
+/// 終了前に実行中のジョブを確認する
+/// ジョブが無ければそのまま終了、あれば一覧を表示して確認を求める
+fn confirm_exit(jobs: &mut JobTable, hup_on_exit: bool) -> bool {
+    jobs.reap();
+    if jobs.running().is_empty() {
+        return true;
+    }
+
+    println!("There are running jobs:");
+    for job in jobs.running() {
+        println!("{}", format_job(job.id, job.state(), &job.command));
+    }
+    print!("Exit anyway? [y/N] ");
+    let _ = io::stdout().flush();
+
+    // 入力が閉じている (EOF) 場合は答えようがないので終了を優先する
+    let mut answer = String::new();
+    let confirmed = match io::stdin().read_line(&mut answer) {
+        Ok(0) | Err(_) => true,
+        Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+    };
+    if !confirmed {
+        return false;
+    }
+
+    if hup_on_exit {
+        jobs.hangup_all();
+    }
+    true
+}
+
+/// `--prompt-command` / `prompt.command` を実行し、標準出力をプロンプトにする
+/// 失敗したり何も出力しなかったりしたら None (内蔵のプロンプトに戻す)。警告は最初の1回だけ出す
+fn command_prompt(command: &str, warned: &mut bool) -> Option<Prompt> {
+    let result = run_substitution(command)
+        .and_then(|output| external_prompt(&output).ok_or("printed nothing".to_string()));
+    match result {
+        Ok(prompt) => Some(prompt),
+        Err(msg) => {
+            if !*warned {
+                eprintln!(
+                    "Warning: prompt command: {}; using the built-in prompt",
+                    msg
+                );
+                *warned = true;
+            }
+            None
+        }
+    }
+}
+
+/// 起動オプションから決まる REPL の設定
+struct ReplOptions {
+    /// `--quiet`
+    quiet: bool,
+    /// `--record` の記録先
+    recorder: Option<Recorder>,
+    /// `--log` の追記先
+    log: Option<SessionLog>,
+    /// `--prompt-command`
+    prompt_command: Option<String>,
 }
 
 // --- メインループ ---
 /// REPL（対話型ループ）のメインロジック
-fn run_repl(target_ctx: Option<&TargetContext>, base_path: &Path) -> Result<()> {
+/// 入力の受け取りとプロンプトの表示を担い、1行ごとの処理は run_line に任せる
+fn run_repl(
+    target_ctx: Option<&TargetContext>,
+    base_path: &Path,
+    app_config: &config::Config,
+    options: ReplOptions,
+    mut startup_timer: Option<Instant>,
+) -> Result<()> {
     let config = Config::builder()
         .history_ignore_space(true)
         .completion_type(CompletionType::List)
         .build();
 
+    let edit_mode = config.edit_mode();
     let context_program = target_ctx.map(|ctx| ctx.program.clone());
 
     // エディタの初期化
-    let mut rl = Editor::<WithHelper, rustyline::history::DefaultHistory>::with_config(config)?;
-    rl.set_helper(Some(WithHelper {
-        completer: rustyline::completion::FilenameCompleter::new(),
-        context_program,
-    }));
-
-    // キーバインド設定: Escキーで入力行を全削除（Windowsライクな挙動）
-    rl.bind_sequence(
-        rustyline::KeyEvent(KeyCode::Esc, Modifiers::NONE),
-        Cmd::Kill(Movement::WholeLine),
-    );
+    let mut rl = Editor::<WithHelper, DefaultHistory>::with_config(config)?;
+    let history_files = HistoryFiles::in_home();
+    let history = history_files
+        .as_ref()
+        .map(|files| files.load(rl.history_mut()))
+        .unwrap_or_default();
+    let mut helper = WithHelper::new(context_program);
+    helper.apply_config(app_config);
+    helper.color = detect_color();
+    let mut theme = helper.theme.clone();
+    rl.set_helper(Some(helper));
 
-    loop {
-        let current_dir = env::current_dir().unwrap_or_default();
-        let dir_name_opt = resolve_display_dir(&current_dir, base_path);
-
-        let branch_opt = get_git_branch(&current_dir);
-
-        // ディレクトリ情報とブランチ情報を結合する
-        let context_info = match (dir_name_opt, branch_opt) {
-            (Some(dir), Some(branch)) => Some(format!("{}: {}", dir, branch)),
-            (Some(dir), None) => Some(dir),
-            (None, Some(branch)) => Some(branch), // dir変化なしでもbranchがあれば出す場合
-            (None, None) => None,
-        };
+    // どの経路で抜けても (エラーの `?`・panic を含む) 後始末が走るよう、状態はガードに持たせる
+    let mut session = Session {
+        state: ReplState::new(target_ctx.cloned(), app_config.clone()),
+        editor: rl,
+        history_files,
+    };
+    let state = &mut session.state;
+    let rl = &mut session.editor;
+    state.history = history;
+    state.recorder = options.recorder;
+    state.log = options.log;
+    if let Some(path) = pins_file_path() {
+        state.history.set_pinned(load_pins(&path));
+        state.pins_file = Some(path);
+    }
+    if state.report_cwd {
+        report_cwd(&env::current_dir().unwrap_or_default());
+    }
+    if options.quiet {
+        state.quiet = true;
+        state.show_summary = false;
+        state.show_session_summary = false;
+    }
+    let mut provider = SystemPromptProvider {
+        base_path: base_path.to_path_buf(),
+        git: app_config.git.clone(),
+        dir_display: app_config.prompt.dir_display,
+        branch_cache: Default::default(),
+        exported: Default::default(),
+    };
 
-        let env_stack = env::var("WITH_CONTEXT_STACK").ok();
+    // readline に入る前の通常モードの端末設定 (コマンドの実行前後にここへ戻す)
+    let terminal = TerminalGuard::capture();
+    let mut prompt_command_warned = false;
+    // WITH_PROMPT は設定ファイルの prompt.format より優先する (config edit で読み直しても保つ)
+    let prompt_format = env::var("WITH_PROMPT").ok();
+    // 削除されたディレクトリにいることに気付いたとき、戻り先を探す起点
+    let mut last_cwd = env::current_dir().unwrap_or_else(|_| base_path.to_path_buf());
 
-        let prompt_cmd_str = if let Some(ctx) = target_ctx {
-            // 親スタックがある場合は結合して表示 (exp. git/cargo)
-            let full_context = if let Some(stack) = &env_stack {
-                format!("{}/{}", stack, ctx.program)
-            } else {
-                ctx.program.clone()
-            };
+    // キーバインド設定: 登録したものを `help` の一覧にも使う
+    state.key_bindings = key_bindings(edit_mode);
+    for binding in &state.key_bindings {
+        if let Some(cmd) = &binding.cmd {
+            rl.bind_sequence(binding.key, cmd.clone());
+        }
+    }
 
-            if ctx.args.is_empty() {
-                full_context
-            } else {
-                format!("{} {}", full_context, ctx.args.join(" "))
+    loop {
+        // `config set` / `config edit` で変わった設定をプロンプトと補完に反映する
+        if state.config_changed {
+            state.config_changed = false;
+            theme = Theme::from_config(&state.config.theme);
+            provider.git = state.config.git.clone();
+            provider.dir_display = state.config.prompt.dir_display;
+            // abbrev_length などが変わっているかもしれないので読み直させる
+            provider.branch_cache = Default::default();
+            if let Some(helper) = rl.helper_mut() {
+                helper.apply_config(&state.config);
             }
-        } else {
-            // コンテキストがなくても文字列を追加
-            if let Some(stack) = &env_stack {
-                format!("{}/", stack)
-            } else {
-                String::new()
+        }
+        let hup_on_exit = state.config.jobs.hup_on_exit;
+
+        // 子プロセスは親のカレントディレクトリを変えられないが、ディレクトリ自体を消すことはある
+        // 消えたディレクトリのままではプロンプトや実行が壊れるので、残っている親 (なければ起動時のディレクトリ) へ移る
+        match env::current_dir() {
+            Ok(cwd) => last_cwd = cwd,
+            Err(_) => {
+                if let Some(dir) = recover_dir(&last_cwd, base_path)
+                    && env::set_current_dir(&dir).is_ok()
+                {
+                    eprintln!(
+                        "with: current directory no longer exists; moved to {}",
+                        dir.display()
+                    );
+                    if state.report_cwd {
+                        report_cwd(&dir);
+                    }
+                    last_cwd = dir;
+                }
             }
-        };
+        }
 
-        let prompt = match (target_ctx, context_info) {
-            (Some(_cmd), Some(info)) => format!("({}) {}> ", info, prompt_cmd_str),
-            (Some(_cmd), None) => format!("{}> ", prompt_cmd_str),
-            (None, Some(info)) => format!("({}) {}> ", info, prompt_cmd_str),
-            (None, None) => format!("{}> ", prompt_cmd_str),
-        };
+        // 終了したバックグラウンドジョブを通知する
+        for (id, command, _) in state.jobs.reap() {
+            println!("{}", format_job(id, "Done", &command));
+        }
 
-        // 現在のプログラムのコンテキスト(exp. git/cargo)を取得
-        let current_context_prog = target_ctx.map(|ctx| ctx.program.as_str());
+        // 色付け済みのプロンプトをヘルパーに渡しておく
+        // switch-context で切り替わった場合も補完・ハイライトが追従するよう毎回反映する
+        // 外部コマンドに任せる場合は、その出力を装飾せずに使う (起動時の指定が設定より優先)
+        let external = options
+            .prompt_command
+            .as_deref()
+            .or(state.config.prompt.command.as_deref())
+            .and_then(|command| command_prompt(command, &mut prompt_command_warned));
+        provider.exported.clone_from(&state.parse_options.exported);
+        let prompt = external.unwrap_or_else(|| {
+            render_prompt(
+                &provider,
+                state.context.as_ref(),
+                state.last_status,
+                &override_format(&state.config.prompt, prompt_format.as_deref()),
+                &theme,
+            )
+        });
+        // 起動時・コンテキスト切り替え後・コマンド終了後にタイトルをコンテキスト表示へ戻す
+        if state.show_title {
+            let cwd = env::current_dir().unwrap_or_default();
+            set_title(&context_title(state.context.as_ref(), &cwd));
+        }
+        if let Some(helper) = rl.helper_mut() {
+            helper.prompt = Some(prompt.clone());
+            helper.context_program = state.context_program().map(|s| s.to_string());
+            // 起動時とコマンド実行ごとに、最近 (またはよく) 使ったサブコマンドを集計し直す
+            helper.preferred_subcommands = preferred_subcommands(
+                state.executed.iter().map(|s| s.as_str()),
+                state.config.completion.subcommand_order,
+            );
+        }
 
+        // 初回のプロンプトを出す直前までを起動時間として表示する
+        if let Some(started) = startup_timer.take() {
+            report_startup(started);
+        }
         // ユーザーの入力を待機
-        let readline = rl.readline(&prompt);
+        let readline = rl.readline(&prompt.plain);
 
         match readline {
             Ok(line) => {
-                let line = line.trim();
-
-                if !line.is_empty() {
-                    rl.add_history_entry(line)?;
+                // `!!` / `!n` を展開した後の行を上矢印で呼び出せるよう、run_line が追加した履歴を同期する
+                let last_id = state.history.last().map(|e| e.id);
+                let generation = state.history.generation();
+                // `!vim` のような対話コマンドと取り合わないよう、実行中は色付けを止めて端末を通常モードにしておく
+                // 終了後は端末設定と文字装飾を戻してから、次のプロンプトの色付けを再開する
+                set_suspended(rl, true);
+                terminal.restore();
+                let result = run_line(&line, state);
+                terminal.restore();
+                set_suspended(rl, false);
+                if state.history.generation() != generation {
+                    // `history clear` で消えたら上矢印の履歴も消し、残したピンの行だけで作り直す
+                    rl.clear_history()?;
+                    for line in state.history.lines() {
+                        rl.add_history_entry(line)?;
+                    }
+                } else if let Some(entry) = state.history.last()
+                    && Some(entry.id) != last_id
+                {
+                    rl.add_history_entry(entry.line.as_str())?;
                 }
 
-                let action = parse_cmd(line, target_ctx);
-
-                match action {
-                    CommandAction::Execute { program, args } => {
-                        execute_child_process(&program, args, current_context_prog);
-                    }
-                    CommandAction::ChangeDirectory(target) => {
-                        if let Some(path) = target
-                            && let Err(e) = env::set_current_dir(&path)
-                        {
-                            eprintln!("Failed to change directory: {}", e);
+                match result {
+                    StepResult::Continue => {}
+                    StepResult::Exit => {
+                        if confirm_exit(&mut state.jobs, hup_on_exit) {
+                            break;
                         }
                     }
-                    CommandAction::Clear(args) => {
-                        let program = "clear";
-                        execute_child_process(program, args, None);
-                    }
-                    CommandAction::Pwd(args) => {
-                        let program = "pwd";
-                        execute_child_process(program, args, None);
-                    }
-                    CommandAction::History => {
-                        for (idx, history) in rl.history().iter().enumerate() {
-                            println!("{: >3}: {}", idx + 1, history);
+                    StepResult::ExitAll => {
+                        if confirm_exit(&mut state.jobs, hup_on_exit) {
+                            // process::exit は Drop を走らせないので、先に後始末を済ませる
+                            drop(session);
+                            process::exit(EXIT_ALL_CODE);
                         }
                     }
-                    CommandAction::Help => {
-                        print_help();
-                    }
-                    CommandAction::DoNothing => {}
-                    CommandAction::Exit => break,
-                    CommandAction::ExitAll => {
-                        process::exit(127);
-                    }
-                    CommandAction::Error(msg) => eprintln!("Error: {}", msg),
                 }
             }
             // Ctrl+C, Ctrl+D で終了した場合
             Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
-                break;
+                if confirm_exit(&mut state.jobs, hup_on_exit) {
+                    break;
+                }
             }
             // その他のエラー
             Err(e) => {
@@ -172,34 +308,187 @@ fn run_repl(target_ctx: Option<&TargetContext>, base_path: &Path) -> Result<()>
     Ok(())
 }
 
+/// ヘルパーの色付けを止める・再開する
+fn set_suspended(rl: &mut Editor<WithHelper, DefaultHistory>, suspended: bool) {
+    if let Some(helper) = rl.helper_mut() {
+        helper.suspended = suspended;
+    }
+}
+
+/// REPL の状態を持ち、破棄されるときに終了時の後始末を行うガード
+/// 正常終了・Ctrl+C/D・readline のエラー・panic のいずれの経路でも一度だけ実行される
+struct Session {
+    state: ReplState,
+    editor: Editor<WithHelper, DefaultHistory>,
+    /// 終了時に入力履歴と ID 付きの履歴を保存するファイル
+    history_files: Option<HistoryFiles>,
+}
+
+impl Session {
+    /// 終了時の後始末 (入力履歴・記録の保存、セッションのサマリ表示など)
+    fn finish(&mut self) {
+        if let Some(recorder) = &self.state.recorder {
+            match recorder.save() {
+                Ok(()) => println!("Recorded to {}", recorder.path().display()),
+                Err(msg) => eprintln!(
+                    "Warning: failed to save {}: {}",
+                    recorder.path().display(),
+                    msg
+                ),
+            }
+        }
+        if let Some(files) = &self.history_files {
+            files.save(self.editor.history_mut(), &self.state.history);
+        }
+        if let Some(summary) = self.state.session_summary() {
+            println!("{}", summary);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
 // --- エントリーポイント ---
+/// `WITH_STARTUP_TIME` を指定したとき、起動から入力待ち (パイプでは実行開始) までの時間を表示する
+fn report_startup(started: Instant) {
+    eprintln!(
+        "with: startup took {:.1}ms",
+        started.elapsed().as_secs_f64() * 1000.0
+    );
+}
+
 fn main() {
+    let started = Instant::now();
+    let startup_timer = env::var_os("WITH_STARTUP_TIME")
+        .is_some()
+        .then_some(started);
+    // Windows の旧来のコンソールでは ANSI エスケープを有効化する
+    // 有効にできなければ色付け・タイトル変更をやめて文字化けを防ぐ (子プロセスにも NO_COLOR で伝える)
+    if !enable_ansi_support() {
+        // SAFETY: まだ他のスレッドを起動していない (Ctrl+C のハンドラもこの後で登録する)
+        unsafe { env::set_var("NO_COLOR", "1") };
+    }
+
     // Rustylineの入力待ち中のCtrl+Cは、Rustyline側が別途ハンドリングしてくれます。
     ctrlc::set_handler(|| {}).expect("Error setting Ctrl-C handler");
 
     // コマンドライン引数を取得
-    let args: Vec<String> = env::args().collect::<Vec<String>>();
+    let args: Vec<String> = env::args().skip(1).collect::<Vec<String>>();
+    let cli = match parse_cli_args(&args) {
+        Ok(cli) => cli,
+        Err(msg) => {
+            eprintln!("Error: {}", msg);
+            process::exit(1);
+        }
+    };
+    for warning in &cli.warnings {
+        eprintln!("with: {}", warning);
+    }
+    let base_path = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
 
-    let target_ctx: Option<TargetContext> = if args.len() >= 2 {
-        let joined_args = args[1..].join(" ");
+    // `--print-stack` は外部のツール・デバッグ用にスタックを1階層ずつ表示するだけ
+    if cli.print_stack {
+        let Ok(stack) = env::var("WITH_CONTEXT_STACK") else {
+            eprintln!("Not running inside with");
+            process::exit(1);
+        };
+        for (depth, level) in parse_context_stack(&stack).iter().enumerate() {
+            println!("{}: {}", depth, level.as_deref().unwrap_or("(no context)"));
+        }
+        return;
+    }
 
-        let split_args = shell_words::split(&joined_args).unwrap_or_default();
+    // `--replay` は記録を再生するだけで REPL には入らない
+    if let Some(path) = &cli.replay {
+        let result = load_recording(path).and_then(|recording| {
+            replay(&recording, &mut io::stdout(), thread::sleep).map_err(|e| e.to_string())
+        });
+        if let Err(msg) = result {
+            eprintln!("Error: {}: {}", path.display(), msg);
+            process::exit(1);
+        }
+        return;
+    }
 
-        if split_args.is_empty() {
-            None
-        } else {
-            Some(TargetContext {
-                program: split_args[0].clone(),
-                args: split_args[1..].to_vec(),
-            })
-        }
-    } else {
-        None
-    };
+    let mut target_ctx: Option<TargetContext> = cli.context;
+    // `$VAR` / `$(...)` は起動時に一度だけ展開する (失敗したものは警告して元のまま使う)
+    if !cli.no_expand
+        && let Some(ctx) = &mut target_ctx
+    {
+        for warning in expand_context_args(ctx, run_substitution) {
+            eprintln!("Warning: {}", warning);
+        }
+    }
+    // cd 後も使えるよう、相対パスを起動時のディレクトリ基準で解決しておく
+    if cli.resolve_paths
+        && let Some(ctx) = &mut target_ctx
+    {
+        resolve_context_paths(ctx, &base_path);
+    }
 
-    let base_path = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+    // PATH に無いコンテキスト (シェルのエイリアス等) は起動時に案内する
+    if let Some(ctx) = &target_ctx
+        && !program_exists(&ctx.program)
+    {
+        let shell = env::var("SHELL").ok();
+        eprintln!(
+            "{}",
+            missing_program_message(&ctx.program, shell.as_deref())
+        );
+    }
+
+    let app_config = config::load_config();
+
+    // stdin が端末でない (パイプの途中) なら REPL に入らず、コンテキストを1回だけ実行して透過する
+    if !io::stdin().is_terminal()
+        && let Some(ctx) = &target_ctx
+    {
+        let mut state = ReplState::new(Some(ctx.clone()), app_config);
+        if let Some(started) = startup_timer {
+            report_startup(started);
+        }
+        let code = state.run_passthrough();
+        process::exit(code.unwrap_or(1));
+    }
 
-    if let Err(e) = run_repl(target_ctx.as_ref(), &base_path) {
+    let recorder = match cli.record {
+        Some(path) => match Recorder::new(path, &app_config.record.exclude) {
+            Ok(recorder) => Some(recorder),
+            Err(msg) => {
+                eprintln!("Error: {}", msg);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let log = match cli.log {
+        Some(path) => match SessionLog::new(path, &app_config.record.exclude) {
+            Ok(log) => Some(log),
+            Err(msg) => {
+                eprintln!("Error: {}", msg);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let options = ReplOptions {
+        quiet: cli.quiet,
+        recorder,
+        log,
+        prompt_command: cli.prompt_command,
+    };
+    if let Err(e) = run_repl(
+        target_ctx.as_ref(),
+        &base_path,
+        &app_config,
+        options,
+        startup_timer,
+    ) {
         eprintln!("Application error: {}", e);
         process::exit(1);
     }
@@ -1,27 +1,980 @@
+use crate::stats::{DEFAULT_STATS_LIMIT, StatsUnit};
+use crate::subcommands;
+use std::collections::BTreeMap;
+use std::env;
 use std::option::Option::{None, Some};
 
 #[derive(Debug, PartialEq)]
 pub enum CommandAction {
-    Execute { program: String, args: Vec<String> },
+    Execute {
+        program: String,
+        args: Vec<String>,
+    },
+    Background {
+        program: String,
+        args: Vec<String>,
+    },
+    /// `cmd | tee [-a] file...`: 標準出力を画面とファイルの両方へ流す
+    Tee {
+        program: String,
+        args: Vec<String>,
+        files: Vec<String>,
+        append: bool,
+    },
+    /// `cmd > file` / `cmd >> file` / `cmd < file`: 標準入出力をファイルにつなぎ替えて実行する
+    Redirect {
+        program: String,
+        args: Vec<String>,
+        redirects: Redirects,
+    },
+    /// `cmd1 | cmd2 | ...`: 前段の標準出力を次段の標準入力へ流す (各段は (program, args))
+    /// `cmd1 < file | cmd2` の入力は先頭の段に、`cmd1 | cmd2 > file` の出力は最後の段に適用する
+    /// `cmd1 | cmd2 | tee file` なら最後の段の出力を画面と tee のファイルの両方へ流す
+    Pipeline {
+        stages: Vec<(String, Vec<String>)>,
+        redirects: Redirects,
+        tee: Option<TeeOutput>,
+    },
+    /// `cmd1; cmd2`: 終了コードに関係なく順に実行する (内部コマンドも混在できる)
+    List(Vec<CommandAction>),
+    /// `cmd1 && cmd2 || cmd3`: 直前の終了コードに応じて次の段を実行するかを決める
+    /// 段には内部コマンドも使える (`cd src && cargo build`)
+    Sequence(Vec<(ChainOp, CommandAction)>),
     ChangeDirectory(Option<String>),
+    Export(Vec<(String, String)>),
+    /// `alias NAME="VALUE"`: 行頭の NAME を VALUE に置き換えて実行する
+    Alias {
+        name: String,
+        expansion: String,
+    },
+    /// 引数なしの `alias`: 定義済みのエイリアス一覧
+    Aliases,
+    /// `unalias NAME...`
+    Unalias(Vec<String>),
+    /// `switch-context` / `ctx`: コンテキストをその場で切り替える (None ならコンテキストなし)
+    SwitchContext(Option<TargetContext>),
+    Jobs,
+    Disown(Option<usize>),
+    /// `in <dir> <cmd...>`: カレントディレクトリを変えずに <dir> で1回だけ実行する
+    InDirectory {
+        dir: String,
+        program: String,
+        args: Vec<String>,
+    },
+    /// `fg [%n]`: 停止中・バックグラウンドのジョブをフォアグラウンドで再開する
+    Fg(Option<usize>),
+    /// `bg [%n]`: 停止中のジョブをバックグラウンドで再開する
+    Bg(Option<usize>),
     Help,
     Clear(Vec<String>),
     Pwd(Vec<String>),
     History,
+    /// `history clear`: 履歴を消す (ID は再利用しない)
+    HistoryClear,
+    /// `history pin <id>`: 履歴をピン留めする (`!p<n>` で呼び出せる)
+    HistoryPin(usize),
+    /// `history unpin <n>`: n 番のピンを外す
+    HistoryUnpin(usize),
+    /// `history pinned`: ピン留めしたコマンドの一覧
+    HistoryPinned,
+    /// `history grep|re [-i] <pattern>`: 履歴の検索 (regex が true なら正規表現)
+    HistorySearch {
+        pattern: String,
+        regex: bool,
+        ignore_case: bool,
+    },
+    /// 履歴の集計 (`stats [first|sub|full] [N]`)
+    Stats {
+        unit: StatsUnit,
+        limit: usize,
+    },
+    /// `cheatsheet [file]` / `stats --export md [file]`: 履歴から Markdown のチートシートを作る
+    /// (output が None なら画面に出す)
+    Cheatsheet {
+        output: Option<String>,
+    },
+    /// `config list|get|set|save|edit`: 設定の参照・変更
+    Config(ConfigCommand),
+    /// `macro define|run|list|show|remove`: 複数コマンドのマクロ
+    Macro(MacroCommand),
     DoNothing,
     Exit,
     ExitAll,
     Error(String),
 }
 
-#[derive(Clone)]
+/// `config` のサブコマンド
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigCommand {
+    List,
+    Get(String),
+    /// (キー, 値の文字列)
+    Set(String, String),
+    /// `config set` で変更した値を設定ファイルへ書き込む
+    Save,
+    /// 設定ファイルを $EDITOR で開き、閉じたら読み直す
+    Edit,
+}
+
+/// `macro` のサブコマンド
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacroCommand {
+    /// `macro define [--keep-going] NAME CMD...`: 登録して設定ファイルへ保存する
+    Define {
+        name: String,
+        commands: Vec<String>,
+        keep_going: bool,
+    },
+    Run(String),
+    List,
+    Show(String),
+    Remove(String),
+}
+
+/// `&&` / `||` でつないだ段の実行条件
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChainOp {
+    /// 先頭の段 (常に実行する)
+    Always,
+    /// `&&`: 直前が成功したときだけ実行する
+    And,
+    /// `||`: 直前が失敗したときだけ実行する
+    Or,
+}
+
+impl ChainOp {
+    /// 直前の終了コードからこの段を実行するかを判定する
+    /// 実行しなかった段は終了コードを変えないので、`a && b || c` は a が失敗すると c を実行する
+    pub fn should_run(self, last_status: Option<i32>) -> bool {
+        match self {
+            ChainOp::Always => true,
+            ChainOp::And => last_status == Some(0),
+            ChainOp::Or => last_status != Some(0),
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            ChainOp::Always => "",
+            ChainOp::And => "&&",
+            ChainOp::Or => "||",
+        }
+    }
+}
+
+/// 標準出力の書き出し先
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputRedirect {
+    pub path: String,
+    /// true なら追記 (`>>`)、false なら上書き (`>`)
+    pub append: bool,
+}
+
+/// コマンドの標準入出力のつなぎ替え
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Redirects {
+    /// 標準入力に読ませるファイル (`< file`)
+    pub input: Option<String>,
+    pub output: Option<OutputRedirect>,
+}
+
+/// パイプ終端の `tee [-a] file...` の書き出し先
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TeeOutput {
+    pub files: Vec<String>,
+    /// `-a` なら追記する
+    pub append: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct TargetContext {
     pub program: String,
     pub args: Vec<String>,
+    /// このコンテキストで実行する全コマンドに設定する環境変数 (`--env KEY=VALUE`)
+    pub env: Vec<(String, String)>,
+}
+
+/// parse_cmd の挙動を切り替えるオプション
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// true なら `cd dir1 dir2` のような余分な引数をエラーにする
+    pub strict_cd: bool,
+    /// 内部コマンドと同名でも、コンテキストのサブコマンドとして扱う名前
+    /// (例: `with docker` での "history" -> `docker history`)
+    pub context_subcommands: Vec<String>,
+    /// true なら入力引数をコンテキスト引数より前に置く
+    /// (例: `with git log` で `-C /path` -> `git -C /path log`)
+    pub input_first: bool,
+    /// 現在のコンテキストのサブコマンドテンプレート (例: "cm" -> "commit -m {}")
+    pub templates: BTreeMap<String, String>,
+    /// `export` した変数 (`$VAR` の展開ではプロセスの環境変数より優先する)
+    pub exported: BTreeMap<String, String>,
+}
+
+/// サブコマンドテンプレートの `{}` に引数を順に差し込む純粋関数
+/// 例: "commit -m {}" + ["fix"] -> ["commit", "-m", "fix"]。余った引数は末尾に付ける
+fn expand_template(name: &str, template: &str, args: &[String]) -> Result<Vec<String>, String> {
+    let words = shell_words::split(template).map_err(|e| format!("{}: {}", name, e))?;
+    let mut rest = args.iter();
+    let mut expanded = Vec::new();
+    for word in words {
+        let mut filled = String::new();
+        let mut parts = word.split("{}");
+        filled.push_str(parts.next().unwrap_or_default());
+        for part in parts {
+            let arg = rest
+                .next()
+                .ok_or_else(|| format!("{}: missing argument for {{}} in '{}'", name, template))?;
+            filled.push_str(arg);
+            filled.push_str(part);
+        }
+        expanded.push(filled);
+    }
+    expanded.extend(rest.cloned());
+    Ok(expanded)
+}
+
+/// コンテキストと入力引数を結合して実行アクションを作る
+/// 先頭がサブコマンドテンプレートに一致すれば展開してから結合する
+fn apply_context(
+    ctx: &TargetContext,
+    mut args: Vec<String>,
+    options: &ParseOptions,
+) -> CommandAction {
+    if let Some(template) = args.first().and_then(|first| options.templates.get(first)) {
+        args = match expand_template(&args[0], template, &args[1..]) {
+            Ok(expanded) => expanded,
+            Err(msg) => return CommandAction::Error(msg),
+        };
+    }
+    let final_args = if options.input_first {
+        args.extend(ctx.args.iter().cloned());
+        args
+    } else {
+        let mut final_args = ctx.args.clone();
+        final_args.append(&mut args);
+        final_args
+    };
+    CommandAction::Execute {
+        program: ctx.program.clone(),
+        args: final_args,
+    }
+}
+
+/// サブコマンドと衝突しうる内部コマンド名
+const BUILTIN_COMMANDS: &[&str] = &[
+    "alias",
+    "bg",
+    "cd",
+    "cheatsheet",
+    "clear",
+    "cls",
+    "ctx",
+    "disown",
+    "export",
+    "fg",
+    "help",
+    "history",
+    "in",
+    "jobs",
+    "macro",
+    "pwd",
+    "stats",
+    "switch-context",
+    "unalias",
+];
+
+/// `in` の後ろ (`<dir> <cmd...>`) を解釈する
+/// <cmd> は元の行から切り出してパースし直すので、クォートや変数の展開・コンテキストの適用は通常の入力と同じ
+fn parse_in_command(
+    rest: &str,
+    context: Option<&TargetContext>,
+    options: &ParseOptions,
+) -> CommandAction {
+    let rest = rest.trim_start();
+    let end = unquoted_positions(rest, b' ')
+        .into_iter()
+        .chain(unquoted_positions(rest, b'\t'))
+        .min()
+        .unwrap_or(rest.len());
+    let mut words = match split_words(&rest[..end], &options.exported) {
+        Ok(words) => words,
+        Err(e) => return CommandAction::Error(format!("in: {}", e)),
+    };
+    let dir = match words.len() {
+        0 => return CommandAction::Error("in: missing directory".to_string()),
+        1 => words.remove(0),
+        _ => return CommandAction::Error("in: ambiguous directory".to_string()),
+    };
+    let command = rest[end..].trim();
+    if command.is_empty() {
+        return CommandAction::Error("in: missing command".to_string());
+    }
+    match parse_cmd_with(command, context, options) {
+        CommandAction::Execute { program, args } => {
+            CommandAction::InDirectory { dir, program, args }
+        }
+        CommandAction::Error(msg) => CommandAction::Error(msg),
+        _ => CommandAction::Error("in: only commands can run in another directory".to_string()),
+    }
+}
+
+/// `stats` の引数 (集計単位と件数、順不同) を解釈する
+fn parse_stats_args(args: &[String]) -> CommandAction {
+    if args.first().is_some_and(|a| a == "--export") {
+        return match &args[1..] {
+            [format, rest @ ..] if format == "md" => parse_cheatsheet_args("stats --export", rest),
+            [format, ..] => CommandAction::Error(format!(
+                "stats: unsupported export format '{}' (expected md)",
+                format
+            )),
+            [] => CommandAction::Error("stats: missing export format (expected md)".to_string()),
+        };
+    }
+    let mut unit = StatsUnit::First;
+    let mut limit = DEFAULT_STATS_LIMIT;
+    for arg in args {
+        if let Some(u) = StatsUnit::parse(arg) {
+            unit = u;
+        } else if let Ok(n) = arg.parse() {
+            limit = n;
+        } else {
+            return CommandAction::Error(format!(
+                "stats: invalid argument '{}' (expected first, sub, full or a number)",
+                arg
+            ));
+        }
+    }
+    CommandAction::Stats { unit, limit }
+}
+
+/// コンテキストのツールが `config` サブコマンドを持つか
+fn has_config_subcommand(program: &str) -> bool {
+    subcommands::builtin()
+        .subcommands(program)
+        .contains(&"config")
+}
+
+/// `config` の引数を解釈する (引数なしは list)
+fn parse_config_args(args: &[String]) -> CommandAction {
+    let Some((sub, rest)) = args.split_first() else {
+        return CommandAction::Config(ConfigCommand::List);
+    };
+    let command = match (sub.as_str(), rest) {
+        ("list", []) => ConfigCommand::List,
+        ("get", [key]) => ConfigCommand::Get(key.clone()),
+        ("set", [key, value @ ..]) if !value.is_empty() => {
+            ConfigCommand::Set(key.clone(), value.join(" "))
+        }
+        ("save", []) => ConfigCommand::Save,
+        ("edit", []) => ConfigCommand::Edit,
+        ("get", _) => return CommandAction::Error("usage: config get <key>".to_string()),
+        ("set", _) => return CommandAction::Error("usage: config set <key> <value>".to_string()),
+        ("list" | "save" | "edit", _) => {
+            return CommandAction::Error(format!("config {}: too many arguments", sub));
+        }
+        _ => {
+            return CommandAction::Error(format!(
+                "config: unknown subcommand '{}' (expected list, get, set, save or edit)",
+                sub
+            ));
+        }
+    };
+    CommandAction::Config(command)
+}
+
+/// `macro` の引数を解釈する (引数なしは list)
+fn parse_macro_args(args: &[String]) -> CommandAction {
+    let Some((sub, rest)) = args.split_first() else {
+        return CommandAction::Macro(MacroCommand::List);
+    };
+    let command = match (sub.as_str(), rest) {
+        ("define", rest) => {
+            let keep_going = rest.first().is_some_and(|a| a == "--keep-going");
+            let rest = if keep_going { &rest[1..] } else { rest };
+            let [name, commands @ ..] = rest else {
+                return CommandAction::Error(
+                    "usage: macro define [--keep-going] <name> <command>...".to_string(),
+                );
+            };
+            if commands.is_empty() {
+                return CommandAction::Error(
+                    "usage: macro define [--keep-going] <name> <command>...".to_string(),
+                );
+            }
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return CommandAction::Error(format!("macro define: invalid name '{}'", name));
+            }
+            // 名前だけで呼び出せるので、内部コマンドと同じ名前は使えない
+            if is_builtin_command(name) {
+                return CommandAction::Error(format!(
+                    "macro define: '{}' is a built-in command",
+                    name
+                ));
+            }
+            MacroCommand::Define {
+                name: name.clone(),
+                commands: commands.to_vec(),
+                keep_going,
+            }
+        }
+        ("run", [name]) => MacroCommand::Run(name.clone()),
+        ("show", [name]) => MacroCommand::Show(name.clone()),
+        ("remove", [name]) => MacroCommand::Remove(name.clone()),
+        ("list", []) => MacroCommand::List,
+        ("run" | "show" | "remove", _) => {
+            return CommandAction::Error(format!("usage: macro {} <name>", sub));
+        }
+        ("list", _) => return CommandAction::Error("macro list: too many arguments".to_string()),
+        _ => {
+            return CommandAction::Error(format!(
+                "macro: unknown subcommand '{}' (expected define, run, list, show or remove)",
+                sub
+            ));
+        }
+    };
+    CommandAction::Macro(command)
+}
+
+/// チートシートの出力先 (省略時は画面) を解釈する
+fn parse_cheatsheet_args(name: &str, args: &[String]) -> CommandAction {
+    match args {
+        [] => CommandAction::Cheatsheet { output: None },
+        [path] => CommandAction::Cheatsheet {
+            output: Some(path.clone()),
+        },
+        _ => CommandAction::Error(format!("{}: too many arguments", name)),
+    }
+}
+
+/// ジョブ操作コマンドの `%n` 引数を解釈する (省略時は None = 最後のジョブ)
+fn parse_job_id(name: &str, arg: Option<&String>) -> Result<Option<usize>, CommandAction> {
+    match arg {
+        None => Ok(None),
+        Some(arg) => match arg.trim_start_matches('%').parse() {
+            Ok(id) => Ok(Some(id)),
+            Err(_) => Err(CommandAction::Error(format!(
+                "{}: invalid job id '{}'",
+                name, arg
+            ))),
+        },
+    }
+}
+
+/// 名前が内部コマンドかどうか
+pub fn is_builtin_command(name: &str) -> bool {
+    BUILTIN_COMMANDS.contains(&name)
+}
+
+/// 環境変数・`~` を展開して単語に分け、クォート外のワイルドカードをファイル名に展開する
+fn split_words(
+    line: &str,
+    exported: &BTreeMap<String, String>,
+) -> Result<Vec<String>, shell_words::ParseError> {
+    let words = shell_words::split(&expand_env(line, exported))?;
+    Ok(words.into_iter().flat_map(expand_glob).collect())
+}
+
+/// クォート外のワイルドカード文字の目印
+/// shell-words で分割した後もクォートの内外を区別できるよう、私用領域の文字に置き換えておく
+const GLOB_MARKS: [(char, char); 4] = [
+    ('*', '\u{E000}'),
+    ('?', '\u{E001}'),
+    ('[', '\u{E002}'),
+    (']', '\u{E003}'),
+];
+
+fn glob_mark(c: char) -> Option<char> {
+    GLOB_MARKS
+        .iter()
+        .find(|(raw, _)| *raw == c)
+        .map(|&(_, mark)| mark)
+}
+
+fn glob_unmark(c: char) -> Option<char> {
+    GLOB_MARKS
+        .iter()
+        .find(|(_, mark)| *mark == c)
+        .map(|&(raw, _)| raw)
+}
+
+/// 目印付きの単語をファイル名に展開する (一致しなければ元の単語のまま。bash の既定と同じ)
+/// クォート内の `*` などはそのままの文字として扱い、`.` で始まる名前は明示しない限り一致させない
+fn expand_glob(word: String) -> Vec<String> {
+    let literal: String = word.chars().map(|c| glob_unmark(c).unwrap_or(c)).collect();
+    let is_glob = word
+        .chars()
+        .any(|c| matches!(glob_unmark(c), Some('*' | '?' | '[')));
+    if !is_glob {
+        return vec![literal];
+    }
+
+    let mut pattern = String::with_capacity(word.len());
+    for c in word.chars() {
+        match glob_unmark(c) {
+            Some(raw) => pattern.push(raw),
+            None if matches!(c, '*' | '?' | '[' | ']') => {
+                pattern.push_str(&glob::Pattern::escape(&c.to_string()))
+            }
+            None => pattern.push(c),
+        }
+    }
+    let options = glob::MatchOptions {
+        require_literal_leading_dot: true,
+        ..Default::default()
+    };
+    let matches: Vec<String> = match glob::glob_with(&pattern, options) {
+        Ok(paths) => paths
+            .filter_map(Result::ok)
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => vec![],
+    };
+    if matches.is_empty() {
+        vec![literal]
+    } else {
+        matches
+    }
+}
+
+/// 入力行の `$VAR` / `${VAR}` を環境変数 (exported を優先) の値に、単語先頭の `~` / `~/...` をホームディレクトリに展開する
+/// シングルクォート内と `\$` は展開せず、未定義の変数は空文字列になる (POSIX と同じ)
+fn expand_env(line: &str, exported: &BTreeMap<String, String>) -> String {
+    let home = env::home_dir().map(|home| home.display().to_string());
+    let lookup = |name: &str| exported.get(name).cloned().or_else(|| env::var(name).ok());
+    expand_with(line, lookup, home.as_deref())
+}
+
+/// lookup で変数の値を引き、home でホームディレクトリを展開する純粋関数
+/// 展開した値は空白やクォートを含んでも1つの引数のまま残るようクォートして埋め込む
+/// `~user` と単語の途中の `~` (`a~b`) はそのまま残す
+fn expand_with(line: &str, lookup: impl Fn(&str) -> Option<String>, home: Option<&str>) -> String {
+    let is_boundary = |c: char| c.is_whitespace() || matches!(c, ';' | '|' | '&' | '<' | '>');
+    let mut out = String::with_capacity(line.len());
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut word_start = true;
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let at_word_start = std::mem::replace(
+            &mut word_start,
+            !escaped && quote.is_none() && is_boundary(c),
+        );
+        if escaped {
+            escaped = false;
+            out.push(c);
+            continue;
+        }
+        match (quote, c) {
+            (None, '*' | '?' | '[' | ']') => {
+                out.extend(glob_mark(c));
+                continue;
+            }
+            (None, '~') if at_word_start => {
+                let ends_word = chars
+                    .peek()
+                    .is_none_or(|&(_, next)| next == '/' || is_boundary(next));
+                if ends_word && let Some(home) = home {
+                    out.push_str(&quote_env_value(home, false));
+                    continue;
+                }
+            }
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(_), '"') => quote = None,
+            (_, '$') => {
+                if let Some((name, len)) = env_var_name(&line[i + 1..]) {
+                    let value = lookup(name).unwrap_or_default();
+                    out.push_str(&quote_env_value(&value, quote.is_some()));
+                    // 変数名は ASCII なのでバイト数ぶん読み飛ばせばよい
+                    for _ in 0..len {
+                        chars.next();
+                    }
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// `$` の直後から変数名を読み取り、(変数名, 読み進めるバイト数) を返す
+pub(crate) fn env_var_name(rest: &str) -> Option<(&str, usize)> {
+    let is_name = |name: &str| {
+        name.chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    if let Some(braced) = rest.strip_prefix('{') {
+        let name = &braced[..braced.find('}')?];
+        return is_name(name).then_some((name, name.len() + 2));
+    }
+    let len = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    let name = &rest[..len];
+    is_name(name).then_some((name, len))
+}
+
+/// `$(` の後ろから対応する `)` の位置を探す (入れ子とクォートを考慮する)
+pub(crate) fn find_closing_paren(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') if depth == 0 => return Some(i),
+            (None, ')') => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// 展開した値を shell-words で1つの引数として読めるように埋め込む
+fn quote_env_value(value: &str, in_double_quotes: bool) -> String {
+    if in_double_quotes {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if matches!(c, '\\' | '"' | '$' | '`') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    } else if value.is_empty() {
+        // クォートの外で空になった変数は引数ごと消える
+        String::new()
+    } else {
+        shell_words::quote(value).into_owned()
+    }
+}
+
+/// クォート・エスケープの外にある target の位置を返す
+fn unquoted_positions(line: &str, target: u8) -> Vec<usize> {
+    let bytes = line.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut escaped = false;
+    let mut positions = Vec::new();
+
+    for (i, &b) in bytes.iter().enumerate() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, b) {
+            (Some(q), _) if b == q => quote = None,
+            (Some(_), _) => {}
+            (None, b'\\') => escaped = true,
+            (None, b'\'' | b'"') => quote = Some(b),
+            (None, b) if b == target => positions.push(i),
+            _ => {}
+        }
+    }
+    positions
+}
+
+/// クォート・エスケープの外にある "|" (`||` は除く) の位置を返す
+fn pipe_positions(line: &str) -> Vec<usize> {
+    let bytes = line.as_bytes();
+    unquoted_positions(line, b'|')
+        .into_iter()
+        .filter(|&i| {
+            let prev_pipe = i > 0 && bytes[i - 1] == b'|';
+            let next_pipe = bytes.get(i + 1) == Some(&b'|');
+            !prev_pipe && !next_pipe
+        })
+        .collect()
+}
+
+/// クォート外の `<` / `>` / `>>` を取り出し、(コマンド部分, つなぎ替え) に分ける
+/// リダイレクトが無ければ None、対象のファイルが無い・同じ向きが複数ある場合は Err を返す
+/// `cmd > out.txt -v` のようにファイル名の後ろに続く引数はコマンド側に戻す
+fn parse_redirects(
+    line: &str,
+    exported: &BTreeMap<String, String>,
+) -> Option<Result<(String, Redirects), String>> {
+    let bytes = line.as_bytes();
+    let mut ops: Vec<usize> = unquoted_positions(line, b'<');
+    ops.extend(unquoted_positions(line, b'>'));
+    ops.sort_unstable();
+    let &first = ops.first()?;
+
+    let mut command = line[..first].to_string();
+    let mut redirects = Redirects::default();
+    let mut idx = 0;
+    while idx < ops.len() {
+        let start = ops[idx];
+        let op = bytes[start];
+        let append = op == b'>' && ops.get(idx + 1) == Some(&(start + 1));
+        idx += if append { 2 } else { 1 };
+        if op == b'>' && start > 0 && matches!(bytes[start - 1], b'0'..=b'9' | b'&') {
+            return Some(Err(
+                "only stdout redirection ('>' / '>>') is supported".to_string()
+            ));
+        }
+
+        let end = ops.get(idx).copied().unwrap_or(line.len());
+        let target_start = start + if append { 2 } else { 1 };
+        let mut words = match split_words(&line[target_start..end], exported) {
+            Ok(words) => words,
+            Err(e) => return Some(Err(e.to_string())),
+        };
+        if words.is_empty() {
+            let symbol = if op == b'<' { "<" } else { ">" };
+            return Some(Err(format!(
+                "syntax error: missing file after '{}'",
+                symbol
+            )));
+        }
+        let path = words.remove(0);
+        if !words.is_empty() {
+            command.push(' ');
+            command.push_str(&shell_words::join(words));
+        }
+
+        let duplicated = if op == b'<' {
+            redirects.input.replace(path).is_some()
+        } else {
+            redirects
+                .output
+                .replace(OutputRedirect { path, append })
+                .is_some()
+        };
+        if duplicated {
+            return Some(Err(
+                "only one redirection per direction is supported".to_string()
+            ));
+        }
+    }
+    Some(Ok((command, redirects)))
+}
+
+/// `;` で区切った各コマンドをパースする
+/// 末尾の `;` は無視し、それ以外の空のコマンドやパースできないコマンドがあれば何も実行しない
+fn parse_list(
+    segments: &[&str],
+    context: Option<&TargetContext>,
+    options: &ParseOptions,
+) -> CommandAction {
+    let segments = match segments.split_last() {
+        Some((last, rest)) if last.trim().is_empty() => rest,
+        _ => segments,
+    };
+    if segments.iter().any(|s| s.trim().is_empty()) {
+        return CommandAction::Error("syntax error near ';'".to_string());
+    }
+    if let [single] = segments {
+        return parse_cmd_with(single, context, options);
+    }
+
+    let mut actions = Vec::new();
+    for segment in segments {
+        match parse_cmd_with(segment, context, options) {
+            CommandAction::Error(msg) => return CommandAction::Error(msg),
+            action => actions.push(action),
+        }
+    }
+    CommandAction::List(actions)
+}
+
+/// クォート・エスケープの外にある `&&` / `||` で行を段に分ける
+/// 各段には直前の演算子 (先頭は ChainOp::Always) を添える
+fn split_chain(line: &str) -> Vec<(ChainOp, &str)> {
+    let mut ops: Vec<(usize, ChainOp)> = Vec::new();
+    for (byte, op) in [(b'&', ChainOp::And), (b'|', ChainOp::Or)] {
+        let positions = unquoted_positions(line, byte);
+        let mut idx = 0;
+        while idx + 1 < positions.len() {
+            if positions[idx + 1] == positions[idx] + 1 {
+                ops.push((positions[idx], op));
+                idx += 2;
+            } else {
+                idx += 1;
+            }
+        }
+    }
+    ops.sort_unstable_by_key(|&(i, _)| i);
+
+    let mut stages = Vec::new();
+    let mut start = 0;
+    let mut current = ChainOp::Always;
+    for (i, op) in ops {
+        stages.push((current, &line[start..i]));
+        start = i + 2;
+        current = op;
+    }
+    stages.push((current, &line[start..]));
+    stages
+}
+
+/// `&&` / `||` でつないだ各段をパースする (コンテキストは全ての段に適用する)
+/// 内部コマンドと外部コマンドは段ごとに判別する
+fn parse_sequence(
+    stages: &[(ChainOp, &str)],
+    context: Option<&TargetContext>,
+    options: &ParseOptions,
+) -> CommandAction {
+    let mut commands = Vec::new();
+    for (i, &(op, stage)) in stages.iter().enumerate() {
+        if stage.trim().is_empty() {
+            // 先頭の段が空なら直後の演算子を示す
+            let symbol = if i == 0 { stages[1].0 } else { op }.symbol();
+            return CommandAction::Error(format!("syntax error near '{}'", symbol));
+        }
+        match parse_cmd_with(stage, context, options) {
+            CommandAction::Error(msg) => return CommandAction::Error(msg),
+            CommandAction::Background { .. } => {
+                return CommandAction::Error("background jobs cannot be chained".to_string());
+            }
+            action => commands.push((op, action)),
+        }
+    }
+    CommandAction::Sequence(commands)
+}
+
+/// クォート・エスケープの外にある最後の "|" (`||` は除く) で行を分割する
+fn split_last_pipe(line: &str) -> Option<(&str, &str)> {
+    let i = *pipe_positions(line).last()?;
+    Some((&line[..i], &line[i + 1..]))
+}
+
+/// クォート・エスケープの外にある "|" で行を段に分ける (パイプが無ければ1段)
+fn split_pipes(line: &str) -> Vec<&str> {
+    let mut stages = Vec::new();
+    let mut start = 0;
+    for i in pipe_positions(line) {
+        stages.push(&line[start..i]);
+        start = i + 1;
+    }
+    stages.push(&line[start..]);
+    stages
+}
+
+/// パイプラインの各段をパースする
+/// コンテキストは先頭の段にだけ適用し、2段目以降は通常のコマンドとして扱う
+/// `<` は先頭の段に、`>` / `>>` は最後の段にだけ書ける
+fn parse_pipeline(
+    stages: &[&str],
+    context: Option<&TargetContext>,
+    options: &ParseOptions,
+) -> CommandAction {
+    let plain = ParseOptions {
+        exported: options.exported.clone(),
+        ..Default::default()
+    };
+    let last = stages.len() - 1;
+    let mut commands = Vec::new();
+    let mut redirects = Redirects::default();
+    for (i, stage) in stages.iter().enumerate() {
+        if stage.trim().is_empty() {
+            return CommandAction::Error("syntax error near '|'".to_string());
+        }
+        let action = if i == 0 {
+            parse_cmd_with(stage, context, options)
+        } else {
+            parse_cmd_with(stage, None, &plain)
+        };
+        match action {
+            CommandAction::Execute { program, args } => commands.push((program, args)),
+            CommandAction::Redirect {
+                program,
+                args,
+                redirects: stage,
+            } => {
+                if stage.input.is_some() && i != 0 {
+                    return CommandAction::Error(
+                        "only the first command of a pipeline can read from a file".to_string(),
+                    );
+                }
+                if stage.output.is_some() && i != last {
+                    return CommandAction::Error(
+                        "only the last command of a pipeline can redirect its output".to_string(),
+                    );
+                }
+                redirects.input = redirects.input.or(stage.input);
+                redirects.output = redirects.output.or(stage.output);
+                commands.push((program, args));
+            }
+            CommandAction::Error(msg) => return CommandAction::Error(msg),
+            _ => return CommandAction::Error("only commands can be piped".to_string()),
+        }
+    }
+    CommandAction::Pipeline {
+        stages: commands,
+        redirects,
+        tee: None,
+    }
+}
+
+/// パイプ終端の `tee [-a] file...` を解釈する
+/// tee 以外が続く場合は None を返す
+fn parse_tee(
+    rest: &str,
+    exported: &BTreeMap<String, String>,
+) -> Option<Result<(Vec<String>, bool), String>> {
+    let words = match split_words(rest, exported) {
+        Ok(w) => w,
+        Err(e) => return Some(Err(e.to_string())),
+    };
+    if words.first().map(|w| w.as_str()) != Some("tee") {
+        return None;
+    }
+
+    let mut append = false;
+    let mut files = Vec::new();
+    for word in &words[1..] {
+        match word.as_str() {
+            "-a" | "--append" => append = true,
+            s if s.starts_with('-') && s.len() > 1 => {
+                return Some(Err(format!("tee: unknown option '{}'", s)));
+            }
+            _ => files.push(word.clone()),
+        }
+    }
+    Some(Ok((files, append)))
+}
+
+/// 行頭の単語がエイリアスなら展開する純粋関数 (展開後の先頭がエイリアスならさらに展開する)
+/// 同じエイリアスは1度しか展開しないので、`alias a="a"` や a → b → a の循環でも止まる
+pub fn expand_alias(line: &str, aliases: &BTreeMap<String, String>) -> String {
+    let mut line = line.trim_start().to_string();
+    let mut seen: Vec<String> = Vec::new();
+    loop {
+        let end = line.find(char::is_whitespace).unwrap_or(line.len());
+        let word = &line[..end];
+        if seen.iter().any(|s| s == word) {
+            break;
+        }
+        let Some(expansion) = aliases.get(word) else {
+            break;
+        };
+        seen.push(word.to_string());
+        line = format!("{}{}", expansion, &line[end..]);
+    }
+    line
 }
 
 /// 入力行とターゲットコマンドを受け取り、アクションを返す
 pub fn parse_cmd(line: &str, context: Option<&TargetContext>) -> CommandAction {
+    parse_cmd_with(line, context, &ParseOptions::default())
+}
+
+/// オプションを指定して入力行をパースする
+pub fn parse_cmd_with(
+    line: &str,
+    context: Option<&TargetContext>,
+    options: &ParseOptions,
+) -> CommandAction {
     let line = line.trim();
 
     // Windows対応: 表示は '\' (バックスラッシュ) だが、
@@ -32,6 +985,91 @@ pub fn parse_cmd(line: &str, context: Option<&TargetContext>) -> CommandAction {
     #[cfg(windows)]
     let line = line_owned.as_str();
 
+    // "a; b" は終了コードに関係なく順に実行する
+    let semicolons = unquoted_positions(line, b';');
+    if !semicolons.is_empty() {
+        let mut segments = Vec::new();
+        let mut start = 0;
+        for i in semicolons {
+            segments.push(&line[start..i]);
+            start = i + 1;
+        }
+        segments.push(&line[start..]);
+        return parse_list(&segments, context, options);
+    }
+
+    // 末尾の "&" はバックグラウンド実行 ("&&" やエスケープされた "\&" は対象外)
+    if let Some(rest) = line.strip_suffix('&')
+        && !rest.ends_with('&')
+        && !rest.ends_with('\\')
+        && !rest.trim().is_empty()
+    {
+        return match parse_cmd_with(rest, context, options) {
+            CommandAction::Execute { program, args } => CommandAction::Background { program, args },
+            CommandAction::Error(msg) => CommandAction::Error(msg),
+            _ => CommandAction::Error("only commands can run in the background".to_string()),
+        };
+    }
+
+    // "a && b || c" は直前の終了コードを見ながら順に実行する
+    let chain = split_chain(line);
+    if chain.len() > 1 {
+        return parse_sequence(&chain, context, options);
+    }
+
+    // パイプ終端の "| tee file" は画面とファイルの両方へ出力する
+    if let Some((left, right)) = split_last_pipe(line)
+        && let Some(tee) = parse_tee(right, &options.exported)
+    {
+        let (files, append) = match tee {
+            Ok(t) => t,
+            Err(msg) => return CommandAction::Error(msg),
+        };
+        return match parse_cmd_with(left, context, options) {
+            CommandAction::Execute { program, args } => CommandAction::Tee {
+                program,
+                args,
+                files,
+                append,
+            },
+            // "a | b | tee file" は最後の段の出力を書き写す ("a | b > f | tee g" は書き写す出力が無い)
+            CommandAction::Pipeline {
+                stages,
+                redirects,
+                tee: None,
+            } if redirects.output.is_none() => CommandAction::Pipeline {
+                stages,
+                redirects,
+                tee: Some(TeeOutput { files, append }),
+            },
+            CommandAction::Error(msg) => CommandAction::Error(msg),
+            _ => CommandAction::Error("tee: only command output can be piped".to_string()),
+        };
+    }
+
+    // "a | b | c" はパイプラインとして実行する (つなぎ替えは各段の中で解釈する)
+    let stages = split_pipes(line);
+    if stages.len() > 1 {
+        return parse_pipeline(&stages, context, options);
+    }
+
+    // "cmd > file" / "cmd >> file" / "cmd < file" は標準入出力をファイルにつなぎ替える
+    if let Some(redirect) = parse_redirects(line, &options.exported) {
+        let (command, redirects) = match redirect {
+            Ok(r) => r,
+            Err(msg) => return CommandAction::Error(msg),
+        };
+        return match parse_cmd_with(&command, context, options) {
+            CommandAction::Execute { program, args } => CommandAction::Redirect {
+                program,
+                args,
+                redirects,
+            },
+            CommandAction::Error(msg) => CommandAction::Error(msg),
+            _ => CommandAction::Error("only commands can be redirected".to_string()),
+        };
+    }
+
     // 終了コマンドの判定
     match line {
         "exit" | "e" => return CommandAction::ExitAll,
@@ -40,7 +1078,7 @@ pub fn parse_cmd(line: &str, context: Option<&TargetContext>) -> CommandAction {
     }
 
     // 引数を分割
-    let mut args = match shell_words::split(line) {
+    let mut args = match split_words(line, &options.exported) {
         Ok(a) => a,
         Err(e) => return CommandAction::Error(e.to_string()),
     };
@@ -58,9 +1096,19 @@ pub fn parse_cmd(line: &str, context: Option<&TargetContext>) -> CommandAction {
     // 先頭の要素（コマンド名候補）を取得
     let first_arg: &str = &args[0];
 
+    // 内部コマンドとサブコマンドが衝突する場合、設定によってはコンテキストを優先する
+    if let Some(ctx) = context
+        && options.context_subcommands.iter().any(|s| s == first_arg)
+    {
+        return apply_context(ctx, args, options);
+    }
+
     match first_arg {
         // --- 内部コマンド (Built-in) ---
         "cd" => {
+            if options.strict_cd && args.len() > 2 {
+                return CommandAction::Error("cd: too many arguments".to_string());
+            }
             let target = if args.len() > 1 {
                 Some(args[1].to_string())
             } else {
@@ -83,7 +1131,135 @@ pub fn parse_cmd(line: &str, context: Option<&TargetContext>) -> CommandAction {
             args.remove(0);
             CommandAction::Pwd(args)
         }
+        "export" => {
+            let mut vars = Vec::new();
+            for arg in &args[1..] {
+                match arg.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => {
+                        vars.push((key.to_string(), value.to_string()))
+                    }
+                    _ => {
+                        return CommandAction::Error(format!(
+                            "export: invalid argument '{}' (expected KEY=VALUE)",
+                            arg
+                        ));
+                    }
+                }
+            }
+            CommandAction::Export(vars)
+        }
+        "alias" => match &args[1..] {
+            [] => CommandAction::Aliases,
+            [definition] => match definition.split_once('=') {
+                Some((name, expansion))
+                    if !name.is_empty() && !name.contains(char::is_whitespace) =>
+                {
+                    CommandAction::Alias {
+                        name: name.to_string(),
+                        expansion: expansion.to_string(),
+                    }
+                }
+                _ => CommandAction::Error(format!(
+                    "alias: invalid argument '{}' (expected NAME=VALUE)",
+                    definition
+                )),
+            },
+            _ => CommandAction::Error(
+                "alias: too many arguments (quote the value: alias NAME=\"VALUE\")".to_string(),
+            ),
+        },
+        "macro" => parse_macro_args(&args[1..]),
+        "unalias" if args.len() > 1 => CommandAction::Unalias(args[1..].to_vec()),
+        "unalias" => CommandAction::Error("unalias: missing alias name".to_string()),
+        "switch-context" | "ctx" => {
+            args.remove(0);
+            if args.is_empty() {
+                return CommandAction::SwitchContext(None);
+            }
+            let program = args.remove(0);
+            CommandAction::SwitchContext(Some(TargetContext {
+                program,
+                args,
+                ..Default::default()
+            }))
+        }
+        "jobs" => CommandAction::Jobs,
+        "disown" => parse_job_id("disown", args.get(1)).map_or_else(|e| e, CommandAction::Disown),
+        "fg" => parse_job_id("fg", args.get(1)).map_or_else(|e| e, CommandAction::Fg),
+        "bg" => parse_job_id("bg", args.get(1)).map_or_else(|e| e, CommandAction::Bg),
+        // クォートした "in" はコマンド名として扱う
+        "in" if line.starts_with("in") => parse_in_command(&line[2..], context, options),
+        "history" if args.get(1).map(|s| s.as_str()) == Some("stats") => {
+            parse_stats_args(&args[2..])
+        }
+        "history" if matches!(args.get(1).map(|s| s.as_str()), Some("grep" | "re")) => {
+            let regex = args[1] == "re";
+            let mut ignore_case = false;
+            let mut pattern = None;
+            for arg in &args[2..] {
+                match arg.as_str() {
+                    "-i" if pattern.is_none() => ignore_case = true,
+                    _ if pattern.is_none() => pattern = Some(arg.clone()),
+                    _ => {
+                        return CommandAction::Error(format!(
+                            "history {}: too many arguments",
+                            args[1]
+                        ));
+                    }
+                }
+            }
+            match pattern {
+                Some(pattern) => CommandAction::HistorySearch {
+                    pattern,
+                    regex,
+                    ignore_case,
+                },
+                None => CommandAction::Error(format!("history {}: missing pattern", args[1])),
+            }
+        }
+        "history" if args.get(1).map(|s| s.as_str()) == Some("clear") => {
+            CommandAction::HistoryClear
+        }
+        "history" if matches!(args.get(1).map(|s| s.as_str()), Some("pin" | "unpin")) => {
+            // unpin はピンの番号なので `!p<n>` と同じ `p<n>` でも指定できる (pin は履歴の ID のみ)
+            let number = args.get(2).map(|arg| match arg.strip_prefix('p') {
+                Some(rest) if args[1] == "unpin" => rest.parse(),
+                _ => arg.parse(),
+            });
+            let number = match number {
+                Some(Ok(number)) if args.len() == 3 => number,
+                Some(Ok(_)) => {
+                    return CommandAction::Error(format!(
+                        "history {}: too many arguments",
+                        args[1]
+                    ));
+                }
+                Some(Err(_)) => {
+                    return CommandAction::Error(format!(
+                        "history {}: invalid number '{}'",
+                        args[1], args[2]
+                    ));
+                }
+                None => {
+                    return CommandAction::Error(format!("history {}: missing number", args[1]));
+                }
+            };
+            if args[1] == "pin" {
+                CommandAction::HistoryPin(number)
+            } else {
+                CommandAction::HistoryUnpin(number)
+            }
+        }
+        "history" if args.get(1).map(|s| s.as_str()) == Some("pinned") => {
+            CommandAction::HistoryPinned
+        }
         "history" => CommandAction::History,
+        "stats" => parse_stats_args(&args[1..]),
+        "cheatsheet" => parse_cheatsheet_args("cheatsheet", &args[1..]),
+        // git config などツール側に同名のサブコマンドがあるコンテキストではツールに渡す
+        "config" if !context.is_some_and(|ctx| has_config_subcommand(&ctx.program)) => {
+            parse_config_args(&args[1..])
+        }
         "help" => CommandAction::Help,
 
         // --- 脱出コマンド (!cmd) ---
@@ -105,13 +1281,7 @@ pub fn parse_cmd(line: &str, context: Option<&TargetContext>) -> CommandAction {
         // --- 通常実行 ---
         _ => {
             if let Some(ctx) = context {
-                let mut final_args = ctx.args.clone();
-                final_args.append(&mut args);
-
-                CommandAction::Execute {
-                    program: ctx.program.clone(),
-                    args: final_args,
-                }
+                apply_context(ctx, args, options)
             } else {
                 let program = args.remove(0);
                 CommandAction::Execute { program, args }
@@ -131,9 +1301,17 @@ mod tests {
         Some(TargetContext {
             program: program.to_string(),
             args: args.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
         })
     }
 
+    fn execute(program: &str, args: &[&str]) -> CommandAction {
+        CommandAction::Execute {
+            program: program.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
     fn assert_execute(action: CommandAction, expected_prog: &str, expected_args: &[&str]) {
         match action {
             CommandAction::Execute { program, args } => {
@@ -249,6 +1427,147 @@ mod tests {
         }
     }
 
+    // --- 内部コマンド (export) テスト ---
+
+    #[test]
+    fn test_export_single() {
+        let action = parse_cmd("export RUST_LOG=debug", None);
+        assert_eq!(
+            action,
+            CommandAction::Export(vec![("RUST_LOG".to_string(), "debug".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_export_multiple_and_quoted() {
+        let ctx = create_ctx("git", &[]);
+        let action = parse_cmd("export A=1 B=\"x y\"", ctx.as_ref());
+        assert_eq!(
+            action,
+            CommandAction::Export(vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "x y".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_export_invalid() {
+        let action = parse_cmd("export NOVALUE", None);
+        assert!(matches!(action, CommandAction::Error(_)));
+    }
+
+    // --- 内部コマンド (alias) テスト ---
+
+    #[test]
+    fn test_alias_definition() {
+        let ctx = create_ctx("git", &[]);
+        assert_eq!(
+            parse_cmd("alias gs=\"git status\"", ctx.as_ref()),
+            CommandAction::Alias {
+                name: "gs".to_string(),
+                expansion: "git status".to_string(),
+            }
+        );
+        assert_eq!(parse_cmd("alias", None), CommandAction::Aliases);
+        assert_eq!(
+            parse_cmd("unalias gs ll", None),
+            CommandAction::Unalias(vec!["gs".to_string(), "ll".to_string()])
+        );
+        // クォートし忘れた値や名前のない定義はエラー
+        assert!(matches!(
+            parse_cmd("alias gs=git status", None),
+            CommandAction::Error(_)
+        ));
+        assert!(matches!(
+            parse_cmd("alias =x", None),
+            CommandAction::Error(_)
+        ));
+        assert!(matches!(
+            parse_cmd("unalias", None),
+            CommandAction::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_expand_alias() {
+        let aliases = BTreeMap::from([
+            ("gs".to_string(), "git status".to_string()),
+            ("g".to_string(), "gs -s".to_string()),
+        ]);
+        assert_eq!(expand_alias("gs --short", &aliases), "git status --short");
+        // 展開後の先頭がエイリアスならさらに展開する
+        assert_eq!(expand_alias("g", &aliases), "git status -s");
+        // 先頭以外の単語は展開しない
+        assert_eq!(expand_alias("echo gs", &aliases), "echo gs");
+    }
+
+    #[test]
+    fn test_expand_alias_self_reference() {
+        let aliases = BTreeMap::from([
+            ("a".to_string(), "a -x".to_string()),
+            ("b".to_string(), "c".to_string()),
+            ("c".to_string(), "b".to_string()),
+        ]);
+        assert_eq!(expand_alias("a", &aliases), "a -x");
+        // 循環していても止まる
+        assert_eq!(expand_alias("b 1", &aliases), "b 1");
+    }
+
+    // --- バックグラウンドジョブのテスト ---
+
+    #[test]
+    fn test_background_command() {
+        let action = parse_cmd("sleep 10 &", None);
+        assert_eq!(
+            action,
+            CommandAction::Background {
+                program: "sleep".to_string(),
+                args: vec!["10".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_background_with_context() {
+        let ctx = create_ctx("cargo", &[]);
+        let action = parse_cmd("build&", ctx.as_ref());
+        assert_eq!(
+            action,
+            CommandAction::Background {
+                program: "cargo".to_string(),
+                args: vec!["build".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_background_builtin_rejected() {
+        let action = parse_cmd("cd src &", None);
+        assert!(matches!(action, CommandAction::Error(_)));
+    }
+
+    #[test]
+    fn test_quoted_ampersand_is_not_background() {
+        let action = parse_cmd("echo \"a &\"", None);
+        assert_execute(action, "echo", &["a &"]);
+    }
+
+    #[test]
+    fn test_jobs_and_disown() {
+        assert_eq!(parse_cmd("jobs", None), CommandAction::Jobs);
+        assert_eq!(parse_cmd("disown", None), CommandAction::Disown(None));
+        assert_eq!(parse_cmd("disown %2", None), CommandAction::Disown(Some(2)));
+        assert_eq!(parse_cmd("fg", None), CommandAction::Fg(None));
+        assert_eq!(parse_cmd("fg %1", None), CommandAction::Fg(Some(1)));
+        assert_eq!(parse_cmd("bg 3", None), CommandAction::Bg(Some(3)));
+        assert!(matches!(parse_cmd("bg %x", None), CommandAction::Error(_)));
+        assert!(matches!(
+            parse_cmd("disown x", None),
+            CommandAction::Error(_)
+        ));
+    }
+
     // --- 終了コマンド (Exit / Quit) テスト [Updated] ---
 
     #[test]
@@ -329,6 +1648,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_cd_with_too_many_args_strict() {
+        let options = ParseOptions {
+            strict_cd: true,
+            ..Default::default()
+        };
+        let action = parse_cmd_with("cd dir1 dir2", None, &options);
+        assert_eq!(
+            action,
+            CommandAction::Error("cd: too many arguments".to_string())
+        );
+
+        // 引数が1つなら厳格モードでも通常通り
+        let action = parse_cmd_with("cd dir1", None, &options);
+        assert_eq!(
+            action,
+            CommandAction::ChangeDirectory(Some("dir1".to_string()))
+        );
+    }
+
     #[test]
     fn test_single_quote_handling() {
         let action = parse_cmd("echo 'foo bar'", None);
@@ -372,6 +1711,91 @@ mod tests {
 
     // --- HISTORY / PWD コマンドのテスト ---
 
+    // --- サブコマンドテンプレートのテスト ---
+
+    #[test]
+    fn test_expand_template() {
+        let args = |xs: &[&str]| xs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            expand_template("cm", "commit -m {}", &args(&["fix bug"])),
+            Ok(args(&["commit", "-m", "fix bug"]))
+        );
+        // トークンの途中の {} と余った引数
+        assert_eq!(
+            expand_template("rb", "rebase -i HEAD~{}", &args(&["3", "--autosquash"])),
+            Ok(args(&["rebase", "-i", "HEAD~3", "--autosquash"]))
+        );
+        assert!(expand_template("cm", "commit -m {}", &[]).is_err());
+    }
+
+    #[test]
+    fn test_template_in_context() {
+        let ctx = create_ctx("git", &[]);
+        let options = ParseOptions {
+            templates: BTreeMap::from([("cm".to_string(), "commit -m {}".to_string())]),
+            ..Default::default()
+        };
+        assert_execute(
+            parse_cmd_with("cm \"first commit\"", ctx.as_ref(), &options),
+            "git",
+            &["commit", "-m", "first commit"],
+        );
+        // 未定義のトークンは通常のサブコマンド、コンテキスト外では展開しない
+        assert_execute(
+            parse_cmd_with("status", ctx.as_ref(), &options),
+            "git",
+            &["status"],
+        );
+        assert_execute(parse_cmd_with("cm x", None, &options), "cm", &["x"]);
+        assert!(matches!(
+            parse_cmd_with("cm", ctx.as_ref(), &options),
+            CommandAction::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_cmd_history_clear() {
+        assert_eq!(
+            parse_cmd("history clear", None),
+            CommandAction::HistoryClear
+        );
+    }
+
+    #[test]
+    fn test_cmd_history_pin() {
+        assert_eq!(
+            parse_cmd("history pin 3", None),
+            CommandAction::HistoryPin(3)
+        );
+        assert_eq!(
+            parse_cmd("history unpin p2", None),
+            CommandAction::HistoryUnpin(2)
+        );
+        assert_eq!(
+            parse_cmd("history pinned", None),
+            CommandAction::HistoryPinned
+        );
+        assert_eq!(
+            parse_cmd("history unpin 2", None),
+            CommandAction::HistoryUnpin(2)
+        );
+        // pin は履歴の ID なので p<n> の形は受け付けない。unpin でも p は1つだけ
+        for line in [
+            "history pin",
+            "history pin x",
+            "history unpin 1 2",
+            "history pin p3",
+            "history pin pp3",
+            "history unpin pp2",
+        ] {
+            assert!(
+                matches!(parse_cmd(line, None), CommandAction::Error(_)),
+                "{}",
+                line
+            );
+        }
+    }
+
     #[test]
     fn test_cmd_history_basic() {
         let action = parse_cmd("history", None);
@@ -404,4 +1828,888 @@ mod tests {
         let action = parse_cmd("add src\\main.rs", ctx.as_ref());
         assert_execute(action, "git", &["add", "srcmain.rs"]);
     }
+
+    // --- tee のテスト ---
+
+    #[test]
+    fn test_tee_basic() {
+        let ctx = create_ctx("git", &[]);
+        let action = parse_cmd("log --oneline | tee log.txt", ctx.as_ref());
+        assert_eq!(
+            action,
+            CommandAction::Tee {
+                program: "git".to_string(),
+                args: vec!["log".to_string(), "--oneline".to_string()],
+                files: vec!["log.txt".to_string()],
+                append: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_tee_append_and_multiple_files() {
+        let action = parse_cmd("ls | tee -a a.txt b.txt", None);
+        assert_eq!(
+            action,
+            CommandAction::Tee {
+                program: "ls".to_string(),
+                args: vec![],
+                files: vec!["a.txt".to_string(), "b.txt".to_string()],
+                append: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_tee_quoted_pipe_is_not_split() {
+        // クォート内の "|" や "||" はパイプとして扱わない
+        assert_execute(parse_cmd("grep 'a | tee b'", None), "grep", &["a | tee b"]);
+        // "||" は tee へのパイプではなく、失敗時に実行する段の区切り
+        assert_eq!(
+            parse_cmd("a || tee b", None),
+            CommandAction::Sequence(vec![
+                (ChainOp::Always, execute("a", &[])),
+                (ChainOp::Or, execute("tee", &["b"])),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_tee_after_pipeline() {
+        let ctx = create_ctx("git", &[]);
+        assert_eq!(
+            parse_cmd("log | grep fix | tee -a out.log", ctx.as_ref()),
+            CommandAction::Pipeline {
+                stages: vec![
+                    ("git".to_string(), vec!["log".to_string()]),
+                    ("grep".to_string(), vec!["fix".to_string()]),
+                ],
+                redirects: Redirects::default(),
+                tee: Some(TeeOutput {
+                    files: vec!["out.log".to_string()],
+                    append: true,
+                }),
+            }
+        );
+        // 最後の段の出力をファイルへつなぎ替えていたら書き写すものが無い
+        assert!(matches!(
+            parse_cmd("ls | sort > a.txt | tee b.txt", None),
+            CommandAction::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_tee_invalid() {
+        assert!(matches!(
+            parse_cmd("ls | tee -x out", None),
+            CommandAction::Error(_)
+        ));
+        assert!(matches!(
+            parse_cmd("cd src | tee out", None),
+            CommandAction::Error(_)
+        ));
+    }
+
+    // --- リダイレクトのテスト ---
+
+    fn redirect(program: &str, args: &[&str], path: &str, append: bool) -> CommandAction {
+        CommandAction::Redirect {
+            program: program.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            redirects: Redirects {
+                output: Some(OutputRedirect {
+                    path: path.to_string(),
+                    append,
+                }),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_redirect_truncate_and_append() {
+        let ctx = create_ctx("cargo", &[]);
+        assert_eq!(
+            parse_cmd("test > out.txt", ctx.as_ref()),
+            redirect("cargo", &["test"], "out.txt", false)
+        );
+        assert_eq!(
+            parse_cmd("test >>out.txt", ctx.as_ref()),
+            redirect("cargo", &["test"], "out.txt", true)
+        );
+    }
+
+    #[test]
+    fn test_redirect_quoted_target_and_trailing_args() {
+        assert_eq!(
+            parse_cmd(r#"ls > "my out.txt" -a"#, None),
+            redirect("ls", &["-a"], "my out.txt", false)
+        );
+        // クォート内の ">" はリダイレクトではない
+        assert_execute(parse_cmd("echo 'a > b'", None), "echo", &["a > b"]);
+    }
+
+    #[test]
+    fn test_redirect_input() {
+        let ctx = create_ctx("cargo", &[]);
+        assert_eq!(
+            parse_cmd("run < input.txt", ctx.as_ref()),
+            CommandAction::Redirect {
+                program: "cargo".to_string(),
+                args: vec!["run".to_string()],
+                redirects: Redirects {
+                    input: Some("input.txt".to_string()),
+                    output: None,
+                },
+            }
+        );
+        assert_eq!(
+            parse_cmd("sort -r < in.txt >> out.txt", None),
+            CommandAction::Redirect {
+                program: "sort".to_string(),
+                args: vec!["-r".to_string()],
+                redirects: Redirects {
+                    input: Some("in.txt".to_string()),
+                    output: Some(OutputRedirect {
+                        path: "out.txt".to_string(),
+                        append: true,
+                    }),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_redirect_invalid() {
+        for line in [
+            "ls >",
+            "ls > a > b",
+            "cd src > out",
+            "make 2> err.txt",
+            "cat <",
+            "cat < a < b",
+        ] {
+            assert!(
+                matches!(parse_cmd(line, None), CommandAction::Error(_)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    // --- パイプラインのテスト ---
+
+    fn stage(program: &str, args: &[&str]) -> (String, Vec<String>) {
+        (
+            program.to_string(),
+            args.iter().map(|s| s.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn test_pipeline_two_stages_context_first_only() {
+        let ctx = create_ctx("git", &[]);
+        assert_eq!(
+            parse_cmd("log --oneline | head -n 5", ctx.as_ref()),
+            CommandAction::Pipeline {
+                stages: vec![
+                    stage("git", &["log", "--oneline"]),
+                    stage("head", &["-n", "5"]),
+                ],
+                redirects: Redirects::default(),
+                tee: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_pipeline_three_stages() {
+        assert_eq!(
+            parse_cmd("cat a.txt | grep 'x|y' | wc -l", None),
+            CommandAction::Pipeline {
+                stages: vec![
+                    stage("cat", &["a.txt"]),
+                    stage("grep", &["x|y"]),
+                    stage("wc", &["-l"]),
+                ],
+                redirects: Redirects::default(),
+                tee: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_pipeline_with_output_redirect() {
+        for (line, append) in [
+            ("echo x | head -1 > o.txt", false),
+            ("echo x | head -1 >> o.txt", true),
+        ] {
+            assert_eq!(
+                parse_cmd(line, None),
+                CommandAction::Pipeline {
+                    stages: vec![stage("echo", &["x"]), stage("head", &["-1"])],
+                    redirects: Redirects {
+                        input: None,
+                        output: Some(OutputRedirect {
+                            path: "o.txt".to_string(),
+                            append,
+                        }),
+                    },
+                    tee: None,
+                }
+            );
+        }
+        // 途中の段の出力はつなぎ替えられない
+        assert!(matches!(
+            parse_cmd("echo x > o.txt | head -1", None),
+            CommandAction::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_pipeline_with_input_redirect() {
+        // `<` とファイル名が後段の引数に混ざらない
+        assert_eq!(
+            parse_cmd("cat < in.txt | tr a-z A-Z", None),
+            CommandAction::Pipeline {
+                stages: vec![stage("cat", &[]), stage("tr", &["a-z", "A-Z"])],
+                redirects: Redirects {
+                    input: Some("in.txt".to_string()),
+                    output: None,
+                },
+                tee: None,
+            }
+        );
+        assert_eq!(
+            parse_cmd("cat < in.txt | sort > out.txt", None),
+            CommandAction::Pipeline {
+                stages: vec![stage("cat", &[]), stage("sort", &[])],
+                redirects: Redirects {
+                    input: Some("in.txt".to_string()),
+                    output: Some(OutputRedirect {
+                        path: "out.txt".to_string(),
+                        append: false,
+                    }),
+                },
+                tee: None,
+            }
+        );
+        // 後段は前段の出力を読むので、ファイルからは読めない
+        assert!(matches!(
+            parse_cmd("cat a.txt | tr a-z A-Z < in.txt", None),
+            CommandAction::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_pipeline_quoted_pipe_is_literal() {
+        assert_execute(parse_cmd(r#"echo "a|b""#, None), "echo", &["a|b"]);
+    }
+
+    #[test]
+    fn test_pipeline_invalid() {
+        for line in ["ls |", "| wc", "ls | cd src"] {
+            assert!(
+                matches!(parse_cmd(line, None), CommandAction::Error(_)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    // --- ワイルドカードの展開のテスト ---
+
+    fn create_glob_fixture() -> tempfile::TempDir {
+        let tmp = tempfile::tempdir().unwrap();
+        for name in ["main.rs", "lib.rs", "README.md", ".hidden.rs"] {
+            std::fs::write(tmp.path().join(name), "").unwrap();
+        }
+        tmp
+    }
+
+    #[test]
+    fn test_glob_expands_matches() {
+        let tmp = create_glob_fixture();
+        let dir = tmp.path().display().to_string();
+        let ctx = create_ctx("git", &[]);
+        let lib = format!("{}/lib.rs", dir);
+        let main = format!("{}/main.rs", dir);
+        // `.` で始まるファイルは含めない
+        assert_execute(
+            parse_cmd(&format!("add {}/*.rs", dir), ctx.as_ref()),
+            "git",
+            &["add", &lib, &main],
+        );
+        assert_execute(
+            parse_cmd(&format!("ls {}/[lm]?i*.rs", dir), None),
+            "ls",
+            &[&main],
+        );
+    }
+
+    #[test]
+    fn test_glob_no_match_keeps_literal() {
+        let tmp = create_glob_fixture();
+        let pattern = format!("{}/*.toml", tmp.path().display());
+        assert_execute(
+            parse_cmd(&format!("ls {}", pattern), None),
+            "ls",
+            &[&pattern],
+        );
+        // グロブ文字を含まない "]" もそのまま
+        assert_execute(parse_cmd("echo a]", None), "echo", &["a]"]);
+    }
+
+    #[test]
+    fn test_glob_quoted_is_literal() {
+        let tmp = create_glob_fixture();
+        let pattern = format!("{}/*.rs", tmp.path().display());
+        for line in [
+            format!("ls '{}'", pattern),
+            format!("ls \"{}\"", pattern),
+            format!("ls {}", pattern.replace('*', "\\*")),
+        ] {
+            assert_execute(parse_cmd(&line, None), "ls", &[&pattern]);
+        }
+    }
+
+    // --- 環境変数の展開のテスト ---
+
+    #[test]
+    fn test_expand_tilde() {
+        let home = Some("/home/user");
+        assert_eq!(expand_with("cd ~", lookup, home), "cd /home/user");
+        assert_eq!(
+            expand_with("cat ~/.gitconfig ~/a", lookup, home),
+            "cat /home/user/.gitconfig /home/user/a"
+        );
+        assert_eq!(
+            expand_with("ls|~/bin/x", lookup, home),
+            "ls|/home/user/bin/x"
+        );
+        // ~user・単語の途中・クォート内・エスケープはそのまま
+        for line in ["ls ~root", "ls a~b", "ls '~/x'", "ls \"~\"", "ls \\~/x"] {
+            assert_eq!(expand_with(line, lookup, home), line);
+        }
+        // 空白を含むホームも1つの引数のまま
+        let expanded = expand_with("cd ~/src", lookup, Some("/home/a b"));
+        assert_eq!(
+            shell_words::split(&expanded).unwrap(),
+            ["cd", "/home/a b/src"]
+        );
+    }
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/user".to_string()),
+            "SPACED" => Some("a b".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_env_defined() {
+        assert_eq!(expand_with("cd $HOME", lookup, None), "cd /home/user");
+        assert_eq!(
+            expand_with("cat $HOME/.withrc", lookup, None),
+            "cat /home/user/.withrc"
+        );
+    }
+
+    #[test]
+    fn test_expand_env_braces() {
+        assert_eq!(
+            expand_with("ls ${HOME}_backup", lookup, None),
+            "ls /home/user_backup"
+        );
+        // 閉じていない・変数名でないものはそのまま
+        assert_eq!(expand_with("echo ${HOME", lookup, None), "echo ${HOME");
+        assert_eq!(expand_with("echo $1 $ $-", lookup, None), "echo $1 $ $-");
+    }
+
+    #[test]
+    fn test_expand_env_undefined() {
+        assert_eq!(expand_with("echo $NOPE", lookup, None), "echo ");
+        assert_eq!(expand_with("echo \"[$NOPE]\"", lookup, None), "echo \"[]\"");
+    }
+
+    #[test]
+    fn test_expand_env_quotes() {
+        // シングルクォート内とエスケープは展開しない
+        assert_eq!(expand_with("echo '$HOME'", lookup, None), "echo '$HOME'");
+        assert_eq!(expand_with("echo \\$HOME", lookup, None), "echo \\$HOME");
+        // ダブルクォート内は展開する
+        assert_eq!(
+            expand_with("echo \"$HOME/x\"", lookup, None),
+            "echo \"/home/user/x\""
+        );
+        // 空白を含む値も1つの引数のまま
+        let expanded = expand_with("echo $SPACED \"$SPACED\"", lookup, None);
+        assert_eq!(
+            shell_words::split(&expanded).unwrap(),
+            ["echo", "a b", "a b"]
+        );
+    }
+
+    #[test]
+    fn test_parse_cmd_expands_env() {
+        let key = "WITH_TEST_PARSER_EXPAND";
+        let options = ParseOptions {
+            exported: BTreeMap::from([(key.to_string(), "src dir".to_string())]),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_cmd_with(&format!("cd ${}", key), None, &options),
+            CommandAction::ChangeDirectory(Some("src dir".to_string()))
+        );
+        assert_execute(
+            parse_cmd_with(&format!("echo '${}' ${{{}}}", key, key), None, &options),
+            "echo",
+            &["$WITH_TEST_PARSER_EXPAND", "src dir"],
+        );
+        // パイプの2段目以降でも展開する
+        assert_eq!(
+            parse_cmd_with(&format!("ls | grep ${}", key), None, &options),
+            CommandAction::Pipeline {
+                stages: vec![
+                    ("ls".to_string(), vec![]),
+                    ("grep".to_string(), vec!["src dir".to_string()]),
+                ],
+                redirects: Redirects::default(),
+                tee: None,
+            }
+        );
+    }
+
+    // --- cheatsheet のテスト ---
+
+    #[test]
+    fn test_cheatsheet_commands() {
+        assert_eq!(
+            parse_cmd("cheatsheet", None),
+            CommandAction::Cheatsheet { output: None }
+        );
+        assert_eq!(
+            parse_cmd("cheatsheet CHEATSHEET.md", None),
+            CommandAction::Cheatsheet {
+                output: Some("CHEATSHEET.md".to_string())
+            }
+        );
+        assert_eq!(
+            parse_cmd("stats --export md out.md", None),
+            CommandAction::Cheatsheet {
+                output: Some("out.md".to_string())
+            }
+        );
+        for line in ["cheatsheet a b", "stats --export csv", "stats --export"] {
+            assert!(
+                matches!(parse_cmd(line, None), CommandAction::Error(_)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    // --- config のテスト ---
+
+    #[test]
+    fn test_config_commands() {
+        let config = |cmd| CommandAction::Config(cmd);
+        assert_eq!(parse_cmd("config", None), config(ConfigCommand::List));
+        assert_eq!(parse_cmd("config list", None), config(ConfigCommand::List));
+        assert_eq!(
+            parse_cmd("config get prompt.symbol", None),
+            config(ConfigCommand::Get("prompt.symbol".to_string()))
+        );
+        assert_eq!(
+            parse_cmd(r#"config set prompt.symbol "$ ""#, None),
+            config(ConfigCommand::Set(
+                "prompt.symbol".to_string(),
+                "$ ".to_string()
+            ))
+        );
+        assert_eq!(parse_cmd("config save", None), config(ConfigCommand::Save));
+        assert_eq!(parse_cmd("config edit", None), config(ConfigCommand::Edit));
+
+        // config サブコマンドを持たないツールのコンテキストでも内部コマンドになる
+        let ctx = create_ctx("cargo", &[]);
+        assert_eq!(
+            parse_cmd("config", ctx.as_ref()),
+            config(ConfigCommand::List)
+        );
+    }
+
+    #[test]
+    fn test_config_invalid() {
+        for line in [
+            "config get",
+            "config set key",
+            "config save now",
+            "config reset",
+        ] {
+            assert!(
+                matches!(parse_cmd(line, None), CommandAction::Error(_)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    // --- macro のテスト ---
+
+    #[test]
+    fn test_macro_commands() {
+        let ctx = create_ctx("git", &[]);
+        assert_eq!(
+            parse_cmd(
+                r#"macro define deploy "git pull" "cargo build""#,
+                ctx.as_ref()
+            ),
+            CommandAction::Macro(MacroCommand::Define {
+                name: "deploy".to_string(),
+                commands: vec!["git pull".to_string(), "cargo build".to_string()],
+                keep_going: false,
+            })
+        );
+        assert_eq!(
+            parse_cmd("macro define --keep-going check 'cargo fmt'", None),
+            CommandAction::Macro(MacroCommand::Define {
+                name: "check".to_string(),
+                commands: vec!["cargo fmt".to_string()],
+                keep_going: true,
+            })
+        );
+        assert_eq!(
+            parse_cmd("macro run deploy", None),
+            CommandAction::Macro(MacroCommand::Run("deploy".to_string()))
+        );
+        assert_eq!(
+            parse_cmd("macro", None),
+            CommandAction::Macro(MacroCommand::List)
+        );
+    }
+
+    #[test]
+    fn test_macro_invalid() {
+        for line in [
+            "macro define deploy",
+            "macro define --keep-going",
+            "macro define cd 'git pull'",
+            "macro show",
+            "macro start deploy",
+        ] {
+            assert!(
+                matches!(parse_cmd(line, None), CommandAction::Error(_)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    // --- ; のテスト ---
+
+    #[test]
+    fn test_list_mixes_builtin_and_command() {
+        let ctx = create_ctx("git", &[]);
+        assert_eq!(
+            parse_cmd("cd src; status -s", ctx.as_ref()),
+            CommandAction::List(vec![
+                CommandAction::ChangeDirectory(Some("src".to_string())),
+                CommandAction::Execute {
+                    program: "git".to_string(),
+                    args: vec!["status".to_string(), "-s".to_string()],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_list_trailing_semicolon() {
+        // 末尾の ";" は空のコマンドにならない
+        assert_execute(parse_cmd("ls -a;", None), "ls", &["-a"]);
+        assert_execute(parse_cmd("ls ; ", None), "ls", &[]);
+    }
+
+    #[test]
+    fn test_list_quoted_semicolon_is_literal() {
+        assert_execute(
+            parse_cmd(r"find . -exec rm {} \; -print", None),
+            "find",
+            &[".", "-exec", "rm", "{}", ";", "-print"],
+        );
+        assert_execute(parse_cmd("echo 'a; b'", None), "echo", &["a; b"]);
+    }
+
+    #[test]
+    fn test_list_invalid() {
+        for line in [";", "; ls", "ls;; pwd", "ls; 'unclosed"] {
+            assert!(
+                matches!(parse_cmd(line, None), CommandAction::Error(_)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    // --- && / || のテスト ---
+
+    #[test]
+    fn test_sequence_applies_context_to_each_stage() {
+        let ctx = create_ctx("cargo", &[]);
+        assert_eq!(
+            parse_cmd("build && test || clean", ctx.as_ref()),
+            CommandAction::Sequence(vec![
+                (ChainOp::Always, execute("cargo", &["build"])),
+                (ChainOp::And, execute("cargo", &["test"])),
+                (ChainOp::Or, execute("cargo", &["clean"])),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_sequence_mixes_builtins() {
+        // 内部コマンドは段ごとに判別し、コンテキストは外部コマンドの段だけに適用する
+        let ctx = create_ctx("cargo", &[]);
+        assert_eq!(
+            parse_cmd("cd src && build || export FAILED=1", ctx.as_ref()),
+            CommandAction::Sequence(vec![
+                (
+                    ChainOp::Always,
+                    CommandAction::ChangeDirectory(Some("src".to_string()))
+                ),
+                (ChainOp::And, execute("cargo", &["build"])),
+                (
+                    ChainOp::Or,
+                    CommandAction::Export(vec![("FAILED".to_string(), "1".to_string())])
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_sequence_quoted_operators_are_literal() {
+        assert_execute(
+            parse_cmd("echo 'a && b' \"c||d\"", None),
+            "echo",
+            &["a && b", "c||d"],
+        );
+    }
+
+    #[test]
+    fn test_sequence_invalid() {
+        for line in ["ls &&", "|| ls", "ls && && pwd", "ls && sleep 1 &"] {
+            assert!(
+                matches!(parse_cmd(line, None), CommandAction::Error(_)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_chain_op_should_run() {
+        assert!(ChainOp::Always.should_run(Some(1)));
+        assert!(ChainOp::And.should_run(Some(0)));
+        assert!(!ChainOp::And.should_run(Some(1)));
+        assert!(!ChainOp::And.should_run(None));
+        assert!(ChainOp::Or.should_run(Some(2)));
+        assert!(!ChainOp::Or.should_run(Some(0)));
+    }
+
+    // --- in <dir> <cmd> のテスト ---
+
+    #[test]
+    fn test_parse_in_directory() {
+        let in_dir = |dir: &str, program: &str, args: &[&str]| CommandAction::InDirectory {
+            dir: dir.to_string(),
+            program: program.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+        };
+        assert_eq!(
+            parse_cmd("in ../other cargo build --release", None),
+            in_dir("../other", "cargo", &["build", "--release"])
+        );
+        // クォートしたディレクトリ名と、コマンド部分のクォート
+        assert_eq!(
+            parse_cmd("in 'my dir' echo \"a b\"", None),
+            in_dir("my dir", "echo", &["a b"])
+        );
+        // コンテキストはコマンド部分に適用する (`!` なら適用しない)
+        let ctx = create_ctx("git", &[]);
+        assert_eq!(
+            parse_cmd("in sub status", ctx.as_ref()),
+            in_dir("sub", "git", &["status"])
+        );
+        assert_eq!(
+            parse_cmd("in sub !ls -a", ctx.as_ref()),
+            in_dir("sub", "ls", &["-a"])
+        );
+
+        assert_eq!(
+            parse_cmd("in", None),
+            CommandAction::Error("in: missing directory".to_string())
+        );
+        assert_eq!(
+            parse_cmd("in ../other", None),
+            CommandAction::Error("in: missing command".to_string())
+        );
+        assert_eq!(
+            parse_cmd("in ../other cd src", None),
+            CommandAction::Error("in: only commands can run in another directory".to_string())
+        );
+    }
+
+    // --- 内部コマンドとサブコマンドの衝突のテスト ---
+
+    #[test]
+    fn test_builtin_wins_by_default() {
+        let ctx = create_ctx("docker", &[]);
+        assert_eq!(parse_cmd("history", ctx.as_ref()), CommandAction::History);
+    }
+
+    #[test]
+    fn test_context_subcommand_preferred() {
+        let ctx = create_ctx("docker", &[]);
+        let options = ParseOptions {
+            context_subcommands: vec!["history".to_string(), "export".to_string()],
+            ..Default::default()
+        };
+        assert_execute(
+            parse_cmd_with("history nginx", ctx.as_ref(), &options),
+            "docker",
+            &["history", "nginx"],
+        );
+        assert_execute(
+            parse_cmd_with("export app", ctx.as_ref(), &options),
+            "docker",
+            &["export", "app"],
+        );
+
+        // コンテキストが無ければ内部コマンドのまま
+        assert_eq!(
+            parse_cmd_with("history", None, &options),
+            CommandAction::History
+        );
+    }
+
+    #[test]
+    fn test_non_builtin_subcommands_go_to_context() {
+        // config / init は内部コマンドではないので常にコンテキストへ渡る
+        let ctx = create_ctx("git", &[]);
+        assert_execute(
+            parse_cmd("config --list", ctx.as_ref()),
+            "git",
+            &["config", "--list"],
+        );
+        assert_execute(parse_cmd("init", ctx.as_ref()), "git", &["init"]);
+    }
+
+    // --- stats のテスト ---
+
+    #[test]
+    fn test_stats_defaults_and_args() {
+        assert_eq!(
+            parse_cmd("stats", None),
+            CommandAction::Stats {
+                unit: StatsUnit::First,
+                limit: DEFAULT_STATS_LIMIT,
+            }
+        );
+        assert_eq!(
+            parse_cmd("history stats full 3", None),
+            CommandAction::Stats {
+                unit: StatsUnit::Full,
+                limit: 3,
+            }
+        );
+        assert_eq!(
+            parse_cmd("stats 5 sub", None),
+            CommandAction::Stats {
+                unit: StatsUnit::Sub,
+                limit: 5,
+            }
+        );
+        assert!(matches!(
+            parse_cmd("stats weekly", None),
+            CommandAction::Error(_)
+        ));
+    }
+
+    // --- history 検索のテスト ---
+
+    #[test]
+    fn test_history_search() {
+        assert_eq!(
+            parse_cmd("history re -i '^git (commit|push)'", None),
+            CommandAction::HistorySearch {
+                pattern: "^git (commit|push)".to_string(),
+                regex: true,
+                ignore_case: true,
+            }
+        );
+        assert_eq!(
+            parse_cmd("history grep status", None),
+            CommandAction::HistorySearch {
+                pattern: "status".to_string(),
+                regex: false,
+                ignore_case: false,
+            }
+        );
+        assert!(matches!(
+            parse_cmd("history re", None),
+            CommandAction::Error(_)
+        ));
+        assert!(matches!(
+            parse_cmd("history grep a b", None),
+            CommandAction::Error(_)
+        ));
+    }
+
+    // --- 結合順序のテスト ---
+
+    #[test]
+    fn test_context_args_first_by_default() {
+        let ctx = create_ctx("git", &["log"]);
+        assert_execute(
+            parse_cmd("-C /path", ctx.as_ref()),
+            "git",
+            &["log", "-C", "/path"],
+        );
+    }
+
+    #[test]
+    fn test_input_first() {
+        let ctx = create_ctx("git", &["log", "--oneline"]);
+        let options = ParseOptions {
+            input_first: true,
+            ..Default::default()
+        };
+        assert_execute(
+            parse_cmd_with("-C /path", ctx.as_ref(), &options),
+            "git",
+            &["-C", "/path", "log", "--oneline"],
+        );
+
+        // 入力が空ならコンテキスト引数だけ
+        assert_execute(
+            parse_cmd_with("", ctx.as_ref(), &options),
+            "git",
+            &["log", "--oneline"],
+        );
+    }
+
+    // --- switch-context のテスト ---
+
+    #[test]
+    fn test_switch_context() {
+        let ctx = create_ctx("git", &[]);
+        assert_eq!(
+            parse_cmd("ctx cargo build --release", ctx.as_ref()),
+            CommandAction::SwitchContext(create_ctx("cargo", &["build", "--release"]))
+        );
+        assert_eq!(
+            parse_cmd("switch-context", ctx.as_ref()),
+            CommandAction::SwitchContext(None)
+        );
+    }
 }
@@ -0,0 +1,1043 @@
+use crate::config::{GitConfig, PromptConfig};
+use crate::context::{
+    BranchCache, BranchState, DirDisplay, GitOperation, get_git_ahead_behind, get_git_branch_state,
+    get_git_operation, get_git_upstream, get_kube_context, git_is_dirty, is_network_fs,
+    is_skipped_path, read_cached_branch, resolve_display_dir_with, run_with_timeout,
+};
+use crate::executor::{format_context_stack, parse_context_stack};
+use crate::parser::TargetContext;
+use crate::theme::Theme;
+use crate::with_helper::{STYLE_BOLD, STYLE_RESET};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::BTreeMap,
+    env,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// プロンプトのコンテキスト部分の表示方法
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextDisplay {
+    /// 常に表示する (例: "(.: main) git> ")
+    #[default]
+    Always,
+    /// ディレクトリ情報がある時は省略する (例: "(.: main) > ")
+    Auto,
+    /// アイコンだけを表示する (例: "(.: main) ⚙> ")
+    Icon,
+}
+
+/// 表示用のプロンプト
+/// plain は rustyline に渡す文字列 (幅計算用)、styled は色付け済みの文字列
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prompt {
+    pub plain: String,
+    pub styled: String,
+}
+
+/// プロンプトを構成する要素
+pub struct PromptParts<'a> {
+    pub dir: Option<&'a str>,
+    pub branch: Option<&'a str>,
+    /// ブランチの状態 (ブランチの色分けが無効、または取得できなければ None)
+    pub branch_state: Option<BranchState>,
+    /// コンテキスト部分 (例: "git/cargo build")
+    pub cmd: &'a str,
+    /// kube-context (テンプレートの {kube})
+    pub kube: Option<&'a str>,
+    /// AWS プロファイル (テンプレートの {aws})
+    pub aws: Option<&'a str>,
+    /// ブランチの追跡先 (テンプレートの {upstream})
+    pub upstream: Option<&'a str>,
+    /// 親の with のスタック (テンプレートの {stack}。例: "git/cargo")
+    pub stack: Option<&'a str>,
+    /// 直前のコマンドが失敗したときの表示 (テンプレートの {status}。例: "✗1")
+    pub status: Option<&'a str>,
+}
+
+/// コンテキスト部分を色付けする
+/// 最後のスラッシュで分割し、親スタックは細字、現在のコンテキストは太字にする
+/// 例: "//git" -> path_str="//", current_str="git"
+fn style_cmd(cmd: &str, theme: &Theme) -> String {
+    let (path_str, current_str) = match cmd.rsplit_once('/') {
+        Some((parent, current)) => (format!("{}/", parent), current),
+        None => (String::new(), cmd),
+    };
+    format!(
+        "{}{}{}{}{}",
+        theme.context, path_str, STYLE_BOLD, current_str, STYLE_RESET
+    )
+}
+
+/// 表示方法に従ってコンテキスト部分の文字列を決める純粋関数
+/// コンテキストが無い (空文字の) 場合はどの表示方法でも空のまま
+fn display_cmd<'a>(parts: &PromptParts<'a>, config: &'a PromptConfig) -> &'a str {
+    if parts.cmd.is_empty() {
+        return parts.cmd;
+    }
+    match config.context_display {
+        ContextDisplay::Always => parts.cmd,
+        ContextDisplay::Auto if parts.dir.is_some() || parts.branch.is_some() => "",
+        ContextDisplay::Auto => parts.cmd,
+        ContextDisplay::Icon => &config.context_icon,
+    }
+}
+
+/// テンプレート中の {token} を要素で置き換える
+/// 値の無い要素は空文字に、未知のトークンはそのまま残す
+fn render_template(template: &str, parts: &PromptParts, cmd: &str, theme: &Theme) -> Prompt {
+    let mut plain = String::new();
+    let mut styled = String::from(STYLE_BOLD);
+    let mut rest = template;
+
+    while let Some(open_idx) = rest.find('{') {
+        let literal = &rest[..open_idx];
+        plain.push_str(literal);
+        styled.push_str(literal);
+
+        let after = &rest[open_idx..];
+        let Some(close_idx) = after.find('}') else {
+            break;
+        };
+        let token = &after[1..close_idx];
+
+        let value = match token {
+            "dir" => Some((parts.dir, theme.dir_color(parts.branch.is_some()))),
+            "branch" => Some((parts.branch, theme.branch_color(parts.branch_state))),
+            "kube" => Some((parts.kube, theme.kube)),
+            "aws" => Some((parts.aws, theme.aws)),
+            "upstream" => Some((parts.upstream, theme.branch)),
+            "stack" => Some((parts.stack, theme.context)),
+            "status" => Some((parts.status, theme.warning)),
+            "cmd" => Some((Some(cmd).filter(|c| !c.is_empty()), theme.context)),
+            _ => None,
+        };
+
+        match value {
+            Some((Some(v), _)) if token == "cmd" => {
+                plain.push_str(v);
+                styled.push_str(&style_cmd(v, theme));
+                styled.push_str(STYLE_BOLD);
+            }
+            Some((Some(v), color)) => {
+                plain.push_str(v);
+                styled.push_str(&format!("{}{}{}{}", color, v, STYLE_RESET, STYLE_BOLD));
+            }
+            Some((None, _)) => {}
+            None => {
+                // 未知のトークンはリテラルとして扱う
+                let raw = &after[..=close_idx];
+                plain.push_str(raw);
+                styled.push_str(raw);
+            }
+        }
+        rest = &after[close_idx + 1..];
+    }
+
+    plain.push_str(rest);
+    styled.push_str(rest);
+    styled.push_str(STYLE_RESET);
+
+    Prompt { plain, styled }
+}
+
+/// 環境変数 WITH_PROMPT のテンプレートを設定の format より優先する純粋関数 (空なら設定のまま)
+pub fn override_format<'a>(
+    config: &'a PromptConfig,
+    format: Option<&str>,
+) -> Cow<'a, PromptConfig> {
+    match format.filter(|format| !format.is_empty()) {
+        Some(format) => Cow::Owned(PromptConfig {
+            format: Some(format.to_string()),
+            ..config.clone()
+        }),
+        None => Cow::Borrowed(config),
+    }
+}
+
+/// 要素と設定からプロンプトを組み立てる (暗背景向けの配色)
+pub fn build_prompt(parts: &PromptParts, config: &PromptConfig) -> Prompt {
+    build_prompt_with(parts, config, &Theme::default())
+}
+
+/// 配色を指定してプロンプトを組み立てる
+/// 文字列を後からパースせずに済むよう、plain と styled を同時に生成する
+pub fn build_prompt_with(parts: &PromptParts, config: &PromptConfig, theme: &Theme) -> Prompt {
+    let cmd = display_cmd(parts, config);
+    if let Some(format) = &config.format {
+        return render_template(format, parts, cmd, theme);
+    }
+
+    let mut plain = String::new();
+    let mut styled = String::new();
+
+    // --- 直前のコマンドが失敗したときの "✗1 " ---
+    if let Some(status) = parts.status {
+        plain.push_str(&format!("{} ", status));
+        styled.push_str(&format!("{}{}{} ", theme.warning, status, STYLE_RESET));
+    }
+
+    // --- ディレクトリ情報部分 "(dir: branch) " ---
+    let dir_color = theme.dir_color(parts.branch.is_some());
+    let branch_color = theme.branch_color(parts.branch_state);
+    let (plain_info, styled_info) = match (parts.dir, parts.branch) {
+        (Some(dir), Some(branch)) => (
+            format!("{}{}{}", dir, config.separator, branch),
+            format!(
+                "{}{}{}{}{}{}{}",
+                dir_color, dir, STYLE_RESET, config.separator, branch_color, branch, STYLE_RESET
+            ),
+        ),
+        (Some(dir), None) => (
+            dir.to_string(),
+            format!("{}{}{}", dir_color, dir, STYLE_RESET),
+        ),
+        (None, Some(branch)) => (
+            branch.to_string(),
+            format!("{}{}{}", branch_color, branch, STYLE_RESET),
+        ),
+        (None, None) => (String::new(), String::new()),
+    };
+
+    if parts.dir.is_some() || parts.branch.is_some() {
+        plain.push_str(&format!("{}{}{} ", config.open, plain_info, config.close));
+        styled.push_str(&format!(
+            "{}{}{}{}{}{} ",
+            STYLE_BOLD, config.open, styled_info, STYLE_BOLD, config.close, STYLE_RESET
+        ));
+    }
+
+    // --- コンテキスト部分 "git/cargo" ---
+    if !cmd.is_empty() {
+        plain.push_str(cmd);
+        styled.push_str(&style_cmd(cmd, theme));
+    }
+
+    // --- プロンプト記号 "> " ---
+    plain.push_str(&config.symbol);
+    styled.push_str(&format!("{}{}{}", STYLE_BOLD, config.symbol, STYLE_RESET));
+
+    Prompt { plain, styled }
+}
+
+/// プロンプトに表示する情報の取得元
+/// 本番はファイルシステムと環境変数を読み、テストでは固定値を返すモックに差し替える
+pub trait PromptProvider {
+    fn current_dir(&self) -> PathBuf;
+    /// ディレクトリの表示名 (例: ".", "src")
+    fn display_dir(&self, cwd: &Path) -> Option<String>;
+    fn git_branch(&self, cwd: &Path) -> Option<String>;
+    /// ブランチの状態 (clean/dirty/detached)
+    fn git_branch_state(&self, cwd: &Path) -> Option<BranchState>;
+    /// 作業ツリーに未コミットの変更があるか
+    fn git_dirty(&self, cwd: &Path) -> bool;
+    /// 追跡先より進んでいる・遅れているコミット数 (追跡先が無ければ None)
+    fn git_ahead_behind(&self, cwd: &Path) -> Option<(usize, usize)>;
+    /// ブランチの追跡先 (例: "origin/main")
+    fn git_upstream(&self, cwd: &Path) -> Option<String>;
+    /// 進行中のマージ・リベースなど
+    fn git_operation(&self, cwd: &Path) -> Option<GitOperation>;
+    fn kube_context(&self) -> Option<String>;
+    fn aws_profile(&self) -> Option<String>;
+    /// 親の with から受け取ったスタック (WITH_CONTEXT_STACK)
+    fn context_stack(&self) -> Option<String>;
+}
+
+/// 実際のファイルシステム・環境変数から情報を取得する PromptProvider
+pub struct SystemPromptProvider {
+    /// 起動時のディレクトリ ("." と表示する基準)
+    pub base_path: PathBuf,
+    /// git 情報取得のスキップ・タイムアウト設定
+    pub git: GitConfig,
+    /// ディレクトリ部分の表示方法
+    pub dir_display: DirDisplay,
+    /// 同じディレクトリでプロンプトを出し直すときに使うブランチ名
+    pub branch_cache: RefCell<BranchCache>,
+    /// REPL で `export` した変数 (プロセスの環境変数より優先する)
+    pub exported: BTreeMap<String, String>,
+}
+
+impl SystemPromptProvider {
+    fn env_var(&self, name: &str) -> Option<String> {
+        self.exported
+            .get(name)
+            .cloned()
+            .or_else(|| env::var(name).ok())
+    }
+
+    /// 遅いファイルシステム上でプロンプトが止まらないよう、git 情報をスキップまたは時間制限付きで取得する
+    fn git_lookup<T, F>(&self, cwd: &Path, f: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Path) -> Option<T> + Send + 'static,
+    {
+        if is_skipped_path(cwd, &self.git.skip_paths)
+            || (self.git.skip_network_fs && is_network_fs(cwd))
+        {
+            return None;
+        }
+        if self.git.timeout_ms == 0 {
+            return f(cwd);
+        }
+        let cwd = cwd.to_path_buf();
+        run_with_timeout(Duration::from_millis(self.git.timeout_ms), move || f(&cwd))
+    }
+}
+
+impl PromptProvider for SystemPromptProvider {
+    fn current_dir(&self) -> PathBuf {
+        env::current_dir().unwrap_or_default()
+    }
+
+    fn display_dir(&self, cwd: &Path) -> Option<String> {
+        resolve_display_dir_with(cwd, &self.base_path, self.dir_display)
+    }
+
+    fn git_branch(&self, cwd: &Path) -> Option<String> {
+        let abbrev = self.git.abbrev_length;
+        self.branch_cache.borrow_mut().get_or_read(cwd, |cwd| {
+            self.git_lookup(cwd, move |cwd| read_cached_branch(cwd, abbrev))
+        })
+    }
+
+    fn git_branch_state(&self, cwd: &Path) -> Option<BranchState> {
+        self.git_lookup(cwd, get_git_branch_state)
+    }
+
+    fn git_dirty(&self, cwd: &Path) -> bool {
+        self.git_lookup(cwd, |cwd| Some(git_is_dirty(cwd)))
+            .unwrap_or(false)
+    }
+
+    fn git_ahead_behind(&self, cwd: &Path) -> Option<(usize, usize)> {
+        self.git_lookup(cwd, get_git_ahead_behind)
+    }
+
+    fn git_upstream(&self, cwd: &Path) -> Option<String> {
+        self.git_lookup(cwd, |cwd| get_git_upstream(cwd).map(|u| u.display_name()))
+    }
+
+    fn git_operation(&self, cwd: &Path) -> Option<GitOperation> {
+        // HEAD が変わらずに始まる (merge など) ので BranchCache には入れず毎回調べる
+        self.git_lookup(cwd, get_git_operation)
+    }
+
+    fn kube_context(&self) -> Option<String> {
+        get_kube_context(self.env_var("KUBECONFIG").as_deref())
+    }
+
+    /// AWS_PROFILE が未設定/空なら None
+    fn aws_profile(&self) -> Option<String> {
+        self.env_var("AWS_PROFILE").filter(|p| !p.is_empty())
+    }
+
+    fn context_stack(&self) -> Option<String> {
+        env::var("WITH_CONTEXT_STACK").ok()
+    }
+}
+
+/// コンテキスト部分の表示文字列を組み立てる純粋関数
+/// 例: stack="git", ctx="cargo" -> "git/cargo"、コンテキストなしなら "git/"
+fn context_label(context: Option<&TargetContext>, stack: Option<&str>) -> String {
+    if let Some(ctx) = context {
+        // 親スタックがある場合は結合して表示 (exp. git/cargo)
+        let full_context = if let Some(stack) = stack {
+            format!("{}/{}", stack, ctx.program)
+        } else {
+            ctx.program.clone()
+        };
+
+        if ctx.args.is_empty() {
+            full_context
+        } else {
+            format!("{} {}", full_context, ctx.args.join(" "))
+        }
+    } else {
+        // コンテキストがなくても文字列を追加
+        if let Some(stack) = stack {
+            format!("{}/", stack)
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// 外部コマンド (starship など) の出力をそのままプロンプトにする純粋関数
+/// 色などの装飾は出力に含まれるものを使い、with の配色では上書きしない。空白だけなら None
+pub fn external_prompt(output: &str) -> Option<Prompt> {
+    if output.trim().is_empty() {
+        return None;
+    }
+    Some(Prompt {
+        plain: output.to_string(),
+        styled: output.to_string(),
+    })
+}
+
+/// 追跡先との差を表示する純粋関数 (例: (2, 1) -> "↑2↓1"。0 の向きは省き、差が無ければ空)
+fn format_ahead_behind(ahead: usize, behind: usize) -> String {
+    let mut out = String::new();
+    if ahead > 0 {
+        out.push_str(&format!("↑{}", ahead));
+    }
+    if behind > 0 {
+        out.push_str(&format!("↓{}", behind));
+    }
+    out
+}
+
+/// 直前の終了コードの表示を決める純粋関数 (例: Some(1) -> "✗1")
+/// 成功・未実行・記号が空なら None (何も表示しない)
+fn format_status(last_status: Option<i32>, symbol: &str) -> Option<String> {
+    match last_status {
+        Some(code) if code != 0 && !symbol.is_empty() => Some(format!("{}{}", symbol, code)),
+        _ => None,
+    }
+}
+
+/// 情報源から値を集めてプロンプトを組み立てる
+/// kube/aws/upstream はテンプレートで使われている場合だけ取得する
+pub fn render_prompt(
+    provider: &dyn PromptProvider,
+    context: Option<&TargetContext>,
+    last_status: Option<i32>,
+    config: &PromptConfig,
+    theme: &Theme,
+) -> Prompt {
+    let cwd = provider.current_dir();
+    let status = format_status(last_status, &config.status_symbol);
+    let dir = provider.display_dir(&cwd);
+    let branch = provider.git_branch(&cwd);
+    let stack = provider
+        .context_stack()
+        .map(|stack| format_context_stack(&parse_context_stack(&stack)));
+    let cmd = context_label(context, stack.as_deref());
+    // git status を伴うため、ブランチの色分けが有効なときだけ取得する
+    let branch_state = (branch.is_some() && theme.colors_branch_state())
+        .then(|| provider.git_branch_state(&cwd))
+        .flatten();
+    // 未コミットの変更の印 (色分けで状態を取得済みなら git を呼び直さない)
+    let dirty = branch.is_some()
+        && !config.dirty_marker.is_empty()
+        && match branch_state {
+            Some(BranchState::Dirty) => true,
+            Some(BranchState::Clean) => false,
+            _ => provider.git_dirty(&cwd),
+        };
+    let branch = match branch {
+        Some(branch) if dirty => Some(format!("{}{}", branch, config.dirty_marker)),
+        branch => branch,
+    };
+    let operation = branch
+        .is_some()
+        .then(|| provider.git_operation(&cwd))
+        .flatten();
+    let branch = match (branch, operation) {
+        (Some(branch), Some(operation)) => Some(format!("{}|{}", branch, operation.label())),
+        (branch, _) => branch,
+    };
+    let counts = (branch.is_some() && config.ahead_behind)
+        .then(|| provider.git_ahead_behind(&cwd))
+        .flatten()
+        .map(|(ahead, behind)| format_ahead_behind(ahead, behind))
+        .filter(|counts| !counts.is_empty());
+    let branch = match (branch, counts) {
+        (Some(branch), Some(counts)) => Some(format!("{} {}", branch, counts)),
+        (branch, _) => branch,
+    };
+
+    let template_uses = |token: &str| config.format.as_deref().is_some_and(|f| f.contains(token));
+    let kube = template_uses("{kube}")
+        .then(|| provider.kube_context())
+        .flatten();
+    let aws = template_uses("{aws}")
+        .then(|| provider.aws_profile())
+        .flatten();
+    let upstream = template_uses("{upstream}")
+        .then(|| provider.git_upstream(&cwd))
+        .flatten();
+
+    build_prompt_with(
+        &PromptParts {
+            dir: dir.as_deref(),
+            branch: branch.as_deref(),
+            branch_state,
+            cmd: &cmd,
+            kube: kube.as_deref(),
+            aws: aws.as_deref(),
+            upstream: upstream.as_deref(),
+            stack: stack.as_deref(),
+            status: status.as_deref(),
+        },
+        config,
+        theme,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::with_helper::{COLOR_MAGENTA, COLOR_RED, COLOR_YELLOW};
+
+    fn strip_ansi(s: &str) -> String {
+        let mut out = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                // "m" まで読み飛ばす
+                for c in chars.by_ref() {
+                    if c == 'm' {
+                        break;
+                    }
+                }
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_external_prompt() {
+        let output = "\x1b[1;32m~/repo\x1b[0m on \x1b[35mmain\x1b[0m ❯ ";
+        let prompt = external_prompt(output).unwrap();
+        assert_eq!(prompt.plain, output);
+        assert_eq!(prompt.styled, output);
+        assert!(external_prompt("").is_none());
+        assert!(external_prompt(" \n").is_none());
+    }
+
+    #[test]
+    fn test_build_prompt_default() {
+        let parts = PromptParts {
+            dir: Some("."),
+            branch: Some("main"),
+            branch_state: None,
+            cmd: "git",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let prompt = build_prompt(&parts, &PromptConfig::default());
+        assert_eq!(prompt.plain, "(.: main) git> ");
+    }
+
+    #[test]
+    fn test_build_prompt_no_info() {
+        let parts = PromptParts {
+            dir: None,
+            branch: None,
+            branch_state: None,
+            cmd: "git/cargo",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let prompt = build_prompt(&parts, &PromptConfig::default());
+        assert_eq!(prompt.plain, "git/cargo> ");
+    }
+
+    #[test]
+    fn test_build_prompt_context_display() {
+        let parts = |dir| PromptParts {
+            dir,
+            branch: None,
+            branch_state: None,
+            cmd: "git",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let with_display = |display| PromptConfig {
+            context_display: display,
+            ..PromptConfig::default()
+        };
+
+        // auto はディレクトリ情報がある時だけ省略する
+        let auto = with_display(ContextDisplay::Auto);
+        let prompt = build_prompt(&parts(Some(".")), &auto);
+        assert_eq!(prompt.plain, "(.) > ");
+        assert_eq!(strip_ansi(&prompt.styled), prompt.plain);
+        assert_eq!(build_prompt(&parts(None), &auto).plain, "git> ");
+
+        let icon = with_display(ContextDisplay::Icon);
+        assert_eq!(build_prompt(&parts(Some(".")), &icon).plain, "(.) ⚙> ");
+    }
+
+    #[test]
+    fn test_template_context_display_auto() {
+        let config = PromptConfig {
+            format: Some("[{dir}] {cmd}> ".to_string()),
+            context_display: ContextDisplay::Auto,
+            ..PromptConfig::default()
+        };
+        let parts = PromptParts {
+            dir: Some("src"),
+            branch: Some("main"),
+            branch_state: None,
+            cmd: "git",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        assert_eq!(build_prompt(&parts, &config).plain, "[src] > ");
+    }
+
+    #[test]
+    fn test_build_prompt_custom_symbols() {
+        let config = PromptConfig {
+            open: "[".to_string(),
+            close: "]".to_string(),
+            separator: " on ".to_string(),
+            symbol: " ❯ ".to_string(),
+            ..PromptConfig::default()
+        };
+        let parts = PromptParts {
+            dir: Some("src"),
+            branch: Some("dev"),
+            branch_state: None,
+            cmd: "cargo",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let prompt = build_prompt(&parts, &config);
+        assert_eq!(prompt.plain, "[src on dev] cargo ❯ ");
+    }
+
+    #[test]
+    fn test_build_prompt_multibyte_styled_matches_plain() {
+        // マルチバイト記号でも色付け後の文字列から色を除けば plain と一致する
+        let config = PromptConfig {
+            open: "「".to_string(),
+            close: "」".to_string(),
+            separator: " ⎇ ".to_string(),
+            symbol: "❯ ".to_string(),
+            ..PromptConfig::default()
+        };
+        let parts = PromptParts {
+            dir: Some("プロジェクト"),
+            branch: Some("main"),
+            branch_state: None,
+            cmd: "git/cargo",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let prompt = build_prompt(&parts, &config);
+        assert_eq!(strip_ansi(&prompt.styled), prompt.plain);
+        assert!(prompt.styled.contains(COLOR_MAGENTA));
+    }
+
+    #[test]
+    fn test_template_kube_and_aws() {
+        let config = PromptConfig {
+            format: Some("[{kube}|{aws}] {cmd}> ".to_string()),
+            ..PromptConfig::default()
+        };
+        let parts = PromptParts {
+            dir: Some("."),
+            branch: None,
+            branch_state: None,
+            cmd: "kubectl",
+            kube: Some("prod-cluster"),
+            aws: Some("dev"),
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let prompt = build_prompt(&parts, &config);
+        assert_eq!(prompt.plain, "[prod-cluster|dev] kubectl> ");
+        assert_eq!(strip_ansi(&prompt.styled), prompt.plain);
+    }
+
+    #[test]
+    fn test_template_missing_values_hidden() {
+        // 未設定の要素は非表示、未知のトークンはそのまま
+        let config = PromptConfig {
+            format: Some("{kube}{aws}{unknown} {cmd}> ".to_string()),
+            ..PromptConfig::default()
+        };
+        let parts = PromptParts {
+            dir: None,
+            branch: None,
+            branch_state: None,
+            cmd: "git",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let prompt = build_prompt(&parts, &config);
+        assert_eq!(prompt.plain, "{unknown} git> ");
+    }
+
+    #[test]
+    fn test_template_upstream() {
+        let config = PromptConfig {
+            format: Some("({branch}→{upstream}) {cmd}> ".to_string()),
+            ..PromptConfig::default()
+        };
+        let mut parts = PromptParts {
+            dir: None,
+            branch: Some("main"),
+            branch_state: None,
+            cmd: "git",
+            kube: None,
+            aws: None,
+            upstream: Some("origin/main"),
+            stack: None,
+            status: None,
+        };
+        assert_eq!(
+            build_prompt(&parts, &config).plain,
+            "(main→origin/main) git> "
+        );
+
+        // 追跡設定が無ければ非表示
+        parts.upstream = None;
+        assert_eq!(build_prompt(&parts, &config).plain, "(main→) git> ");
+    }
+
+    #[test]
+    fn test_template_stack_and_omitted_branch() {
+        let config = PromptConfig {
+            format: Some("{stack} > {dir} {cmd}> ".to_string()),
+            ..PromptConfig::default()
+        };
+        let parts = PromptParts {
+            dir: Some("with"),
+            branch: Some("main"),
+            branch_state: None,
+            cmd: "git/cargo",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: Some("git"),
+            status: None,
+        };
+        // テンプレートに無い要素 (branch) は表示しない
+        assert_eq!(
+            build_prompt(&parts, &config).plain,
+            "git > with git/cargo> "
+        );
+    }
+
+    #[test]
+    fn test_override_format() {
+        let config = PromptConfig {
+            format: Some("{cmd}> ".to_string()),
+            ..PromptConfig::default()
+        };
+        assert_eq!(
+            override_format(&config, Some("{dir} $ ")).format.as_deref(),
+            Some("{dir} $ ")
+        );
+        assert_eq!(
+            override_format(&config, Some("")).format.as_deref(),
+            Some("{cmd}> ")
+        );
+        assert!(matches!(override_format(&config, None), Cow::Borrowed(_)));
+    }
+
+    // --- PromptProvider のテスト ---
+
+    /// 固定値を返すモック (呼ばれた取得処理を記録する)
+    #[derive(Default)]
+    struct MockProvider {
+        stack: Option<String>,
+        kube_calls: std::cell::Cell<usize>,
+        branch_state_calls: std::cell::Cell<usize>,
+        dirty: bool,
+        dirty_calls: std::cell::Cell<usize>,
+        ahead_behind: Option<(usize, usize)>,
+        operation: Option<GitOperation>,
+    }
+
+    impl PromptProvider for MockProvider {
+        fn current_dir(&self) -> PathBuf {
+            PathBuf::from("/work/app")
+        }
+        fn display_dir(&self, _cwd: &Path) -> Option<String> {
+            Some("app".to_string())
+        }
+        fn git_branch(&self, _cwd: &Path) -> Option<String> {
+            Some("main".to_string())
+        }
+        fn git_branch_state(&self, _cwd: &Path) -> Option<BranchState> {
+            self.branch_state_calls
+                .set(self.branch_state_calls.get() + 1);
+            Some(BranchState::Dirty)
+        }
+        fn git_dirty(&self, _cwd: &Path) -> bool {
+            self.dirty_calls.set(self.dirty_calls.get() + 1);
+            self.dirty
+        }
+        fn git_ahead_behind(&self, _cwd: &Path) -> Option<(usize, usize)> {
+            self.ahead_behind
+        }
+        fn git_upstream(&self, _cwd: &Path) -> Option<String> {
+            Some("origin/main".to_string())
+        }
+        fn git_operation(&self, _cwd: &Path) -> Option<GitOperation> {
+            self.operation
+        }
+        fn kube_context(&self) -> Option<String> {
+            self.kube_calls.set(self.kube_calls.get() + 1);
+            Some("prod".to_string())
+        }
+        fn aws_profile(&self) -> Option<String> {
+            None
+        }
+        fn context_stack(&self) -> Option<String> {
+            self.stack.clone()
+        }
+    }
+
+    fn target(program: &str, args: &[&str]) -> TargetContext {
+        TargetContext {
+            program: program.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render_prompt_with_mock() {
+        let provider = MockProvider::default();
+        let ctx = target("git", &[]);
+        let prompt = render_prompt(
+            &provider,
+            Some(&ctx),
+            None,
+            &PromptConfig::default(),
+            &Theme::default(),
+        );
+        assert_eq!(prompt.plain, "(app: main) git> ");
+
+        // テンプレートで使わない情報・無効な色分けのための状態は取得しない
+        assert_eq!(provider.kube_calls.get(), 0);
+        assert_eq!(provider.branch_state_calls.get(), 0);
+    }
+
+    #[test]
+    fn test_render_prompt_branch_state_color() {
+        let provider = MockProvider::default();
+        let theme = Theme {
+            branch_dirty: Some(COLOR_YELLOW),
+            branch_detached: Some(COLOR_RED),
+            ..Theme::default()
+        };
+        let prompt = render_prompt(&provider, None, None, &PromptConfig::default(), &theme);
+        assert!(prompt.styled.contains(&format!("{}main", COLOR_YELLOW)));
+        assert_eq!(provider.branch_state_calls.get(), 1);
+    }
+
+    #[test]
+    fn test_render_prompt_last_status() {
+        let provider = MockProvider::default();
+        let config = PromptConfig::default();
+        let theme = Theme::default();
+        let prompt = render_prompt(&provider, None, Some(1), &config, &theme);
+        assert_eq!(prompt.plain, "✗1 (app: main) > ");
+        assert!(prompt.styled.contains(&format!("{}✗1", theme.warning)));
+
+        // 成功・未実行なら表示しない
+        for status in [Some(0), None] {
+            let prompt = render_prompt(&provider, None, status, &config, &theme);
+            assert_eq!(prompt.plain, "(app: main) > ");
+        }
+
+        // テンプレートでは {status} の位置に出し、記号が空なら表示しない
+        let config = PromptConfig {
+            format: Some("{dir}{status} > ".to_string()),
+            ..PromptConfig::default()
+        };
+        let prompt = render_prompt(&provider, None, Some(130), &config, &theme);
+        assert_eq!(prompt.plain, "app✗130 > ");
+        let config = PromptConfig {
+            status_symbol: String::new(),
+            ..PromptConfig::default()
+        };
+        let prompt = render_prompt(&provider, None, Some(1), &config, &theme);
+        assert_eq!(prompt.plain, "(app: main) > ");
+    }
+
+    #[test]
+    fn test_render_prompt_git_operation() {
+        let config = PromptConfig {
+            dirty_marker: "*".to_string(),
+            ..PromptConfig::default()
+        };
+        let provider = MockProvider {
+            dirty: true,
+            operation: Some(GitOperation::Merging),
+            ..Default::default()
+        };
+        let prompt = render_prompt(&provider, None, None, &config, &Theme::default());
+        assert_eq!(prompt.plain, "(app: main*|MERGING) > ");
+
+        // 進行中の操作が無ければ表示しない
+        let provider = MockProvider::default();
+        let prompt = render_prompt(&provider, None, None, &config, &Theme::default());
+        assert_eq!(prompt.plain, "(app: main) > ");
+    }
+
+    #[test]
+    fn test_render_prompt_dirty_marker() {
+        let config = PromptConfig {
+            dirty_marker: "*".to_string(),
+            ..PromptConfig::default()
+        };
+        let provider = MockProvider {
+            dirty: true,
+            ..Default::default()
+        };
+        let prompt = render_prompt(&provider, None, None, &config, &Theme::default());
+        assert_eq!(prompt.plain, "(app: main*) > ");
+
+        let provider = MockProvider::default();
+        let prompt = render_prompt(&provider, None, None, &config, &Theme::default());
+        assert_eq!(prompt.plain, "(app: main) > ");
+
+        // 既定では印を付けず、git status も実行しない
+        let prompt = render_prompt(
+            &provider,
+            None,
+            None,
+            &PromptConfig::default(),
+            &Theme::default(),
+        );
+        assert_eq!(prompt.plain, "(app: main) > ");
+        assert_eq!(provider.dirty_calls.get(), 1);
+    }
+
+    #[test]
+    fn test_render_prompt_ahead_behind() {
+        let config = PromptConfig {
+            ahead_behind: true,
+            dirty_marker: "*".to_string(),
+            ..PromptConfig::default()
+        };
+        let provider = MockProvider {
+            dirty: true,
+            ahead_behind: Some((2, 1)),
+            ..Default::default()
+        };
+        let prompt = render_prompt(&provider, None, None, &config, &Theme::default());
+        assert_eq!(prompt.plain, "(app: main* ↑2↓1) > ");
+
+        // 追跡先が無い・差が無い場合は付けない
+        for ahead_behind in [None, Some((0, 0))] {
+            let provider = MockProvider {
+                ahead_behind,
+                ..Default::default()
+            };
+            let prompt = render_prompt(&provider, None, None, &config, &Theme::default());
+            assert_eq!(prompt.plain, "(app: main) > ");
+        }
+    }
+
+    #[test]
+    fn test_format_ahead_behind() {
+        assert_eq!(format_ahead_behind(2, 1), "↑2↓1");
+        assert_eq!(format_ahead_behind(3, 0), "↑3");
+        assert_eq!(format_ahead_behind(0, 4), "↓4");
+        assert_eq!(format_ahead_behind(0, 0), "");
+    }
+
+    #[test]
+    fn test_render_prompt_template_and_stack() {
+        let provider = MockProvider {
+            stack: Some(r#"["git"]"#.to_string()),
+            ..Default::default()
+        };
+        let config = PromptConfig {
+            format: Some("[{kube}{aws}] {upstream} {cmd}> ".to_string()),
+            ..PromptConfig::default()
+        };
+        let ctx = target("cargo", &["run"]);
+        let prompt = render_prompt(&provider, Some(&ctx), None, &config, &Theme::dark());
+        assert_eq!(prompt.plain, "[prod] origin/main git/cargo run> ");
+        assert_eq!(provider.kube_calls.get(), 1);
+    }
+
+    #[test]
+    fn test_context_label() {
+        assert_eq!(context_label(None, None), "");
+        assert_eq!(context_label(None, Some("git")), "git/");
+        assert_eq!(
+            context_label(Some(&target("npm", &["run"])), None),
+            "npm run"
+        );
+    }
+
+    #[test]
+    fn test_build_prompt_light_theme() {
+        let parts = PromptParts {
+            dir: Some("."),
+            branch: None,
+            branch_state: None,
+            cmd: "git",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let light = Theme::light();
+        let prompt = build_prompt_with(&parts, &PromptConfig::default(), &light);
+        assert!(prompt.styled.contains(light.context));
+        assert_eq!(strip_ansi(&prompt.styled), prompt.plain);
+    }
+
+    #[test]
+    fn test_build_prompt_dir_color_by_git() {
+        let theme = Theme {
+            dir_outside_git: Some("\x1b[90m"),
+            ..Theme::dark()
+        };
+        let outside = PromptParts {
+            dir: Some("tmp"),
+            branch: None,
+            branch_state: None,
+            cmd: "git",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let prompt = build_prompt_with(&outside, &PromptConfig::default(), &theme);
+        assert!(prompt.styled.contains("\x1b[90mtmp"));
+
+        // git 管理下 (ブランチあり) なら通常の色。テンプレートでも同じ
+        let inside = PromptParts {
+            branch: Some("main"),
+            ..outside
+        };
+        let config = PromptConfig {
+            format: Some("{dir} {cmd}> ".to_string()),
+            ..PromptConfig::default()
+        };
+        let prompt = build_prompt_with(&inside, &config, &theme);
+        assert!(prompt.styled.contains(&format!("{}tmp", theme.dir)));
+    }
+}
@@ -0,0 +1,387 @@
+use crate::stats::format_utc;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// 記録ファイルの形式のバージョン
+pub const RECORDING_VERSION: u32 = 1;
+
+/// 再生時に待つ間隔の上限 (長く放置した区間をそのまま再現しない)
+const REPLAY_IDLE_LIMIT: Duration = Duration::from_secs(3);
+
+/// `--record` で記録した1行分の入力と結果
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RecordEvent {
+    /// 記録開始から入力を確定するまでの秒数
+    pub time: f64,
+    pub input: String,
+    /// 画面に流したコマンドの標準出力 (内部コマンドの表示は含まない)
+    pub output: String,
+    pub status: Option<i32>,
+    /// 実行にかかった秒数
+    pub duration: f64,
+}
+
+/// 記録ファイル (JSON) の内容
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Recording {
+    pub version: u32,
+    pub events: Vec<RecordEvent>,
+}
+
+/// セッションの入力・出力を記録し、終了時にファイルへ書き出す
+pub struct Recorder {
+    path: PathBuf,
+    started: Instant,
+    /// 一致した入力は記録しない (トークンやパスワードを含むコマンドなど)
+    exclude: Vec<Regex>,
+    events: Vec<RecordEvent>,
+}
+
+impl Recorder {
+    /// 除外パターンが正規表現として不正なら Err
+    pub fn new(path: PathBuf, exclude: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            path,
+            started: Instant::now(),
+            exclude: compile_exclude(exclude)?,
+            events: Vec::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 入力が除外パターンに一致するか
+    pub fn is_excluded(&self, input: &str) -> bool {
+        self.exclude.iter().any(|re| re.is_match(input))
+    }
+
+    /// started_at に確定した入力とその結果を記録する (空行と除外対象は記録しない)
+    pub fn record(
+        &mut self,
+        input: &str,
+        started_at: Instant,
+        output: String,
+        status: Option<i32>,
+    ) {
+        if input.is_empty() || self.is_excluded(input) {
+            return;
+        }
+        self.events.push(RecordEvent {
+            time: started_at.duration_since(self.started).as_secs_f64(),
+            input: input.to_string(),
+            output,
+            status,
+            duration: started_at.elapsed().as_secs_f64(),
+        });
+    }
+
+    pub fn recording(&self) -> Recording {
+        Recording {
+            version: RECORDING_VERSION,
+            events: self.events.clone(),
+        }
+    }
+
+    /// 記録をファイルへ書き出す
+    pub fn save(&self) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(&self.recording()).map_err(|e| e.to_string())?;
+        fs::write(&self.path, content).map_err(|e| e.to_string())
+    }
+}
+
+/// `[record] exclude` の正規表現をコンパイルする (`--record` と `--log` で共通)
+fn compile_exclude(exclude: &[String]) -> Result<Vec<Regex>, String> {
+    exclude
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| format!("invalid exclude pattern '{}': {}", pattern, e))
+        })
+        .collect()
+}
+
+/// `--log` で追記する1行分の入力と結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry<'a> {
+    /// 入力を確定した時刻
+    pub time: SystemTime,
+    pub input: &'a str,
+    pub stdout: &'a [u8],
+    pub stderr: &'a [u8],
+    pub status: Option<i32>,
+    pub duration: Duration,
+}
+
+/// 出力をログに書く形にする (NUL を含むか UTF-8 でなければバイナリとみなし、大きさだけ残す)
+fn log_output(output: &[u8]) -> String {
+    match std::str::from_utf8(output) {
+        Ok(text) if !text.contains('\0') => text.to_string(),
+        _ => format!("(binary output, {} bytes omitted)\n", output.len()),
+    }
+}
+
+/// ログの1件を組み立てる純粋関数
+/// 例: "[2024-02-29 12:34 UTC] $ git status (exit 0, 0.12s)" の後に stdout / stderr を続ける
+pub fn format_log_entry(entry: &LogEntry) -> String {
+    let status = entry
+        .status
+        .map_or_else(|| "-".to_string(), |code| code.to_string());
+    let mut out = format!(
+        "[{} UTC] $ {} (exit {}, {:.2}s)\n",
+        format_utc(entry.time),
+        entry.input,
+        status,
+        entry.duration.as_secs_f64()
+    );
+    for (name, output) in [("stdout", entry.stdout), ("stderr", entry.stderr)] {
+        if output.is_empty() {
+            continue;
+        }
+        let text = log_output(output);
+        out.push_str(&format!("--- {}\n{}", name, text));
+        if !text.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+    out.push('\n');
+    out
+}
+
+/// セッション中の入力と出力を1件ずつファイルへ追記する (`--log`)
+/// 書き込みに失敗しても REPL は止めず、最初の1回だけ警告する
+pub struct SessionLog {
+    path: PathBuf,
+    /// 一致した入力は記録しない (`[record] exclude` を共有する)
+    exclude: Vec<Regex>,
+    warned: bool,
+}
+
+impl SessionLog {
+    /// 除外パターンが正規表現として不正なら Err
+    pub fn new(path: PathBuf, exclude: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            path,
+            exclude: compile_exclude(exclude)?,
+            warned: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 1件を追記する (空行と除外対象は書かない)
+    pub fn append(&mut self, entry: &LogEntry) {
+        if entry.input.is_empty() || self.exclude.iter().any(|re| re.is_match(entry.input)) {
+            return;
+        }
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(format_log_entry(entry).as_bytes()));
+        if let Err(e) = result
+            && !self.warned
+        {
+            eprintln!(
+                "Warning: failed to write log {}: {}",
+                self.path.display(),
+                e
+            );
+            self.warned = true;
+        }
+    }
+}
+
+/// 記録ファイルを読み込む
+pub fn load_recording(path: &Path) -> Result<Recording, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let recording: Recording = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+    if recording.version != RECORDING_VERSION {
+        return Err(format!(
+            "unsupported recording version {}",
+            recording.version
+        ));
+    }
+    Ok(recording)
+}
+
+/// 記録を入力の間隔と実行時間を再現しながら out へ流す
+/// 待ち時間は sleep に渡す (テストでは実際には待たない)。間隔は REPLAY_IDLE_LIMIT で打ち切る
+pub fn replay(
+    recording: &Recording,
+    out: &mut impl Write,
+    mut sleep: impl FnMut(Duration),
+) -> io::Result<()> {
+    let mut last = 0.0;
+    for event in &recording.events {
+        sleep(idle(event.time - last));
+        writeln!(out, "> {}", event.input)?;
+        out.flush()?;
+        sleep(idle(event.duration));
+        write!(out, "{}", event.output)?;
+        if !event.output.is_empty() && !event.output.ends_with('\n') {
+            writeln!(out)?;
+        }
+        out.flush()?;
+        last = event.time + event.duration;
+    }
+    Ok(())
+}
+
+fn idle(secs: f64) -> Duration {
+    Duration::from_secs_f64(secs.max(0.0)).min(REPLAY_IDLE_LIMIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(time: f64, input: &str, output: &str, duration: f64) -> RecordEvent {
+        RecordEvent {
+            time,
+            input: input.to_string(),
+            output: output.to_string(),
+            status: Some(0),
+            duration,
+        }
+    }
+
+    #[test]
+    fn test_recorder_excludes_patterns() {
+        let mut recorder = Recorder::new(
+            "rec.json".into(),
+            &["(?i)token".to_string(), "^export ".to_string()],
+        )
+        .unwrap();
+        let now = Instant::now();
+        recorder.record("git status", now, "clean\n".to_string(), Some(0));
+        recorder.record("export GH_TOKEN=secret", now, String::new(), None);
+        recorder.record("curl -H 'Token: x'", now, String::new(), None);
+        recorder.record("", now, String::new(), None);
+
+        let recording = recorder.recording();
+        assert_eq!(recording.events.len(), 1);
+        assert_eq!(recording.events[0].input, "git status");
+        assert_eq!(recording.events[0].output, "clean\n");
+
+        assert!(Recorder::new("rec.json".into(), &["(".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_format_log_entry() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_209_440);
+        let entry = LogEntry {
+            time,
+            input: "git status",
+            stdout: b"clean",
+            stderr: b"",
+            status: Some(0),
+            duration: Duration::from_millis(120),
+        };
+        assert_eq!(
+            format_log_entry(&entry),
+            "[2024-02-29 12:24 UTC] $ git status (exit 0, 0.12s)\n--- stdout\nclean\n\n"
+        );
+
+        // バイナリ出力は大きさだけ残す
+        let entry = LogEntry {
+            stdout: b"\x89PNG\0\xff",
+            stderr: b"warning\n",
+            status: None,
+            ..entry
+        };
+        assert_eq!(
+            format_log_entry(&entry),
+            "[2024-02-29 12:24 UTC] $ git status (exit -, 0.12s)\n\
+             --- stdout\n(binary output, 6 bytes omitted)\n\
+             --- stderr\nwarning\n\n"
+        );
+    }
+
+    #[test]
+    fn test_session_log_appends_and_excludes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("with.log");
+        fs::write(&path, "previous\n").unwrap();
+        let mut log = SessionLog::new(path.clone(), &["(?i)token".to_string()]).unwrap();
+        let entry = LogEntry {
+            time: SystemTime::UNIX_EPOCH,
+            input: "ls",
+            stdout: b"a\n",
+            stderr: b"",
+            status: Some(0),
+            duration: Duration::ZERO,
+        };
+        log.append(&entry);
+        log.append(&LogEntry {
+            input: "export TOKEN=x",
+            ..entry.clone()
+        });
+        log.append(&LogEntry { input: "", ..entry });
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            "previous\n[1970-01-01 00:00 UTC] $ ls (exit 0, 0.00s)\n--- stdout\na\n\n"
+        );
+        assert!(SessionLog::new(path, &["(".to_string()]).is_err());
+
+        // 書き込めなくても panic せずに続ける
+        let mut broken = SessionLog::new(tmp.path().join("missing/with.log"), &[]).unwrap();
+        broken.append(&entry);
+        broken.append(&entry);
+        assert!(broken.warned);
+    }
+
+    #[test]
+    fn test_save_and_load_recording() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("session.json");
+        let mut recorder = Recorder::new(path.clone(), &[]).unwrap();
+        recorder.record("cargo build", Instant::now(), "ok\n".to_string(), Some(0));
+        recorder.save().unwrap();
+
+        let loaded = load_recording(&path).unwrap();
+        assert_eq!(loaded, recorder.recording());
+
+        fs::write(&path, r#"{"version": 99, "events": []}"#).unwrap();
+        assert!(load_recording(&path).is_err());
+    }
+
+    #[test]
+    fn test_replay_reproduces_intervals() {
+        let recording = Recording {
+            version: RECORDING_VERSION,
+            events: vec![
+                event(0.5, "git status", "clean\n", 0.25),
+                event(60.0, "cargo build", "done", 1.0),
+            ],
+        };
+        let mut out = Vec::new();
+        let mut waits = Vec::new();
+        replay(&recording, &mut out, |d| waits.push(d)).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "> git status\nclean\n> cargo build\ndone\n"
+        );
+        // 長い間隔は上限で打ち切る
+        assert_eq!(
+            waits,
+            vec![
+                Duration::from_millis(500),
+                Duration::from_millis(250),
+                REPLAY_IDLE_LIMIT,
+                Duration::from_secs(1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,2138 @@
+use crate::config;
+use crate::dir_env::{DIR_FILE_NAME, DirEnv};
+use crate::executor::{
+    CapturedOutput, Foreground, WaitResult, continue_job, execute_capturing, execute_child_process,
+    execute_in_dir, execute_pipeline, execute_with_redirects, execute_with_tee, format_summary,
+    resume_foreground, spawn_background,
+};
+use crate::history::{History, build_search_regex, format_history_line, save_pins, search_history};
+use crate::jobs::{JobTable, format_job};
+use crate::keybind::{KeyBinding, format_key_bindings, key_bindings};
+use crate::parser::{
+    CommandAction, ConfigCommand, MacroCommand, ParseOptions, Redirects, TargetContext, TeeOutput,
+    expand_alias, is_builtin_command, parse_cmd, parse_cmd_with,
+};
+use crate::record::{LogEntry, Recorder, SessionLog};
+use crate::select::select;
+use crate::stats::{
+    DEFAULT_STATS_LIMIT, SessionStats, format_cheatsheet, format_ranking, format_session_summary,
+    rank_commands,
+};
+use crate::subcommands::suggest_subcommands;
+use crate::title::{report_cwd, running_title, set_title, title_supported};
+use crate::with_helper::get_subcommands;
+use rustyline::EditMode;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    process::Child,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// コマンドの実行 (副作用) を担う
+/// テストやフロントエンドから差し替えられるようにトレイトにしている
+pub trait Executor {
+    /// フォアグラウンドで実行し、終了コード (または Ctrl+Z で停止した子) を返す
+    fn execute(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+    ) -> Foreground;
+
+    /// 標準入出力をファイルにつなぎ替えて実行する (`cmd > file` など)
+    fn execute_with_redirects(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+        redirects: &Redirects,
+    ) -> Foreground;
+
+    /// 作業ディレクトリを指定して実行する (`in <dir> <cmd>`)
+    fn execute_in_dir(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+        dir: &Path,
+    ) -> Foreground;
+
+    /// 標準出力をファイルにも書き写しながら実行する
+    fn execute_with_tee(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+        files: &[String],
+        append: bool,
+    ) -> Foreground;
+
+    /// 出力を画面に流しながら取り込み、(結果, 出力) を返す (`--record` / `--log` 中に使う)
+    fn execute_capturing(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+    ) -> (Foreground, CapturedOutput);
+
+    /// パイプでつないだコマンドを実行する (終了コードは最後の段のもの)
+    /// tee を渡すと最後の段の標準出力をファイルにも書き写し、capture を渡すと画面に流しながら取り込む
+    fn execute_pipeline(
+        &mut self,
+        stages: Vec<(String, Vec<String>)>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+        redirects: &Redirects,
+        tee: Option<&TeeOutput>,
+        capture: Option<&mut Vec<u8>>,
+    ) -> Foreground;
+
+    /// バックグラウンドで起動する
+    fn spawn_background(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+    ) -> io::Result<Child>;
+}
+
+/// 実際に子プロセスを起動する Executor
+pub struct SystemExecutor {
+    /// この時間を過ぎても終わらないコマンドに Ctrl+Z での停止を案内する
+    pub suspend_hint: Option<Duration>,
+}
+
+impl Executor for SystemExecutor {
+    fn execute(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+    ) -> Foreground {
+        execute_child_process(program, args, context, exported, self.suspend_hint)
+    }
+
+    fn execute_with_redirects(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+        redirects: &Redirects,
+    ) -> Foreground {
+        execute_with_redirects(
+            program,
+            args,
+            context,
+            exported,
+            redirects,
+            self.suspend_hint,
+        )
+    }
+
+    fn execute_in_dir(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+        dir: &Path,
+    ) -> Foreground {
+        execute_in_dir(program, args, context, exported, dir, self.suspend_hint)
+    }
+
+    fn execute_with_tee(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+        files: &[String],
+        append: bool,
+    ) -> Foreground {
+        execute_with_tee(
+            program,
+            args,
+            context,
+            exported,
+            files,
+            append,
+            self.suspend_hint,
+        )
+    }
+
+    fn execute_capturing(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+    ) -> (Foreground, CapturedOutput) {
+        execute_capturing(program, args, context, exported, self.suspend_hint)
+    }
+
+    fn execute_pipeline(
+        &mut self,
+        stages: Vec<(String, Vec<String>)>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+        redirects: &Redirects,
+        tee: Option<&TeeOutput>,
+        capture: Option<&mut Vec<u8>>,
+    ) -> Foreground {
+        execute_pipeline(
+            stages,
+            context,
+            exported,
+            redirects,
+            tee,
+            capture,
+            self.suspend_hint,
+        )
+    }
+
+    fn spawn_background(
+        &mut self,
+        program: &str,
+        args: Vec<String>,
+        context: Option<&TargetContext>,
+        exported: &BTreeMap<String, String>,
+    ) -> io::Result<Child> {
+        spawn_background(program, args, context, exported)
+    }
+}
+
+/// 1行処理した結果、呼び出し側がすべきこと
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepResult {
+    /// 次の入力を待つ
+    Continue,
+    /// この階層の with を終了する (quit)
+    Exit,
+    /// ネストした with も含めて全て終了する (exit)
+    ExitAll,
+}
+
+/// REPL の状態
+/// カレントディレクトリはプロセス全体の状態をそのまま使う
+/// `export` した環境変数は parse_options.exported に持ち、コマンドを起動するたびにその子へ渡す
+pub struct ReplState {
+    pub context: Option<TargetContext>,
+    pub history: History,
+    /// 実際に実行したコマンド行 (コンテキスト適用後。補完の優先順位付けに使う)
+    pub executed: Vec<String>,
+    pub jobs: JobTable,
+    pub dir_env: DirEnv,
+    pub parse_options: ParseOptions,
+    pub config: config::Config,
+    /// `config save|edit` と `macro define|remove` で書き込む設定ファイル
+    pub config_file: Option<PathBuf>,
+    /// `history pin|unpin` のたびにピンを保存するファイル (None なら保存しない)
+    pub pins_file: Option<PathBuf>,
+    /// 直前に実行したコマンドの終了コード
+    pub last_status: Option<i32>,
+    /// 今の入力行で終了コードが決まったか (終了コードを変えない内部コマンドの行は記録に終了コードを残さない)
+    status_set: bool,
+    /// 直前のコマンドの標準出力 (出力キャプチャが有効なときだけ保持する。`$?out` で参照する)
+    pub last_output: Option<String>,
+    /// 実行後に要約行を表示するか
+    pub show_summary: bool,
+    /// セッション中の実行コマンド数・所要時間・失敗数
+    pub session: SessionStats,
+    /// 終了時にセッションのサマリを表示するか
+    pub show_session_summary: bool,
+    /// コマンド実行中にターミナルタイトルを実行中のコマンドにするか
+    pub show_title: bool,
+    /// 起動時と cd 後に端末へカレントディレクトリを知らせるか (OSC 7)
+    pub report_cwd: bool,
+    pub use_color: bool,
+    /// true なら要約行・セッションのサマリを出さない (`--quiet`。`config set` しても変えない)
+    pub quiet: bool,
+    /// `config set` で変更し、まだ `config save` していない値
+    config_edits: Vec<(String, toml::Value)>,
+    /// `--record` で起動したときの記録先
+    pub recorder: Option<Recorder>,
+    /// `--log` で起動したときの追記先
+    pub log: Option<SessionLog>,
+    /// 記録中に取り込んだ、いま実行している行の出力
+    captured: CapturedOutput,
+    /// `alias` で定義したエイリアス (名前 → 置き換え後の文字列)
+    pub aliases: BTreeMap<String, String>,
+    /// 設定が変わったら true (プロンプトや配色を作り直したら呼び出し側が false に戻す)
+    pub config_changed: bool,
+    /// エディタに設定したキー操作 (`help` の一覧に使う)
+    pub key_bindings: Vec<KeyBinding>,
+    pub executor: Box<dyn Executor>,
+}
+
+/// 設定ファイルで定義されたコンテキストの環境変数を補う (起動時の `--env` が優先)
+fn with_config_env(
+    context: Option<TargetContext>,
+    config: &config::Config,
+) -> Option<TargetContext> {
+    context.map(|mut ctx| {
+        let mut env = config.context_env(&ctx.program);
+        env.retain(|(key, _)| !ctx.env.iter().any(|(k, _)| k == key));
+        env.append(&mut ctx.env);
+        ctx.env = env;
+        ctx
+    })
+}
+
+/// 内部コマンドと衝突するサブコマンドは、設定に応じてコンテキストを優先する
+fn context_subcommands(context: Option<&TargetContext>, config: &config::Config) -> Vec<String> {
+    match context {
+        Some(ctx) if config.context.prefer_subcommands => get_subcommands(&ctx.program)
+            .into_iter()
+            .filter(|sub| is_builtin_command(sub))
+            .map(|sub| sub.to_string())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// `cd @name` の移動先を決める
+/// 登録名に無ければ前方一致するブックマークを探し、1件ならそこへ、複数なら端末で選ばせる
+/// 端末でなければ選べないのでエラーにする。移動しない場合 (キャンセルを含む) は None
+fn bookmark_target(config: &config::Config, target: &str) -> Option<PathBuf> {
+    if let Some(path) = config.bookmark_path(target) {
+        return Some(path);
+    }
+    let matches = config.bookmark_matches(target);
+    let chosen = match matches.len() {
+        0 => {
+            eprintln!("cd: no such bookmark: {}", target);
+            return None;
+        }
+        1 => 0,
+        _ if io::stdin().is_terminal() => {
+            let items: Vec<String> = matches
+                .iter()
+                .map(|m| {
+                    let path = config.bookmark_path(m).unwrap_or_default();
+                    format!("{}  {}", m, path.display())
+                })
+                .collect();
+            let title = format!("cd: '{}' matches {} bookmarks:", target, matches.len());
+            select(&title, &items)?
+        }
+        _ => {
+            eprintln!(
+                "cd: ambiguous bookmark: {} ({})",
+                target,
+                matches.join(", ")
+            );
+            return None;
+        }
+    };
+    config.bookmark_path(&matches[chosen])
+}
+
+/// 現在のコンテキストのサブコマンドテンプレート
+fn context_templates(
+    context: Option<&TargetContext>,
+    config: &config::Config,
+) -> BTreeMap<String, String> {
+    context
+        .map(|ctx| config.context_templates(&ctx.program))
+        .unwrap_or_default()
+}
+
+impl ReplState {
+    /// 設定から状態を組み立てる (実行は SystemExecutor)
+    pub fn new(context: Option<TargetContext>, config: config::Config) -> Self {
+        let context = with_config_env(context, &config);
+        let suspend_hint = (config.jobs.suspend_hint_secs > 0)
+            .then(|| Duration::from_secs(config.jobs.suspend_hint_secs));
+        let parse_options = ParseOptions {
+            strict_cd: config.cd.strict,
+            context_subcommands: context_subcommands(context.as_ref(), &config),
+            input_first: config.context.input_first,
+            templates: context_templates(context.as_ref(), &config),
+            exported: BTreeMap::new(),
+        };
+        Self {
+            context,
+            history: History::default(),
+            executed: Vec::new(),
+            jobs: JobTable::default(),
+            dir_env: DirEnv::default(),
+            parse_options,
+            // 要約行は端末に出力しているときだけ表示する (パイプ先を汚さない)
+            show_summary: config.summary.enabled && io::stdout().is_terminal(),
+            session: SessionStats::default(),
+            show_session_summary: config.summary.session && io::stdout().is_terminal(),
+            show_title: config.title.enabled && title_supported(),
+            report_cwd: config.terminal.report_cwd && io::stdout().is_terminal(),
+            use_color: env::var_os("NO_COLOR").is_none(),
+            config,
+            config_file: config::config_path(),
+            pins_file: None,
+            last_status: None,
+            status_set: false,
+            last_output: None,
+            quiet: false,
+            config_edits: Vec::new(),
+            recorder: None,
+            log: None,
+            captured: CapturedOutput::default(),
+            aliases: BTreeMap::new(),
+            config_changed: false,
+            key_bindings: key_bindings(EditMode::Emacs),
+            executor: Box::new(SystemExecutor { suspend_hint }),
+        }
+    }
+
+    /// 現在のコンテキストのプログラム名
+    pub fn context_program(&self) -> Option<&str> {
+        self.context.as_ref().map(|ctx| ctx.program.as_str())
+    }
+
+    /// 終了コードを設定する (`$?` と記録の終了コードになる)
+    fn set_status(&mut self, code: Option<i32>) {
+        self.last_status = code;
+        self.status_set = true;
+    }
+
+    /// 環境変数の値を返す (`export` した値を優先する)
+    pub fn env_var(&self, name: &str) -> Option<String> {
+        self.parse_options
+            .exported
+            .get(name)
+            .cloned()
+            .or_else(|| env::var(name).ok())
+    }
+
+    /// コンテキストをその場で差し替える (履歴やジョブはそのまま)
+    pub fn switch_context(&mut self, context: Option<TargetContext>) {
+        let context = with_config_env(context, &self.config);
+        self.parse_options.context_subcommands =
+            context_subcommands(context.as_ref(), &self.config);
+        self.parse_options.templates = context_templates(context.as_ref(), &self.config);
+        self.context = context;
+    }
+
+    /// 設定を差し替え、設定から決まる状態を作り直す (`config set` / `config edit`)
+    pub fn apply_config(&mut self, config: config::Config) {
+        self.config = config;
+        self.parse_options.strict_cd = self.config.cd.strict;
+        self.parse_options.input_first = self.config.context.input_first;
+        let context = self.context.take();
+        self.switch_context(context);
+        if !self.quiet {
+            self.show_summary = self.config.summary.enabled && io::stdout().is_terminal();
+            self.show_session_summary = self.config.summary.session && io::stdout().is_terminal();
+        }
+        self.show_title = self.config.title.enabled && title_supported();
+        self.report_cwd = self.config.terminal.report_cwd && io::stdout().is_terminal();
+        self.config_changed = true;
+    }
+
+    /// 終了時に表示するセッションのサマリ (無効なら None)
+    pub fn session_summary(&self) -> Option<String> {
+        self.show_session_summary
+            .then(|| format_session_summary(&self.session))
+    }
+
+    /// 非対話モード: コンテキストのコマンドを引数そのままで1回だけ実行する
+    /// パイプの途中 (`echo foo | with cat | grep f`) で使われたときのためのもので、標準入出力は子にそのまま継承する
+    pub fn run_passthrough(&mut self) -> Option<i32> {
+        let context = self.context.clone()?;
+        self.executor
+            .execute(
+                &context.program,
+                context.args.clone(),
+                Some(&context),
+                &self.parse_options.exported,
+            )
+            .code()
+    }
+}
+
+/// ヘルプを表示する (ショートカットは実際に設定したキー操作から作る)
+pub fn print_help(bindings: &[KeyBinding]) {
+    println!("With - Command Wrapper Tool");
+    println!();
+    println!("Usage:");
+    println!("  <command> [args]  Execute command in the target context");
+    println!("  cd <path>         Change current directory");
+    println!("  ! <command>       Execute external command (e.g. !ls, !vim)");
+    println!("  clear/cls         Clear the screen");
+    println!("  help              Show this help message");
+    println!("  config list|get <key>|set <key> <value>|save|edit  View or change settings");
+    println!("  export KEY=VALUE  Set environment variable");
+    println!("  alias [NAME=\"VALUE\"]  Define an alias for the first word (no args: list them)");
+    println!("  unalias NAME      Remove an alias");
+    println!(
+        "  macro define [--keep-going] <name> <command>...  Define a macro (saved to ~/.withrc)"
+    );
+    println!(
+        "  macro run|show|remove <name> / macro list  Run (or just type <name>) and manage macros"
+    );
+    println!("  pwd               Show current pass");
+    println!("  history           Show command history");
+    println!("  history grep|re [-i] <pattern>  Search history (text / regex)");
+    println!("  history clear     Clear history (ids are never reused, pinned commands are kept)");
+    println!("  history pin <n> / unpin <n> / pinned  Pin commands (run them with !p<n>)");
+    println!("  !! / !n           Re-run the last command / the command with id n");
+    println!("  stats [first|sub|full] [N]  Show the most used commands");
+    println!("  cheatsheet [file] Write the most used commands as Markdown (or stats --export md)");
+    println!("  <command> | tee [-a] <file>  Show output and save it to file");
+    println!(
+        "  <command> | <command>  Pipe output to the next command (context applies to the first)"
+    );
+    println!("  <command>; <command>  Run commands in order (built-ins can be mixed)");
+    println!(
+        "  <command> && <command>  Run the next command only on success (|| on failure; built-ins like cd count)"
+    );
+    println!("  <command> &       Run command in the background");
+    println!("  in <dir> <command>  Run command in dir without changing the current directory");
+    println!("  ctx [<command> [args]]  Switch the context in place (no args: clear it)");
+    println!("  jobs              List background jobs");
+    println!("  disown [%n]       Detach a background job from with");
+    println!("  fg/bg [%n]        Resume a job stopped with Ctrl+Z (Unix only)");
+    println!("  exit/quit (e/q)   Exit the application");
+    println!();
+    println!("Keyboard Shortcuts:");
+    for line in format_key_bindings(bindings) {
+        println!("{}", line);
+    }
+}
+
+/// 引数を空白で繋いだ表示用のコマンド行
+fn command_line(program: &str, args: &[String]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().map(|s| s.as_str()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// .with.dir に書かれたコマンドを順に実行する
+/// コンテキストは適用せず、export・alias・unalias と外部コマンドのみを扱う
+fn run_dir_script(lines: &[String], state: &mut ReplState) {
+    for line in lines {
+        match parse_cmd(line, None) {
+            CommandAction::Export(vars) => {
+                for (key, value) in vars {
+                    state
+                        .dir_env
+                        .set_var(&mut state.parse_options.exported, &key, &value);
+                }
+            }
+            CommandAction::Alias { name, expansion } => {
+                state
+                    .dir_env
+                    .set_alias(&mut state.aliases, &name, &expansion);
+            }
+            CommandAction::Unalias(names) => {
+                for name in names {
+                    if !state.dir_env.unalias(&mut state.aliases, &name) {
+                        eprintln!("{}: unalias: {}: not found", DIR_FILE_NAME, name);
+                    }
+                }
+            }
+            CommandAction::Execute { program, args } => {
+                state
+                    .executor
+                    .execute(&program, args, None, &state.parse_options.exported);
+            }
+            CommandAction::DoNothing => {}
+            CommandAction::Error(msg) => eprintln!("{}: {}", DIR_FILE_NAME, msg),
+            _ => eprintln!("{}: unsupported command '{}'", DIR_FILE_NAME, line),
+        }
+    }
+}
+
+/// フォアグラウンドで実行する
+/// `--record` / `--log` 中は出力を取り込んで記録に回し、標準出力は `$?out` でも参照できるようにする
+fn execute_foreground(
+    state: &mut ReplState,
+    program: &str,
+    args: Vec<String>,
+    context: Option<&TargetContext>,
+) -> Foreground {
+    if state.recorder.is_none() && state.log.is_none() {
+        return state
+            .executor
+            .execute(program, args, context, &state.parse_options.exported);
+    }
+    let (result, output) =
+        state
+            .executor
+            .execute_capturing(program, args, context, &state.parse_options.exported);
+    state.captured.stdout.extend_from_slice(&output.stdout);
+    state.captured.stderr.extend_from_slice(&output.stderr);
+    state.last_output = Some(String::from_utf8_lossy(&output.stdout).into_owned());
+    result
+}
+
+/// フォアグラウンドで実行した結果を反映する (終了コード・セッション集計・要約行、停止ならジョブ登録)
+/// 子の with が全終了したときは ExitAll を返し、呼び出し側に後始末をしてから終了させる
+fn finish_foreground(
+    state: &mut ReplState,
+    command_line: String,
+    started: Instant,
+    result: Foreground,
+) -> StepResult {
+    let step = match result {
+        Foreground::ExitAll => StepResult::ExitAll,
+        _ => StepResult::Continue,
+    };
+    let code = match result {
+        Foreground::Stopped { child, upstream } => {
+            suspend_job(state, command_line, child, upstream);
+            return StepResult::Continue;
+        }
+        result => result.code(),
+    };
+    state.set_status(code);
+    state.session.record(code, started.elapsed());
+
+    if state.show_summary {
+        println!(
+            "{}",
+            format_summary(
+                &command_line,
+                code == Some(0),
+                started.elapsed(),
+                state.use_color
+            )
+        );
+    }
+    step
+}
+
+/// Ctrl+Z で停止したコマンドをジョブ一覧に登録してプロンプトに戻る
+fn suspend_job(state: &mut ReplState, command_line: String, child: Child, upstream: Vec<Child>) {
+    let id = state.jobs.add_stopped(command_line, child, upstream);
+    if let Some(job) = state.jobs.get_mut(Some(id)) {
+        // 端末の ^Z の後ろで改行してから表示する
+        println!();
+        println!("{}", format_job(job.id, job.state(), &job.command));
+    }
+}
+
+/// ピンをファイルに保存する (保存先が無ければ何もしない)
+fn save_state_pins(state: &ReplState) {
+    if let Some(path) = &state.pins_file {
+        save_pins(path, state.history.pinned());
+    }
+}
+
+/// `!!` (直前のコマンド)・`!n` (ID が n のコマンド)・`!p<n>` (n 番のピン) を履歴の内容に展開する純粋関数
+/// 展開しない入力は Ok(None)、該当する履歴が無ければ (削除済みの ID も) Err を返す
+/// `!ls` のような脱出コマンドはそのまま (履歴には `!` 付きの入力行が残る)
+pub fn expand_history(line: &str, history: &History) -> Result<Option<String>, String> {
+    let Some(rest) = line.strip_prefix('!') else {
+        return Ok(None);
+    };
+    let (event, tail) = match rest.find(char::is_whitespace) {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, ""),
+    };
+
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    let line = if event == "!" {
+        history.last().map(|e| e.line.as_str())
+    } else if is_number(event) {
+        event
+            .parse::<usize>()
+            .ok()
+            .and_then(|id| history.get(id))
+            .map(|e| e.line.as_str())
+    } else if let Some(number) = event.strip_prefix('p')
+        && is_number(number)
+    {
+        // `!p<n>`: n 番のピン
+        number
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| history.pinned().get(n.checked_sub(1)?))
+            .map(|line| line.as_str())
+    } else {
+        return Ok(None);
+    };
+
+    match line {
+        Some(line) => Ok(Some(format!("{}{}", line, tail))),
+        None => Err(format!("!{}: event not found", event)),
+    }
+}
+
+/// 入力中の `$?out` (直前の標準出力、末尾の改行は除く) と `$?` (直前の終了コード) を展開する純粋関数
+/// シングルクォート内とエスケープした `\$` は展開しない。値が無ければ空に展開する
+pub fn expand_status_vars(line: &str, status: Option<i32>, output: Option<&str>) -> String {
+    let status = status.map(|code| code.to_string()).unwrap_or_default();
+    let output = output
+        .map(|out| out.trim_end_matches(['\n', '\r']))
+        .unwrap_or("");
+
+    let mut expanded = String::with_capacity(line.len());
+    let mut in_single = false;
+    let mut in_double = false;
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let len = c.len_utf8();
+        match c {
+            '\\' if !in_single => {
+                // エスケープされた文字はそのまま残す (shell-words が解釈する)
+                let escaped = rest[len..].chars().next().map_or(0, char::len_utf8);
+                expanded.push_str(&rest[..len + escaped]);
+                rest = &rest[len + escaped..];
+                continue;
+            }
+            '\'' if !in_double => in_single = !in_single,
+            '"' if !in_single => in_double = !in_double,
+            '$' if !in_single => {
+                if let Some(after) = rest.strip_prefix("$?out") {
+                    expanded.push_str(output);
+                    rest = after;
+                    continue;
+                }
+                if let Some(after) = rest.strip_prefix("$?") {
+                    expanded.push_str(&status);
+                    rest = after;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        expanded.push(c);
+        rest = &rest[len..];
+    }
+    expanded
+}
+
+/// 入力1行をパースして実行する
+/// 終了の確認 (実行中ジョブの扱い) は呼び出し側に任せ、StepResult で終了要求だけを返す
+/// 履歴にはコンテキストを適用する前の入力行 (`!!` / `!n` は展開後) を保存する
+pub fn run_line(line: &str, state: &mut ReplState) -> StepResult {
+    let time = SystemTime::now();
+    let started = Instant::now();
+    state.status_set = false;
+    let result = run_input(line.trim(), state);
+    let output = std::mem::take(&mut state.captured);
+    let status = state.last_status.filter(|_| state.status_set);
+    if let Some(log) = &mut state.log {
+        log.append(&LogEntry {
+            time,
+            input: line.trim(),
+            stdout: &output.stdout,
+            stderr: &output.stderr,
+            status,
+            duration: started.elapsed(),
+        });
+    }
+    if let Some(recorder) = &mut state.recorder {
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        recorder.record(line.trim(), started, stdout, status);
+    }
+    result
+}
+
+/// 入力行を展開・パースして実行する
+fn run_input(line: &str, state: &mut ReplState) -> StepResult {
+    let expanded = match expand_history(line, &state.history) {
+        Ok(expanded) => expanded,
+        Err(msg) => {
+            eprintln!("Error: {}", msg);
+            return StepResult::Continue;
+        }
+    };
+    let line = match &expanded {
+        Some(expanded) => {
+            // 展開した場合は実際に実行する行を表示する
+            println!("{}", expanded);
+            expanded.as_str()
+        }
+        None => line,
+    };
+    let context_program = state.context_program().map(|s| s.to_string());
+    state.history.push_in(line, context_program);
+
+    // 履歴には `$?` のまま残し、実行する行だけを展開する
+    let line = &expand_status_vars(line, state.last_status, state.last_output.as_deref());
+    let line = &expand_alias(line, &state.aliases);
+    // マクロは名前だけで呼び出せる
+    if state.config.macros.contains_key(line.trim()) {
+        return run_macro(line.trim(), state);
+    }
+    let action = parse_cmd_with(line, state.context.as_ref(), &state.parse_options);
+    // コンテキストのコマンドとして実行した行だけ、失敗時に打ち間違いを案内する
+    let suggest = state.config.context.suggest_subcommands
+        && matches!(&action, CommandAction::Execute { program, .. }
+            if state.context_program() == Some(program.as_str()));
+    let result = run_action(action, state);
+    if suggest
+        && state.last_status.is_some_and(|code| code != 0)
+        && let Some(program) = state.context_program()
+    {
+        let table = state.config.subcommand_table();
+        let hint = subcommand_hint(
+            line,
+            program,
+            &table.subcommands(program),
+            &state.parse_options.templates,
+        );
+        if let Some(hint) = hint {
+            eprintln!("{}", hint);
+        }
+    }
+    result
+}
+
+/// 先頭の単語が未知のサブコマンドなら、近いサブコマンドの案内文を組み立てる純粋関数
+/// 例: "commti -m x" -> "Hint: 'commti' is not a git subcommand. Did you mean 'commit'?"
+/// 既知のサブコマンド・テンプレート・フラグの場合や、近い候補が無い場合は None
+fn subcommand_hint(
+    line: &str,
+    program: &str,
+    subcommands: &[&str],
+    templates: &BTreeMap<String, String>,
+) -> Option<String> {
+    let words = shell_words::split(line).ok()?;
+    let word = words.first()?;
+    if word.starts_with(['-', '!'])
+        || subcommands.contains(&word.as_str())
+        || templates.contains_key(word)
+    {
+        return None;
+    }
+    let suggestions = suggest_subcommands(word, subcommands);
+    if suggestions.is_empty() {
+        return None;
+    }
+    let quoted: Vec<String> = suggestions.iter().map(|s| format!("'{}'", s)).collect();
+    Some(format!(
+        "Hint: '{}' is not a {} subcommand. Did you mean {}?",
+        word,
+        program,
+        quoted.join(" or ")
+    ))
+}
+
+/// マクロのコマンドを順に実行する
+/// keep_going でなければ、失敗したところで残りを実行せずに止める
+fn run_macro(name: &str, state: &mut ReplState) -> StepResult {
+    let Some(definition) = state.config.macros.get(name).cloned() else {
+        eprintln!("macro: {}: not found", name);
+        return StepResult::Continue;
+    };
+    let total = definition.commands.len();
+    for (i, line) in definition.commands.iter().enumerate() {
+        println!("[{} {}/{}] {}", name, i + 1, total, line);
+        let line = expand_status_vars(line, state.last_status, state.last_output.as_deref());
+        let action = parse_cmd_with(&line, state.context.as_ref(), &state.parse_options);
+        // 内部コマンドは終了コードを変えないので、成功扱いから始める
+        let failed_to_parse = matches!(action, CommandAction::Error(_));
+        state.set_status(Some(0));
+        let result = run_action(action, state);
+        if result != StepResult::Continue {
+            return result;
+        }
+        if !definition.keep_going && (failed_to_parse || state.last_status != Some(0)) {
+            eprintln!("macro {}: stopped at '{}'", name, line);
+            break;
+        }
+    }
+    StepResult::Continue
+}
+
+/// `macro` のサブコマンドを実行する
+fn run_macro_command(command: MacroCommand, state: &mut ReplState) -> StepResult {
+    match command {
+        MacroCommand::Define {
+            name,
+            commands,
+            keep_going,
+        } => {
+            let definition = config::MacroDefinition {
+                commands,
+                keep_going,
+            };
+            save_macro(state, &name, Some(&definition));
+            state.config.macros.insert(name, definition);
+        }
+        MacroCommand::Run(name) => return run_macro(&name, state),
+        MacroCommand::List => {
+            for (name, definition) in &state.config.macros {
+                println!("{}  {}", name, definition.commands.join(" && "));
+            }
+        }
+        MacroCommand::Show(name) => match state.config.macros.get(&name) {
+            Some(definition) => {
+                for line in &definition.commands {
+                    println!("{}", line);
+                }
+                if definition.keep_going {
+                    println!("(keep going on failure)");
+                }
+            }
+            None => eprintln!("macro: {}: not found", name),
+        },
+        MacroCommand::Remove(name) => {
+            if state.config.macros.remove(&name).is_none() {
+                eprintln!("macro: {}: not found", name);
+            } else {
+                save_macro(state, &name, None);
+            }
+        }
+    }
+    StepResult::Continue
+}
+
+/// マクロの変更を設定ファイルへ反映する (失敗してもセッション中は使える)
+fn save_macro(state: &ReplState, name: &str, definition: Option<&config::MacroDefinition>) {
+    let Some(path) = &state.config_file else {
+        return;
+    };
+    if let Err(msg) = config::save_macro(path, name, definition) {
+        eprintln!("macro: {}: {}", path.display(), msg);
+    }
+}
+
+/// `config` のサブコマンドを実行する
+fn run_config(command: ConfigCommand, state: &mut ReplState) {
+    match command {
+        ConfigCommand::List => {
+            for (key, value) in config::list_values(&state.config) {
+                println!("{} = {}", key, value);
+            }
+        }
+        ConfigCommand::Get(key) => match config::get_value(&state.config, &key) {
+            Ok(value) => println!("{}", value),
+            Err(msg) => eprintln!("config: {}", msg),
+        },
+        ConfigCommand::Set(key, raw) => match config::set_value(&state.config, &key, &raw) {
+            Ok((updated, value)) => {
+                state.config_edits.retain(|(k, _)| *k != key);
+                state.config_edits.push((key, value));
+                state.apply_config(updated);
+            }
+            Err(msg) => eprintln!("config: {}", msg),
+        },
+        ConfigCommand::Save => {
+            let Some(path) = state.config_file.clone() else {
+                eprintln!("config: cannot determine the home directory");
+                return;
+            };
+            if state.config_edits.is_empty() {
+                println!("config: nothing to save");
+                return;
+            }
+            match config::save_values(&path, &state.config_edits) {
+                Ok(()) => {
+                    println!("Saved to {}", path.display());
+                    state.config_edits.clear();
+                }
+                Err(msg) => eprintln!("config: {}: {}", path.display(), msg),
+            }
+        }
+        ConfigCommand::Edit => {
+            let Some(path) = state.config_file.clone() else {
+                eprintln!("config: cannot determine the home directory");
+                return;
+            };
+            let editor = state
+                .env_var("EDITOR")
+                .unwrap_or_else(|| default_editor().to_string());
+            let mut args = match shell_words::split(&editor) {
+                Ok(args) if !args.is_empty() => args,
+                _ => {
+                    eprintln!("config: invalid EDITOR '{}'", editor);
+                    return;
+                }
+            };
+            let program = args.remove(0);
+            args.push(path.display().to_string());
+            if state
+                .executor
+                .execute(&program, args, None, &state.parse_options.exported)
+                .code()
+                != Some(0)
+            {
+                eprintln!("config: {} exited with an error", program);
+                return;
+            }
+            // 保存していない `config set` の値は、読み直した内容で置き換わる
+            if !state.config_edits.is_empty() {
+                eprintln!("config: discarded changes not saved with 'config save'");
+                state.config_edits.clear();
+            }
+            match config::reload_config(&path) {
+                Ok(updated) => state.apply_config(updated),
+                Err(msg) => eprintln!("config: failed to parse {}: {}", path.display(), msg),
+            }
+        }
+    }
+}
+
+/// EDITOR が未設定のときに使うエディタ
+fn default_editor() -> &'static str {
+    if cfg!(windows) { "notepad" } else { "vi" }
+}
+
+/// パース済みのアクションを実行する
+fn run_action(action: CommandAction, state: &mut ReplState) -> StepResult {
+    let context = state.context.clone();
+    let context = context.as_ref();
+
+    match action {
+        CommandAction::Execute { program, args } => {
+            let command_line = command_line(&program, &args);
+            state.executed.push(command_line.clone());
+            if state.show_title {
+                set_title(&running_title(&command_line));
+            }
+            let started = Instant::now();
+            let result = execute_foreground(state, &program, args, context);
+            return finish_foreground(state, command_line, started, result);
+        }
+        CommandAction::Redirect {
+            program,
+            args,
+            redirects,
+        } => {
+            let command_line = command_line(&program, &args);
+            state.executed.push(command_line.clone());
+            if state.show_title {
+                set_title(&running_title(&command_line));
+            }
+            let started = Instant::now();
+            let result = state.executor.execute_with_redirects(
+                &program,
+                args,
+                context,
+                &state.parse_options.exported,
+                &redirects,
+            );
+            return finish_foreground(state, command_line, started, result);
+        }
+        CommandAction::InDirectory { dir, program, args } => {
+            // 存在しないディレクトリではコマンドを起動しない
+            let path = PathBuf::from(&dir);
+            if !path.is_dir() {
+                eprintln!("in: {}: No such directory", dir);
+                state.set_status(Some(1));
+                return StepResult::Continue;
+            }
+            let command_line = command_line(&program, &args);
+            state.executed.push(command_line.clone());
+            if state.show_title {
+                set_title(&running_title(&command_line));
+            }
+            let started = Instant::now();
+            let result = state.executor.execute_in_dir(
+                &program,
+                args,
+                context,
+                &state.parse_options.exported,
+                &path,
+            );
+            return finish_foreground(state, command_line, started, result);
+        }
+        CommandAction::Tee {
+            program,
+            args,
+            files,
+            append,
+        } => {
+            let command_line = command_line(&program, &args);
+            state.executed.push(command_line.clone());
+            if state.show_title {
+                set_title(&running_title(&command_line));
+            }
+            let started = Instant::now();
+            let result = state.executor.execute_with_tee(
+                &program,
+                args,
+                context,
+                &state.parse_options.exported,
+                &files,
+                append,
+            );
+            return finish_foreground(state, command_line, started, result);
+        }
+        CommandAction::Pipeline {
+            stages,
+            redirects,
+            tee,
+        } => {
+            let command_line = stages
+                .iter()
+                .map(|(program, args)| command_line(program, args))
+                .collect::<Vec<_>>()
+                .join(" | ");
+            if state.show_title {
+                set_title(&running_title(&command_line));
+            }
+            state.executed.push(command_line.clone());
+            let started = Instant::now();
+            // `--record` / `--log` 中は最後の段の出力を取り込み、`$?out` でも参照できるようにする
+            let capture =
+                (state.recorder.is_some() || state.log.is_some()) && redirects.output.is_none();
+            let mut output = Vec::new();
+            let result = state.executor.execute_pipeline(
+                stages,
+                context,
+                &state.parse_options.exported,
+                &redirects,
+                tee.as_ref(),
+                capture.then_some(&mut output),
+            );
+            if capture {
+                state.captured.stdout.extend_from_slice(&output);
+                state.last_output = Some(String::from_utf8_lossy(&output).into_owned());
+            }
+            return finish_foreground(state, command_line, started, result);
+        }
+        CommandAction::List(actions) => {
+            for action in actions {
+                let result = run_action(action, state);
+                if result != StepResult::Continue {
+                    return result;
+                }
+            }
+        }
+        CommandAction::Sequence(stages) => {
+            for (op, stage) in stages {
+                // 実行しなかった段は終了コードを変えない
+                if !op.should_run(state.last_status) {
+                    continue;
+                }
+                let CommandAction::Execute { program, args } = stage else {
+                    // 内部コマンドは成功扱いから始め、失敗したら (cd できないなど) 1 になる
+                    state.set_status(Some(0));
+                    let result = run_action(stage, state);
+                    if result != StepResult::Continue {
+                        return result;
+                    }
+                    continue;
+                };
+                let command_line = command_line(&program, &args);
+                state.executed.push(command_line.clone());
+                if state.show_title {
+                    set_title(&running_title(&command_line));
+                }
+                let started = Instant::now();
+                let result = execute_foreground(state, &program, args, context);
+                // Ctrl+Z で止めたら残りの段は実行しない
+                let stopped = matches!(result, Foreground::Stopped { .. });
+                let step = finish_foreground(state, command_line, started, result);
+                if step != StepResult::Continue {
+                    return step;
+                }
+                if stopped {
+                    break;
+                }
+            }
+        }
+        CommandAction::Background { program, args } => {
+            let command_line = command_line(&program, &args);
+            state.executed.push(command_line.clone());
+            match state.executor.spawn_background(
+                &program,
+                args,
+                context,
+                &state.parse_options.exported,
+            ) {
+                Ok(child) => {
+                    let pid = child.id();
+                    let id = state.jobs.add(command_line, child);
+                    println!("[{}] {}", id, pid);
+                }
+                Err(e) => eprintln!("Failed to execute command '{}': {}", program, e),
+            }
+        }
+        CommandAction::Jobs => {
+            for job in state.jobs.running() {
+                println!("{}", format_job(job.id, job.state(), &job.command));
+            }
+        }
+        CommandAction::Fg(id) => {
+            let Some(job) = state.jobs.get_mut(id) else {
+                eprintln!("fg: no such job");
+                return StepResult::Continue;
+            };
+            println!("{}", job.command);
+            job.stopped = false;
+            match resume_foreground(&job.processes()) {
+                Ok(WaitResult::Exited(code)) => {
+                    let id = job.id;
+                    state.jobs.remove(id);
+                    state.set_status(code);
+                }
+                Ok(WaitResult::Stopped) => {
+                    job.stopped = true;
+                    println!();
+                    println!("{}", format_job(job.id, job.state(), &job.command));
+                }
+                Err(e) => eprintln!("fg: {}", e),
+            }
+        }
+        CommandAction::Bg(id) => {
+            let Some(job) = state.jobs.get_mut(id) else {
+                eprintln!("bg: no such job");
+                return StepResult::Continue;
+            };
+            match continue_job(job.leader()) {
+                Ok(()) => {
+                    job.stopped = false;
+                    println!("[{}] {} &", job.id, job.command);
+                }
+                Err(e) => eprintln!("bg: {}", e),
+            }
+        }
+        CommandAction::Disown(id) => match state.jobs.disown(id) {
+            Some(job) => println!("{}", format_job(job.id, "Disowned", &job.command)),
+            None => eprintln!("disown: no such job"),
+        },
+        CommandAction::ChangeDirectory(target) => {
+            if let Some(path) = target {
+                // `cd @name` は設定ファイルのブックマークへ移動する
+                let path = if path.starts_with('@') {
+                    match bookmark_target(&state.config, &path) {
+                        Some(resolved) => resolved,
+                        None => {
+                            state.set_status(Some(1));
+                            return StepResult::Continue;
+                        }
+                    }
+                } else {
+                    PathBuf::from(path)
+                };
+                match env::set_current_dir(&path) {
+                    Ok(()) => {
+                        let cwd = env::current_dir().unwrap_or_default();
+                        if state.report_cwd {
+                            report_cwd(&cwd);
+                        }
+                        if state.config.dir_env.enabled {
+                            let restore = state.config.dir_env.restore_on_leave;
+                            let lines = state.dir_env.on_change_dir(
+                                &mut state.parse_options.exported,
+                                &mut state.aliases,
+                                &cwd,
+                                restore,
+                            );
+                            run_dir_script(&lines, state);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to change directory: {}", e);
+                        state.set_status(Some(1));
+                    }
+                }
+            }
+        }
+        CommandAction::Export(vars) => {
+            if vars.is_empty() {
+                let mut all: BTreeMap<String, String> = env::vars().collect();
+                all.extend(state.parse_options.exported.clone());
+                for (key, value) in all {
+                    println!("{}={}", key, value);
+                }
+            }
+            // with 自身の環境変数は変えず、子プロセスの起動と `$VAR` の展開に使う
+            state.parse_options.exported.extend(vars);
+        }
+        CommandAction::Alias { name, expansion } => {
+            state.aliases.insert(name, expansion);
+        }
+        CommandAction::Aliases => {
+            for (name, expansion) in &state.aliases {
+                println!("alias {}={}", name, shell_words::quote(expansion));
+            }
+        }
+        CommandAction::Unalias(names) => {
+            for name in names {
+                if state.aliases.remove(&name).is_none() {
+                    eprintln!("unalias: {}: not found", name);
+                }
+            }
+        }
+        CommandAction::Clear(args) => {
+            state
+                .executor
+                .execute("clear", args, None, &state.parse_options.exported);
+        }
+        CommandAction::Pwd(args) => {
+            state
+                .executor
+                .execute("pwd", args, None, &state.parse_options.exported);
+        }
+        CommandAction::History => {
+            for entry in state.history.entries() {
+                let line = format_history_line(entry.id, &entry.line, None, false);
+                // ピン留めしたものは `!p<n>` の番号を添える
+                match state.history.pin_number(&entry.line) {
+                    Some(number) => println!("{}  [p{}]", line, number),
+                    None => println!("{}", line),
+                }
+            }
+        }
+        CommandAction::HistoryClear => state.history.clear(),
+        CommandAction::HistoryPin(id) => match state.history.pin(id) {
+            Ok(number) => {
+                println!("Pinned as !p{}", number);
+                save_state_pins(state);
+            }
+            Err(msg) => {
+                eprintln!("{}", msg);
+                state.set_status(Some(1));
+            }
+        },
+        CommandAction::HistoryUnpin(number) => match state.history.unpin(number) {
+            Some(line) => {
+                println!("Unpinned: {}", line);
+                save_state_pins(state);
+            }
+            None => {
+                eprintln!("history unpin: p{}: no such pin", number);
+                state.set_status(Some(1));
+            }
+        },
+        CommandAction::HistoryPinned => {
+            for (i, line) in state.history.pinned().iter().enumerate() {
+                println!("{: >3}: {}", format!("p{}", i + 1), line);
+            }
+        }
+        CommandAction::HistorySearch {
+            pattern,
+            regex,
+            ignore_case,
+        } => match build_search_regex(&pattern, regex, ignore_case) {
+            Ok(re) => {
+                for (number, entry) in search_history(&state.history, &re) {
+                    println!(
+                        "{}",
+                        format_history_line(number, entry, Some(&re), state.use_color)
+                    );
+                }
+            }
+            Err(e) => eprintln!("Error: invalid pattern: {}", e),
+        },
+        CommandAction::Stats { unit, limit } => {
+            let entries = state.history.lines();
+            for row in format_ranking(&rank_commands(entries, unit, limit)) {
+                println!("{}", row);
+            }
+        }
+        CommandAction::SwitchContext(context) => state.switch_context(context),
+        CommandAction::Cheatsheet { output } => {
+            match (
+                format_cheatsheet(state.history.entries(), DEFAULT_STATS_LIMIT),
+                output,
+            ) {
+                (None, _) => println!("no data"),
+                (Some(markdown), None) => print!("{}", markdown),
+                (Some(markdown), Some(path)) => match fs::write(&path, markdown) {
+                    Ok(()) => println!("Wrote cheatsheet to {}", path),
+                    Err(e) => eprintln!("cheatsheet: {}: {}", path, e),
+                },
+            }
+        }
+        CommandAction::Config(command) => run_config(command, state),
+        CommandAction::Macro(command) => return run_macro_command(command, state),
+        CommandAction::Help => print_help(&state.key_bindings),
+        CommandAction::DoNothing => {}
+        CommandAction::Exit => return StepResult::Exit,
+        CommandAction::ExitAll => return StepResult::ExitAll,
+        CommandAction::Error(msg) => eprintln!("Error: {}", msg),
+    }
+    StepResult::Continue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::EXIT_ALL_CODE;
+    use crate::history::load_pins;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// 実行内容を記録するだけの Executor
+    #[derive(Default)]
+    struct RecordingExecutor {
+        calls: Rc<RefCell<Vec<String>>>,
+        exit_code: i32,
+        /// exit_code に関係なく成功 (0) を返すプログラム
+        succeeding: Vec<&'static str>,
+    }
+
+    impl RecordingExecutor {
+        fn code_for(&self, program: &str) -> i32 {
+            if self.succeeding.contains(&program) {
+                0
+            } else {
+                self.exit_code
+            }
+        }
+    }
+
+    impl Executor for RecordingExecutor {
+        fn execute(
+            &mut self,
+            program: &str,
+            args: Vec<String>,
+            _context: Option<&TargetContext>,
+            _exported: &BTreeMap<String, String>,
+        ) -> Foreground {
+            self.calls.borrow_mut().push(command_line(program, &args));
+            Foreground::exited(Some(self.code_for(program)))
+        }
+
+        fn execute_with_redirects(
+            &mut self,
+            program: &str,
+            args: Vec<String>,
+            _context: Option<&TargetContext>,
+            _exported: &BTreeMap<String, String>,
+            redirects: &Redirects,
+        ) -> Foreground {
+            let mut line = command_line(program, &args);
+            if let Some(input) = &redirects.input {
+                line = format!("{} < {}", line, input);
+            }
+            if let Some(output) = &redirects.output {
+                let op = if output.append { ">>" } else { ">" };
+                line = format!("{} {} {}", line, op, output.path);
+            }
+            self.calls.borrow_mut().push(line);
+            Foreground::exited(Some(self.exit_code))
+        }
+
+        fn execute_in_dir(
+            &mut self,
+            program: &str,
+            args: Vec<String>,
+            _context: Option<&TargetContext>,
+            _exported: &BTreeMap<String, String>,
+            dir: &Path,
+        ) -> Foreground {
+            self.calls.borrow_mut().push(format!(
+                "in {} {}",
+                dir.display(),
+                command_line(program, &args)
+            ));
+            Foreground::exited(Some(self.code_for(program)))
+        }
+
+        fn execute_with_tee(
+            &mut self,
+            program: &str,
+            args: Vec<String>,
+            _context: Option<&TargetContext>,
+            _exported: &BTreeMap<String, String>,
+            files: &[String],
+            _append: bool,
+        ) -> Foreground {
+            self.calls.borrow_mut().push(format!(
+                "{} | tee {}",
+                command_line(program, &args),
+                files.join(" ")
+            ));
+            Foreground::exited(Some(self.exit_code))
+        }
+
+        fn execute_capturing(
+            &mut self,
+            program: &str,
+            args: Vec<String>,
+            _context: Option<&TargetContext>,
+            _exported: &BTreeMap<String, String>,
+        ) -> (Foreground, CapturedOutput) {
+            let line = command_line(program, &args);
+            self.calls.borrow_mut().push(line.clone());
+            let output = CapturedOutput {
+                stdout: format!("output of {}\n", line).into_bytes(),
+                stderr: format!("error of {}\n", line).into_bytes(),
+            };
+            (Foreground::exited(Some(self.code_for(program))), output)
+        }
+
+        fn execute_pipeline(
+            &mut self,
+            stages: Vec<(String, Vec<String>)>,
+            _context: Option<&TargetContext>,
+            _exported: &BTreeMap<String, String>,
+            redirects: &Redirects,
+            tee: Option<&TeeOutput>,
+            capture: Option<&mut Vec<u8>>,
+        ) -> Foreground {
+            let mut line = stages
+                .iter()
+                .map(|(program, args)| command_line(program, args))
+                .collect::<Vec<_>>()
+                .join(" | ");
+            if let Some(input) = &redirects.input {
+                line = format!("{} < {}", line, input);
+            }
+            if let Some(output) = &redirects.output {
+                let op = if output.append { ">>" } else { ">" };
+                line = format!("{} {} {}", line, op, output.path);
+            }
+            if let Some(tee) = tee {
+                line = format!("{} | tee {}", line, tee.files.join(" "));
+            }
+            if let Some(buf) = capture {
+                buf.extend_from_slice(format!("output of {}\n", line).as_bytes());
+            }
+            self.calls.borrow_mut().push(line);
+            Foreground::exited(Some(self.exit_code))
+        }
+
+        fn spawn_background(
+            &mut self,
+            _program: &str,
+            _args: Vec<String>,
+            _context: Option<&TargetContext>,
+            _exported: &BTreeMap<String, String>,
+        ) -> io::Result<Child> {
+            Err(io::Error::other("not supported in tests"))
+        }
+    }
+
+    fn create_state(context: Option<(&str, &[&str])>) -> (ReplState, Rc<RefCell<Vec<String>>>) {
+        let context = context.map(|(program, args)| TargetContext {
+            program: program.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        });
+        let mut state = ReplState::new(context, config::Config::default());
+        // テストからホームの設定ファイルへ書き込まない
+        state.config_file = None;
+        let executor = RecordingExecutor {
+            exit_code: 1,
+            ..Default::default()
+        };
+        let calls = executor.calls.clone();
+        state.executor = Box::new(executor);
+        (state, calls)
+    }
+
+    #[test]
+    fn test_run_line_executes_in_context() {
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        assert_eq!(run_line("status -s", &mut state), StepResult::Continue);
+        assert_eq!(calls.borrow().as_slice(), ["git status -s"]);
+        assert_eq!(state.last_status, Some(1));
+    }
+
+    #[test]
+    fn test_run_line_tee_and_escape() {
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        run_line("log | tee out.txt", &mut state);
+        assert_eq!(state.last_status, Some(1));
+        assert_eq!(state.executed, ["git log"]);
+        assert_eq!(state.session.commands, 1);
+        run_line("!ls -a", &mut state);
+        run_line("log | grep fix | wc -l", &mut state);
+        assert_eq!(
+            calls.borrow().as_slice(),
+            [
+                "git log | tee out.txt",
+                "ls -a",
+                "git log | grep fix | wc -l"
+            ]
+        );
+        run_line("log | grep fix | tee out.txt", &mut state);
+        assert_eq!(
+            calls.borrow().last().unwrap(),
+            "git log | grep fix | tee out.txt"
+        );
+    }
+
+    #[test]
+    fn test_run_line_failed_builtin_sets_status() {
+        let (mut state, _calls) = create_state(Some(("git", &[])));
+        state.last_status = Some(0);
+        run_line("cd ./no-such-dir-for-with-test", &mut state);
+        assert_eq!(state.last_status, Some(1));
+    }
+
+    #[test]
+    fn test_run_line_in_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().display().to_string();
+        let (mut state, calls) = create_state(Some(("cargo", &[])));
+        let cwd = env::current_dir().unwrap();
+
+        run_line(&format!("in '{}' build", dir), &mut state);
+        assert_eq!(
+            calls.borrow().as_slice(),
+            [format!("in {} cargo build", dir)]
+        );
+        // with 自身のカレントディレクトリは変わらない
+        assert_eq!(env::current_dir().unwrap(), cwd);
+
+        // 存在しないディレクトリでは実行しない
+        run_line("in ./with-no-such-dir build", &mut state);
+        assert_eq!(calls.borrow().len(), 1);
+        assert_eq!(state.last_status, Some(1));
+    }
+
+    #[test]
+    fn test_run_line_list_with_cd() {
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        // 他のテストに影響しないよう、カレントディレクトリは変えずに cd を混ぜる
+        assert_eq!(
+            run_line("cd .; status; pwd", &mut state),
+            StepResult::Continue
+        );
+        assert_eq!(calls.borrow().as_slice(), ["git status", "pwd"]);
+        // 1行で入力したものは履歴でも1件
+        assert_eq!(state.history.entries().len(), 1);
+
+        // 途中の exit で残りは実行しない
+        assert_eq!(run_line("status; q; log", &mut state), StepResult::Exit);
+        assert_eq!(calls.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_run_line_config_set_applies_immediately() {
+        let (mut state, calls) = create_state(Some(("cargo", &[])));
+        run_line("config set cd.strict true", &mut state);
+        assert!(state.config.cd.strict);
+        assert!(state.parse_options.strict_cd);
+        assert!(state.config_changed);
+        assert_eq!(state.config_edits.len(), 1);
+
+        // 同じキーを変えたら未保存の値は1件のまま
+        run_line("config set cd.strict false", &mut state);
+        assert_eq!(state.config_edits.len(), 1);
+
+        // 無効なキー・型の合わない値は変更しない
+        state.config_changed = false;
+        run_line("config set cd.unknown true", &mut state);
+        run_line("config set git.timeout_ms soon", &mut state);
+        assert!(!state.config_changed);
+        assert_eq!(state.config.git.timeout_ms, 500);
+        assert!(calls.borrow().is_empty());
+
+        // git のコンテキストでは git config になる
+        state.switch_context(Some(TargetContext {
+            program: "git".to_string(),
+            ..Default::default()
+        }));
+        run_line("config --list", &mut state);
+        assert_eq!(calls.borrow().as_slice(), ["git config --list"]);
+    }
+
+    #[test]
+    fn test_run_line_cheatsheet_to_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("cheatsheet.md");
+        let (mut state, _) = create_state(Some(("git", &[])));
+        let line = format!("cheatsheet {}", path.display());
+
+        // 履歴が空ならファイルを作らない
+        run_line(&line, &mut state);
+        assert!(!path.exists());
+
+        run_line("status", &mut state);
+        run_line(&line, &mut state);
+        let markdown = fs::read_to_string(&path).unwrap();
+        assert!(markdown.contains("## git"));
+        assert!(markdown.contains("| `git status` | 1 |"));
+    }
+
+    #[test]
+    fn test_run_line_alias() {
+        let (mut state, calls) = create_state(None);
+        run_line("alias gs=\"git status\"", &mut state);
+        run_line("gs -s", &mut state);
+        assert_eq!(calls.borrow().as_slice(), ["git status -s"]);
+        // 履歴には入力したままの行が残る
+        assert_eq!(state.history.entries()[1].line, "gs -s");
+
+        // 自分自身を参照するエイリアスは1度だけ展開する
+        run_line("alias ls=\"ls -F\"", &mut state);
+        run_line("ls", &mut state);
+        assert_eq!(calls.borrow().last().unwrap(), "ls -F");
+
+        run_line("unalias gs", &mut state);
+        run_line("gs", &mut state);
+        assert_eq!(calls.borrow().last().unwrap(), "gs");
+        assert_eq!(state.aliases.len(), 1);
+    }
+
+    #[test]
+    fn test_run_dir_script_aliases() {
+        let (mut state, calls) = create_state(None);
+        run_line("alias gs=\"git status\"", &mut state);
+        let lines = [
+            "export RUST_LOG=debug".to_string(),
+            "alias t=\"cargo test\"".to_string(),
+            "unalias gs".to_string(),
+        ];
+        run_dir_script(&lines, &mut state);
+        assert!(calls.borrow().is_empty());
+        assert_eq!(state.env_var("RUST_LOG").as_deref(), Some("debug"));
+        assert_eq!(
+            state.aliases,
+            BTreeMap::from([("t".to_string(), "cargo test".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_run_line_macro() {
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        let tmp = tempfile::tempdir().unwrap();
+        state.config_file = Some(tmp.path().join(".withrc"));
+
+        // 登録したマクロは設定ファイルにも保存される
+        run_line(
+            r#"macro define deploy pull "!cargo build" push"#,
+            &mut state,
+        );
+        let saved = config::reload_config(state.config_file.as_ref().unwrap()).unwrap();
+        assert_eq!(saved.macros["deploy"].commands.len(), 3);
+
+        // 名前だけで呼び出せ、失敗したところで止まる
+        run_line("deploy", &mut state);
+        assert_eq!(calls.borrow().as_slice(), ["git pull"]);
+
+        run_line(
+            r#"macro define --keep-going deploy pull "!cargo build""#,
+            &mut state,
+        );
+        run_line("macro run deploy", &mut state);
+        assert_eq!(
+            calls.borrow().as_slice(),
+            ["git pull", "git pull", "cargo build"]
+        );
+
+        run_line("macro remove deploy", &mut state);
+        assert!(state.config.macros.is_empty());
+    }
+
+    #[test]
+    fn test_run_line_sequence() {
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        // 先頭が失敗したら && の後ろは実行せず、|| の後ろは実行する
+        run_line("fetch && rebase && push || status", &mut state);
+        assert_eq!(calls.borrow().as_slice(), ["git fetch", "git status"]);
+        assert_eq!(state.last_status, Some(1));
+
+        let (mut state, calls) = create_state(None);
+        state.executor = Box::new(RecordingExecutor {
+            calls: calls.clone(),
+            exit_code: 1,
+            succeeding: vec!["true"],
+        });
+        run_line("true && make || echo failed", &mut state);
+        assert_eq!(calls.borrow().as_slice(), ["true", "make", "echo failed"]);
+    }
+
+    #[test]
+    fn test_run_line_sequence_with_builtins() {
+        let (mut state, calls) = create_state(Some(("cargo", &[])));
+        state.executor = Box::new(RecordingExecutor {
+            calls: calls.clone(),
+            exit_code: 1,
+            succeeding: vec!["cargo"],
+        });
+        // 他のテストに影響しないよう、カレントディレクトリは変えずに cd を混ぜる
+        state.last_status = Some(1);
+        run_line("cd . && build", &mut state);
+        assert_eq!(calls.borrow().as_slice(), ["cargo build"]);
+        assert_eq!(state.last_status, Some(0));
+
+        // cd に失敗したら && の後ろは飛ばし、|| の後ろを実行する
+        calls.borrow_mut().clear();
+        run_line(
+            "cd ./no-such-dir-for-with-test && build || clean",
+            &mut state,
+        );
+        assert_eq!(calls.borrow().as_slice(), ["cargo clean"]);
+
+        calls.borrow_mut().clear();
+        run_line(
+            "cd ./no-such-dir-for-with-test || export WITH_TEST_CHAIN=1",
+            &mut state,
+        );
+        assert!(calls.borrow().is_empty());
+        assert_eq!(state.last_status, Some(0));
+        assert_eq!(state.env_var("WITH_TEST_CHAIN").as_deref(), Some("1"));
+        // with 自身の環境変数は変えない
+        assert!(env::var("WITH_TEST_CHAIN").is_err());
+
+        // 途中の exit で残りは実行しない
+        assert_eq!(run_line("build && q && test", &mut state), StepResult::Exit);
+        assert_eq!(calls.borrow().as_slice(), ["cargo build"]);
+    }
+
+    #[test]
+    fn test_run_line_counts_session() {
+        let (mut state, _) = create_state(Some(("git", &[])));
+        run_line("status", &mut state);
+        run_line("log | tee out.txt", &mut state);
+        run_line("pwd", &mut state);
+        assert_eq!(state.session.commands, 2);
+        assert_eq!(state.session.failures, 2);
+
+        // 設定で有効にしたときだけサマリを返す
+        assert!(state.session_summary().is_none());
+        state.show_session_summary = true;
+        assert!(
+            state
+                .session_summary()
+                .unwrap()
+                .starts_with("Session: 2 commands, 2 failed")
+        );
+    }
+
+    #[test]
+    fn test_run_line_history() {
+        let (mut state, _) = create_state(None);
+        run_line("echo a", &mut state);
+        run_line("echo a", &mut state);
+        run_line("  ", &mut state);
+        run_line("history", &mut state);
+        assert_eq!(
+            state.history.lines().collect::<Vec<_>>(),
+            vec!["echo a", "history"]
+        );
+    }
+
+    #[test]
+    fn test_run_line_history_ids_survive_clear() {
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        run_line("status", &mut state);
+        run_line("log", &mut state);
+        run_line("history clear", &mut state);
+        run_line("push", &mut state);
+
+        // 消した ID は再利用されず、`!1` が別のコマンドを呼ぶことはない
+        assert_eq!(state.history.last().map(|e| e.id), Some(4));
+        run_line("!1", &mut state);
+        run_line("!4", &mut state);
+        assert_eq!(
+            calls.borrow().as_slice(),
+            ["git status", "git log", "git push", "git push"]
+        );
+    }
+
+    #[test]
+    fn test_expand_history() {
+        let mut history = History::default();
+        history.push("status");
+        history.push("!ls -a");
+        assert_eq!(
+            expand_history("!!", &history),
+            Ok(Some("!ls -a".to_string()))
+        );
+        assert_eq!(
+            expand_history("!1 -s", &history),
+            Ok(Some("status -s".to_string()))
+        );
+        assert!(expand_history("!3", &history).is_err());
+        assert!(expand_history("!!", &History::default()).is_err());
+
+        // 脱出コマンドや通常の入力は展開しない
+        assert_eq!(expand_history("!ls", &history), Ok(None));
+        assert_eq!(expand_history("! 1", &history), Ok(None));
+        assert_eq!(expand_history("log", &history), Ok(None));
+    }
+
+    #[test]
+    fn test_run_line_history_pin() {
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        let tmp = tempfile::tempdir().unwrap();
+        let pins = tmp.path().join(".with_pins");
+        state.pins_file = Some(pins.clone());
+        run_line("status", &mut state);
+        run_line("log --oneline", &mut state);
+        run_line("history pin 2", &mut state);
+        assert_eq!(load_pins(&pins), ["log --oneline"]);
+
+        // ピンは `history clear` でも消えず、`!p<n>` で呼び出せる
+        run_line("history clear", &mut state);
+        run_line("!p1", &mut state);
+        assert_eq!(
+            calls.borrow().as_slice(),
+            ["git status", "git log --oneline", "git log --oneline"]
+        );
+        assert!(expand_history("!p2", &state.history).is_err());
+
+        run_line("history pin 99", &mut state);
+        assert_eq!(state.last_status, Some(1));
+        run_line("history unpin 1", &mut state);
+        assert!(state.history.pinned().is_empty());
+        assert!(load_pins(&pins).is_empty());
+    }
+
+    #[test]
+    fn test_expand_status_vars() {
+        assert_eq!(expand_status_vars("echo $?", Some(2), None), "echo 2");
+        assert_eq!(
+            expand_status_vars("echo \"$?out\" $?", Some(0), Some("main\n")),
+            "echo \"main\" 0"
+        );
+        // 出力キャプチャが無効 (None) なら空、終了コードが無ければ空
+        assert_eq!(expand_status_vars("echo $?out-$?", None, None), "echo -");
+        // シングルクォート内とエスケープは展開しない
+        assert_eq!(
+            expand_status_vars(r"echo '$?' \$?", Some(1), None),
+            r"echo '$?' \$?"
+        );
+    }
+
+    #[test]
+    fn test_run_line_expands_status() {
+        let (mut state, calls) = create_state(None);
+        run_line("false", &mut state);
+        run_line("echo $?", &mut state);
+        assert_eq!(calls.borrow().as_slice(), ["false", "echo 1"]);
+        assert_eq!(state.history.last().unwrap().line, "echo $?");
+    }
+
+    #[test]
+    fn test_run_line_history_keeps_escape_form() {
+        // 履歴には最終的なコマンド (git ls) ではなく入力行 (!ls) を保存する
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        run_line("status", &mut state);
+        run_line("!ls", &mut state);
+        run_line("!!", &mut state);
+        run_line("!1", &mut state);
+        assert_eq!(
+            state.history.lines().collect::<Vec<_>>(),
+            vec!["status", "!ls", "status"]
+        );
+        assert_eq!(
+            calls.borrow().as_slice(),
+            ["git status", "ls", "ls", "git status"]
+        );
+    }
+
+    #[test]
+    fn test_run_line_switch_context() {
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        run_line("status", &mut state);
+        run_line("switch-context cargo", &mut state);
+        run_line("build", &mut state);
+        run_line("ctx npm run", &mut state);
+        run_line("test", &mut state);
+        run_line("ctx", &mut state);
+        run_line("ls", &mut state);
+
+        assert_eq!(
+            calls.borrow().as_slice(),
+            ["git status", "cargo build", "npm run test", "ls"]
+        );
+        assert_eq!(state.context, None);
+        // 履歴は切り替え前のものも残る
+        assert_eq!(state.history.lines().next(), Some("status"));
+    }
+
+    #[test]
+    fn test_run_passthrough() {
+        let (mut state, calls) = create_state(Some(("grep", &["-i", "foo"])));
+        assert_eq!(state.run_passthrough(), Some(1));
+        assert_eq!(calls.borrow().as_slice(), ["grep -i foo"]);
+        // 履歴には残さない
+        assert!(state.history.entries().is_empty());
+
+        let (mut state, calls) = create_state(None);
+        assert_eq!(state.run_passthrough(), None);
+        assert!(calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_run_line_records_executed() {
+        let (mut state, _calls) = create_state(Some(("git", &[])));
+        run_line("status", &mut state);
+        run_line("jobs", &mut state);
+        run_line("!ls -a", &mut state);
+        assert_eq!(state.executed, vec!["git status", "ls -a"]);
+    }
+
+    #[test]
+    fn test_run_line_appends_to_log() {
+        let (mut state, _calls) = create_state(Some(("git", &[])));
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("with.log");
+        state.log = Some(SessionLog::new(path.clone(), &["secret".to_string()]).unwrap());
+        run_line("status", &mut state);
+        run_line("push secret", &mut state);
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("$ status (exit 1, "));
+        assert!(
+            content.contains("--- stdout\noutput of git status\n--- stderr\nerror of git status\n")
+        );
+        assert!(!content.contains("secret"));
+        assert_eq!(
+            state.last_output.as_deref(),
+            Some("output of git push secret\n")
+        );
+    }
+
+    #[test]
+    fn test_run_line_pipeline_finishes_like_execute() {
+        let (mut state, _calls) = create_state(Some(("git", &[])));
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("with.log");
+        state.log = Some(SessionLog::new(path.clone(), &[]).unwrap());
+        run_line("log | grep fix", &mut state);
+        assert_eq!(state.session.commands, 1);
+        assert_eq!(state.session.failures, 1);
+        assert_eq!(
+            state.last_output.as_deref(),
+            Some("output of git log | grep fix\n")
+        );
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("--- stdout\noutput of git log | grep fix\n"));
+
+        // ファイルへ書き出す場合は取り込まない
+        run_line("log | grep fix > out.txt", &mut state);
+        assert_eq!(state.session.commands, 2);
+        assert_eq!(
+            state.last_output.as_deref(),
+            Some("output of git log | grep fix\n")
+        );
+    }
+
+    #[test]
+    fn test_run_line_records_session() {
+        let (mut state, _calls) = create_state(Some(("git", &[])));
+        let tmp = tempfile::tempdir().unwrap();
+        state.recorder =
+            Some(Recorder::new(tmp.path().join("rec.json"), &["secret".to_string()]).unwrap());
+        run_line("status", &mut state);
+        run_line("push secret", &mut state);
+        run_line("jobs", &mut state);
+
+        assert_eq!(
+            state.last_output.as_deref(),
+            Some("output of git push secret\n")
+        );
+        let events = state.recorder.as_ref().unwrap().recording().events;
+        let recorded: Vec<_> = events
+            .iter()
+            .map(|e| (e.input.as_str(), e.output.as_str(), e.status))
+            .collect();
+        // 除外パターンに一致した行は残さず、内部コマンドは出力・終了コードなしで残す
+        assert_eq!(
+            recorded,
+            vec![
+                ("status", "output of git status\n", Some(1)),
+                ("jobs", "", None),
+            ]
+        );
+        // 終了コードを決める内部コマンド (cd の失敗など) はその終了コードを残す
+        run_line("cd ./no-such-dir-for-with-test", &mut state);
+        let events = state.recorder.as_ref().unwrap().recording().events;
+        assert_eq!(events.last().map(|e| e.status), Some(Some(1)));
+    }
+
+    #[test]
+    fn test_run_line_templates_follow_context() {
+        let config =
+            config::parse_config("[contexts.git.templates]\ncm = \"commit -m {}\"\n").unwrap();
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        state.config = config;
+        state.switch_context(state.context.clone());
+        run_line("cm 'fix bug'", &mut state);
+        run_line("ctx cargo", &mut state);
+        run_line("cm x", &mut state);
+        assert_eq!(
+            calls.borrow().as_slice(),
+            ["git commit -m fix bug", "cargo cm x"]
+        );
+    }
+
+    #[test]
+    fn test_context_env_from_config() {
+        let config =
+            config::parse_config("[contexts.cargo]\nenv = { RUST_LOG = \"info\", A = \"1\" }\n")
+                .unwrap();
+        let ctx = TargetContext {
+            program: "cargo".to_string(),
+            env: vec![("RUST_LOG".to_string(), "debug".to_string())],
+            ..Default::default()
+        };
+
+        // 起動時の --env が設定ファイルより優先される
+        let mut state = ReplState::new(Some(ctx), config);
+        assert_eq!(
+            state.context.as_ref().unwrap().env,
+            vec![
+                ("A".to_string(), "1".to_string()),
+                ("RUST_LOG".to_string(), "debug".to_string()),
+            ]
+        );
+
+        // 切り替え先のコンテキストにも設定ファイルの定義が適用される
+        run_line("ctx git", &mut state);
+        assert!(state.context.as_ref().unwrap().env.is_empty());
+        run_line("ctx cargo", &mut state);
+        assert_eq!(
+            state.context.as_ref().unwrap().env,
+            vec![
+                ("A".to_string(), "1".to_string()),
+                ("RUST_LOG".to_string(), "info".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subcommand_hint() {
+        let git = ["commit", "checkout", "status", "stash"];
+        let templates = BTreeMap::from([("cm".to_string(), "commit -m {}".to_string())]);
+        assert_eq!(
+            subcommand_hint("commti -m 'fix'", "git", &git, &templates).as_deref(),
+            Some("Hint: 'commti' is not a git subcommand. Did you mean 'commit'?")
+        );
+        assert_eq!(
+            subcommand_hint("stasus", "git", &git, &templates).as_deref(),
+            Some("Hint: 'stasus' is not a git subcommand. Did you mean 'status' or 'stash'?")
+        );
+        // 既知のサブコマンド・テンプレート・フラグ・遠い単語では案内しない
+        assert_eq!(
+            subcommand_hint("commit -m x", "git", &git, &templates),
+            None
+        );
+        assert_eq!(subcommand_hint("cm x", "git", &git, &templates), None);
+        assert_eq!(subcommand_hint("--version", "git", &git, &templates), None);
+        assert_eq!(subcommand_hint("frobnicate", "git", &git, &templates), None);
+        assert_eq!(subcommand_hint("", "git", &git, &templates), None);
+    }
+
+    #[test]
+    fn test_run_line_exit() {
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        assert_eq!(run_line("quit", &mut state), StepResult::Exit);
+        assert_eq!(run_line("exit", &mut state), StepResult::ExitAll);
+        assert!(calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_run_line_child_exit_all() {
+        // 子の with が全終了したら、残りは実行せず ExitAll を返して呼び出し側に後始末させる
+        let (mut state, calls) = create_state(Some(("git", &[])));
+        state.executor = Box::new(RecordingExecutor {
+            calls: calls.clone(),
+            exit_code: EXIT_ALL_CODE,
+            succeeding: vec![],
+        });
+        assert_eq!(run_line("status && log", &mut state), StepResult::ExitAll);
+        assert_eq!(calls.borrow().as_slice(), ["git status"]);
+        assert_eq!(state.last_status, Some(EXIT_ALL_CODE));
+        assert_eq!(
+            run_line("log | tee out.txt", &mut state),
+            StepResult::ExitAll
+        );
+        assert_eq!(run_line("log | wc -l", &mut state), StepResult::ExitAll);
+    }
+}
@@ -0,0 +1,218 @@
+use std::io::{self, Write};
+
+/// 選択 UI で受け付けるキー
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectKey {
+    Up,
+    Down,
+    Enter,
+    /// Esc / Ctrl+C / q
+    Cancel,
+    /// 1~9 で直接選ぶ (0 始まりの位置)
+    Digit(usize),
+    Other,
+}
+
+/// キーを1つ処理した結果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectStep {
+    /// まだ選んでいる (選択位置が動いた場合を含む)
+    Pending,
+    Chosen(usize),
+    Cancelled,
+}
+
+/// 端末から一度に読んだバイト列をキーに変換する純粋関数
+/// 矢印キーは `ESC [ A` / `ESC O A` のように1回の読み取りで届く前提で、ESC 単独なら Esc とみなす
+pub fn parse_key(bytes: &[u8]) -> SelectKey {
+    match bytes {
+        b"\x1b[A" | b"\x1bOA" | b"k" => SelectKey::Up,
+        b"\x1b[B" | b"\x1bOB" | b"j" => SelectKey::Down,
+        b"\r" | b"\n" => SelectKey::Enter,
+        b"\x1b" | b"\x03" | b"q" => SelectKey::Cancel,
+        [digit @ b'1'..=b'9'] => SelectKey::Digit((digit - b'1') as usize),
+        _ => SelectKey::Other,
+    }
+}
+
+/// キー入力で選択位置 index を動かす純粋関数 (上下は端で反対側へ回り込む)
+pub fn handle_key(index: &mut usize, len: usize, key: SelectKey) -> SelectStep {
+    match key {
+        SelectKey::Up => *index = (*index + len - 1) % len,
+        SelectKey::Down => *index = (*index + 1) % len,
+        SelectKey::Enter => return SelectStep::Chosen(*index),
+        SelectKey::Cancel => return SelectStep::Cancelled,
+        SelectKey::Digit(n) if n < len => return SelectStep::Chosen(n),
+        SelectKey::Digit(_) | SelectKey::Other => {}
+    }
+    SelectStep::Pending
+}
+
+/// 候補の一覧を組み立てる純粋関数 (選択中の行に "> " を付ける)
+pub fn render_items(items: &[String], index: usize) -> Vec<String> {
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let marker = if i == index { ">" } else { " " };
+            format!("{} {}. {}", marker, i + 1, item)
+        })
+        .collect()
+}
+
+/// 候補から1つを選ばせる (Enter で確定、Esc で None)
+/// 表示は標準エラー出力に出し、標準出力のパイプ先を汚さない。端末でなければ呼ばないこと
+pub fn select(title: &str, items: &[String]) -> Option<usize> {
+    if items.is_empty() {
+        return None;
+    }
+    let mut out = io::stderr();
+    let _ = writeln!(out, "{}", title);
+    select_items(&mut out, items)
+}
+
+/// 矢印キーで選ぶ (raw モードにできなければ番号の入力で選ぶ)
+#[cfg(unix)]
+fn select_items(out: &mut impl Write, items: &[String]) -> Option<usize> {
+    let Some(_raw) = RawMode::enable() else {
+        return select_by_number(out, items);
+    };
+    let mut index = 0;
+    draw(out, items, index, false);
+    loop {
+        let mut buf = [0u8; 8];
+        // SAFETY: buf は読み取るバイト数分の長さを持つ
+        let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+        if n <= 0 {
+            return None;
+        }
+        match handle_key(&mut index, items.len(), parse_key(&buf[..n as usize])) {
+            SelectStep::Pending => draw(out, items, index, true),
+            SelectStep::Chosen(i) => return Some(i),
+            SelectStep::Cancelled => return None,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn select_items(out: &mut impl Write, items: &[String]) -> Option<usize> {
+    select_by_number(out, items)
+}
+
+/// 一覧を描く (redraw なら前回描いた行の上に描き直す)
+#[cfg(unix)]
+fn draw(out: &mut impl Write, items: &[String], index: usize, redraw: bool) {
+    if redraw {
+        let _ = write!(out, "\x1b[{}A", items.len());
+    }
+    for line in render_items(items, index) {
+        let _ = write!(out, "\r\x1b[2K{}\n", line);
+    }
+    let _ = out.flush();
+}
+
+/// 一覧を番号付きで表示し、番号を1行で入力させる (空行や範囲外ならキャンセル)
+fn select_by_number(out: &mut impl Write, items: &[String]) -> Option<usize> {
+    for (i, item) in items.iter().enumerate() {
+        let _ = writeln!(out, "  {}. {}", i + 1, item);
+    }
+    let _ = write!(out, "Select [1-{}]: ", items.len());
+    let _ = out.flush();
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).ok()?;
+    let n: usize = line.trim().parse().ok()?;
+    (1..=items.len()).contains(&n).then(|| n - 1)
+}
+
+/// 行単位の入力とエコーを止め、キーを1つずつ読めるようにする (破棄すると元に戻す)
+#[cfg(unix)]
+struct RawMode {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> Option<Self> {
+        // SAFETY: termios は tcgetattr が埋める
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return None;
+        }
+        let original = termios;
+        // Ctrl+C もシグナルではなくキーとして受け取り、キャンセルに使う
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return None;
+        }
+        Some(Self { original })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: enable で取得した元の設定に戻す
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key(b"\x1b[A"), SelectKey::Up);
+        assert_eq!(parse_key(b"\x1bOB"), SelectKey::Down);
+        assert_eq!(parse_key(b"\r"), SelectKey::Enter);
+        assert_eq!(parse_key(b"\x1b"), SelectKey::Cancel);
+        assert_eq!(parse_key(b"\x03"), SelectKey::Cancel);
+        assert_eq!(parse_key(b"2"), SelectKey::Digit(1));
+        assert_eq!(parse_key(b"0"), SelectKey::Other);
+        assert_eq!(parse_key(b"\x1b[C"), SelectKey::Other);
+    }
+
+    #[test]
+    fn test_handle_key_moves_and_wraps() {
+        let mut index = 0;
+        assert_eq!(
+            handle_key(&mut index, 3, SelectKey::Up),
+            SelectStep::Pending
+        );
+        assert_eq!(index, 2);
+        assert_eq!(
+            handle_key(&mut index, 3, SelectKey::Down),
+            SelectStep::Pending
+        );
+        assert_eq!(index, 0);
+        handle_key(&mut index, 3, SelectKey::Down);
+        assert_eq!(
+            handle_key(&mut index, 3, SelectKey::Enter),
+            SelectStep::Chosen(1)
+        );
+        assert_eq!(
+            handle_key(&mut index, 3, SelectKey::Digit(2)),
+            SelectStep::Chosen(2)
+        );
+        // 範囲外の番号は無視する
+        assert_eq!(
+            handle_key(&mut index, 3, SelectKey::Digit(5)),
+            SelectStep::Pending
+        );
+        assert_eq!(
+            handle_key(&mut index, 3, SelectKey::Cancel),
+            SelectStep::Cancelled
+        );
+    }
+
+    #[test]
+    fn test_render_items() {
+        let items = vec!["@web  /srv/web".to_string(), "@work  /srv/work".to_string()];
+        assert_eq!(
+            render_items(&items, 1),
+            vec!["  1. @web  /srv/web", "> 2. @work  /srv/work"]
+        );
+    }
+}
@@ -0,0 +1,435 @@
+use crate::history::HistoryEntry;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// 集計単位
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatsUnit {
+    /// 先頭のコマンドだけ (例: "git")
+    First,
+    /// サブコマンドまで (例: "git commit")
+    Sub,
+    /// 入力行全体 (例: "git commit -m fix")
+    Full,
+}
+
+impl StatsUnit {
+    /// 引数の文字列から集計単位を決める
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "first" | "cmd" => Some(Self::First),
+            "sub" | "subcommand" => Some(Self::Sub),
+            "full" => Some(Self::Full),
+            _ => None,
+        }
+    }
+}
+
+/// 表示する件数の既定値
+pub const DEFAULT_STATS_LIMIT: usize = 10;
+
+/// 履歴1行から集計用のキーを取り出す純粋関数
+fn stats_key(entry: &str, unit: StatsUnit) -> Option<String> {
+    let mut words = shell_words::split(entry).ok()?;
+    // `!ls` / `! ls` の脱出コマンドはコンテキストなしの `ls` として数える
+    if let Some(first) = words.first_mut()
+        && let Some(rest) = first.strip_prefix('!')
+    {
+        if rest.is_empty() {
+            words.remove(0);
+        } else {
+            *first = rest.to_string();
+        }
+    }
+    // 空行と集計コマンド自体は数えない
+    match words
+        .iter()
+        .map(|w| w.as_str())
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] | ["stats", ..] | ["history", "stats", ..] | ["cheatsheet", ..] => return None,
+        _ => {}
+    }
+    let key = match unit {
+        StatsUnit::First => words[0].clone(),
+        StatsUnit::Sub => words.iter().take(2).cloned().collect::<Vec<_>>().join(" "),
+        StatsUnit::Full => words.join(" "),
+    };
+    Some(key)
+}
+
+/// 履歴を集計し、実行回数の多い順に上位 limit 件を返す純粋関数
+/// 同数の場合は名前順に並べる
+pub fn rank_commands<'a, I>(entries: I, unit: StatsUnit, limit: usize) -> Vec<(String, usize)>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for entry in entries {
+        if let Some(key) = stats_key(entry, unit) {
+            *counts.entry(key).or_default() += 1;
+        }
+    }
+
+    let mut ranking: Vec<(String, usize)> = counts.into_iter().collect();
+    ranking.sort_by(|(a_key, a_count), (b_key, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_key.cmp(b_key))
+    });
+    ranking.truncate(limit);
+    ranking
+}
+
+/// 補完でサブコマンドを優先表示する順序
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubcommandOrder {
+    /// 最近使った順
+    #[default]
+    Recent,
+    /// よく使う順
+    Frequency,
+}
+
+/// 実行したコマンド行から、ツールごとに優先するサブコマンドを並べる純粋関数
+/// 例: ["git push", "git commit"] (Recent) -> {"git": ["commit", "push"]}
+pub fn preferred_subcommands<'a, I>(
+    entries: I,
+    order: SubcommandOrder,
+) -> HashMap<String, Vec<String>>
+where
+    I: IntoIterator<Item = &'a str>,
+    I::IntoIter: DoubleEndedIterator,
+{
+    let keys: Vec<String> = match order {
+        SubcommandOrder::Recent => entries
+            .into_iter()
+            .rev()
+            .filter_map(|entry| stats_key(entry, StatsUnit::Sub))
+            .collect(),
+        SubcommandOrder::Frequency => rank_commands(entries, StatsUnit::Sub, usize::MAX)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect(),
+    };
+
+    let mut preferred: HashMap<String, Vec<String>> = HashMap::new();
+    for key in keys {
+        let Some((tool, sub)) = key.split_once(' ') else {
+            continue;
+        };
+        let subs = preferred.entry(tool.to_string()).or_default();
+        if !subs.iter().any(|s| s == sub) {
+            subs.push(sub.to_string());
+        }
+    }
+    preferred
+}
+
+/// セッション中に実行したコマンドの集計 (終了時のサマリ用)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionStats {
+    pub commands: usize,
+    pub failures: usize,
+    pub elapsed: Duration,
+}
+
+impl SessionStats {
+    /// 外部コマンド1回分の結果を加える (終了コードが 0 以外、または取れなければ失敗)
+    pub fn record(&mut self, code: Option<i32>, elapsed: Duration) {
+        self.commands += 1;
+        if code != Some(0) {
+            self.failures += 1;
+        }
+        self.elapsed += elapsed;
+    }
+}
+
+/// セッションのサマリ行を組み立てる純粋関数 (例: "Session: 12 commands, 1 failed, 3.45s")
+pub fn format_session_summary(stats: &SessionStats) -> String {
+    let noun = if stats.commands == 1 {
+        "command"
+    } else {
+        "commands"
+    };
+    format!(
+        "Session: {} {}, {} failed, {:.2}s",
+        stats.commands,
+        noun,
+        stats.failures,
+        stats.elapsed.as_secs_f64()
+    )
+}
+
+/// ランキングの表示行を組み立てる純粋関数 (例: "  1.    12  git status")
+pub fn format_ranking(ranking: &[(String, usize)]) -> Vec<String> {
+    if ranking.is_empty() {
+        return vec!["no history".to_string()];
+    }
+    ranking
+        .iter()
+        .enumerate()
+        .map(|(i, (key, count))| format!("{: >3}. {: >5}  {}", i + 1, count, key))
+        .collect()
+}
+
+/// SystemTime を UTC の "YYYY-MM-DD HH:MM" にする純粋関数
+pub fn format_utc(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // 1970-01-01 からの日数を暦日に変換する (Howard Hinnant の civil_from_days)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60
+    )
+}
+
+/// チートシートの1行分の集計 (実行回数と最後に実行した日時)
+#[derive(Default)]
+struct CheatsheetRow {
+    count: usize,
+    last: Option<SystemTime>,
+}
+
+/// 履歴をコンテキスト別・コマンド別に集計し、Markdown のチートシートを組み立てる純粋関数
+/// 各コンテキストで実行回数の多い順に上位 limit 件を載せる (履歴が無ければ None)
+pub fn format_cheatsheet(entries: &[HistoryEntry], limit: usize) -> Option<String> {
+    let mut groups: HashMap<Option<&str>, HashMap<String, CheatsheetRow>> = HashMap::new();
+    for entry in entries {
+        let Some(key) = stats_key(&entry.line, StatsUnit::Full) else {
+            continue;
+        };
+        // `!` の脱出コマンドはコンテキストなしとして扱う
+        let context = entry
+            .context
+            .as_deref()
+            .filter(|_| !entry.line.starts_with('!'));
+        let command = match context {
+            Some(program) => format!("{} {}", program, key),
+            None => key,
+        };
+        let row = groups
+            .entry(context)
+            .or_default()
+            .entry(command)
+            .or_default();
+        row.count += 1;
+        row.last = row.last.max(entry.time);
+    }
+    if groups.is_empty() {
+        return None;
+    }
+
+    // 実行回数の多いコンテキストから並べる
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    let total =
+        |rows: &HashMap<String, CheatsheetRow>| rows.values().map(|r| r.count).sum::<usize>();
+    groups.sort_by(|(a_ctx, a_rows), (b_ctx, b_rows)| {
+        total(b_rows)
+            .cmp(&total(a_rows))
+            .then_with(|| a_ctx.cmp(b_ctx))
+    });
+
+    let mut out = String::from("# with cheatsheet\n");
+    for (context, rows) in groups {
+        out.push_str(&format!("\n## {}\n\n", context.unwrap_or("(no context)")));
+        out.push_str("| Command | Count | Last run (UTC) |\n");
+        out.push_str("| --- | ---: | --- |\n");
+
+        let mut rows: Vec<_> = rows.into_iter().collect();
+        rows.sort_by(|(a_cmd, a), (b_cmd, b)| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| b.last.cmp(&a.last))
+                .then_with(|| a_cmd.cmp(b_cmd))
+        });
+        for (command, row) in rows.into_iter().take(limit) {
+            let last = row.last.map(format_utc).unwrap_or_else(|| "-".to_string());
+            out.push_str(&format!(
+                "| `{}` | {} | {} |\n",
+                command.replace('|', "\\|"),
+                row.count,
+                last
+            ));
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HISTORY: &[&str] = &[
+        "git status",
+        "git commit -m 'fix bug'",
+        "git status",
+        "cargo build",
+        "git commit -m wip",
+        "ls",
+        "stats sub",
+    ];
+
+    #[test]
+    fn test_rank_by_first() {
+        let ranking = rank_commands(HISTORY.iter().copied(), StatsUnit::First, 10);
+        assert_eq!(
+            ranking,
+            vec![
+                ("git".to_string(), 4),
+                ("cargo".to_string(), 1),
+                ("ls".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rank_by_sub_and_limit() {
+        let ranking = rank_commands(HISTORY.iter().copied(), StatsUnit::Sub, 2);
+        assert_eq!(
+            ranking,
+            vec![("git commit".to_string(), 2), ("git status".to_string(), 2),]
+        );
+    }
+
+    #[test]
+    fn test_rank_by_full() {
+        let ranking = rank_commands(HISTORY.iter().copied(), StatsUnit::Full, 1);
+        assert_eq!(ranking, vec![("git status".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_rank_counts_escape_as_plain_command() {
+        let entries = ["!ls -la", "ls", "! cargo build", "!"];
+        let ranking = rank_commands(entries.iter().copied(), StatsUnit::Sub, 10);
+        assert_eq!(
+            ranking,
+            vec![
+                ("cargo build".to_string(), 1),
+                ("ls".to_string(), 1),
+                ("ls -la".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preferred_subcommands_recent() {
+        let preferred = preferred_subcommands(HISTORY.iter().copied(), SubcommandOrder::Recent);
+        assert_eq!(preferred["git"], vec!["commit", "status"]);
+        assert_eq!(preferred["cargo"], vec!["build"]);
+        // サブコマンドの無い行は対象外
+        assert!(!preferred.contains_key("ls"));
+    }
+
+    #[test]
+    fn test_preferred_subcommands_frequency() {
+        let entries = ["git push", "git status", "git push", "git commit"];
+        let preferred = preferred_subcommands(entries, SubcommandOrder::Frequency);
+        assert_eq!(preferred["git"], vec!["push", "commit", "status"]);
+    }
+
+    #[test]
+    fn test_session_stats_summary() {
+        let mut stats = SessionStats::default();
+        assert_eq!(
+            format_session_summary(&stats),
+            "Session: 0 commands, 0 failed, 0.00s"
+        );
+
+        stats.record(Some(0), Duration::from_millis(1200));
+        assert_eq!(
+            format_session_summary(&stats),
+            "Session: 1 command, 0 failed, 1.20s"
+        );
+
+        stats.record(Some(1), Duration::from_millis(300));
+        stats.record(None, Duration::from_millis(50));
+        assert_eq!(
+            format_session_summary(&stats),
+            "Session: 3 commands, 2 failed, 1.55s"
+        );
+    }
+
+    // --- チートシートのテスト ---
+
+    fn entry(id: usize, line: &str, context: Option<&str>, secs: u64) -> HistoryEntry {
+        HistoryEntry {
+            id,
+            line: line.to_string(),
+            context: context.map(|s| s.to_string()),
+            time: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01 00:00");
+        // 2024-02-29 12:34:56 UTC (うるう日)
+        assert_eq!(
+            format_utc(UNIX_EPOCH + Duration::from_secs(1_709_210_096)),
+            "2024-02-29 12:34"
+        );
+    }
+
+    #[test]
+    fn test_format_cheatsheet_groups_by_context() {
+        let entries = vec![
+            entry(1, "status", Some("git"), 0),
+            entry(2, "log | head", Some("git"), 60),
+            entry(3, "status", Some("git"), 120),
+            entry(4, "!ls -a", Some("git"), 180),
+            entry(5, "cheatsheet", Some("git"), 240),
+        ];
+        let md = format_cheatsheet(&entries, 10).unwrap();
+        assert_eq!(
+            md,
+            "# with cheatsheet\n\
+             \n## git\n\n\
+             | Command | Count | Last run (UTC) |\n\
+             | --- | ---: | --- |\n\
+             | `git status` | 2 | 1970-01-01 00:02 |\n\
+             | `git log \\| head` | 1 | 1970-01-01 00:01 |\n\
+             \n## (no context)\n\n\
+             | Command | Count | Last run (UTC) |\n\
+             | --- | ---: | --- |\n\
+             | `ls -a` | 1 | 1970-01-01 00:03 |\n"
+        );
+    }
+
+    #[test]
+    fn test_format_cheatsheet_empty() {
+        assert!(format_cheatsheet(&[], 10).is_none());
+        // 集計対象外のコマンドだけなら空扱い
+        assert!(format_cheatsheet(&[entry(1, "stats", None, 0)], 10).is_none());
+    }
+
+    #[test]
+    fn test_format_ranking_empty() {
+        assert_eq!(format_ranking(&[]), vec!["no history"]);
+        assert_eq!(
+            format_ranking(&[("git".to_string(), 12)]),
+            vec!["  1.    12  git"]
+        );
+    }
+}
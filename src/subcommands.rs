@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+
+/// 1つのコマンドのサブコマンド定義
+/// フラグや説明などの情報もここにフィールドを足せば同じデータで扱える
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CommandSpec {
+    /// 同じ定義を使う別名 (例: kubectl に対する "k")
+    pub aliases: Vec<String>,
+    pub subcommands: Vec<String>,
+    /// 2階層目のサブコマンド (例: "remote" -> ["add", "remove", ...])
+    pub nested: BTreeMap<String, Vec<String>>,
+}
+
+impl CommandSpec {
+    /// 別の定義の内容を追加する (既存の並びを保ち、重複は追加しない)
+    fn merge(&mut self, other: CommandSpec) {
+        extend_unique(&mut self.aliases, other.aliases);
+        extend_unique(&mut self.subcommands, other.subcommands);
+        for (sub, nested) in other.nested {
+            extend_unique(self.nested.entry(sub).or_default(), nested);
+        }
+    }
+}
+
+fn extend_unique(list: &mut Vec<String>, items: Vec<String>) {
+    for item in items {
+        if !list.contains(&item) {
+            list.push(item);
+        }
+    }
+}
+
+/// コマンド名 (別名を含む) からサブコマンド定義を引く表
+#[derive(Debug, Clone, Default)]
+pub struct SubcommandTable {
+    specs: HashMap<String, CommandSpec>,
+}
+
+impl SubcommandTable {
+    /// TOML の定義を読み込む (`[git]` の下に subcommands / aliases / nested)
+    pub fn parse(content: &str) -> Result<Self, toml::de::Error> {
+        let defs: BTreeMap<String, CommandSpec> = toml::from_str(content)?;
+        let mut table = Self::default();
+        table.extend(defs);
+        Ok(table)
+    }
+
+    /// 定義を追加する (同名のコマンドがあれば内容をマージし、別名は同じ定義を指す)
+    pub fn extend(&mut self, defs: BTreeMap<String, CommandSpec>) {
+        for (name, spec) in defs {
+            let merged = self.specs.entry(name).or_default();
+            merged.merge(spec);
+            let merged = merged.clone();
+            for alias in &merged.aliases {
+                self.specs.insert(alias.clone(), merged.clone());
+            }
+        }
+    }
+
+    /// サブコマンドの一覧 (未定義なら空)
+    pub fn subcommands(&self, command: &str) -> Vec<&str> {
+        self.specs
+            .get(command)
+            .map(|spec| spec.subcommands.iter().map(|s| s.as_str()).collect())
+            .unwrap_or_default()
+    }
+
+    /// 2階層目のサブコマンドの一覧 (未定義なら空)
+    pub fn nested(&self, command: &str, subcommand: &str) -> Vec<&str> {
+        self.specs
+            .get(command)
+            .and_then(|spec| spec.nested.get(subcommand))
+            .map(|list| list.iter().map(|s| s.as_str()).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// 組み込みの定義 (src/subcommands.toml) を初回に読み込んでキャッシュする
+pub fn builtin() -> &'static SubcommandTable {
+    static TABLE: OnceLock<SubcommandTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        SubcommandTable::parse(include_str!("subcommands.toml"))
+            .expect("built-in subcommands.toml must be valid")
+    })
+}
+
+/// 打ち間違いとみなす候補を近い順に返す純粋関数 (例: "commti" -> ["commit"])
+/// 編集距離が単語の長さの 1/3 (最小 1・最大 2) 以内のものを、最大 3 件まで選ぶ
+pub fn suggest_subcommands<'a>(word: &str, candidates: &[&'a str]) -> Vec<&'a str> {
+    let limit = (word.chars().count() / 3).clamp(1, 2);
+    let mut close: Vec<(usize, &str)> = candidates
+        .iter()
+        .map(|candidate| (edit_distance(word, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    close.sort();
+    close.dedup();
+    close.into_iter().take(3).map(|(_, name)| name).collect()
+}
+
+/// 2つの文字列の編集距離 (隣り合う文字の入れ替えも1回と数える)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // dist[i][j]: a[..i] と b[..j] の距離
+    let mut dist = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    dist[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut d = (dist[i - 1][j] + 1)
+                .min(dist[i][j - 1] + 1)
+                .min(dist[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = d.min(dist[i - 2][j - 2] + 1);
+            }
+            dist[i][j] = d;
+        }
+    }
+    dist[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_table_parses() {
+        let table = builtin();
+        assert!(table.subcommands("git").contains(&"status"));
+        assert_eq!(table.subcommands("k"), table.subcommands("kubectl"));
+        assert_eq!(
+            table.nested("tf", "state"),
+            table.nested("terraform", "state")
+        );
+        assert!(table.subcommands("unknown").is_empty());
+    }
+
+    #[test]
+    fn test_extend_merges_with_existing() {
+        let mut table =
+            SubcommandTable::parse("[git]\nsubcommands = [\"add\", \"status\"]\n").unwrap();
+        let extra: BTreeMap<String, CommandSpec> = toml::from_str(
+            "[git]\naliases = [\"g\"]\nsubcommands = [\"status\", \"sync\"]\n\n[git.nested]\nsync = [\"now\"]\n",
+        )
+        .unwrap();
+        table.extend(extra);
+
+        assert_eq!(table.subcommands("git"), vec!["add", "status", "sync"]);
+        assert_eq!(table.subcommands("g"), vec!["add", "status", "sync"]);
+        assert_eq!(table.nested("git", "sync"), vec!["now"]);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("commit", "commit"), 0);
+        assert_eq!(edit_distance("commti", "commit"), 1);
+        assert_eq!(edit_distance("stats", "status"), 1);
+        assert_eq!(edit_distance("", "log"), 3);
+        assert_eq!(edit_distance("push", "pull"), 2);
+    }
+
+    #[test]
+    fn test_suggest_subcommands() {
+        let git = builtin().subcommands("git");
+        assert_eq!(suggest_subcommands("commti", &git), vec!["commit"]);
+        assert_eq!(suggest_subcommands("stauts", &git), vec!["status"]);
+        assert!(suggest_subcommands("xyzzy", &git).is_empty());
+        // 短い単語は 1 文字違いまで
+        assert_eq!(suggest_subcommands("pul", &["pull", "push"]), vec!["pull"]);
+        assert!(suggest_subcommands("ab", &["status"]).is_empty());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::config::ThemeConfig;
+use crate::context::BranchState;
+use crate::with_helper::{
+    COLOR_BLUE, COLOR_CYAN, COLOR_GREEN, COLOR_MAGENTA, COLOR_RED, COLOR_WHITE, COLOR_YELLOW,
+    STYLE_BOLD,
+};
+use serde::{Deserialize, Serialize};
+use std::env;
+
+/// 端末の背景
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Background {
+    /// COLORFGBG から推定する (推定できなければ dark)
+    #[default]
+    Auto,
+    Light,
+    Dark,
+}
+
+/// git 管理外のディレクトリの既定色 (グレー)
+const COLOR_GRAY: &str = "\x1b[90m";
+
+/// プロンプトとハイライトで使う色のセット
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub dir: &'static str,
+    /// git 管理外のディレクトリの色 (None なら管理下/外で色を変えない)
+    pub dir_outside_git: Option<&'static str>,
+    pub branch: &'static str,
+    /// 未コミットの変更があるブランチの色 (None ならブランチの状態で色を変えない)
+    pub branch_dirty: Option<&'static str>,
+    /// detached HEAD の色 (None ならブランチの状態で色を変えない)
+    pub branch_detached: Option<&'static str>,
+    /// コンテキスト部分 (例: "git/cargo") と親コマンド
+    pub context: &'static str,
+    pub kube: &'static str,
+    pub aws: &'static str,
+    pub subcommand: &'static str,
+    /// 2階層目のサブコマンド (例: "git remote add" の "add")
+    pub nested: &'static str,
+    pub option: &'static str,
+    pub string: &'static str,
+    /// `$VAR` / `${VAR}` / `$(...)`
+    pub variable: &'static str,
+    /// `|` / `&&` / `||` / `;` / `>` などの演算子
+    pub operator: &'static str,
+    /// 閉じていない `${` / `$(` と、見つからないコマンド
+    pub warning: &'static str,
+}
+
+impl Theme {
+    /// 暗い背景向け (従来の配色)
+    pub fn dark() -> Self {
+        Self {
+            dir: COLOR_GREEN,
+            dir_outside_git: None,
+            branch: COLOR_MAGENTA,
+            branch_dirty: None,
+            branch_detached: None,
+            context: COLOR_CYAN,
+            kube: COLOR_BLUE,
+            aws: COLOR_YELLOW,
+            subcommand: COLOR_GREEN,
+            nested: COLOR_BLUE,
+            option: COLOR_YELLOW,
+            string: COLOR_WHITE,
+            variable: "\x1b[96m",
+            operator: STYLE_BOLD,
+            warning: COLOR_RED,
+        }
+    }
+
+    /// 明るい背景向け (シアン・黄・白を濃い色に置き換える)
+    pub fn light() -> Self {
+        Self {
+            dir: COLOR_GREEN,
+            dir_outside_git: None,
+            branch: COLOR_MAGENTA,
+            branch_dirty: None,
+            branch_detached: None,
+            context: "\x1b[38;5;25m",
+            kube: COLOR_BLUE,
+            aws: "\x1b[38;5;130m",
+            subcommand: COLOR_GREEN,
+            nested: COLOR_BLUE,
+            option: "\x1b[38;5;130m",
+            string: "\x1b[38;5;240m",
+            variable: "\x1b[38;5;30m",
+            operator: STYLE_BOLD,
+            warning: COLOR_RED,
+        }
+    }
+
+    /// 設定と環境変数から色のセットを選ぶ
+    pub fn detect(background: Background) -> Self {
+        let background = match background {
+            Background::Auto => env::var("COLORFGBG")
+                .ok()
+                .and_then(|v| parse_colorfgbg(&v))
+                .unwrap_or(Background::Dark),
+            other => other,
+        };
+        match background {
+            Background::Light => Self::light(),
+            _ => Self::dark(),
+        }
+    }
+
+    /// 設定ファイルの [theme] から色のセットを組み立てる
+    /// git_dir_colors が有効なら git 管理下/外でディレクトリの色を変える (NO_COLOR 指定時は変えない)
+    pub fn from_config(config: &ThemeConfig) -> Self {
+        let mut theme = Self::detect(config.background);
+        if config.git_dir_colors && env::var_os("NO_COLOR").is_none() {
+            let named = |name: &Option<String>| name.as_deref().and_then(parse_color_name);
+            if let Some(color) = named(&config.dir_in_git) {
+                theme.dir = color;
+            }
+            theme.dir_outside_git = Some(named(&config.dir_outside_git).unwrap_or(COLOR_GRAY));
+        }
+        if config.branch_state_colors && env::var_os("NO_COLOR").is_none() {
+            let named = |name: &Option<String>| name.as_deref().and_then(parse_color_name);
+            if let Some(color) = named(&config.branch_clean) {
+                theme.branch = color;
+            }
+            theme.branch_dirty = Some(named(&config.branch_dirty).unwrap_or(COLOR_YELLOW));
+            theme.branch_detached = Some(named(&config.branch_detached).unwrap_or(COLOR_RED));
+        }
+        theme
+    }
+
+    /// ブランチの状態で色を変えるか (状態の取得は有効なときだけ行う)
+    pub fn colors_branch_state(&self) -> bool {
+        self.branch_dirty.is_some() || self.branch_detached.is_some()
+    }
+
+    /// ブランチ部分の色 (状態が取れなければ通常のブランチ色)
+    pub fn branch_color(&self, state: Option<BranchState>) -> &'static str {
+        let color = match state {
+            Some(BranchState::Dirty) => self.branch_dirty,
+            Some(BranchState::Detached) => self.branch_detached,
+            _ => None,
+        };
+        color.unwrap_or(self.branch)
+    }
+
+    /// ディレクトリ部分の色 (in_git は git 管理下かどうか)
+    pub fn dir_color(&self, in_git: bool) -> &'static str {
+        match self.dir_outside_git {
+            Some(color) if !in_git => color,
+            _ => self.dir,
+        }
+    }
+}
+
+/// 端末で ANSI エスケープ (色・画面制御) を使えるようにする
+/// Windows の旧来のコンソールでは仮想端末処理を有効化し、失敗したら false を返す (Unix では何もしない)
+#[cfg(windows)]
+pub fn enable_ansi_support() -> bool {
+    use windows_sys::Win32::System::Console::{
+        CONSOLE_MODE, ENABLE_VIRTUAL_TERMINAL_PROCESSING, GetConsoleMode, GetStdHandle,
+        STD_OUTPUT_HANDLE, SetConsoleMode,
+    };
+    // SAFETY: 標準出力のコンソールハンドルのモードを読み書きするだけ
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut mode: CONSOLE_MODE = 0;
+        if GetConsoleMode(handle, &mut mode) == 0 {
+            return false;
+        }
+        mode & ENABLE_VIRTUAL_TERMINAL_PROCESSING != 0
+            || SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0
+    }
+}
+
+#[cfg(not(windows))]
+pub fn enable_ansi_support() -> bool {
+    true
+}
+
+/// 設定ファイルの色名を ANSI エスケープに変換する純粋関数
+fn parse_color_name(name: &str) -> Option<&'static str> {
+    match name {
+        "red" => Some(COLOR_RED),
+        "green" => Some(COLOR_GREEN),
+        "yellow" => Some(COLOR_YELLOW),
+        "blue" => Some(COLOR_BLUE),
+        "magenta" => Some(COLOR_MAGENTA),
+        "cyan" => Some(COLOR_CYAN),
+        "white" => Some(COLOR_WHITE),
+        "gray" | "grey" => Some(COLOR_GRAY),
+        _ => None,
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+/// COLORFGBG ("前景;背景" 例: "0;15") から背景の明暗を推定する純粋関数
+/// 背景色の番号が 7 (白) または 9~15 (明るい色) なら明背景とみなす
+fn parse_colorfgbg(value: &str) -> Option<Background> {
+    let bg: u8 = value.rsplit(';').next()?.trim().parse().ok()?;
+    match bg {
+        7 | 9..=15 => Some(Background::Light),
+        0..=6 | 8 => Some(Background::Dark),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_colorfgbg() {
+        assert_eq!(parse_colorfgbg("0;15"), Some(Background::Light));
+        assert_eq!(parse_colorfgbg("15;0"), Some(Background::Dark));
+        // rxvt 形式 "前景;default;背景"
+        assert_eq!(parse_colorfgbg("0;default;7"), Some(Background::Light));
+        assert_eq!(parse_colorfgbg("default"), None);
+        assert_eq!(parse_colorfgbg(""), None);
+    }
+
+    #[test]
+    fn test_parse_color_name() {
+        assert_eq!(parse_color_name("blue"), Some(COLOR_BLUE));
+        assert_eq!(parse_color_name("grey"), Some(COLOR_GRAY));
+        assert_eq!(parse_color_name("orange"), None);
+    }
+
+    #[test]
+    fn test_dir_color() {
+        // 既定では git 管理下/外で色を変えない
+        let theme = Theme::dark();
+        assert_eq!(theme.dir_color(false), COLOR_GREEN);
+
+        let theme = Theme {
+            dir_outside_git: Some(COLOR_GRAY),
+            ..Theme::dark()
+        };
+        assert_eq!(theme.dir_color(true), COLOR_GREEN);
+        assert_eq!(theme.dir_color(false), COLOR_GRAY);
+    }
+
+    #[test]
+    fn test_branch_color() {
+        // 既定では状態にかかわらず通常のブランチ色
+        let theme = Theme::dark();
+        assert!(!theme.colors_branch_state());
+        assert_eq!(theme.branch_color(Some(BranchState::Dirty)), COLOR_MAGENTA);
+
+        let theme = Theme {
+            branch_dirty: Some(COLOR_YELLOW),
+            branch_detached: Some(COLOR_RED),
+            ..Theme::dark()
+        };
+        assert_eq!(theme.branch_color(Some(BranchState::Clean)), COLOR_MAGENTA);
+        assert_eq!(theme.branch_color(Some(BranchState::Dirty)), COLOR_YELLOW);
+        assert_eq!(theme.branch_color(Some(BranchState::Detached)), COLOR_RED);
+        assert_eq!(theme.branch_color(None), COLOR_MAGENTA);
+    }
+
+    #[test]
+    fn test_detect_forced() {
+        // 設定で強制した場合は環境変数を見ない
+        assert_eq!(Theme::detect(Background::Light), Theme::light());
+        assert_eq!(Theme::detect(Background::Dark), Theme::dark());
+        assert_ne!(Theme::light().context, Theme::dark().context);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::parser::TargetContext;
+use std::{
+    env,
+    io::{self, IsTerminal, Write},
+    path::Path,
+};
+
+/// ターミナルタイトルを設定する OSC シーケンスを組み立てる純粋関数
+/// 制御文字はタイトルを壊すので取り除く
+pub fn title_sequence(title: &str) -> String {
+    let title: String = title.chars().filter(|c| !c.is_control()).collect();
+    format!("\x1b]0;{}\x07", title)
+}
+
+/// 入力待ちのときのタイトル (例: "with: git (my-repo)")
+pub fn context_title(context: Option<&TargetContext>, cwd: &Path) -> String {
+    let dir = cwd
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| cwd.display().to_string());
+    match context {
+        Some(ctx) if ctx.args.is_empty() => format!("with: {} ({})", ctx.program, dir),
+        Some(ctx) => format!("with: {} {} ({})", ctx.program, ctx.args.join(" "), dir),
+        None => format!("with ({})", dir),
+    }
+}
+
+/// コマンド実行中のタイトル (例: "with: git push")
+pub fn running_title(command_line: &str) -> String {
+    format!("with: {}", command_line)
+}
+
+/// タイトルを変更できる端末か (出力先が端末で、TERM が dumb でなく、NO_COLOR が指定されていない)
+pub fn title_supported() -> bool {
+    io::stdout().is_terminal()
+        && env::var("TERM").map_or(true, |term| term != "dumb")
+        && env::var_os("NO_COLOR").is_none()
+}
+
+/// ターミナルタイトルを変更する
+pub fn set_title(title: &str) {
+    write_sequence(&title_sequence(title));
+}
+
+fn write_sequence(sequence: &str) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(sequence.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// 端末にカレントディレクトリを知らせる OSC 7 シーケンスを組み立てる純粋関数
+/// (例: "\x1b]7;file://host/home/user/my%20repo\x07")
+/// Windows の "C:\dir" は "/C:/dir" にする
+pub fn cwd_sequence(host: &str, path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let path = if path.starts_with('/') {
+        path
+    } else {
+        format!("/{}", path)
+    };
+    format!("\x1b]7;file://{}{}\x07", host, percent_encode(&path))
+}
+
+/// パスを URL のパス部分として使えるようにパーセントエンコードする
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &b in path.as_bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'/' | b'-' | b'_' | b'.' | b'~' | b':') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// OSC 7 に載せるホスト名 (取れなければ空 = "file:///path")
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: 長さを渡したバッファに書き込ませるだけ
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// 端末にカレントディレクトリを知らせる (新しいタブを同じディレクトリで開けるようにする)
+pub fn report_cwd(cwd: &Path) {
+    write_sequence(&cwd_sequence(&hostname(), cwd));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_title_sequence() {
+        assert_eq!(title_sequence("with: git"), "\x1b]0;with: git\x07");
+        // BEL などの制御文字でシーケンスが途切れないようにする
+        assert_eq!(title_sequence("a\x07b\nc"), "\x1b]0;abc\x07");
+    }
+
+    #[test]
+    fn test_cwd_sequence() {
+        assert_eq!(
+            cwd_sequence("host", Path::new("/home/user/my repo")),
+            "\x1b]7;file://host/home/user/my%20repo\x07"
+        );
+        // 非 ASCII は UTF-8 のバイトごとにエンコードする
+        assert_eq!(
+            cwd_sequence("", Path::new("/tmp/日本#1")),
+            "\x1b]7;file:///tmp/%E6%97%A5%E6%9C%AC%231\x07"
+        );
+        assert_eq!(
+            cwd_sequence("pc", Path::new("C:\\Users\\me")),
+            "\x1b]7;file://pc/C:/Users/me\x07"
+        );
+    }
+
+    #[test]
+    fn test_context_title() {
+        let ctx = TargetContext {
+            program: "git".to_string(),
+            ..Default::default()
+        };
+        let cwd = Path::new("/home/user/my-repo");
+        assert_eq!(context_title(Some(&ctx), cwd), "with: git (my-repo)");
+
+        let ctx = TargetContext {
+            program: "docker".to_string(),
+            args: vec!["compose".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            context_title(Some(&ctx), cwd),
+            "with: docker compose (my-repo)"
+        );
+        assert_eq!(context_title(None, Path::new("/")), "with (/)");
+    }
+}
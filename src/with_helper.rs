@@ -1,41 +1,253 @@
+use crate::async_lookup::AsyncLookup;
+use crate::config::{self, ExtensionRule, SubcommandDefinition};
+use crate::context::{list_git_branches, list_git_remotes, list_git_tags};
+use crate::executor::program_exists;
+use crate::parser::{find_closing_paren, is_builtin_command};
+use crate::prompt::Prompt;
+use crate::subcommands::{self, SubcommandTable};
+use crate::theme::Theme;
 use rustyline::{
-    Context, Helper, Hinter, Validator,
+    Context, Helper,
     completion::{Completer, FilenameCompleter, Pair},
     highlight::Highlighter,
+    hint::Hinter,
+    history::SearchDirection,
+    validate::{ValidationContext, ValidationResult, Validator},
 };
 use std::{
     borrow::Cow,
+    cell::{OnceCell, RefCell},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env,
+    ffi::OsStr,
+    io::{self, IsTerminal},
     iter::{IntoIterator, Iterator},
     option::Option::{self, None, Some},
+    path::Path,
+    process,
+    time::Duration,
     vec::Vec,
 };
 
+/// ファイル候補のキャッシュを使い回す時間
+const FILE_CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// ファイル補完の結果 (置換開始位置と候補)
+type FileCompletion = Option<(usize, Vec<Pair>)>;
+
 // --- Rustylineのヘルパー設定 ---
-#[derive(Helper, Hinter, Validator)]
+#[derive(Helper)]
 pub struct WithHelper {
     pub completer: FilenameCompleter,
     pub context_program: Option<String>,
+    /// 現在表示中のプロンプト (色付け済みの文字列を保持する)
+    pub prompt: Option<Prompt>,
+    /// ハイライトの配色
+    pub theme: Theme,
+    /// false なら入力行とプロンプトを色付けしない (NO_COLOR や ANSI 非対応の端末。detect_color で判定する)
+    pub color: bool,
+    /// true の間は入力行・プロンプト・ヒントを色付けしない (run_repl がコマンドの実行中に立てる)
+    pub suspended: bool,
+    /// true なら未登録ツールのサブコマンドを `--help` の出力から学習する
+    pub learn_from_help: bool,
+    /// 補完・ハイライトに使うサブコマンドの表 (組み込みに設定ファイルの定義をマージしたもの)
+    /// 起動を軽くするため、初めて補完・ハイライトするときに組み立てる
+    subcommands: OnceCell<SubcommandTable>,
+    /// subcommands に組み込みへマージする設定ファイルの `[subcommands]`
+    subcommand_defs: BTreeMap<String, SubcommandDefinition>,
+    /// `--help` から学習したサブコマンドのキャッシュ
+    learned_subcommands: RefCell<HashMap<String, Vec<String>>>,
+    /// true ならサブコマンドを部分列でも一致させる (`cmt` -> `commit`。前方一致を先に並べる)
+    pub fuzzy: bool,
+    /// true なら `~/` のパス補完を展開後のパスで置き換える (false なら `~` の形を保つ)
+    pub expand_tilde: bool,
+    /// ツールごとに候補の先頭へ出すサブコマンド (run_repl が実行履歴から集計して渡す)
+    pub preferred_subcommands: HashMap<String, Vec<String>>,
+    /// `cd @name` で補完するブックマーク (名前, パス)
+    pub bookmarks: Vec<(String, String)>,
+    /// ファイル候補を拡張子で絞り込むルール
+    pub extension_rules: Vec<ExtensionRule>,
+    /// ファイル走査・`--help` の実行を待つ時間 (ゼロなら待ち続ける)
+    pub completion_timeout: Duration,
+    /// 直前に求めた git の引数候補 ((カレントディレクトリ, 補完中の単語より前の入力), 候補)
+    git_arg_cache: RefCell<Option<(String, Vec<String>)>>,
+    /// 先頭の単語がコマンドとして見つかるか (キーを押すたびに PATH を探さないよう単語ごとに覚える)
+    resolved_programs: RefCell<HashMap<String, bool>>,
+    /// PATH から集めた実行ファイル名 (初めて使うときに一度だけ走査する)
+    path_executables: RefCell<Option<Vec<String>>>,
+    /// ワーカースレッドで走査したファイル候補
+    file_lookup: RefCell<AsyncLookup<FileCompletion>>,
+    /// ワーカースレッドで実行した `--help` の学習結果
+    help_lookup: RefCell<AsyncLookup<Vec<String>>>,
 }
 
-// プロンプトの色付け用
-const COLOR_GREEN: &str = "\x1b[32m";
-const COLOR_YELLOW: &str = "\x1b[33m";
-const COLOR_MAGENTA: &str = "\x1b[35m";
-const COLOR_CYAN: &str = "\x1b[36m";
-const COLOR_WHITE: &str = "\x1b[37m";
-const STYLE_BOLD: &str = "\x1b[1m";
-const STYLE_RESET: &str = "\x1b[0m";
+impl WithHelper {
+    pub fn new(context_program: Option<String>) -> Self {
+        Self {
+            completer: FilenameCompleter::new(),
+            context_program,
+            prompt: None,
+            theme: Theme::default(),
+            color: true,
+            suspended: false,
+            learn_from_help: true,
+            subcommands: OnceCell::new(),
+            subcommand_defs: BTreeMap::new(),
+            learned_subcommands: RefCell::new(HashMap::new()),
+            fuzzy: false,
+            expand_tilde: false,
+            preferred_subcommands: HashMap::new(),
+            bookmarks: Vec::new(),
+            extension_rules: default_extension_rules(),
+            completion_timeout: Duration::from_millis(200),
+            git_arg_cache: RefCell::new(None),
+            resolved_programs: RefCell::new(HashMap::new()),
+            path_executables: RefCell::new(None),
+            file_lookup: RefCell::new(AsyncLookup::new(FILE_CACHE_TTL)),
+            help_lookup: RefCell::new(AsyncLookup::new(Duration::MAX)),
+        }
+    }
 
-impl Completer for WithHelper {
-    type Candidate = Pair;
+    /// サブコマンドの表 (初回に組み込みの定義と設定ファイルの定義から組み立てる)
+    fn subcommands(&self) -> &SubcommandTable {
+        self.subcommands
+            .get_or_init(|| config::merged_subcommand_table(&self.subcommand_defs))
+    }
 
-    fn complete(
+    /// 設定ファイルの内容 (配色・補完の設定・ブックマーク) を反映する
+    /// 起動時と `config set` / `config edit` の後に呼ばれる
+    pub fn apply_config(&mut self, config: &config::Config) {
+        self.theme = Theme::from_config(&config.theme);
+        self.learn_from_help = config.completion.learn_from_help;
+        self.fuzzy = config.completion.fuzzy;
+        self.expand_tilde = config.completion.expand_tilde;
+        self.subcommand_defs = config.subcommands.clone();
+        self.subcommands = OnceCell::new();
+        self.completion_timeout = Duration::from_millis(config.completion.timeout_ms);
+        self.bookmarks = config
+            .bookmarks
+            .iter()
+            .map(|(name, path)| (name.clone(), path.clone()))
+            .collect();
+        // 設定のルールを組み込みより優先する
+        self.extension_rules = config.completion.extension_rules.clone();
+        self.extension_rules.extend(default_extension_rules());
+    }
+
+    /// ファイル補完をワーカースレッドで行う
+    /// 遅い FS で時間内に終わらなければ候補なしで戻り、次の Tab で結果を受け取る
+    fn complete_files(&self, line: &str, pos: usize) -> (usize, Vec<Pair>) {
+        if self.completion_timeout.is_zero() {
+            return self
+                .completer
+                .complete_path(line, pos)
+                .unwrap_or((pos, vec![]));
+        }
+
+        let cwd = env::current_dir().unwrap_or_default();
+        let key = format!("{}\0{}", cwd.display(), &line[..pos]);
+        let line = line.to_string();
+        self.file_lookup
+            .borrow_mut()
+            .get_or_spawn(&key, self.completion_timeout, move || {
+                FilenameCompleter::new().complete_path(&line, pos).ok()
+            })
+            .flatten()
+            .unwrap_or((pos, vec![]))
+    }
+
+    /// `~/` / `~user/` で始まる単語をホームディレクトリに展開してからファイル補完する
+    /// 候補の置き換えは `~` の形のまま (expand_tilde なら展開後のパス) にし、展開できなければ None を返して通常の補完に任せる
+    fn complete_tilde_files(
         &self,
         line: &str,
         pos: usize,
-        ctx: &Context<'_>,
-    ) -> rustyline::Result<(usize, Vec<Pair>)> {
-        // 処理を標準のFilenameCompleterに丸投げ（委譲）する
+        start: usize,
+        home: impl Fn(&str) -> Option<String>,
+    ) -> Option<(usize, Vec<Pair>)> {
+        let (tilde_len, home) = split_tilde_prefix(&line[start..pos], home)?;
+        let home = home.replace(' ', "\\ ");
+        let expanded = format!("{}{}{}", &line[..start], home, &line[start + tilde_len..]);
+        let (expanded_start, files) = self.complete_files(&expanded, pos - tilde_len + home.len());
+        // 候補は単語全体を置き換える (クォートの途中などで区切りがずれたら諦める)
+        if expanded_start != start {
+            return None;
+        }
+        if self.expand_tilde {
+            return Some((start, files));
+        }
+        let tilde = &line[start..start + tilde_len];
+        let files = files
+            .into_iter()
+            .map(|pair| Pair {
+                replacement: match pair.replacement.strip_prefix(&home) {
+                    Some(rest) => format!("{}{}", tilde, rest),
+                    None => pair.replacement,
+                },
+                display: pair.display,
+            })
+            .collect();
+        Some((start, files))
+    }
+
+    /// git の引数候補を返す
+    /// 同じ行で Tab を押し直したとき (補完中の単語より前が同じとき) は前回の結果を使い回す
+    fn cached_git_arg_candidates(&self, subcommand: &str, cwd: &Path, before: &str) -> Vec<String> {
+        let key = format!("{}\0{}", cwd.display(), before);
+        if let Some((cached_key, candidates)) = &*self.git_arg_cache.borrow()
+            && *cached_key == key
+        {
+            return candidates.clone();
+        }
+        let candidates = get_git_arg_candidates(subcommand, cwd);
+        *self.git_arg_cache.borrow_mut() = Some((key, candidates.clone()));
+        candidates
+    }
+
+    /// 先頭の単語が実行できるコマンドか (内部コマンドも含む)
+    /// 変数やクォートを含む単語は実行時まで分からないので見つかるものとして扱う
+    /// パス指定 (`./run.sh`) は cd で結果が変わるので覚えない
+    fn program_resolvable(&self, name: &str) -> bool {
+        if is_builtin_command(name) || name.contains(['$', '"', '\'', '`']) {
+            return true;
+        }
+        if name.contains(std::path::is_separator) {
+            return program_exists(name);
+        }
+        if let Some(&found) = self.resolved_programs.borrow().get(name) {
+            return found;
+        }
+        let found = program_exists(name);
+        self.resolved_programs
+            .borrow_mut()
+            .insert(name.to_string(), found);
+        found
+    }
+
+    /// PATH 上の実行ファイルのうち prefix で始まるものを返す
+    fn path_executable_candidates(&self, prefix: &str) -> Vec<String> {
+        let mut cache = self.path_executables.borrow_mut();
+        let executables = cache.get_or_insert_with(|| {
+            env::var_os("PATH")
+                .map(|path| list_path_executables(&path))
+                .unwrap_or_default()
+        });
+        executables
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// 補完用のサブコマンド候補を返す
+    /// 静的リストに無いツールは `--help` の出力から学習した結果を使う (初回のみ実行)
+    /// 補完の本体 (context_program はコンテキスト、`!` 脱出中は None)
+    fn complete_line(
+        &self,
+        line: &str,
+        pos: usize,
+        context_program: Option<&str>,
+    ) -> (usize, Vec<Pair>) {
         let line_up_to_cursor = &line[..pos];
 
         let args = shell_words::split(line_up_to_cursor).unwrap_or_default();
@@ -52,9 +264,110 @@ impl Completer for WithHelper {
             args.len() - 1
         };
 
-        let target_cmd = if let Some(prog) = &self.context_program {
+        // 現在入力中の単語と、その開始位置
+        let word = if has_trailing_space {
+            ""
+        } else {
+            args.last().map(|s| s.as_str()).unwrap_or("")
+        };
+        let start = pos - word.len();
+
+        // --- cd のブックマーク補完 (`@` で始まるときはブックマークだけを候補にする) ---
+        if args.first().is_some_and(|a| a == "cd")
+            && current_arg_index == 1
+            && let Some(prefix) = word.strip_prefix('@')
+        {
+            return (start, self.bookmark_candidates(prefix));
+        }
+
+        // --- コンテキストなしの先頭の単語は PATH 上のコマンド名を補完する ---
+        // パスを入力しているときや候補が無いときはファイル補完に落ちる
+        if context_program.is_none()
+            && current_arg_index == 0
+            && !word.contains(std::path::is_separator)
+        {
+            let matches: Vec<Pair> = self
+                .path_executable_candidates(word)
+                .into_iter()
+                .map(|c| Pair {
+                    display: c.clone(),
+                    replacement: c,
+                })
+                .collect();
+            if !matches.is_empty() {
+                return (start, matches);
+            }
+        }
+
+        // --- git の引数補完 (リモート名・ブランチ名・タグ名) ---
+        // コンテキストありなら 0 番目、なしなら 1 番目がサブコマンド
+        let (program, subcmd_idx) = match context_program {
+            Some(prog) => (Some(prog), 0),
+            None => (args.first().map(|s| s.as_str()), 1),
+        };
+        if program == Some("git") && current_arg_index == subcmd_idx + 1 {
+            let cwd = env::current_dir().unwrap_or_default();
+            let matches: Vec<Pair> = self
+                .cached_git_arg_candidates(&args[subcmd_idx], &cwd, &line[..start])
+                .into_iter()
+                .filter(|c| c.starts_with(word))
+                .map(|c| Pair {
+                    display: c.clone(),
+                    replacement: c,
+                })
+                .collect();
+
+            if !matches.is_empty() {
+                return (start, matches);
+            }
+        }
+
+        // --- npm / pnpm / yarn の run 補完 (package.json の scripts) ---
+        // package.json が無ければ下のファイル補完に落ちる
+        if let Some(program) = program
+            && current_arg_index == subcmd_idx + 1
+            && let Some(scripts) = get_script_candidates(
+                program,
+                &args[subcmd_idx],
+                &env::current_dir().unwrap_or_default(),
+            )
+        {
+            let matches: Vec<Pair> = scripts
+                .into_iter()
+                .filter(|c| c.starts_with(word))
+                .map(|c| Pair {
+                    display: c.clone(),
+                    replacement: c,
+                })
+                .collect();
+            return (start, matches);
+        }
+
+        // --- 2階層目のサブコマンド補完 (`git remote add`, `docker compose up` など) ---
+        if let Some(program) = program
+            && current_arg_index == subcmd_idx + 1
+        {
+            let nested: Vec<String> = self
+                .subcommands()
+                .nested(program, &args[subcmd_idx])
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect();
+            let matches: Vec<Pair> = match_candidates(nested, word, self.fuzzy)
+                .into_iter()
+                .map(|c| Pair {
+                    display: c.clone(),
+                    replacement: c,
+                })
+                .collect();
+            if !matches.is_empty() {
+                return (start, matches);
+            }
+        }
+
+        let target_cmd = if let Some(prog) = context_program {
             if current_arg_index == 0 {
-                Some(prog.as_str())
+                Some(prog)
             } else {
                 None
             }
@@ -65,67 +378,105 @@ impl Completer for WithHelper {
         };
 
         if let Some(cmd) = target_cmd {
-            let word = if has_trailing_space {
-                ""
-            } else {
-                args.last().map(|s| s.as_str()).unwrap_or("")
+            let candidates = match self.preferred_subcommands.get(cmd) {
+                Some(preferred) => order_by_preference(self.completion_subcommands(cmd), preferred),
+                None => self.completion_subcommands(cmd),
             };
-
-            let start = pos - word.len();
-
-            let candidates = get_subcommands(cmd);
-            let matches: Vec<Pair> = candidates
+            let matches: Vec<Pair> = match_candidates(candidates, word, self.fuzzy)
                 .into_iter()
-                .filter(|c| c.starts_with(word))
                 .map(|c| Pair {
-                    display: c.to_string(),
-                    replacement: c.to_string(),
+                    display: c.clone(),
+                    replacement: c,
                 })
                 .collect();
 
             if !matches.is_empty() {
-                return Ok((start, matches));
+                return (start, matches);
             }
         }
 
-        self.completer.complete(line, pos, ctx)
+        let (file_start, files) = self
+            .complete_tilde_files(line, pos, start, home_for)
+            .unwrap_or_else(|| self.complete_files(line, pos));
+
+        // --- 拡張子による絞り込み (例: `git apply` の後は *.patch / *.diff) ---
+        let prev_token = current_arg_index.checked_sub(1).and_then(|i| args.get(i));
+        let subcommand = args
+            .get(subcmd_idx)
+            .filter(|_| current_arg_index > subcmd_idx);
+        if let Some(program) = program
+            && let Some(rule) = find_extension_rule(
+                &self.extension_rules,
+                program,
+                subcommand.map(|s| s.as_str()),
+                prev_token.map(|s| s.as_str()),
+            )
+        {
+            return (file_start, filter_by_extension(files, &rule.extensions));
+        }
+
+        (file_start, files)
     }
-}
 
-impl Highlighter for WithHelper {
-    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
-        // 色付けする必要がない（空行など）場合はそのまま返す
-        if line.trim().is_empty() {
-            return Cow::Borrowed(line);
-        }
+    /// 名前が prefix で始まるブックマークを候補にする (表示にはパスを添える)
+    fn bookmark_candidates(&self, prefix: &str) -> Vec<Pair> {
+        self.bookmarks
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, path)| Pair {
+                display: format!("@{}  {}", name, path),
+                replacement: format!("@{}", name),
+            })
+            .collect()
+    }
 
+    /// ハイライトの本体 (context_program はコンテキスト、`!` 脱出中は None)
+    fn highlight_line(&self, line: &str, context_program: Option<&str>) -> String {
         // 単語の境界（開始位置と終了位置）を探す簡易パーサ
         // ※ shell_words::split だと空白が消えてしまうため、表示用に位置だけ特定する
         let mut word_ranges = Vec::new();
         let mut in_word = false;
         let mut start_idx = 0;
         let mut in_quote = None; // クォート内判定用
+        let mut subst_depth = 0; // `$(...)` の入れ子の深さ (中の空白では区切らない)
+        let mut in_operator = false; // 読んでいる単語が演算子 (`|`, `&&`, `>` など) か
+        let mut escaped = false; // 直前が `\` (次の文字は演算子にしない)
+        let mut prev = None;
 
         for (i, c) in line.char_indices() {
+            let after_dollar = prev.replace(c) == Some('$');
+            if in_quote.is_none() && c == '(' && after_dollar {
+                subst_depth += 1;
+            } else if in_quote.is_none() && c == ')' && subst_depth > 0 {
+                subst_depth -= 1;
+            }
+
             if let Some(q) = in_quote {
                 if c == q {
                     in_quote = None; // クォート終了
                 }
-            } else if c == '"' || c == '\'' {
-                in_quote = Some(c); // クォート開始
-                if !in_word {
-                    start_idx = i;
-                    in_word = true;
-                }
-            } else if c.is_whitespace() {
+            } else if c.is_whitespace() && subst_depth == 0 {
                 if in_word {
                     word_ranges.push((start_idx, i)); // 単語の終わり
                     in_word = false;
                 }
-            } else if !in_word {
-                start_idx = i; // 単語の始まり
-                in_word = true;
+            } else {
+                // 演算子は空白が無くても前後の単語と分ける (`git log|head`)
+                let operator = subst_depth == 0 && !escaped && is_operator_char(c);
+                if in_word && operator != in_operator {
+                    word_ranges.push((start_idx, i));
+                    in_word = false;
+                }
+                if !in_word {
+                    start_idx = i; // 単語の始まり
+                    in_word = true;
+                    in_operator = operator;
+                }
+                if c == '"' || c == '\'' {
+                    in_quote = Some(c); // クォート開始
+                }
             }
+            escaped = in_quote.is_none() && !escaped && c == '\\';
         }
         // 最後の単語を処理
         if in_word {
@@ -134,8 +485,8 @@ impl Highlighter for WithHelper {
 
         // --- 色判定 ---
         // 親コマンド名の特定
-        let parent_cmd_name = if let Some(ctx_prog) = &self.context_program {
-            Some(ctx_prog.as_str())
+        let parent_cmd_name = if let Some(ctx_prog) = context_program {
+            Some(ctx_prog)
         } else if !word_ranges.is_empty() {
             let (s, e) = word_ranges[0];
             Some(&line[s..e])
@@ -145,11 +496,11 @@ impl Highlighter for WithHelper {
 
         // 親コマンドがサブコマンドを持つコマンドかを確認
         let expects_subcommand = parent_cmd_name
-            .map(|name| !get_subcommands(name).is_empty())
+            .map(|name| !self.subcommands().subcommands(name).is_empty())
             .unwrap_or(false);
 
         // 何番目の単語をどう色付けするか決める
-        let (prog_idx, subcmd_idx) = if self.context_program.is_some() {
+        let (prog_idx, subcmd_idx) = if context_program.is_some() {
             // Case A: `with git` (コンテキストあり)
             // 0番目の単語 = サブコマンド (例: "status")
             (None, if expects_subcommand { Some(0) } else { None })
@@ -160,6 +511,15 @@ impl Highlighter for WithHelper {
             (Some(0), if expects_subcommand { Some(1) } else { None })
         };
 
+        // 2階層目のサブコマンド (例: "git remote add" の "add")
+        // 定義済みの語だけを色付けし、未定義なら通常の引数色のまま
+        let nested_idx = subcmd_idx.and_then(|idx| {
+            let (s, e) = *word_ranges.get(idx)?;
+            let (ns, ne) = *word_ranges.get(idx + 1)?;
+            let nested = self.subcommands().nested(parent_cmd_name?, &line[s..e]);
+            nested.contains(&&line[ns..ne]).then_some(idx + 1)
+        });
+
         // 文字列を再構築する
         let mut new_line = String::with_capacity(line.len() + 20);
         let mut last_idx = 0;
@@ -168,31 +528,48 @@ impl Highlighter for WithHelper {
             // 前の単語との間の空白などを追加
             new_line.push_str(&line[last_idx..*start]);
 
-            let word = &line[*start..*end];
+            let raw = &line[*start..*end];
 
             // 色を決定
-            if Some(i) == prog_idx {
-                // 親コマンド: 緑
-                new_line.push_str(COLOR_CYAN);
-                new_line.push_str(word);
-                new_line.push_str(STYLE_RESET);
+            let color = if raw.chars().all(is_operator_char) {
+                // 演算子: 太字 (クォート内の記号は文字列の一部なのでここには来ない)
+                Some(self.theme.operator)
+            } else if Some(i) == prog_idx {
+                // 親コマンド: シアン (見つからなければ赤)
+                if self.program_resolvable(raw) {
+                    Some(self.theme.context)
+                } else {
+                    Some(self.theme.warning)
+                }
             } else if Some(i) == subcmd_idx {
                 // サブコマンド: シアン
-                new_line.push_str(COLOR_GREEN);
-                new_line.push_str(word);
-                new_line.push_str(STYLE_RESET);
-            } else if word.starts_with('"') || word.starts_with('\'') {
-                new_line.push_str(COLOR_WHITE);
-                new_line.push_str(word);
-                new_line.push_str(STYLE_RESET);
-            } else if word.starts_with('-') {
+                Some(self.theme.subcommand)
+            } else if Some(i) == nested_idx {
+                // 2階層目のサブコマンド: 青
+                Some(self.theme.nested)
+            } else if raw.starts_with('"') || raw.starts_with('\'') {
+                Some(self.theme.string)
+            } else if raw.starts_with('-') {
                 // オプション引数: 黄色
-                new_line.push_str(COLOR_YELLOW);
-                new_line.push_str(word);
-                new_line.push_str(STYLE_RESET);
+                Some(self.theme.option)
             } else {
                 // その他: そのまま
-                new_line.push_str(word);
+                None
+            };
+
+            // 単語の中の変数・コマンド置換は専用色にする
+            let word = if raw.contains('$') {
+                Cow::Owned(self.highlight_vars(raw, color.unwrap_or("")))
+            } else {
+                Cow::Borrowed(raw)
+            };
+            match color {
+                Some(color) => {
+                    new_line.push_str(color);
+                    new_line.push_str(&word);
+                    new_line.push_str(STYLE_RESET);
+                }
+                None => new_line.push_str(&word),
             }
 
             last_idx = *end;
@@ -201,341 +578,846 @@ impl Highlighter for WithHelper {
         // 末尾の残りの文字（空白など）を追加
         new_line.push_str(&line[last_idx..]);
 
-        Cow::Owned(new_line)
-    }
-
-    fn highlight_char(
-        &self,
-        _line: &str,
-        _pos: usize,
-        _kind: rustyline::highlight::CmdKind,
-    ) -> bool {
-        true
+        new_line
     }
 
-    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
-        &'s self,
-        prompt: &'p str,
-        _default: bool,
-    ) -> Cow<'b, str> {
-        if let Some(end_arrow) = prompt.rfind("> ") {
-            // パターン1: ディレクトリ情報あり "(.: branch) git/cargo >"
-            if prompt.starts_with('(') {
-                if let Some(close_paren) = prompt.find(") ") {
-                    // --- ディレクトリ表示部分 (既存のまま) ---
-                    let content_inside = &prompt[1..close_paren];
-
-                    let styled_content = if let Some(sep_idx) = content_inside.find(": ") {
-                        let path_part = &content_inside[0..sep_idx];
-                        let branch_part = &content_inside[sep_idx + 2..];
-
-                        format!(
-                            "{}{}{}{}{}: {}{}{}",
-                            COLOR_GREEN, // Path Color
-                            path_part,
-                            STYLE_RESET,
-                            STYLE_BOLD, // Separator Style
-                            STYLE_RESET,
-                            COLOR_MAGENTA, // Branch Color
-                            branch_part,
-                            STYLE_RESET // Reset before ')'
-                        )
+    /// 単語の中の `$VAR` / `${VAR}` / `$(...)` を色付けする (base は単語自体の色)
+    /// `$(...)` の中はコマンドとして色付けし直し、閉じていないものは警告色にする
+    /// シングルクォート内とエスケープされた `\$` は展開されないので装飾しない
+    fn highlight_vars(&self, word: &str, base: &str) -> String {
+        let var = self.theme.variable;
+        let mut out = String::with_capacity(word.len() + 20);
+        let mut quote = None;
+        let mut escaped = false;
+        let mut chars = word.char_indices();
+
+        while let Some((i, c)) = chars.next() {
+            if escaped {
+                escaped = false;
+                out.push(c);
+                continue;
+            }
+            let rest = &word[i + c.len_utf8()..];
+            let len = match (quote, c) {
+                (Some('\''), '\'') | (Some('"'), '"') => {
+                    quote = None;
+                    None
+                }
+                (Some('\''), _) => None,
+                (None, '\'' | '"') => {
+                    quote = Some(c);
+                    None
+                }
+                (_, '\\') => {
+                    escaped = true;
+                    None
+                }
+                (_, '$') if rest.starts_with('(') => match find_closing_paren(&rest[1..]) {
+                    Some(end) => {
+                        let inner = self.highlight_line(&rest[1..1 + end], None);
+                        out.push_str(&format!(
+                            "{var}$({STYLE_RESET}{inner}{var}){STYLE_RESET}{base}"
+                        ));
+                        Some(end + 2)
+                    }
+                    None => {
+                        out.push_str(&format!(
+                            "{}{}{}",
+                            self.theme.warning,
+                            &word[i..],
+                            STYLE_RESET
+                        ));
+                        return out;
+                    }
+                },
+                (_, '$') if rest.starts_with('{') => match rest.find('}') {
+                    Some(end) => {
+                        out.push_str(&format!("{var}${}{STYLE_RESET}{base}", &rest[..=end]));
+                        Some(end + 1)
+                    }
+                    None => {
+                        out.push_str(&format!(
+                            "{}{}{}",
+                            self.theme.warning,
+                            &word[i..],
+                            STYLE_RESET
+                        ));
+                        return out;
+                    }
+                },
+                (_, '$') => {
+                    // `$?` / `$?out` も with が展開する変数として扱う
+                    let name_len = if rest.starts_with("?out") {
+                        4
+                    } else if rest.starts_with('?') {
+                        1
                     } else {
-                        format!("{}{}{}", COLOR_GREEN, content_inside, STYLE_RESET)
+                        rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                            .unwrap_or(rest.len())
                     };
-                    // -------------------------------------
-
-                    let cmd_start = close_paren + 2;
-                    let cmd_part = &prompt[cmd_start..end_arrow];
-
-                    // 最後のスラッシュで分割
-                    // 例: "//git" -> parent="/", current="git" -> path_str="//", current_str="git"
-                    let (path_str, current_str) =
-                        if let Some((parent, current)) = cmd_part.rsplit_once('/') {
-                            (format!("{}/", parent), current)
-                        } else {
-                            (String::new(), cmd_part)
-                        };
-
-                    // ★修正済み: フォーマット指定子 {} の数を引数に合わせています
-                    let styled = format!(
-                        "{}({}) {}{}{}{}{}{}{}{}> {}", // {} を1つ追加 (合計11個)
-                        STYLE_BOLD,                    // 1: (
-                        styled_content,                // 2: dir info
-                        STYLE_BOLD,                    // 3: )
-                        STYLE_RESET,                   // ★4: 追加！ ここで一度太字をリセットします
-                        COLOR_CYAN,                    // 5: path color
-                        path_str,                      // 6: path string (これで細字+水色になります)
-                        STYLE_BOLD,                    // 7: current bold
-                        current_str,                   // 8: current string (これは太字+水色)
-                        STYLE_RESET,                   // 9: reset current
-                        STYLE_BOLD,                    // 10: >
-                        STYLE_RESET                    // 11: reset all
-                    );
-                    return Cow::Owned(styled);
+                    (name_len > 0).then(|| {
+                        out.push_str(&format!("{var}${}{STYLE_RESET}{base}", &rest[..name_len]));
+                        name_len
+                    })
                 }
+                _ => None,
+            };
+            match len {
+                // 色付けした分 (ASCII の記号と変数名) を読み飛ばす
+                Some(len) => {
+                    let skip_to = i + 1 + len;
+                    while chars.clone().next().is_some_and(|(j, _)| j < skip_to) {
+                        chars.next();
+                    }
+                }
+                None => out.push(c),
             }
-            // パターン2: ディレクトリ情報なし "git/cargo >"
-            else {
-                let cmd_part = &prompt[0..end_arrow];
+        }
+        out
+    }
 
-                let (path_str, current_str) =
-                    if let Some((parent, current)) = cmd_part.rsplit_once('/') {
-                        (format!("{}/", parent), current)
-                    } else {
-                        (String::new(), cmd_part)
-                    };
+    fn completion_subcommands(&self, command: &str) -> Vec<String> {
+        let builtin = self.subcommands().subcommands(command);
+        if !builtin.is_empty() || !self.learn_from_help {
+            return builtin.into_iter().map(|s| s.to_string()).collect();
+        }
+
+        if let Some(learned) = self.learned_subcommands.borrow().get(command) {
+            return learned.clone();
+        }
 
-                // ★修正済み: こちらも {} の数を修正
-                let styled = format!(
-                    "{}{}{}{}{}{}{}{}> {}",
-                    STYLE_BOLD,  // 1: Bold start
-                    STYLE_RESET, // 2: Reset (safety)
-                    COLOR_CYAN,  // 3: Color
-                    path_str,    // 4: path (Fine)
-                    STYLE_BOLD,  // 5: current Bold
-                    current_str, // 6: current
-                    STYLE_RESET, // 7: reset
-                    STYLE_BOLD,  // 8: >
-                    STYLE_RESET  // 9: reset all
-                );
-                return Cow::Owned(styled);
+        // `--help` の実行が遅いツールでも入力を止めないよう、ワーカースレッドで学習する
+        let owned = command.to_string();
+        let timeout = if self.completion_timeout.is_zero() {
+            Duration::MAX
+        } else {
+            self.completion_timeout
+        };
+        let learned = self
+            .help_lookup
+            .borrow_mut()
+            .get_or_spawn(command, timeout, move || learn_subcommands(&owned));
+        match learned {
+            Some(learned) => {
+                self.learned_subcommands
+                    .borrow_mut()
+                    .insert(command.to_string(), learned.clone());
+                learned
             }
+            None => vec![],
         }
-        Cow::Borrowed(prompt)
+    }
+
+    /// サブコマンドの位置で入力中の単語と、それに前方一致するサブコマンド
+    /// 候補はよく使う順に並べ、よく使うものとして集計されていれば true を添える
+    fn hint_subcommands<'l>(&self, line: &'l str) -> Option<(&'l str, Vec<(String, bool)>)> {
+        let (line, context_program) = match escape_offset(line) {
+            Some(offset) => (&line[offset..], None),
+            None => (line, self.context_program.as_deref()),
+        };
+        if line.ends_with(char::is_whitespace) {
+            return None;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (program, typed) = match (context_program, words.as_slice()) {
+            (Some(program), [typed]) => (program, *typed),
+            (None, [program, typed]) => (*program, *typed),
+            _ => return None,
+        };
+        // キーを押すたびに `--help` を実行しないよう、定義済みと学習済みの候補だけを使う
+        let mut candidates: Vec<String> = self
+            .subcommands()
+            .subcommands(program)
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect();
+        if candidates.is_empty() {
+            candidates = self
+                .learned_subcommands
+                .borrow()
+                .get(program)
+                .cloned()
+                .unwrap_or_default();
+        }
+        let preferred = self
+            .preferred_subcommands
+            .get(program)
+            .map(|p| p.as_slice())
+            .unwrap_or_default();
+        let matches = order_by_preference(candidates, preferred)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(typed) && candidate.len() > typed.len())
+            .map(|candidate| {
+                let is_preferred = preferred.contains(&candidate);
+                (candidate, is_preferred)
+            })
+            .collect();
+        Some((typed, matches))
     }
 }
 
-/// 指定されたコマンドに対するサブコマンドのリストを返す
-pub fn get_subcommands(command: &str) -> Vec<&str> {
-    match command {
-        "git" => vec![
-            "add", "bisect", "blame", "branch", "checkout", "clean", "clone", "commit", "config",
-            "diff", "fetch", "grep", "init", "log", "merge", "mv", "pull", "push", "rebase",
-            "reflog", "remote", "reset", "restore", "revert", "rm", "show", "stash", "status",
-            "switch", "tag",
-        ],
-
-        "cargo" => vec![
-            "add", "bench", "build", "check", "clean", "clippy", "doc", "expand", "fix", "fmt",
-            "init", "install", "metadata", "new", "publish", "remove", "run", "search", "test",
-            "tree", "update", "yank",
-        ],
-
-        "pnpm" | "bun" | "npm" | "yarn" => vec![
-            "add",
-            "audit",
-            "build",
-            "ci",
-            "create",
-            "exec",
-            "init",
-            "install",
-            "link",
-            "list",
-            "outdated",
-            "pack",
-            "publish",
-            "remove",
-            "restart",
-            "run",
-            "start",
-            "stop",
-            "test",
-            "uninstall",
-            "unlink",
-            "update",
-            "why",
-        ],
-
-        "docker" => vec![
-            "attach", "build", "compose", "cp", "create", "diff", "events", "exec", "export",
-            "history", "images", "import", "info", "inspect", "kill", "load", "login", "logout",
-            "logs", "network", "pause", "port", "ps", "pull", "push", "rename", "restart", "rm",
-            "rmi", "run", "save", "search", "start", "stats", "stop", "system", "tag", "top",
-            "unpause", "update", "version", "volume", "wait",
-        ],
-
-        "uv" => vec![
-            "add", "cache", "clean", "export", "init", "lock", "pip", "python", "remove", "run",
-            "self", "sync", "tool", "tree", "venv", "version",
-        ],
-
-        "pip" | "pip3" => vec![
-            "check",
-            "config",
-            "debug",
-            "download",
-            "freeze",
-            "hash",
-            "install",
-            "list",
-            "show",
-            "uninstall",
-            "wheel",
-        ],
-
-        "kubectl" | "k" => vec![
-            "apply",
-            "api-resources",
-            "attach",
-            "auth",
-            "autoscale",
-            "certificate",
-            "cluster-info",
-            "config",
-            "cordon",
-            "cp",
-            "create",
-            "delete",
-            "describe",
-            "diff",
-            "drain",
-            "edit",
-            "exec",
-            "explain",
-            "expose",
-            "get",
-            "label",
-            "logs",
-            "options",
-            "patch",
-            "plugin",
-            "port-forward",
-            "proxy",
-            "replace",
-            "rollout",
-            "run",
-            "scale",
-            "set",
-            "taint",
-            "top",
-            "uncordon",
-            "version",
-            "wait",
-        ],
-
-        "terraform" | "tf" => vec![
-            "apply",
-            "console",
-            "destroy",
-            "fmt",
-            "get",
-            "graph",
-            "import",
-            "init",
-            "login",
-            "logout",
-            "output",
-            "plan",
-            "providers",
-            "refresh",
-            "show",
-            "state",
-            "taint",
-            "test",
-            "untaint",
-            "validate",
-            "version",
-            "workspace",
-        ],
+/// `!` 脱出 (コンテキストを適用しない入力) なら、`!` の直後の位置を返す純粋関数
+/// parse_cmd と同じく、先頭の空白を除いて `!` で始まる入力を対象にする
+pub(crate) fn escape_offset(line: &str) -> Option<usize> {
+    let trimmed = line.trim_start();
+    trimmed
+        .starts_with('!')
+        .then(|| line.len() - trimmed.len() + 1)
+}
 
-        _ => vec![],
+/// ハイライトで演算子として区切る文字 (`|`, `&&`, `||`, `;`, `>`, `>>`, `<`)
+fn is_operator_char(c: char) -> bool {
+    matches!(c, '|' | '&' | ';' | '<' | '>')
+}
+
+/// 優先するものを先頭 (preferred の順) に、残りをアルファベット順に並べる純粋関数
+/// preferred が空なら元の並びのまま
+fn order_by_preference(candidates: Vec<String>, preferred: &[String]) -> Vec<String> {
+    if preferred.is_empty() {
+        return candidates;
     }
+    let (mut first, mut rest): (Vec<String>, Vec<String>) =
+        candidates.into_iter().partition(|c| preferred.contains(c));
+    first.sort_by_key(|c| preferred.iter().position(|p| p == c));
+    rest.sort();
+    first.append(&mut rest);
+    first
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rustyline::Context;
-    use rustyline::history::DefaultHistory;
+// プロンプトの色付け用
+pub(crate) const COLOR_RED: &str = "\x1b[31m";
+pub(crate) const COLOR_GREEN: &str = "\x1b[32m";
+pub(crate) const COLOR_YELLOW: &str = "\x1b[33m";
+pub(crate) const COLOR_BLUE: &str = "\x1b[34m";
+pub(crate) const COLOR_MAGENTA: &str = "\x1b[35m";
+pub(crate) const COLOR_CYAN: &str = "\x1b[36m";
+pub(crate) const COLOR_WHITE: &str = "\x1b[37m";
+pub(crate) const STYLE_BOLD: &str = "\x1b[1m";
+pub(crate) const STYLE_DIM: &str = "\x1b[2m";
+pub(crate) const STYLE_RESET: &str = "\x1b[0m";
+
+/// 色付けしてよい環境かを判定する純粋関数
+/// NO_COLOR が (値に関係なく) 指定されている、TERM が dumb、出力先が端末でない場合は色を付けない
+pub fn color_allowed(no_color: bool, term: Option<&str>, is_terminal: bool) -> bool {
+    !no_color && term != Some("dumb") && is_terminal
+}
 
-    // テスト用のヘルパー作成関数
-    fn create_helper(context_program: Option<&str>) -> WithHelper {
-        WithHelper {
-            completer: FilenameCompleter::new(),
-            context_program: context_program.map(|s| s.to_string()),
-        }
-    }
+/// 現在の環境で色付けするか (起動時に一度だけ判定して WithHelper::color に入れる)
+pub fn detect_color() -> bool {
+    color_allowed(
+        env::var_os("NO_COLOR").is_some(),
+        env::var("TERM").ok().as_deref(),
+        io::stdout().is_terminal(),
+    )
+}
 
-    // 補完結果に特定の文字列が含まれているかチェックする
-    fn assert_contains(candidates: &[Pair], value: &str) {
-        let found = candidates.iter().any(|p| p.replacement == value);
-        let debug_list: Vec<&str> = candidates.iter().map(|p| p.replacement.as_str()).collect();
-        assert!(
-            found,
-            "Expected completion '{}' not found in {:?}",
-            value, debug_list
-        );
+impl Completer for WithHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        // `!` 脱出はコンテキストを無視し、`!` の後ろを通常の入力として補完する
+        if let Some(offset) = escape_offset(line)
+            && pos >= offset
+        {
+            let (start, candidates) = self.complete_line(&line[offset..], pos - offset, None);
+            let start = start + offset;
+            return Ok((
+                start,
+                self.emphasize_candidates(&line[start..pos], candidates),
+            ));
+        }
+        let (start, candidates) = self.complete_line(line, pos, self.context_program.as_deref());
+        Ok((
+            start,
+            self.emphasize_candidates(&line[start..pos], candidates),
+        ))
     }
+}
 
-    // 補完結果に特定の文字列が含まれて *いない* ことチェックする
-    fn assert_not_contains(candidates: &[Pair], value: &str) {
-        let found = candidates.iter().any(|p| p.replacement == value);
-        assert!(!found, "Unexpected completion '{}' found", value);
+impl WithHelper {
+    /// 候補一覧の表示で入力に一致した部分を強調する (replacement は装飾しない)
+    fn emphasize_candidates(&self, typed: &str, candidates: Vec<Pair>) -> Vec<Pair> {
+        if !self.color || typed.is_empty() {
+            return candidates;
+        }
+        candidates
+            .into_iter()
+            .map(|pair| {
+                let matched = prefix_match(&pair.display, typed).or_else(|| {
+                    self.fuzzy
+                        .then(|| fuzzy_match(&pair.display, typed))
+                        .flatten()
+                });
+                match matched {
+                    Some(matched) => Pair {
+                        display: emphasize_matches(&pair.display, &matched),
+                        replacement: pair.replacement,
+                    },
+                    None => pair,
+                }
+            })
+            .collect()
     }
+}
 
-    #[test]
-    fn test_context_mode_subcommand() {
-        // ケース: `with git` で起動中 (context_program = "git")
-        let helper = create_helper(Some("git"));
-        let history = DefaultHistory::new();
-        let ctx = Context::new(&history);
+/// display が typed で始まれば (大文字・小文字は区別しない)、一致した文字の位置 (バイト単位) を返す
+fn prefix_match(display: &str, typed: &str) -> Option<Vec<usize>> {
+    let mut chars = display.char_indices();
+    typed
+        .chars()
+        .map(|t| {
+            let (i, c) = chars.next()?;
+            eq_ignore_case(c, t).then_some(i)
+        })
+        .collect()
+}
 
-        // 1. "st" と入力 -> "status" が出るべき (index 0)
-        let line = "st";
-        let pos = line.len();
-        let (start, res) = helper.complete(line, pos, &ctx).unwrap();
+/// 大文字・小文字を区別せずに1文字を比べる
+fn eq_ignore_case(a: char, b: char) -> bool {
+    a == b || a.to_lowercase().eq(b.to_lowercase())
+}
 
-        // start位置は 0 (先頭から置換)
-        assert_eq!(start, 0);
-        assert_contains(&res, "status");
+/// typed の文字が display に順に現れれば (部分列なら)、一致した文字の位置 (バイト単位) を返す
+/// 大文字・小文字は区別しない
+fn fuzzy_match(display: &str, typed: &str) -> Option<Vec<usize>> {
+    let mut matched = Vec::new();
+    let mut chars = display.char_indices();
+    for t in typed.chars() {
+        let (i, _) = chars.find(|(_, c)| eq_ignore_case(*c, t))?;
+        matched.push(i);
+    }
+    Some(matched)
+}
 
-        // 2. "status " (スペースあり) -> サブコマンド補完は出ないべき (index 1)
-        // ※実際にはファイル補完が走るが、ここでは "status" 等が出ないことを確認
-        let line = "status ";
-        let pos = line.len();
-        let (_, res) = helper.complete(line, pos, &ctx).unwrap();
+/// 入力に一致する候補を返す純粋関数 (大文字・小文字は区別せず、候補の表記のまま返す)
+/// 前方一致の候補を元の並びのまま先に置き、fuzzy なら部分列で一致する候補を近い順に続ける
+/// (一致した文字が詰まっているほど、先頭に近いほど近いとみなす)
+fn match_candidates(candidates: Vec<String>, typed: &str, fuzzy: bool) -> Vec<String> {
+    let (mut matches, rest): (Vec<String>, Vec<String>) = candidates
+        .into_iter()
+        .partition(|c| prefix_match(c, typed).is_some());
+    if !fuzzy {
+        return matches;
+    }
+    let mut fuzzy_matches: Vec<((usize, usize), String)> = rest
+        .into_iter()
+        .filter_map(|c| {
+            let matched = fuzzy_match(&c, typed)?;
+            let first = *matched.first()?;
+            let span = matched.last()? - first;
+            Some(((span, first), c))
+        })
+        .collect();
+    fuzzy_matches.sort_by_key(|(score, _)| *score);
+    matches.extend(fuzzy_matches.into_iter().map(|(_, c)| c));
+    matches
+}
 
-        // 次の引数には "status" コマンドは提案されないはず
-        assert_not_contains(&res, "status");
+/// matched の位置の文字を太字にする純粋関数 (連続する文字はまとめて囲む)
+/// 前方一致なら先頭の連続した範囲、ファジーマッチなら飛び飛びの文字を渡す
+pub(crate) fn emphasize_matches(display: &str, matched: &[usize]) -> String {
+    let mut out = String::new();
+    let mut in_match = false;
+    for (i, c) in display.char_indices() {
+        let hit = matched.contains(&i);
+        if hit && !in_match {
+            out.push_str(STYLE_BOLD);
+        } else if !hit && in_match {
+            out.push_str(STYLE_RESET);
+        }
+        in_match = hit;
+        out.push(c);
     }
+    if in_match {
+        out.push_str(STYLE_RESET);
+    }
+    out
+}
 
-    #[test]
-    fn test_no_context_mode_subcommand() {
-        // ケース: `with` 単体起動 (context_program = None)
-        let helper = create_helper(None);
-        let history = DefaultHistory::new();
-        let ctx = Context::new(&history);
+/// 組み込みの拡張子絞り込みルール
+fn default_extension_rules() -> Vec<ExtensionRule> {
+    let rule = |command: &str, after: &str, extensions: &[&str]| ExtensionRule {
+        command: command.to_string(),
+        after: after.to_string(),
+        extensions: extensions.iter().map(|e| e.to_string()).collect(),
+    };
+    vec![
+        rule("cargo", "--manifest-path", &["toml"]),
+        rule("git", "apply", &["patch", "diff"]),
+        rule("git", "am", &["patch", "mbox"]),
+        rule("docker", "--env-file", &["env"]),
+        rule("kubectl", "-f", &["yaml", "yml", "json"]),
+        rule("terraform", "-var-file", &["tfvars", "json"]),
+    ]
+}
 
-        // 1. "git " (スペース直後) -> "status" 等が出るべき (index 1)
-        let line = "git ";
-        let pos = line.len();
-        let (start, res) = helper.complete(line, pos, &ctx).unwrap();
+/// 単語の先頭の `~` / `~user` (後ろに `/` が続くもの) をホームディレクトリに置き換える準備をする純粋関数
+/// 戻り値は (`~user/` 部分の長さ, ホームディレクトリ)。home はユーザー名 (空なら自分) からホームを引く
+fn split_tilde_prefix(
+    word: &str,
+    home: impl Fn(&str) -> Option<String>,
+) -> Option<(usize, String)> {
+    let rest = word.strip_prefix('~')?;
+    let slash = rest.find('/')?;
+    let home = home(&rest[..slash])?;
+    Some((slash + 2, format!("{}/", home.trim_end_matches('/'))))
+}
 
-        // start位置は pos と同じ (現在のカーソル位置から挿入)
-        assert_eq!(start, pos);
-        assert_contains(&res, "status");
-        assert_contains(&res, "commit");
+/// ユーザーのホームディレクトリ (空なら自分のもの)
+fn home_for(user: &str) -> Option<String> {
+    if user.is_empty() {
+        return env::home_dir().map(|home| home.display().to_string());
+    }
+    user_home(user)
+}
 
-        // 2. "git st" -> "status" が出るべき
-        let line = "git st";
-        let pos = line.len();
-        let (start, res) = helper.complete(line, pos, &ctx).unwrap();
+/// passwd データベースから他のユーザーのホームディレクトリを引く
+#[cfg(unix)]
+fn user_home(user: &str) -> Option<String> {
+    use std::ffi::{CStr, CString};
+    let name = CString::new(user).ok()?;
+    // SAFETY: getpwnam の戻り値は次の呼び出しまで有効なので、すぐに文字列へ複製する
+    unsafe {
+        let entry = libc::getpwnam(name.as_ptr());
+        if entry.is_null() || (*entry).pw_dir.is_null() {
+            return None;
+        }
+        Some(
+            CStr::from_ptr((*entry).pw_dir)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
 
-        // "git " の長さは4なので、4バイト目から置換開始
-        assert_eq!(start, 4);
-        assert_contains(&res, "status");
+#[cfg(not(unix))]
+fn user_home(_user: &str) -> Option<String> {
+    None
+}
 
-        // 3. "cargo b" -> "build", "bench" が出るべき
-        let line = "cargo b";
-        let pos = line.len();
-        let (_, res) = helper.complete(line, pos, &ctx).unwrap();
+/// 補完位置に適用するルールを探す純粋関数
+/// 直前のトークン (フラグ) を優先し、無ければサブコマンドで判定する
+fn find_extension_rule<'a>(
+    rules: &'a [ExtensionRule],
+    program: &str,
+    subcommand: Option<&str>,
+    prev_token: Option<&str>,
+) -> Option<&'a ExtensionRule> {
+    let for_program = || rules.iter().filter(move |r| r.command == program);
+    for_program()
+        .find(|r| prev_token == Some(r.after.as_str()))
+        .or_else(|| for_program().find(|r| subcommand == Some(r.after.as_str())))
+}
 
-        assert_contains(&res, "build");
-        assert_contains(&res, "bench");
-        // cargoの補完リストに "status" はないはず
-        assert_not_contains(&res, "status");
+/// ファイル候補を拡張子で絞り込む純粋関数 (ディレクトリは常に残す)
+fn filter_by_extension(candidates: Vec<Pair>, extensions: &[String]) -> Vec<Pair> {
+    candidates
+        .into_iter()
+        .filter(|pair| {
+            let name = pair.replacement.as_str();
+            if name.ends_with('/') || name.ends_with(std::path::MAIN_SEPARATOR) {
+                return true;
+            }
+            Path::new(name)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+        })
+        .collect()
+}
+
+impl WithHelper {
+    /// 色付けしてよいか (NO_COLOR でなく、コマンドの実行中でもない)
+    fn styling(&self) -> bool {
+        self.color && !self.suspended
+    }
+}
+
+impl Highlighter for WithHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        // 色付けする必要がない（空行など）場合はそのまま返す
+        if !self.styling() || line.trim().is_empty() {
+            return Cow::Borrowed(line);
+        }
+
+        // `!` 脱出はコンテキストを無視して、`!` の後ろをコンテキストなしとして色付けする
+        if let Some(offset) = escape_offset(line) {
+            let rest = self.highlight_line(&line[offset..], None);
+            return Cow::Owned(format!("{}{}", &line[..offset], rest));
+        }
+        Cow::Owned(self.highlight_line(line, self.context_program.as_deref()))
+    }
+
+    fn highlight_char(
+        &self,
+        _line: &str,
+        _pos: usize,
+        _kind: rustyline::highlight::CmdKind,
+    ) -> bool {
+        !self.suspended
+    }
+
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        _default: bool,
+    ) -> Cow<'b, str> {
+        // run_repl が組み立てた色付け済みプロンプトをそのまま使う
+        // (区切り記号が変わっても文字列をパースし直さずに済む)
+        if let Some(p) = &self.prompt
+            && p.plain == prompt
+            && self.styling()
+        {
+            return Cow::Borrowed(&p.styled);
+        }
+        Cow::Borrowed(prompt)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        if !self.styling() {
+            return Cow::Borrowed(hint);
+        }
+        Cow::Owned(format!("{}{}{}", STYLE_DIM, hint, STYLE_RESET))
+    }
+}
+
+/// 入力行の構文チェックの結果
+#[derive(Debug, PartialEq)]
+enum LineStatus {
+    Valid,
+    /// 未閉鎖のクォートや行末の "\\" (続きの行を待つ)
+    Incomplete,
+    /// 明確な構文エラー (Enter で確定させない)
+    Invalid(String),
+}
+
+/// 入力行のクォート・エスケープ・パイプの整合性を調べる純粋関数
+fn check_line(line: &str) -> LineStatus {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for c in line.chars() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (Some('"'), '"') => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            _ => {}
+        }
+    }
+    if quote.is_some() || escaped {
+        return LineStatus::Incomplete;
+    }
+
+    // パイプの前後にコマンドが無い (例: "| tee a", "git log |")
+    let trimmed = line.trim();
+    let dangling_pipe = (trimmed.starts_with('|') && !trimmed.starts_with("||"))
+        || (trimmed.ends_with('|') && !trimmed.ends_with("||") && !trimmed.ends_with("\\|"));
+    if dangling_pipe {
+        return LineStatus::Invalid("syntax error near unexpected token '|'".to_string());
+    }
+    LineStatus::Valid
+}
+
+impl Hinter for WithHelper {
+    type Hint = String;
+
+    /// 行末で入力中のサブコマンドの続きを薄く表示する (→ キーで確定できる)
+    /// よく使うサブコマンド > 入力が前方一致する最新の履歴 > 定義順の最初の候補 の順に選ぶ
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() || line.trim().is_empty() {
+            return None;
+        }
+        let (typed, candidates) = self.hint_subcommands(line).unwrap_or_default();
+        if let Some((first, true)) = candidates.first() {
+            return Some(first[typed.len()..].to_string());
+        }
+        if let Some(entry) = latest_history_match(line, ctx) {
+            return Some(entry[line.len()..].to_string());
+        }
+        candidates
+            .first()
+            .map(|(candidate, _)| candidate[typed.len()..].to_string())
+    }
+}
+
+/// 入力が前方一致する最新の履歴 (入力と同じものは除く)
+fn latest_history_match(line: &str, ctx: &Context<'_>) -> Option<String> {
+    let history = ctx.history();
+    if history.is_empty() {
+        return None;
+    }
+    let start = ctx.history_index().min(history.len() - 1);
+    let found = history
+        .starts_with(line, start, SearchDirection::Reverse)
+        .ok()??;
+    (found.entry.len() > line.len()).then(|| found.entry.into_owned())
+}
+
+impl Validator for WithHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(match check_line(ctx.input()) {
+            LineStatus::Valid => ValidationResult::Valid(None),
+            LineStatus::Incomplete => ValidationResult::Incomplete,
+            LineStatus::Invalid(msg) => {
+                ValidationResult::Invalid(Some(format!("  {}{}{}", COLOR_RED, msg, STYLE_RESET)))
+            }
+        })
+    }
+}
+
+/// `--help` の出力からサブコマンドらしき語を抽出する純粋関数
+/// 「インデント + 語 + 2つ以上の空白 + 説明」の形の行だけを拾い、誤抽出を減らす
+/// 例: "    build, b    Compile the current package" -> "build"
+fn parse_help_subcommands(output: &str) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+
+    for line in output.lines() {
+        // インデントされていない行 (見出しや Usage) は対象外
+        if !line.starts_with(' ') && !line.starts_with('\t') {
+            continue;
+        }
+        let trimmed = line.trim_start();
+
+        // 語の後に2つ以上の空白を挟んで説明が続くこと
+        let Some((head, description)) = trimmed.split_once("  ") else {
+            continue;
+        };
+        if description.trim().is_empty() {
+            continue;
+        }
+
+        // "build, b" のような別名付きは先頭の語を使う
+        let word = head.split([',', ' ']).next().unwrap_or("");
+        let is_word = word.chars().next().is_some_and(|c| c.is_ascii_lowercase())
+            && word
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+        if is_word && !result.iter().any(|w| w == word) {
+            result.push(word.to_string());
+        }
+    }
+    result
+}
+
+/// `<tool> --help` (失敗時は `<tool> help`) を実行してサブコマンドを学習する
+fn learn_subcommands(command: &str) -> Vec<String> {
+    for help_args in [["--help"], ["help"]] {
+        let output = process::Command::new(command)
+            .args(help_args)
+            .stdin(process::Stdio::null())
+            .output();
+
+        if let Ok(output) = output {
+            // ツールによってはヘルプを stderr に出すため両方を見る
+            let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+            text.push_str(&String::from_utf8_lossy(&output.stderr));
+
+            let subcommands = parse_help_subcommands(&text);
+            if !subcommands.is_empty() {
+                return subcommands;
+            }
+        }
+    }
+    vec![]
+}
+
+/// git のサブコマンドの直後に補完する候補を返す
+/// push/pull/fetch はリモート名、switch/merge/rebase はブランチ名、checkout はブランチ名とタグ名
+fn get_git_arg_candidates(subcommand: &str, cwd: &Path) -> Vec<String> {
+    match subcommand {
+        "push" | "pull" | "fetch" => list_git_remotes(cwd),
+        "switch" | "merge" | "rebase" => list_git_branches(cwd),
+        "checkout" => {
+            let mut candidates = list_git_branches(cwd);
+            candidates.extend(list_git_tags(cwd));
+            candidates
+        }
+        _ => vec![],
+    }
+}
+
+/// PATH の各ディレクトリにある実行ファイル名を重複なく名前順で返す
+/// 読めないディレクトリは飛ばす
+fn list_path_executables(path: &OsStr) -> Vec<String> {
+    let mut names = BTreeSet::new();
+    for dir in env::split_paths(path) {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if let Some(name) = executable_name(&entry.path()) {
+                names.insert(name);
+            }
+        }
+    }
+    names.into_iter().collect()
+}
+
+/// 実行権限のあるファイルならコマンド名を返す (シンボリックリンクは辿る)
+#[cfg(unix)]
+fn executable_name(path: &Path) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = std::fs::metadata(path).ok()?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return None;
+    }
+    path.file_name()?.to_str().map(String::from)
+}
+
+/// 実行できる拡張子のファイルなら拡張子を除いたコマンド名を返す
+#[cfg(not(unix))]
+fn executable_name(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    if !matches!(extension.as_str(), "exe" | "bat" | "cmd" | "com") || !path.is_file() {
+        return None;
+    }
+    path.file_stem()?.to_str().map(String::from)
+}
+
+/// `npm run` / `pnpm run` / `yarn run` の直後に補完するスクリプト名を返す
+/// 対象外のコマンドや cwd に package.json が無い (読めない) ときは None
+fn get_script_candidates(program: &str, subcommand: &str, cwd: &Path) -> Option<Vec<String>> {
+    let is_run = match program {
+        "npm" => matches!(subcommand, "run" | "run-script"),
+        "pnpm" | "yarn" => subcommand == "run",
+        _ => false,
+    };
+    if !is_run {
+        return None;
+    }
+    let content = std::fs::read_to_string(cwd.join("package.json")).ok()?;
+    Some(parse_package_scripts(&content))
+}
+
+/// package.json の `scripts` のキーを返す純粋関数 (不正な JSON や scripts が無ければ空)
+fn parse_package_scripts(content: &str) -> Vec<String> {
+    let Ok(package) = serde_json::from_str::<serde_json::Value>(content) else {
+        return vec![];
+    };
+    package
+        .get("scripts")
+        .and_then(|scripts| scripts.as_object())
+        .map(|scripts| scripts.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// 指定されたコマンドに対するサブコマンドのリストを返す
+/// 定義は src/subcommands.toml にあり、初回に読み込んだ表を引く
+pub fn get_subcommands(command: &str) -> Vec<&'static str> {
+    subcommands::builtin().subcommands(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustyline::Context;
+    use rustyline::history::{DefaultHistory, History};
+
+    // テスト用のヘルパー作成関数
+    fn create_helper(context_program: Option<&str>) -> WithHelper {
+        let mut helper = WithHelper::new(context_program.map(|s| s.to_string()));
+        // テストで外部コマンドを実行しないようにする
+        helper.learn_from_help = false;
+        helper
+    }
+
+    // 補完結果に特定の文字列が含まれているかチェックする
+    fn assert_contains(candidates: &[Pair], value: &str) {
+        let found = candidates.iter().any(|p| p.replacement == value);
+        let debug_list: Vec<&str> = candidates.iter().map(|p| p.replacement.as_str()).collect();
+        assert!(
+            found,
+            "Expected completion '{}' not found in {:?}",
+            value, debug_list
+        );
+    }
+
+    fn pair(s: &str) -> Pair {
+        Pair {
+            display: s.to_string(),
+            replacement: s.to_string(),
+        }
+    }
+
+    // 補完結果に特定の文字列が含まれて *いない* ことチェックする
+    fn assert_not_contains(candidates: &[Pair], value: &str) {
+        let found = candidates.iter().any(|p| p.replacement == value);
+        assert!(!found, "Unexpected completion '{}' found", value);
+    }
+
+    #[test]
+    fn test_context_mode_subcommand() {
+        // ケース: `with git` で起動中 (context_program = "git")
+        let helper = create_helper(Some("git"));
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        // 1. "st" と入力 -> "status" が出るべき (index 0)
+        let line = "st";
+        let pos = line.len();
+        let (start, res) = helper.complete(line, pos, &ctx).unwrap();
+
+        // start位置は 0 (先頭から置換)
+        assert_eq!(start, 0);
+        assert_contains(&res, "status");
+
+        // 2. "status " (スペースあり) -> サブコマンド補完は出ないべき (index 1)
+        // ※実際にはファイル補完が走るが、ここでは "status" 等が出ないことを確認
+        let line = "status ";
+        let pos = line.len();
+        let (_, res) = helper.complete(line, pos, &ctx).unwrap();
+
+        // 次の引数には "status" コマンドは提案されないはず
+        assert_not_contains(&res, "status");
+    }
+
+    #[test]
+    fn test_no_context_mode_subcommand() {
+        // ケース: `with` 単体起動 (context_program = None)
+        let helper = create_helper(None);
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        // 1. "git " (スペース直後) -> "status" 等が出るべき (index 1)
+        let line = "git ";
+        let pos = line.len();
+        let (start, res) = helper.complete(line, pos, &ctx).unwrap();
+
+        // start位置は pos と同じ (現在のカーソル位置から挿入)
+        assert_eq!(start, pos);
+        assert_contains(&res, "status");
+        assert_contains(&res, "commit");
+
+        // 2. "git st" -> "status" が出るべき
+        let line = "git st";
+        let pos = line.len();
+        let (start, res) = helper.complete(line, pos, &ctx).unwrap();
+
+        // "git " の長さは4なので、4バイト目から置換開始
+        assert_eq!(start, 4);
+        assert_contains(&res, "status");
+
+        // 3. "cargo b" -> "build", "bench" が出るべき
+        let line = "cargo b";
+        let pos = line.len();
+        let (_, res) = helper.complete(line, pos, &ctx).unwrap();
+
+        assert_contains(&res, "build");
+        assert_contains(&res, "bench");
+        // cargoの補完リストに "status" はないはず
+        assert_not_contains(&res, "status");
     }
 
     #[test]
@@ -609,6 +1491,28 @@ mod tests {
         assert!(!highlighted.contains(&format!("{}{}", COLOR_GREEN, "my_folder")));
     }
 
+    #[test]
+    fn test_highlight_unknown_command_in_red() {
+        let helper = create_helper(None);
+        let highlighted = helper.highlight("with-no-such-command --flag", 0);
+        assert!(highlighted.contains(&format!("{}with-no-such-command", COLOR_RED)));
+        assert!(!highlighted.contains(COLOR_CYAN));
+        // 結果は単語ごとに覚える
+        assert_eq!(
+            helper
+                .resolved_programs
+                .borrow()
+                .get("with-no-such-command"),
+            Some(&false)
+        );
+
+        // 内部コマンドや変数を含む単語は赤にしない
+        for line in ["cd src", "$EDITOR file"] {
+            let highlighted = helper.highlight(line, 0);
+            assert!(!highlighted.contains(COLOR_RED), "{}", line);
+        }
+    }
+
     #[test]
     fn test_highlight_flags() {
         // ケース: -v や --help (フラグ)
@@ -643,6 +1547,118 @@ mod tests {
         assert!(highlighted.contains(COLOR_CYAN));
     }
 
+    #[test]
+    fn test_escape_ignores_context_completion() {
+        let helper = create_helper(Some("git"));
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        // `! cargo b` は git ではなく cargo のサブコマンドを補完する
+        let line = "! cargo b";
+        let (start, candidates) = helper.complete(line, line.len(), &ctx).unwrap();
+        assert_eq!(start, 8);
+        assert_contains(&candidates, "build");
+        assert_not_contains(&candidates, "branch");
+
+        // `!cargo b` のように空白なしでも同じ
+        let line = "!cargo b";
+        let (start, candidates) = helper.complete(line, line.len(), &ctx).unwrap();
+        assert_eq!(start, 7);
+        assert_contains(&candidates, "build");
+        assert_not_contains(&candidates, "branch");
+    }
+
+    #[test]
+    fn test_escape_ignores_context_highlight() {
+        let helper = create_helper(Some("git"));
+        let highlighted = helper.highlight("! cargo build", 0);
+        assert_eq!(
+            highlighted,
+            format!(
+                "! {}cargo{} {}build{}",
+                helper.theme.context, STYLE_RESET, helper.theme.subcommand, STYLE_RESET
+            )
+        );
+    }
+
+    #[test]
+    fn test_escape_offset() {
+        assert_eq!(escape_offset("!ls"), Some(1));
+        assert_eq!(escape_offset("  ! cargo build"), Some(3));
+        assert_eq!(escape_offset("status"), None);
+    }
+
+    #[test]
+    fn test_custom_subcommands_from_config() {
+        let mut helper = create_helper(None);
+        let config = config::parse_config("[subcommands]\nmake = [\"build\", \"test\"]\n").unwrap();
+        helper.apply_config(&config);
+        // 表は初めて使うときまで組み立てない
+        assert!(helper.subcommands.get().is_none());
+
+        // 設定で追加したツールも補完・ハイライトの対象になる
+        assert_eq!(helper.completion_subcommands("make"), vec!["build", "test"]);
+        let highlighted = helper.highlight("make test", 0);
+        assert!(highlighted.contains(&format!("{}test{}", COLOR_GREEN, STYLE_RESET)));
+    }
+
+    #[test]
+    fn test_highlight_operators() {
+        let helper = create_helper(None);
+        let op = helper.theme.operator;
+
+        let highlighted = helper.highlight("git log | head", 0);
+        assert!(highlighted.contains(&format!(" {op}|{STYLE_RESET} head")));
+        // 演算子は空白が無くても隣の単語と分けて色付けする
+        let highlighted = helper.highlight("ls&&pwd >> out.txt", 0);
+        assert!(highlighted.contains(&format!("{op}&&{STYLE_RESET}pwd")));
+        assert!(highlighted.contains(&format!("{op}>>{STYLE_RESET}")));
+
+        // クォート内とエスケープされた記号は演算子ではない
+        let highlighted = helper.highlight("echo \"a|b\"", 0);
+        assert!(!highlighted.contains(op));
+        assert!(highlighted.contains(&format!("{}\"a|b\"{}", helper.theme.string, STYLE_RESET)));
+        assert!(!helper.highlight("echo a\\;b", 0).contains(op));
+    }
+
+    #[test]
+    fn test_highlight_variables() {
+        let helper = create_helper(None);
+        let var = helper.theme.variable;
+
+        let highlighted = helper.highlight("echo $HOME ${USER}x", 0);
+        assert!(highlighted.contains(&format!("{var}$HOME{STYLE_RESET}")));
+        assert!(highlighted.contains(&format!("{var}${{USER}}{STYLE_RESET}x")));
+
+        // シングルクォート内とエスケープは展開されないので装飾しない
+        let highlighted = helper.highlight("echo '$HOME' \\$HOME", 0);
+        assert!(!highlighted.contains(var));
+    }
+
+    #[test]
+    fn test_highlight_command_substitution() {
+        let helper = create_helper(None);
+        let var = helper.theme.variable;
+
+        // 中身は空白を含んでも1つの置換として扱い、コマンドとして色付けする
+        let highlighted = helper.highlight("echo $(git status)", 0);
+        assert!(highlighted.contains(&format!("{var}$({STYLE_RESET}")));
+        assert!(highlighted.contains(&format!("{}git{}", COLOR_CYAN, STYLE_RESET)));
+        assert!(highlighted.contains(&format!("{}status{}", COLOR_GREEN, STYLE_RESET)));
+        assert!(highlighted.ends_with(&format!("{var}){STYLE_RESET}")));
+    }
+
+    #[test]
+    fn test_highlight_unclosed_expansion_as_warning() {
+        let helper = create_helper(None);
+        let warning = helper.theme.warning;
+
+        let highlighted = helper.highlight("echo ${HOME", 0);
+        assert!(highlighted.contains(&format!("{warning}${{HOME{STYLE_RESET}")));
+        let highlighted = helper.highlight("echo $(git status", 0);
+        assert!(highlighted.contains(&format!("{warning}$(git status{STYLE_RESET}")));
+    }
+
     #[test]
     fn test_highlight_context_mode() {
         // ケース: with git 起動中に "status" と入力
@@ -657,4 +1673,739 @@ mod tests {
         // 親コマンド($1)の色(CYAN)は使われないはず
         assert!(!highlighted.contains(COLOR_CYAN));
     }
+
+    #[test]
+    fn test_cd_bookmark_completion() {
+        let mut helper = create_helper(Some("git"));
+        helper.bookmarks = vec![
+            ("work".to_string(), "~/work".to_string()),
+            ("web".to_string(), "/srv/www".to_string()),
+            ("dotfiles".to_string(), "~/.dotfiles".to_string()),
+        ];
+        helper.color = false;
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        let line = "cd @w";
+        let (start, candidates) = helper.complete(line, line.len(), &ctx).unwrap();
+        assert_eq!(start, 3);
+        assert_eq!(
+            candidates
+                .iter()
+                .map(|c| (c.replacement.as_str(), c.display.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("@work", "@work  ~/work"), ("@web", "@web  /srv/www")]
+        );
+
+        // `@` で始まる限りディレクトリ候補は混ぜない
+        let line = "cd @zzz";
+        let (_, candidates) = helper.complete(line, line.len(), &ctx).unwrap();
+        assert!(candidates.is_empty());
+    }
+
+    #[test]
+    fn test_highlight_prompt_without_context_label() {
+        // コンテキスト表示を省略したプロンプトでも組み立て済みの色付けを使う
+        let mut helper = create_helper(Some("git"));
+        let config = crate::config::PromptConfig {
+            context_display: crate::prompt::ContextDisplay::Auto,
+            ..Default::default()
+        };
+        let parts = crate::prompt::PromptParts {
+            dir: Some("."),
+            branch: Some("main"),
+            branch_state: None,
+            cmd: "git",
+            kube: None,
+            aws: None,
+            upstream: None,
+            stack: None,
+            status: None,
+        };
+        let prompt = crate::prompt::build_prompt(&parts, &config);
+        helper.prompt = Some(prompt.clone());
+
+        assert_eq!(helper.highlight_prompt("(.: main) > ", true), prompt.styled);
+        assert_eq!(helper.highlight_prompt("other> ", true), "other> ");
+    }
+
+    // --- git 引数補完のテスト ---
+
+    fn create_git_fixture() -> tempfile::TempDir {
+        let tmp = tempfile::tempdir().unwrap();
+        let git_dir = tmp.path().join(".git");
+        std::fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+        std::fs::create_dir_all(git_dir.join("refs/tags")).unwrap();
+        std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        std::fs::write(git_dir.join("refs/heads/main"), "abc\n").unwrap();
+        std::fs::write(git_dir.join("refs/tags/v1.0.0"), "abc\n").unwrap();
+        std::fs::write(git_dir.join("config"), "[remote \"origin\"]\n").unwrap();
+        tmp
+    }
+
+    #[test]
+    fn test_git_arg_candidates_push_remotes() {
+        let tmp = create_git_fixture();
+        let res = get_git_arg_candidates("push", tmp.path());
+        assert_eq!(res, vec!["origin"]);
+    }
+
+    #[test]
+    fn test_git_arg_candidates_checkout_branches_and_tags() {
+        let tmp = create_git_fixture();
+        let res = get_git_arg_candidates("checkout", tmp.path());
+        assert_eq!(res, vec!["main", "v1.0.0"]);
+    }
+
+    #[test]
+    fn test_git_arg_candidates_switch_merge_rebase_branches() {
+        let tmp = create_git_fixture();
+        std::fs::create_dir_all(tmp.path().join(".git/refs/heads/feature")).unwrap();
+        std::fs::write(tmp.path().join(".git/refs/heads/feature/login"), "abc\n").unwrap();
+        for sub in ["switch", "merge", "rebase"] {
+            assert_eq!(
+                get_git_arg_candidates(sub, tmp.path()),
+                vec!["feature/login", "main"],
+                "{}",
+                sub
+            );
+        }
+    }
+
+    #[test]
+    fn test_git_arg_candidates_cached_per_line() {
+        let tmp = create_git_fixture();
+        let helper = create_helper(Some("git"));
+        assert_eq!(
+            helper.cached_git_arg_candidates("switch", tmp.path(), "switch "),
+            vec!["main"]
+        );
+
+        // 同じ行の補完中は読み直さない
+        std::fs::write(tmp.path().join(".git/refs/heads/dev"), "abc\n").unwrap();
+        assert_eq!(
+            helper.cached_git_arg_candidates("switch", tmp.path(), "switch "),
+            vec!["main"]
+        );
+        // 行が変われば読み直す
+        assert_eq!(
+            helper.cached_git_arg_candidates("merge", tmp.path(), "merge "),
+            vec!["dev", "main"]
+        );
+    }
+
+    #[test]
+    fn test_git_arg_candidates_outside_repo() {
+        // git 管理外では候補なし (ファイル補完に落ちる)
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(get_git_arg_candidates("push", tmp.path()).is_empty());
+        assert!(get_git_arg_candidates("status", tmp.path()).is_empty());
+    }
+
+    // --- PATH のコマンド名補完のテスト ---
+
+    #[cfg(unix)]
+    fn write_executable(dir: &Path, name: &str, mode: u32) {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.join(name);
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_list_path_executables() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        write_executable(first.path(), "with-fake-tool", 0o755);
+        write_executable(first.path(), "with-not-executable", 0o644);
+        write_executable(second.path(), "with-fake-tool", 0o755);
+        write_executable(second.path(), "with-other", 0o700);
+        std::fs::create_dir(second.path().join("with-dir")).unwrap();
+
+        let path =
+            env::join_paths([first.path(), Path::new("/nonexistent/with"), second.path()]).unwrap();
+        // 実行権限のあるファイルだけを重複なく返す
+        assert_eq!(
+            list_path_executables(&path),
+            vec!["with-fake-tool", "with-other"]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_complete_first_word_from_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_executable(tmp.path(), "with-fake-tool", 0o755);
+        let helper = create_helper(None);
+        // 走査結果を差し替えて、実際の PATH に依存しないようにする
+        *helper.path_executables.borrow_mut() = Some(list_path_executables(tmp.path().as_os_str()));
+
+        let (start, res) = helper.complete_line("with-f", 6, None);
+        assert_eq!(start, 0);
+        let names: Vec<&str> = res.iter().map(|p| p.replacement.as_str()).collect();
+        assert_eq!(names, vec!["with-fake-tool"]);
+
+        // コンテキストありでは使わない
+        let (_, res) = create_helper(Some("git")).complete_line("with-f", 6, Some("git"));
+        assert_not_contains(&res, "with-fake-tool");
+    }
+
+    // --- package.json の scripts 補完のテスト ---
+
+    fn create_package_fixture() -> tempfile::TempDir {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(
+            tmp.path().join("package.json"),
+            r#"{
+  "name": "app",
+  "scripts": {
+    "dev": "vite",
+    "build": "vite build",
+    "test": "vitest",
+    "lint": "eslint ."
+  },
+  "devDependencies": { "vite": "^5.0.0" }
+}"#,
+        )
+        .unwrap();
+        tmp
+    }
+
+    #[test]
+    fn test_script_candidates_from_package_json() {
+        let tmp = create_package_fixture();
+        for (program, sub) in [
+            ("npm", "run"),
+            ("npm", "run-script"),
+            ("pnpm", "run"),
+            ("yarn", "run"),
+        ] {
+            assert_eq!(
+                get_script_candidates(program, sub, tmp.path()),
+                Some(vec![
+                    "build".to_string(),
+                    "dev".to_string(),
+                    "lint".to_string(),
+                    "test".to_string(),
+                ]),
+                "{} {}",
+                program,
+                sub
+            );
+        }
+
+        // run 以外や対象外のコマンドでは使わない
+        assert_eq!(get_script_candidates("npm", "install", tmp.path()), None);
+        assert_eq!(get_script_candidates("cargo", "run", tmp.path()), None);
+    }
+
+    #[test]
+    fn test_script_candidates_without_package_json() {
+        // package.json が無ければファイル補完に落ちる
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(get_script_candidates("npm", "run", tmp.path()), None);
+    }
+
+    #[test]
+    fn test_parse_package_scripts_invalid() {
+        assert!(parse_package_scripts(r#"{"name": "app"}"#).is_empty());
+        assert!(parse_package_scripts(r#"{"scripts": ["dev"]}"#).is_empty());
+        assert!(parse_package_scripts("{ not json").is_empty());
+    }
+
+    // --- --help からのサブコマンド学習のテスト ---
+
+    #[test]
+    fn test_parse_help_subcommands_cargo_style() {
+        let output = [
+            "Rust's package manager",
+            "",
+            "Usage: cargo [OPTIONS] [COMMAND]",
+            "",
+            "Options:",
+            "  -V, --version   Print version info and exit",
+            "  -v, --verbose   Use verbose output",
+            "",
+            "Commands:",
+            "    build, b    Compile the current package",
+            "    check, c    Analyze the current package",
+            "    new         Create a new cargo package",
+            "    ...         See all commands with --list",
+        ]
+        .join("\n");
+        assert_eq!(
+            parse_help_subcommands(&output),
+            vec!["build", "check", "new"]
+        );
+    }
+
+    #[test]
+    fn test_parse_help_subcommands_ignores_prose() {
+        // 説明文の折り返し (語の後に2連続空白が無い) や大文字始まりは拾わない
+        let output = [
+            "Usage: tool <command>",
+            "  run        Run the project",
+            "             continues the description here",
+            "  Examples:  see the docs",
+            "  this is a wrapped sentence of text",
+        ]
+        .join("\n");
+        assert_eq!(parse_help_subcommands(&output), vec!["run"]);
+    }
+
+    #[test]
+    fn test_parse_help_subcommands_empty() {
+        assert!(parse_help_subcommands("").is_empty());
+    }
+
+    #[test]
+    fn test_learned_subcommands_cached() {
+        // キャッシュ済みなら外部コマンドを実行せずに候補を返す
+        let mut helper = create_helper(None);
+        helper.learn_from_help = true;
+        helper
+            .learned_subcommands
+            .borrow_mut()
+            .insert("mytool".to_string(), vec!["deploy".to_string()]);
+
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+        let line = "mytool de";
+        let (start, res) = helper.complete(line, line.len(), &ctx).unwrap();
+        assert_eq!(start, 7);
+        assert_contains(&res, "deploy");
+    }
+
+    // --- 補完候補の一致部分の強調のテスト ---
+
+    #[test]
+    fn test_emphasize_matches() {
+        // 前方一致は先頭の範囲をまとめて囲む
+        assert_eq!(
+            emphasize_matches("status", &[0, 1]),
+            format!("{}st{}atus", STYLE_BOLD, STYLE_RESET)
+        );
+        // 飛び飛びの一致は文字ごとに囲む
+        assert_eq!(
+            emphasize_matches("commit", &[0, 2, 3]),
+            format!("{b}c{r}o{b}mm{r}it", b = STYLE_BOLD, r = STYLE_RESET)
+        );
+        assert_eq!(emphasize_matches("log", &[]), "log");
+    }
+
+    #[test]
+    fn test_complete_emphasizes_typed_prefix() {
+        let mut helper = create_helper(Some("git"));
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        let (_, res) = helper.complete("sta", 3, &ctx).unwrap();
+        let status = res.iter().find(|p| p.replacement == "status").unwrap();
+        assert_eq!(
+            status.display,
+            format!("{}sta{}tus", STYLE_BOLD, STYLE_RESET)
+        );
+
+        // NO_COLOR 相当なら装飾しない
+        helper.color = false;
+        let (_, res) = helper.complete("sta", 3, &ctx).unwrap();
+        assert!(res.iter().any(|p| p.display == "status"));
+    }
+
+    // --- 2階層目のハイライトのテスト ---
+
+    #[test]
+    fn test_complete_nested_subcommand() {
+        let helper = create_helper(None);
+        let (start, res) = helper.complete_line("git remote ", 11, None);
+        assert_eq!(start, 11);
+        for sub in ["add", "remove", "rename", "set-url"] {
+            assert_contains(&res, sub);
+        }
+
+        let (start, res) = helper.complete_line("docker compose u", 16, None);
+        assert_eq!(start, 15);
+        let names: Vec<&str> = res.iter().map(|p| p.replacement.as_str()).collect();
+        assert_eq!(names, vec!["up"]);
+
+        let (_, res) = helper.complete_line("kubectl rollout ", 16, None);
+        assert_contains(&res, "restart");
+        assert_contains(&res, "undo");
+    }
+
+    #[test]
+    fn test_complete_nested_subcommand_context_mode() {
+        let helper = create_helper(Some("git"));
+        let (start, res) = helper.complete_line("remote re", 9, Some("git"));
+        assert_eq!(start, 7);
+        let names: Vec<&str> = res.iter().map(|p| p.replacement.as_str()).collect();
+        assert_eq!(names, vec!["remove", "rename"]);
+
+        // 2階層目の定義が無いサブコマンドはファイル補完に落ちる
+        let (_, res) = create_helper(Some("cargo")).complete_line("build ", 6, Some("cargo"));
+        assert_not_contains(&res, "add");
+    }
+
+    #[test]
+    fn test_highlight_nested_subcommand() {
+        let helper = create_helper(None);
+        let highlighted = helper.highlight("git remote add origin", 0);
+        assert!(highlighted.contains(&format!("{}{}", COLOR_GREEN, "remote")));
+        assert!(highlighted.contains(&format!("{}{}", COLOR_BLUE, "add")));
+        // 3階層目以降 (origin) は無装飾
+        assert!(!highlighted.contains(&format!("{}{}", COLOR_BLUE, "origin")));
+    }
+
+    #[test]
+    fn test_highlight_nested_subcommand_context_mode() {
+        let helper = create_helper(Some("docker"));
+        let highlighted = helper.highlight("compose up -d", 0);
+        assert!(highlighted.contains(&format!("{}{}", COLOR_GREEN, "compose")));
+        assert!(highlighted.contains(&format!("{}{}", COLOR_BLUE, "up")));
+    }
+
+    #[test]
+    fn test_highlight_nested_undefined_fallback() {
+        // 2階層目が定義されていないサブコマンドは従来通り
+        let helper = create_helper(None);
+        let highlighted = helper.highlight("git commit add", 0);
+        assert!(!highlighted.contains(COLOR_BLUE));
+
+        // 定義はあっても未知の語なら色付けしない
+        let highlighted = helper.highlight("git remote unknown", 0);
+        assert!(!highlighted.contains(COLOR_BLUE));
+    }
+
+    // --- 最近使ったサブコマンドの優先表示のテスト ---
+
+    #[test]
+    fn test_order_by_preference() {
+        let candidates = vec![
+            "status".to_string(),
+            "commit".to_string(),
+            "add".to_string(),
+            "push".to_string(),
+        ];
+        let preferred = vec!["push".to_string(), "commit".to_string()];
+        assert_eq!(
+            order_by_preference(candidates.clone(), &preferred),
+            vec!["push", "commit", "add", "status"]
+        );
+        // 優先するものが無ければ元の並び
+        assert_eq!(order_by_preference(candidates.clone(), &[]), candidates);
+    }
+
+    #[test]
+    fn test_case_insensitive_subcommand_completion() {
+        // コンテキストあり: 大文字で入力しても候補の表記 (小文字) で補完する
+        let helper = create_helper(Some("git"));
+        let (start, res) = helper.complete_line("ST", 2, Some("git"));
+        assert_eq!(start, 0);
+        assert_contains(&res, "status");
+        assert_contains(&res, "stash");
+        assert_not_contains(&res, "ST");
+
+        let (_, res) = helper.complete_line("cHeCkO", 6, Some("git"));
+        let names: Vec<&str> = res.iter().map(|p| p.replacement.as_str()).collect();
+        assert_eq!(names, vec!["checkout"]);
+
+        // コンテキストなし
+        let helper = create_helper(None);
+        let (start, res) = helper.complete_line("cargo BU", 8, None);
+        assert_eq!(start, 6);
+        assert_contains(&res, "build");
+    }
+
+    #[test]
+    fn test_prefix_match_ignores_case() {
+        assert_eq!(prefix_match("status", "ST"), Some(vec![0, 1]));
+        assert_eq!(prefix_match("status", "sta"), Some(vec![0, 1, 2]));
+        assert_eq!(prefix_match("status", "stx"), None);
+        assert_eq!(prefix_match("st", "sta"), None);
+        assert_eq!(fuzzy_match("commit", "CMT"), Some(vec![0, 2, 5]));
+    }
+
+    #[test]
+    fn test_fuzzy_subcommand_completion() {
+        // 既定の前方一致では部分列に一致しない
+        let helper = create_helper(Some("git"));
+        let (_, res) = helper.complete_line("stt", 3, Some("git"));
+        assert_not_contains(&res, "status");
+
+        let mut helper = create_helper(Some("git"));
+        helper.fuzzy = true;
+        let (_, res) = helper.complete_line("stt", 3, Some("git"));
+        assert_contains(&res, "status");
+        let (_, res) = helper.complete_line("cmt", 3, Some("git"));
+        assert_contains(&res, "commit");
+    }
+
+    #[test]
+    fn test_match_candidates_ranks_prefix_first() {
+        let candidates: Vec<String> = ["sort", "restore", "status", "stash"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            match_candidates(candidates.clone(), "st", false),
+            vec!["status", "stash"]
+        );
+        // 前方一致の後に、一致した文字が詰まっている順で部分列の候補を並べる
+        assert_eq!(
+            match_candidates(candidates, "st", true),
+            vec!["status", "stash", "restore", "sort"]
+        );
+        assert_eq!(fuzzy_match("commit", "cmt"), Some(vec![0, 2, 5]));
+        assert_eq!(fuzzy_match("commit", "ctm"), None);
+    }
+
+    #[test]
+    fn test_complete_prefers_recent_subcommands() {
+        let mut helper = create_helper(Some("git"));
+        helper.preferred_subcommands.insert(
+            "git".to_string(),
+            vec!["push".to_string(), "pull".to_string()],
+        );
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        let (_, res) = helper.complete("p", 1, &ctx).unwrap();
+        let names: Vec<&str> = res.iter().map(|p| p.replacement.as_str()).collect();
+        assert_eq!(names.first().copied(), Some("push"));
+        assert_eq!(names.get(1).copied(), Some("pull"));
+    }
+
+    // --- ヒント (ゴーストテキスト) のテスト ---
+
+    #[test]
+    fn test_hint_completes_subcommand() {
+        let mut helper = create_helper(None);
+        let mut history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        assert_eq!(helper.hint("git sta", 7, &ctx), Some("sh".to_string()));
+        // よく使うサブコマンドを優先する
+        helper
+            .preferred_subcommands
+            .insert("git".to_string(), vec!["status".to_string()]);
+        assert_eq!(helper.hint("git sta", 7, &ctx), Some("tus".to_string()));
+        // 入力し終えた単語・単語の区切りではヒントを出さない
+        assert_eq!(helper.hint("git status", 10, &ctx), None);
+        assert_eq!(helper.hint("git ", 4, &ctx), None);
+        assert_eq!(helper.hint("", 0, &ctx), None);
+
+        // 候補が無ければ前方一致する最新の履歴を使う
+        history.add("git log --oneline").unwrap();
+        let ctx = Context::new(&history);
+        assert_eq!(
+            helper.hint("git log -", 9, &ctx),
+            Some("-oneline".to_string())
+        );
+    }
+
+    #[test]
+    fn test_hint_only_at_end_of_line() {
+        let helper = create_helper(Some("git"));
+        let history = DefaultHistory::new();
+        let ctx = Context::new(&history);
+
+        assert_eq!(helper.hint("sta", 3, &ctx), Some("sh".to_string()));
+        assert_eq!(helper.hint("sta", 2, &ctx), None);
+        assert_eq!(helper.hint("sta --short", 3, &ctx), None);
+    }
+
+    #[test]
+    fn test_highlight_disabled_without_color() {
+        let mut helper = create_helper(Some("git"));
+        helper.color = false;
+        assert_eq!(helper.highlight("commit -m 'x'", 0), "commit -m 'x'");
+    }
+
+    #[test]
+    fn test_no_color_emits_no_escape_sequences() {
+        let mut helper = create_helper(Some("git"));
+        helper.color = false;
+        let prompt = crate::prompt::build_prompt(
+            &crate::prompt::PromptParts {
+                dir: Some("."),
+                branch: Some("main"),
+                branch_state: None,
+                cmd: "git",
+                kube: None,
+                aws: None,
+                upstream: None,
+                stack: None,
+                status: None,
+            },
+            &Default::default(),
+        );
+        helper.prompt = Some(prompt.clone());
+
+        for line in [
+            "commit -m 'x' | grep $HOME && log ${X}",
+            "!no-such-program-for-with --flag \"$(date)\"",
+            "push origin > out.txt",
+        ] {
+            let highlighted = helper.highlight(line, 0);
+            assert!(matches!(highlighted, Cow::Borrowed(_)));
+            assert!(!highlighted.contains('\x1b'), "{:?}", highlighted);
+        }
+        assert!(matches!(
+            helper.highlight_prompt(&prompt.plain, true),
+            Cow::Borrowed(p) if p == prompt.plain
+        ));
+        assert!(!helper.highlight_hint("tus").contains('\x1b'));
+    }
+
+    #[test]
+    fn test_color_allowed() {
+        assert!(color_allowed(false, Some("xterm-256color"), true));
+        assert!(color_allowed(false, None, true));
+        assert!(!color_allowed(true, Some("xterm-256color"), true));
+        assert!(!color_allowed(false, Some("dumb"), true));
+        // パイプやファイルに出すときも色を付けない
+        assert!(!color_allowed(false, Some("xterm"), false));
+    }
+
+    #[test]
+    fn test_highlight_disabled_while_suspended() {
+        let mut helper = create_helper(Some("git"));
+        helper.suspended = true;
+        assert_eq!(helper.highlight("commit -m 'x'", 0), "commit -m 'x'");
+        assert_eq!(helper.highlight_hint("ommit"), "ommit");
+        assert!(!helper.highlight_char("commit", 0, rustyline::highlight::CmdKind::Other));
+
+        helper.suspended = false;
+        assert_ne!(helper.highlight("commit -m 'x'", 0), "commit -m 'x'");
+    }
+
+    // --- `~` を含むパスの補完のテスト ---
+
+    #[test]
+    fn test_split_tilde_prefix() {
+        let home = |user: &str| match user {
+            "" => Some("/home/me".to_string()),
+            "alice" => Some("/home/alice/".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            split_tilde_prefix("~/src", home),
+            Some((2, "/home/me/".to_string()))
+        );
+        assert_eq!(
+            split_tilde_prefix("~alice/src", home),
+            Some((7, "/home/alice/".to_string()))
+        );
+        // ユーザー名の入力中や未知のユーザー、`~` で始まらない単語は展開しない
+        assert_eq!(split_tilde_prefix("~ali", home), None);
+        assert_eq!(split_tilde_prefix("~bob/src", home), None);
+        assert_eq!(split_tilde_prefix("src/~", home), None);
+    }
+
+    #[test]
+    fn test_complete_tilde_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("projects")).unwrap();
+        std::fs::write(tmp.path().join("profile.txt"), "").unwrap();
+        let home_dir = tmp.path().display().to_string();
+        let home = |user: &str| (user.is_empty() || user == "alice").then(|| home_dir.clone());
+
+        let mut helper = create_helper(None);
+        helper.completion_timeout = Duration::ZERO;
+
+        // `~` の形のまま、ファイル名の部分だけを置き換える
+        let line = "cat ~/pro";
+        let (start, files) = helper
+            .complete_tilde_files(line, line.len(), 4, home)
+            .unwrap();
+        assert_eq!(start, 4);
+        assert_contains(&files, "~/projects/");
+        assert_contains(&files, "~/profile.txt");
+
+        let line = "cat ~alice/proj";
+        let (start, files) = helper
+            .complete_tilde_files(line, line.len(), 4, home)
+            .unwrap();
+        assert_eq!(start, 4);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].replacement, "~alice/projects/");
+
+        // 展開できなければ通常のファイル補完に任せる
+        assert!(
+            helper
+                .complete_tilde_files("cat ~bob/p", 10, 4, home)
+                .is_none()
+        );
+
+        // expand_tilde なら展開後のパスで置き換える
+        helper.expand_tilde = true;
+        let line = "cat ~/proj";
+        let (start, files) = helper
+            .complete_tilde_files(line, line.len(), 4, home)
+            .unwrap();
+        assert_eq!(start, 4);
+        assert_eq!(files[0].replacement, format!("{}/projects/", home_dir));
+    }
+
+    // --- 拡張子による絞り込みのテスト ---
+
+    #[test]
+    fn test_find_extension_rule() {
+        let rules = default_extension_rules();
+
+        // フラグの直後
+        let rule = find_extension_rule(&rules, "cargo", Some("run"), Some("--manifest-path"));
+        assert_eq!(rule.map(|r| r.after.as_str()), Some("--manifest-path"));
+
+        // サブコマンドの後ならどの位置でも
+        let rule = find_extension_rule(&rules, "git", Some("apply"), Some("--check"));
+        assert_eq!(rule.map(|r| r.after.as_str()), Some("apply"));
+
+        // 該当なし -> 通常のファイル補完
+        assert!(find_extension_rule(&rules, "git", Some("add"), Some("add")).is_none());
+        assert!(find_extension_rule(&rules, "cargo", Some("run"), Some("run")).is_none());
+    }
+
+    #[test]
+    fn test_filter_by_extension_keeps_dirs() {
+        let candidates = vec![
+            pair("fix.patch"),
+            pair("notes.txt"),
+            pair("src/"),
+            pair("OLD.DIFF"),
+        ];
+        let filtered = filter_by_extension(candidates, &["patch".to_string(), "diff".to_string()]);
+        assert_contains(&filtered, "fix.patch");
+        assert_contains(&filtered, "src/");
+        assert_contains(&filtered, "OLD.DIFF");
+        assert_not_contains(&filtered, "notes.txt");
+    }
+
+    // --- 構文チェックのテスト ---
+
+    #[test]
+    fn test_check_line_valid() {
+        assert_eq!(check_line("commit -m 'fix bug'"), LineStatus::Valid);
+        assert_eq!(check_line("echo \"it's\""), LineStatus::Valid);
+        assert_eq!(check_line("log | tee out.txt"), LineStatus::Valid);
+        assert_eq!(check_line(""), LineStatus::Valid);
+    }
+
+    #[test]
+    fn test_check_line_incomplete() {
+        // 未閉鎖のクォートと行末のエスケープは続きの入力を待つ
+        assert_eq!(check_line("commit -m 'fix"), LineStatus::Incomplete);
+        assert_eq!(check_line("echo \"a"), LineStatus::Incomplete);
+        assert_eq!(check_line("build \\"), LineStatus::Incomplete);
+        // 複数行にまたがって閉じれば有効
+        assert_eq!(check_line("commit -m 'line1\nline2'"), LineStatus::Valid);
+    }
+
+    #[test]
+    fn test_check_line_invalid() {
+        assert!(matches!(check_line("| tee a"), LineStatus::Invalid(_)));
+        assert!(matches!(check_line("log |"), LineStatus::Invalid(_)));
+        assert_eq!(check_line("echo '|'"), LineStatus::Valid);
+    }
 }